@@ -0,0 +1,116 @@
+//! Latency and throughput the mux adds to `request_identities` and `sign`,
+//! against in-process mock targets.
+//!
+//! Run with `cargo bench`.  Each latency case is measured both directly
+//! against a target and through the mux, so the overhead is the difference.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ssh_agent_lib::{agent::Session, client::Client, proto::SignRequest};
+use tokio::net::UnixStream;
+use tokio::runtime::Runtime;
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+const CLIENTS: u64 = 16;
+
+fn sign_request() -> SignRequest {
+    SignRequest {
+        pubkey: key(2),
+        data: vec![0; 64],
+        flags: 0,
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Case {
+    RequestIdentities,
+    Sign,
+}
+
+async fn run(client: &mut Client<UnixStream>, case: Case, iterations: u64) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        match case {
+            Case::RequestIdentities => drop(client.request_identities().await.unwrap()),
+            Case::Sign => drop(client.sign(sign_request()).await.unwrap()),
+        }
+    }
+    start.elapsed()
+}
+
+fn latency(c: &mut Criterion, runtime: &Runtime, case: Case, direct: &Path, mux: &Path) {
+    let mut direct_client = runtime.block_on(connect(direct));
+    let mut mux_client = runtime.block_on(connect(mux));
+    // Populates the mux's key index for the sign case.
+    runtime.block_on(mux_client.request_identities()).unwrap();
+
+    let mut group = c.benchmark_group(format!("{case:?}"));
+    group.bench_function("direct", |b| {
+        b.iter_custom(|iterations| runtime.block_on(run(&mut direct_client, case, iterations)))
+    });
+    group.bench_function("mux", |b| {
+        b.iter_custom(|iterations| runtime.block_on(run(&mut mux_client, case, iterations)))
+    });
+    group.finish();
+}
+
+/// Signatures made by [`CLIENTS`] clients at once, each signing once per
+/// iteration.
+fn throughput(c: &mut Criterion, runtime: &Runtime, mux: &Path) {
+    let mut group = c.benchmark_group("sign throughput");
+    group.throughput(Throughput::Elements(CLIENTS));
+    group.bench_function(BenchmarkId::new("clients", CLIENTS), |b| {
+        b.iter_custom(|iterations| {
+            runtime.block_on(async {
+                let mut clients = Vec::new();
+                for _ in 0..CLIENTS {
+                    let mut client = connect(mux).await;
+                    client.request_identities().await.unwrap();
+                    clients.push(client);
+                }
+                let start = Instant::now();
+                let signing: Vec<_> = clients
+                    .into_iter()
+                    .map(|mut client| {
+                        tokio::spawn(async move { run(&mut client, Case::Sign, iterations).await })
+                    })
+                    .collect();
+                for signing in signing {
+                    signing.await.unwrap();
+                }
+                start.elapsed()
+            })
+        })
+    });
+    group.finish();
+}
+
+fn mux(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let _entered = runtime.enter();
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(2), "two");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    latency(
+        c,
+        &runtime,
+        Case::RequestIdentities,
+        &mock2.socket(&dir),
+        &mux,
+    );
+    latency(c, &runtime, Case::Sign, &mock2.socket(&dir), &mux);
+    throughput(c, &runtime, &mux);
+}
+
+criterion_group!(benches, mux);
+criterion_main!(benches);
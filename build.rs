@@ -0,0 +1,14 @@
+//! Generates the gRPC admin service from its protobuf description, compiled
+//! with protox so that building needs no `protoc`.  The service is only
+//! served on Unix, so nothing is generated for other targets.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    const PROTO: &str = "src/admin/admin.proto";
+    println!("cargo:rerun-if-changed={PROTO}");
+    if std::env::var_os("CARGO_CFG_UNIX").is_none() {
+        return Ok(());
+    }
+    let descriptors = protox::compile([PROTO], ["src/admin"])?;
+    tonic_prost_build::configure().compile_fds(descriptors)?;
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! An HTTP endpoint for looking into a running mux with e.g. curl, without
+//! a client of the agent protocol.
+//!
+//! `GET /status`, `/targets`, `/keys` and `/activity`, the last signatures
+//! made and denied, are answered with JSON, and `POST /reload` reloads the
+//! config as SIGHUP does.  `GET /` is a page showing the rest, refreshed
+//! every few seconds.  Anyone who can connect may use them, so the endpoint
+//! only listens on a Unix socket or a loopback address.  Each connection is
+//! one request, closed once answered.
+//!
+//! [`grpc`] serves the same, and more, to orchestration tooling.
+
+#[cfg(unix)]
+pub mod grpc;
+
+use std::io;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::audit::json_string;
+use crate::config::{Config, ConfigError};
+use crate::{events, export, logging, MuxState};
+
+/// Longest request head read, beyond which requests are refused.
+const MAX_HEAD: usize = 8192;
+
+const DASHBOARD: &str = include_str!("admin/dashboard.html");
+
+type Reload = dyn Fn() -> Result<Config, ConfigError> + Send + Sync;
+
+/// Answers requests on `listener` about `state` until it fails, reloading
+/// the config with `reload`.
+pub async fn serve(
+    listener: service_binding::Listener,
+    state: MuxState,
+    reload: impl Fn() -> Result<Config, ConfigError> + Send + Sync + 'static,
+) -> io::Result<()> {
+    let reload: Arc<Reload> = Arc::new(reload);
+    events::keep_recent_signatures();
+    match listener {
+        #[cfg(unix)]
+        service_binding::Listener::Unix(listener) => {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::UnixListener::from_std(listener)?;
+            log::info!("Serving the admin endpoint; socket = {listener:?}");
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(respond(stream, state.clone(), reload.clone()));
+            }
+        }
+        service_binding::Listener::Tcp(listener) => {
+            listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            log::info!("Serving the admin endpoint; socket = {listener:?}");
+            loop {
+                let (stream, _) = listener.accept().await?;
+                tokio::spawn(respond(stream, state.clone(), reload.clone()));
+            }
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(io::Error::other("Unsupported type of a listener.")),
+    }
+}
+
+async fn respond(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    state: MuxState,
+    reload: Arc<Reload>,
+) {
+    let response = match read_head(&mut stream).await {
+        Ok(Some(head)) => {
+            let mut words = head.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some(method), Some(target)) => {
+                    let path = target.split('?').next().unwrap_or_default();
+                    log::debug!("Admin request: {method} {path}");
+                    route(method, path, &state, &*reload).await
+                }
+                _ => error("400 Bad Request", "malformed request line"),
+            }
+        }
+        Ok(None) => error("431 Request Header Fields Too Large", "request too large"),
+        Err(e) => {
+            log::debug!("Admin client went away: {e}");
+            return;
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        log::debug!("Admin client went away: {e}");
+    }
+    let _ = stream.shutdown().await;
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(json: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/json",
+            body: json + "\n",
+        }
+    }
+}
+
+async fn route(method: &str, path: &str, state: &MuxState, reload: &Reload) -> Response {
+    match (method, path) {
+        ("GET", "/") => Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: DASHBOARD.to_owned(),
+        },
+        ("GET", "/status") => Response::ok(state.status_json()),
+        ("GET", "/targets") => Response::ok(state.targets_json()),
+        ("GET", "/keys") => match state.list_identities().await {
+            Ok(identities) => Response::ok(export::identities_json(&identities)),
+            Err(e) => error("502 Bad Gateway", &e.to_string()),
+        },
+        ("GET", "/activity") => {
+            let recent = events::recent_signatures();
+            Response::ok(format!("[{}]", recent.join(",")))
+        }
+        ("POST", "/reload") => match apply_reload(state, reload) {
+            Ok(()) => Response::ok("{\"reloaded\":true}".to_owned()),
+            Err(e) => error("422 Unprocessable Entity", &e.to_string()),
+        },
+        (_, "/" | "/status" | "/targets" | "/keys" | "/activity" | "/reload") => {
+            error("405 Method Not Allowed", "method not allowed")
+        }
+        _ => error("404 Not Found", "not found"),
+    }
+}
+
+/// Reloads the config with `reload` and applies it to `state`, keeping the
+/// current one if it fails.
+fn apply_reload(state: &MuxState, reload: &Reload) -> Result<(), ConfigError> {
+    match reload() {
+        Ok(config) => {
+            log::info!("Reloading the config");
+            logging::set_levels(&config.log_levels);
+            logging::set_nicknames(&config.keys);
+            state.reload(&config);
+            Ok(())
+        }
+        Err(e) => {
+            log::error!("Failed to reload the config, keeping the current one: {e}");
+            Err(e)
+        }
+    }
+}
+
+fn error(status: &'static str, message: &str) -> Response {
+    Response {
+        status,
+        ..Response::ok(format!("{{\"error\":{}}}", json_string(message)))
+    }
+}
+
+/// Reads up to the end of the request head, which is all there is to
+/// requests of this endpoint.  `None` if it is too long.
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|end| end == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Ok(None);
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    Ok(Some(String::from_utf8_lossy(&head).into_owned()))
+}
@@ -0,0 +1,187 @@
+//! A gRPC service for managing running muxes from orchestration tooling,
+//! with the typed methods of `admin.proto`: the status, targets, keys and
+//! key policies, reloading the config, scanning target directories again
+//! and unlocking hidden targets.  Like the HTTP endpoint, anyone who can
+//! connect may use it, so it only listens on a Unix socket.
+
+use std::io;
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ssh_key::{HashAlg, PublicKey};
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::{Request, Response, Status};
+
+use super::{apply_reload, Reload};
+use crate::config::{Config, ConfigError};
+use crate::error::Error;
+use crate::{logging, metrics, KeyState, MuxState, TargetStatus};
+
+/// The messages of the service, with its client and server.
+pub mod proto {
+    tonic::include_proto!("ssh_agent_mux.admin.v1");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+
+/// Answers calls on `listener` about `state` until it fails, reloading the
+/// config with `reload`.
+pub async fn serve(
+    listener: UnixListener,
+    state: MuxState,
+    reload: impl Fn() -> Result<Config, ConfigError> + Send + Sync + 'static,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    log::info!("Serving the gRPC admin service; socket = {listener:?}");
+    let service = Service {
+        state,
+        reload: Arc::new(reload),
+    };
+    tonic::transport::Server::builder()
+        .add_service(AdminServer::new(service))
+        .serve_with_incoming(UnixListenerStream::new(listener))
+        .await
+        .map_err(io::Error::other)
+}
+
+struct Service {
+    state: MuxState,
+    reload: Arc<Reload>,
+}
+
+#[tonic::async_trait]
+impl Admin for Service {
+    async fn get_status(
+        &self,
+        _: Request<proto::GetStatusRequest>,
+    ) -> Result<Response<proto::GetStatusResponse>, Status> {
+        let targets = self.state.targets();
+        Ok(Response::new(proto::GetStatusResponse {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            healthy_targets: count(targets.iter().filter(|target| target.healthy).count()),
+            targets: targets.into_iter().map(target).collect(),
+            sessions: count(self.state.sessions()),
+            keys: self.state.keys().map(count),
+            keys_used: count(metrics::key_usage().len()),
+        }))
+    }
+
+    async fn list_targets(
+        &self,
+        _: Request<proto::ListTargetsRequest>,
+    ) -> Result<Response<proto::ListTargetsResponse>, Status> {
+        Ok(Response::new(proto::ListTargetsResponse {
+            targets: self.state.targets().into_iter().map(target).collect(),
+        }))
+    }
+
+    async fn list_keys(
+        &self,
+        _: Request<proto::ListKeysRequest>,
+    ) -> Result<Response<proto::ListKeysResponse>, Status> {
+        let identities = self
+            .state
+            .list_identities()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let keys = identities
+            .into_iter()
+            .map(|(identity, targets)| proto::Key {
+                public_key: PublicKey::new(identity.pubkey.clone(), &identity.comment)
+                    .to_openssh()
+                    .unwrap_or_default(),
+                fingerprint: identity.pubkey.fingerprint(HashAlg::Sha256).to_string(),
+                nickname: logging::key_nickname(&identity.pubkey),
+                algorithm: identity.pubkey.algorithm().as_str().to_owned(),
+                comment: identity.comment,
+                targets,
+            })
+            .collect();
+        Ok(Response::new(proto::ListKeysResponse { keys }))
+    }
+
+    async fn reload(
+        &self,
+        _: Request<proto::ReloadRequest>,
+    ) -> Result<Response<proto::ReloadResponse>, Status> {
+        apply_reload(&self.state, &*self.reload)
+            .map(|()| Response::new(proto::ReloadResponse {}))
+            .map_err(|e| Status::invalid_argument(e.to_string()))
+    }
+
+    async fn rescan_targets(
+        &self,
+        _: Request<proto::RescanTargetsRequest>,
+    ) -> Result<Response<proto::RescanTargetsResponse>, Status> {
+        self.state.rescan_targets();
+        Ok(Response::new(proto::RescanTargetsResponse {}))
+    }
+
+    async fn unlock_target(
+        &self,
+        request: Request<proto::UnlockTargetRequest>,
+    ) -> Result<Response<proto::UnlockTargetResponse>, Status> {
+        let request = request.into_inner();
+        let duration = Duration::from_secs(request.duration_seconds);
+        match self.state.unlock_target(&request.target, duration) {
+            Ok(()) => Ok(Response::new(proto::UnlockTargetResponse {})),
+            Err(e @ Error::UnknownTarget(_)) => Err(Status::not_found(e.to_string())),
+            Err(e @ Error::NotHidden(_)) => Err(Status::failed_precondition(e.to_string())),
+            Err(e) => Err(Status::internal(e.to_string())),
+        }
+    }
+
+    async fn list_key_policies(
+        &self,
+        _: Request<proto::ListKeyPoliciesRequest>,
+    ) -> Result<Response<proto::ListKeyPoliciesResponse>, Status> {
+        Ok(Response::new(proto::ListKeyPoliciesResponse {
+            policies: self.state.key_policies().into_iter().map(policy).collect(),
+        }))
+    }
+}
+
+fn target(target: TargetStatus) -> proto::Target {
+    proto::Target {
+        name: target.name,
+        healthy: target.healthy,
+        failed_seconds_ago: target.failed.map(|failed| failed.as_secs()),
+        priority: target.priority,
+        sign_latency_ms: target
+            .sign_latency
+            .map(|latency| latency.as_millis() as u64),
+        hidden: target.hidden,
+    }
+}
+
+fn policy(state: KeyState) -> proto::KeyPolicy {
+    let config = state.config;
+    proto::KeyPolicy {
+        fingerprint: config.fingerprint.to_string(),
+        nickname: config.nickname,
+        max_signatures_per_hour: config.max_signatures_per_hour,
+        signatures_this_hour: count(state.signatures_this_hour),
+        cooldown_seconds: config.cooldown.map(|cooldown| cooldown.as_secs()),
+        last_signed_seconds_ago: state
+            .last_signed
+            .map(|last_signed| last_signed.elapsed().as_secs()),
+        allowed_host_keys: config
+            .allowed_host_keys
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        require_session_bind: config.require_session_bind,
+        allowed_times: config
+            .allowed_times
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+        exec: config.exec.map(|program| program.display().to_string()),
+    }
+}
+
+fn count(n: usize) -> u32 {
+    n.try_into().unwrap_or(u32::MAX)
+}
@@ -0,0 +1,73 @@
+//! One-shot alerts on error rates, so that a target degrading silently,
+//! e.g. failing every few requests while still answering the rest, gets
+//! noticed.
+//!
+//! A target failing at the transport level as many times as the threshold
+//! within the window is notified of once, as an `error_rate` audit event,
+//! and again only after its failures within the window fell below the
+//! threshold.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::audit;
+use crate::config::AlertsConfig;
+
+/// Window failures are counted within, unless configured.
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+static TARGET_FAILURES: OnceLock<(usize, Duration)> = OnceLock::new();
+
+/// Alerts on targets failing as often as `config` says from now on.
+pub fn set_thresholds(config: &AlertsConfig) {
+    if let Some(failures) = config.target_failures {
+        let window = config.window.unwrap_or(DEFAULT_WINDOW);
+        let _ = TARGET_FAILURES.set((failures, window));
+    }
+}
+
+/// Recent failures of a target, and whether they were alerted of.
+#[derive(Default)]
+pub(crate) struct FailureRate(Mutex<Failures>);
+
+#[derive(Default)]
+struct Failures {
+    /// The last ones within the window, up to the threshold.
+    times: VecDeque<Instant>,
+    alerted: bool,
+}
+
+impl FailureRate {
+    /// Counts a failure of `target`, alerting of it if that makes the
+    /// threshold.
+    pub(crate) fn failed(&self, target: &str) {
+        let Some(&(threshold, window)) = TARGET_FAILURES.get() else {
+            return;
+        };
+        let now = Instant::now();
+        let mut failures = self.0.lock().unwrap();
+        failures.times.push_back(now);
+        if failures.times.len() > threshold {
+            failures.times.pop_front();
+        }
+        while failures
+            .times
+            .front()
+            .is_some_and(|&time| now.duration_since(time) > window)
+        {
+            failures.times.pop_front();
+        }
+        if failures.times.len() < threshold {
+            failures.alerted = false;
+        } else if !failures.alerted {
+            failures.alerted = true;
+            drop(failures);
+            audit::record(audit::Event::ErrorRate {
+                target,
+                failures: threshold,
+                window,
+            });
+        }
+    }
+}
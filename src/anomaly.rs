@@ -0,0 +1,150 @@
+//! Usage anomalies: signatures unlike those a key made so far.
+//!
+//! A baseline of each key's signatures is learned while the mux runs: how
+//! many it makes a minute, the hosts its sessions were bound to and the
+//! hours of the day it signs at.  Once a key has made
+//! [`BASELINE_SIGNATURES`], signatures far above its usual rate or toward a
+//! host it never signed toward are anomalies, and once it has signed for a
+//! day, so are signatures at an hour it never signed at.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ssh_key::{public::KeyData, Fingerprint, HashAlg};
+
+use crate::policy;
+
+/// Signatures a key makes before its usage is judged.
+const BASELINE_SIGNATURES: u64 = 10;
+/// How long a key signs for before the hours it signs at are judged.
+const HOURS_BASELINE: Duration = Duration::from_secs(24 * 60 * 60);
+const MINUTE: Duration = Duration::from_secs(60);
+/// Signatures in a minute are a spike beyond this many times a key's
+/// average...
+const SPIKE_FACTOR: u64 = 10;
+/// ...and this many.
+const SPIKE_MINIMUM: usize = 10;
+
+/// A signature unlike a key's baseline.
+#[derive(Debug)]
+pub enum Anomaly {
+    /// The key made far more signatures in the last minute than it
+    /// averages.
+    Spike { per_minute: usize },
+    /// The session is bound to a host the key never signed toward.
+    NewHost { host_key: Fingerprint },
+    /// The key never signed at this hour of the day, local time.
+    UnusualHour { hour: u32 },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Anomaly::Spike { per_minute } => {
+                write!(f, "{per_minute} signatures in the last minute")
+            }
+            Anomaly::NewHost { host_key } => write!(f, "first use toward host key {host_key}"),
+            Anomaly::UnusualHour { hour } => write!(f, "first use at {hour:02}:00-{hour:02}:59"),
+        }
+    }
+}
+
+/// What a key's signatures were like.
+struct KeyBaseline {
+    first_used: Instant,
+    signatures: u64,
+    /// Times of the signatures of the last minute, oldest first.
+    recent: VecDeque<Instant>,
+    /// Hosts sessions were bound to, on every hop.
+    hosts: Vec<KeyData>,
+    /// Bit `n` is set once the key signed at hour `n`.
+    hours: u32,
+}
+
+/// The baselines of all keys, shared by all sessions and kept across
+/// reloads.
+#[derive(Default)]
+pub struct Baseline {
+    keys: Mutex<BTreeMap<Fingerprint, KeyBaseline>>,
+}
+
+impl Baseline {
+    /// How a signature with `key` now, in a session bound to `bound_hosts`,
+    /// is unlike the key's baseline.
+    pub fn check(&self, key: &KeyData, bound_hosts: &[KeyData]) -> Vec<Anomaly> {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().unwrap();
+        let Some(baseline) = keys.get_mut(&key.fingerprint(HashAlg::Sha256)) else {
+            return Vec::new();
+        };
+        baseline.forget_before(now);
+        if baseline.signatures < BASELINE_SIGNATURES {
+            return Vec::new();
+        }
+        let mut anomalies = Vec::new();
+        let minutes = now.duration_since(baseline.first_used).as_secs() / 60;
+        let average = baseline.signatures / minutes.max(1);
+        let per_minute = baseline.recent.len() + 1;
+        if per_minute > SPIKE_MINIMUM && per_minute as u64 > average * SPIKE_FACTOR {
+            anomalies.push(Anomaly::Spike { per_minute });
+        }
+        for host_key in bound_hosts {
+            if !baseline.hosts.contains(host_key) {
+                anomalies.push(Anomaly::NewHost {
+                    host_key: host_key.fingerprint(HashAlg::Sha256),
+                });
+            }
+        }
+        let hour = local_hour();
+        if now.duration_since(baseline.first_used) >= HOURS_BASELINE
+            && baseline.hours & 1 << hour == 0
+        {
+            anomalies.push(Anomaly::UnusualHour { hour });
+        }
+        anomalies
+    }
+
+    /// Adds a signature made with `key` in a session bound to
+    /// `bound_hosts` to the key's baseline.
+    pub fn learn(&self, key: &KeyData, bound_hosts: &[KeyData]) {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().unwrap();
+        let baseline = keys
+            .entry(key.fingerprint(HashAlg::Sha256))
+            .or_insert_with(|| KeyBaseline {
+                first_used: now,
+                signatures: 0,
+                recent: VecDeque::new(),
+                hosts: Vec::new(),
+                hours: 0,
+            });
+        baseline.forget_before(now);
+        baseline.signatures += 1;
+        baseline.recent.push_back(now);
+        for host_key in bound_hosts {
+            if !baseline.hosts.contains(host_key) {
+                baseline.hosts.push(host_key.clone());
+            }
+        }
+        baseline.hours |= 1 << local_hour();
+    }
+}
+
+impl KeyBaseline {
+    /// Drops the recent signatures older than a minute before `now`.
+    fn forget_before(&mut self, now: Instant) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|&time| now.duration_since(time) >= MINUTE)
+        {
+            self.recent.pop_front();
+        }
+    }
+}
+
+fn local_hour() -> u32 {
+    policy::local_time().1 / 60
+}
@@ -0,0 +1,298 @@
+//! Confirmations remembered for a while, so that a burst of signatures, e.g.
+//! of a `git push`, only asks once.
+//!
+//! A confirmation is remembered for the key, the client and the hosts the
+//! session is bound to.  The approvals of a running mux are listed and
+//! revoked through extensions of the agent protocol it answers itself.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{extension::MessageExtension, Extension, ProtoError, Request, Response},
+    ssh_encoding::{self, CheckedSum, Decode, Encode, Reader, Writer},
+};
+use ssh_key::{public::KeyData, HashAlg};
+
+use crate::client::Client;
+use crate::error::Error;
+use crate::logging;
+
+const LIST_APPROVALS: &str = "list-approvals@rfdonnelly.github.io";
+const REVOKE_APPROVALS: &str = "revoke-approvals@rfdonnelly.github.io";
+
+/// A remembered confirmation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Approval {
+    /// SHA-256 fingerprint of the key.
+    pub key: String,
+    /// Empty when unknown.
+    pub client: String,
+    /// SHA-256 fingerprints of the hosts the session was bound to, one per
+    /// hop.
+    pub hosts: Vec<String>,
+    /// How long until it is forgotten.
+    pub remaining: Duration,
+}
+
+impl fmt::Display for Approval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", logging::Fingerprint(&self.key))?;
+        if !self.client.is_empty() {
+            write!(f, " by {}", self.client)?;
+        }
+        if self.hosts.is_empty() {
+            f.write_str(", unbound")?;
+        } else {
+            write!(f, ", bound to {}", self.hosts.join(", "))?;
+        }
+        write!(
+            f,
+            ", for {} more",
+            humantime::format_duration(Duration::from_secs(self.remaining.as_secs()))
+        )
+    }
+}
+
+impl Decode for Approval {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            key: String::decode(reader)?,
+            client: String::decode(reader)?,
+            hosts: Vec::decode(reader)?,
+            remaining: Duration::from_secs(u32::decode(reader)?.into()),
+        })
+    }
+}
+
+impl Encode for Approval {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        [
+            self.key.encoded_len()?,
+            self.client.encoded_len()?,
+            self.hosts.encoded_len()?,
+            0u32.encoded_len()?,
+        ]
+        .checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        self.key.encode(writer)?;
+        self.client.encode(writer)?;
+        self.hosts.encode(writer)?;
+        let seconds = self.remaining.as_secs().try_into().unwrap_or(u32::MAX);
+        seconds.encode(writer)
+    }
+}
+
+/// `list-approvals@rfdonnelly.github.io`: lists the remembered
+/// confirmations, answered with [`Approvals`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListApprovals;
+
+impl MessageExtension for ListApprovals {
+    const NAME: &'static str = LIST_APPROVALS;
+}
+
+impl Decode for ListApprovals {
+    type Error = ProtoError;
+
+    fn decode(_reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self)
+    }
+}
+
+impl Encode for ListApprovals {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        Ok(0)
+    }
+
+    fn encode(&self, _writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        Ok(())
+    }
+}
+
+/// `revoke-approvals@rfdonnelly.github.io`: forgets the remembered
+/// confirmations of a key, by fingerprint or nickname, or all of them when
+/// empty, answered with the [`Approvals`] revoked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RevokeApprovals {
+    pub key: String,
+}
+
+impl MessageExtension for RevokeApprovals {
+    const NAME: &'static str = REVOKE_APPROVALS;
+}
+
+impl Decode for RevokeApprovals {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            key: String::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for RevokeApprovals {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        self.key.encoded_len()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        self.key.encode(writer)
+    }
+}
+
+/// The answer to [`ListApprovals`] and [`RevokeApprovals`], named after
+/// the request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Approvals(pub Vec<Approval>);
+
+impl Decode for Approvals {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        let mut approvals = Vec::new();
+        while !reader.is_finished() {
+            approvals.push(Approval::decode(reader)?);
+        }
+        Ok(Self(approvals))
+    }
+}
+
+impl Encode for Approvals {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        self.0
+            .iter()
+            .map(Encode::encoded_len)
+            .collect::<Result<Vec<_>, _>>()?
+            .checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        self.0
+            .iter()
+            .try_for_each(|approval| approval.encode(writer))
+    }
+}
+
+struct Entry {
+    key: KeyData,
+    client: Option<String>,
+    hosts: Vec<KeyData>,
+    expires: Instant,
+}
+
+impl Entry {
+    fn approval(&self, now: Instant) -> Approval {
+        Approval {
+            key: self.key.fingerprint(HashAlg::Sha256).to_string(),
+            client: self.client.clone().unwrap_or_default(),
+            hosts: self
+                .hosts
+                .iter()
+                .map(|host_key| host_key.fingerprint(HashAlg::Sha256).to_string())
+                .collect(),
+            remaining: self.expires.saturating_duration_since(now),
+        }
+    }
+}
+
+/// The remembered confirmations of a mux, shared by all sessions.
+#[derive(Default)]
+pub(crate) struct ApprovalCache {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl ApprovalCache {
+    /// Whether signatures with `key` by `client`, in sessions bound to
+    /// `hosts`, were confirmed recently enough.
+    pub fn approved(&self, key: &KeyData, client: Option<&str>, hosts: &[KeyData]) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|entry| entry.expires > now);
+        entries.iter().any(|entry| {
+            entry.key == *key && entry.client.as_deref() == client && entry.hosts == hosts
+        })
+    }
+
+    /// Remembers that signatures with `key` by `client`, in sessions bound
+    /// to `hosts`, were confirmed, for `duration`.
+    pub fn approve(
+        &self,
+        key: &KeyData,
+        client: Option<&str>,
+        hosts: &[KeyData],
+        duration: Duration,
+    ) {
+        self.entries.lock().unwrap().push(Entry {
+            key: key.clone(),
+            client: client.map(str::to_owned),
+            hosts: hosts.to_vec(),
+            expires: Instant::now() + duration,
+        });
+    }
+
+    pub fn list(&self) -> Vec<Approval> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|entry| entry.expires > now);
+        entries.iter().map(|entry| entry.approval(now)).collect()
+    }
+
+    /// Forgets the approvals of the key with the SHA-256 fingerprint or
+    /// nickname `key`, or all of them if it is empty, returning them.
+    pub fn revoke(&self, key: &str) -> Vec<Approval> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        let mut revoked = Vec::new();
+        entries.retain(|entry| {
+            let matches = key.is_empty()
+                || entry.key.fingerprint(HashAlg::Sha256).to_string() == key
+                || logging::key_nickname(&entry.key).is_some_and(|nickname| nickname == key);
+            if matches && entry.expires > now {
+                revoked.push(entry.approval(now));
+            }
+            !matches && entry.expires > now
+        });
+        revoked
+    }
+}
+
+/// Lists the approvals of the mux serving on `host`.
+pub async fn list_approvals(host: &Binding) -> Result<Vec<Approval>, Error> {
+    request(host, Extension::new_message(ListApprovals), LIST_APPROVALS).await
+}
+
+/// Revokes the approvals of `key`, by fingerprint or nickname, or all of
+/// them if it is empty, of the mux serving on `host`.
+pub async fn revoke_approvals(host: &Binding, key: &str) -> Result<Vec<Approval>, Error> {
+    let extension = Extension::new_message(RevokeApprovals {
+        key: key.to_owned(),
+    });
+    request(host, extension, REVOKE_APPROVALS).await
+}
+
+async fn request(
+    host: &Binding,
+    extension: Result<Extension, ProtoError>,
+    name: &str,
+) -> Result<Vec<Approval>, Error> {
+    let request = async {
+        let mut client = Client::connect(host.clone().try_into()?)?;
+        match client.handle(Request::Extension(extension?)).await? {
+            Response::ExtensionResponse(response) if response.name == name => {
+                Ok(response.details.parse::<Approvals>()?.0)
+            }
+            Response::Failure | Response::ExtensionFailure => Err(AgentError::Failure),
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    };
+    request.await.map_err(Error::Approvals)
+}
@@ -0,0 +1,162 @@
+//! Approval of signatures, by the user or by external programs, e.g. a chat
+//! bot or a TOTP prompt.
+//!
+//! Confirmations that policy asks for go to the mux's [`Approver`]: by
+//! default the one asking on the terminal or through `SSH_ASKPASS`, or one
+//! given with [`MuxAgentBind::with_approver`](crate::MuxAgentBind::with_approver)
+//! by programs embedding the mux, e.g. with an approval UI of their own.
+//!
+//! The program of a key's `exec` policy, a [`Program`] approver, is run for
+//! each signature with the details of the request in `SSH_AGENT_MUX_*`
+//! environment variables and as a JSON object on its stdin.  Exiting with 0
+//! approves the signature.
+
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use sha2::{Digest, Sha256};
+use ssh_agent_lib::async_trait;
+use ssh_key::{public::KeyData, HashAlg};
+
+use crate::audit::json_string;
+
+/// What an approver is told of a sign request.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub key: KeyData,
+    /// Names of the targets that may sign, in order.
+    pub targets: Vec<String>,
+    /// Host keys the session is bound to.
+    pub bound_hosts: Vec<KeyData>,
+    /// The user of a Unix socket client, e.g. `uid 1000`, or the address of
+    /// a TCP one, when known.
+    pub client: Option<String>,
+    /// What makes the signature unusual, e.g. a host the key never signed
+    /// toward, when that is why it is asked about.
+    pub reasons: Vec<String>,
+    pub data: Vec<u8>,
+    pub flags: u32,
+}
+
+impl Request {
+    pub(crate) fn to_json(&self) -> String {
+        let list = |items: &[String]| {
+            let items: Vec<_> = items.iter().map(|item| json_string(item)).collect();
+            format!("[{}]", items.join(","))
+        };
+        format!(
+            "{{\"key\":{},\"algorithm\":{},\"targets\":{},\"bound_hosts\":{},\"data_sha256\":{},\"flags\":{}}}\n",
+            json_string(&self.key.fingerprint(HashAlg::Sha256).to_string()),
+            json_string(self.key.algorithm().as_str()),
+            list(&self.targets),
+            list(&self.bound_host_fingerprints()),
+            json_string(&self.data_sha256()),
+            self.flags
+        )
+    }
+
+    /// The question to ask the user, e.g. "Allow use of key SHA256:... by
+    /// uid 1000?", with a line for each bound host and reason after it.
+    pub fn question(&self) -> String {
+        let mut question = format!("Allow use of key {}", self.key.fingerprint(HashAlg::Sha256));
+        if let Some(client) = &self.client {
+            let _ = write!(question, " by {client}");
+        }
+        question.push('?');
+        for host_key in self.bound_host_fingerprints() {
+            let _ = write!(question, "\nBound to host key {host_key}");
+        }
+        for reason in &self.reasons {
+            let _ = write!(question, "\nUnusual: {reason}");
+        }
+        question
+    }
+
+    fn bound_host_fingerprints(&self) -> Vec<String> {
+        self.bound_hosts
+            .iter()
+            .map(|host_key| host_key.fingerprint(HashAlg::Sha256).to_string())
+            .collect()
+    }
+
+    /// Hex-encoded SHA-256 of the data to sign, rather than the data, which
+    /// holds the session ID.
+    fn data_sha256(&self) -> String {
+        Sha256::digest(&self.data)
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+impl From<bool> for Decision {
+    fn from(allow: bool) -> Self {
+        if allow {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        }
+    }
+}
+
+/// Decides whether signatures may be made.  Failing to decide, e.g. for
+/// lack of a UI to ask in, is a denial.
+#[async_trait]
+pub trait Approver: Send + Sync {
+    async fn decide(&self, request: &Request) -> Decision;
+}
+
+/// A program approving by exiting with 0.  Anything else, including a
+/// program that can't be run, is a denial.
+pub struct Program(pub PathBuf);
+
+#[async_trait]
+impl Approver for Program {
+    async fn decide(&self, request: &Request) -> Decision {
+        let program = self.0.clone();
+        let request = request.clone();
+        let asking = tokio::task::spawn_blocking(move || {
+            run(&program, &request)
+                .inspect_err(|e| log::warn!("Failed to run approver {}: {e}", program.display()))
+                .is_ok_and(|approved| approved)
+        });
+        asking.await.unwrap_or(false).into()
+    }
+}
+
+fn run(program: &Path, request: &Request) -> io::Result<bool> {
+    let mut child = Command::new(program)
+        .env(
+            "SSH_AGENT_MUX_KEY",
+            request.key.fingerprint(HashAlg::Sha256).to_string(),
+        )
+        .env("SSH_AGENT_MUX_TARGETS", request.targets.join(" "))
+        .env(
+            "SSH_AGENT_MUX_BOUND_HOSTS",
+            request.bound_host_fingerprints().join(" "),
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // Programs may well decide without reading their stdin.
+    match stdin.write_all(request.to_json().as_bytes()) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+        _ => drop(stdin),
+    }
+    Ok(child.wait()?.success())
+}
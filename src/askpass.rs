@@ -0,0 +1,131 @@
+//! Confirmation through the `SSH_ASKPASS` program, the way ssh-agent asks
+//! before using keys added with `ssh-add -c`, or with a y/N prompt on the
+//! terminal the mux runs in.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use ssh_agent_lib::async_trait;
+
+use crate::approver::{Approver, Decision, Request};
+use crate::config::ConfirmPrompt;
+
+/// Asked when `SSH_ASKPASS` isn't set.
+const DEFAULT_ASKPASS: &str = "ssh-askpass";
+
+/// The controlling terminal, when confirmations are asked on it.
+static TERMINAL: OnceLock<Terminal> = OnceLock::new();
+
+/// Asks on the controlling terminal from now on, as `prompt` says: always,
+/// or by default when the mux runs in the foreground of one without
+/// `SSH_ASKPASS` set.  Opened before the sandbox may hide it.
+pub fn open_terminal(prompt: ConfirmPrompt) -> io::Result<()> {
+    let terminal = match prompt {
+        ConfirmPrompt::Askpass => return Ok(()),
+        ConfirmPrompt::Terminal => Terminal::open()?,
+        ConfirmPrompt::Auto => {
+            if std::env::var_os("SSH_ASKPASS").is_some() || !io::stderr().is_terminal() {
+                return Ok(());
+            }
+            match Terminal::open() {
+                Ok(terminal) => terminal,
+                Err(_) => return Ok(()),
+            }
+        }
+    };
+    let _ = TERMINAL.set(terminal);
+    Ok(())
+}
+
+/// Asks on the terminal opened with [`open_terminal`], if any, or else
+/// through `SSH_ASKPASS`.  The approver of a mux unless given another.
+pub struct Prompt;
+
+#[async_trait]
+impl Approver for Prompt {
+    async fn decide(&self, request: &Request) -> Decision {
+        match TERMINAL.get() {
+            Some(terminal) => terminal.decide(request).await,
+            None => Askpass.decide(request).await,
+        }
+    }
+}
+
+/// Runs the `SSH_ASKPASS` program, or `ssh-askpass` without one, with the
+/// question.  Anything but a yes, including a program that can't be run, is
+/// a no.
+pub struct Askpass;
+
+#[async_trait]
+impl Approver for Askpass {
+    async fn decide(&self, request: &Request) -> Decision {
+        let question = request.question();
+        let asking = tokio::task::spawn_blocking(move || {
+            let program = std::env::var_os("SSH_ASKPASS").unwrap_or_else(|| DEFAULT_ASKPASS.into());
+            Command::new(&program)
+                .arg(&question)
+                .env("SSH_ASKPASS_PROMPT", "confirm")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .status()
+                .inspect_err(|e| log::warn!("Failed to run {}: {e}", program.to_string_lossy()))
+                .is_ok_and(|status| status.success())
+        });
+        asking.await.unwrap_or(false).into()
+    }
+}
+
+/// Asks with a y/N prompt on the controlling terminal, one question at a
+/// time.
+#[derive(Clone)]
+pub struct Terminal(Arc<Mutex<File>>);
+
+impl Terminal {
+    #[cfg(unix)]
+    pub fn open() -> io::Result<Self> {
+        let tty = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")?;
+        Ok(Self(Arc::new(Mutex::new(tty))))
+    }
+
+    #[cfg(not(unix))]
+    pub fn open() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "prompts need a Unix terminal",
+        ))
+    }
+}
+
+#[async_trait]
+impl Approver for Terminal {
+    async fn decide(&self, request: &Request) -> Decision {
+        let terminal = self.0.clone();
+        let question = request.question();
+        let asking = tokio::task::spawn_blocking(move || {
+            let terminal = terminal.lock().unwrap();
+            ask(BufReader::new(&*terminal), &*terminal, &question)
+                .inspect_err(|e| log::warn!("Failed to ask on the terminal: {e}"))
+                .unwrap_or(false)
+        });
+        asking.await.unwrap_or(false).into()
+    }
+}
+
+/// Writes `question` as a y/N prompt to `output` and reads the answer, a
+/// line of `input`.  Anything but a yes, including the end of `input`, is a
+/// no.
+pub fn ask(mut input: impl BufRead, mut output: impl Write, question: &str) -> io::Result<bool> {
+    write!(output, "\nssh-agent-mux: {question} [y/N] ")?;
+    output.flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
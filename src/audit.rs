@@ -0,0 +1,476 @@
+//! Audit events.
+//!
+//! Audit events are logged under the `audit` target so they can be routed
+//! separately, e.g. `RUST_LOG=audit=info`.  As JSON objects, they are
+//! appended to the audit log file, posted to the webhook, streamed to event
+//! socket subscribers and given to plugins, and they are emitted as D-Bus
+//! signals.
+//!
+//! With a digest window, notifications (webhook posts and D-Bus signals)
+//! that follow another within the window are held back and sent as one
+//! `digest` once it closes, so that a burst of signatures, e.g. during a
+//! `git push`, doesn't notify the user of each.  Event socket subscribers
+//! are still sent every event as it happens, and canary keys used and error
+//! rate alerts are notified of right away.
+
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ssh_key::{public::KeyData, HashAlg};
+
+use crate::anomaly::Anomaly;
+use crate::policy::Denial;
+use crate::{events, logging, metrics, plugin, webhook};
+
+pub(crate) enum Event<'a> {
+    KeyUsed {
+        key: &'a KeyData,
+        target: &'a str,
+        client: Option<&'a str>,
+    },
+    TargetDown {
+        target: &'a str,
+        error: &'a dyn fmt::Display,
+    },
+    TargetUp {
+        target: &'a str,
+    },
+    /// A target failed as many times as the alert threshold within its
+    /// window.
+    ErrorRate {
+        target: &'a str,
+        failures: usize,
+        window: Duration,
+    },
+    TargetUnlocked {
+        target: &'a str,
+        duration: Duration,
+    },
+    /// The identities listed to clients changed.
+    IdentitiesChanged {
+        count: usize,
+    },
+    /// A target listed keys it didn't list the time before, or stopped
+    /// listing some.
+    TargetIdentitiesChanged {
+        target: &'a str,
+        added: &'a [KeyData],
+        removed: &'a [KeyData],
+    },
+    SignDenied {
+        key: &'a KeyData,
+        denial: &'a Denial,
+        client: Option<&'a str>,
+    },
+    /// A client tried to sign with a decoy key.
+    CanaryUsed {
+        key: &'a KeyData,
+        client: Option<&'a str>,
+    },
+    /// A signature unlike those the key made so far.
+    Anomaly {
+        key: &'a KeyData,
+        anomaly: &'a Anomaly,
+        client: Option<&'a str>,
+    },
+}
+
+impl Event<'_> {
+    /// Name of the event type in JSON.
+    fn name(&self) -> &'static str {
+        match self {
+            Event::KeyUsed { .. } => "key_used",
+            Event::TargetDown { .. } => "target_down",
+            Event::TargetUp { .. } => "target_up",
+            Event::ErrorRate { .. } => "error_rate",
+            Event::TargetUnlocked { .. } => "target_unlocked",
+            Event::IdentitiesChanged { .. } => "identities_changed",
+            Event::TargetIdentitiesChanged { .. } => "target_identities_changed",
+            Event::SignDenied { .. } => "sign_denied",
+            Event::CanaryUsed { .. } => "canary_used",
+            Event::Anomaly { .. } => "anomaly",
+        }
+    }
+
+    /// Details of the event in JSON.  The client and nickname of a key are
+    /// empty when unknown, rather than left out, so that D-Bus signals keep
+    /// their arguments in place.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Event::KeyUsed {
+                key,
+                target,
+                client,
+            } => vec![
+                ("key", key.fingerprint(HashAlg::Sha256).to_string()),
+                ("target", target.to_string()),
+                ("client", client.unwrap_or_default().to_owned()),
+                ("nickname", logging::key_nickname(key).unwrap_or_default()),
+            ],
+            Event::TargetDown { target, error } => {
+                vec![("target", target.to_string()), ("error", error.to_string())]
+            }
+            Event::TargetUp { target } => vec![("target", target.to_string())],
+            Event::ErrorRate {
+                target,
+                failures,
+                window,
+            } => vec![
+                ("target", target.to_string()),
+                ("failures", failures.to_string()),
+                ("window", window.as_secs().to_string()),
+            ],
+            Event::TargetUnlocked { target, duration } => vec![
+                ("target", target.to_string()),
+                ("duration", duration.as_secs().to_string()),
+            ],
+            Event::IdentitiesChanged { count } => vec![("count", count.to_string())],
+            Event::TargetIdentitiesChanged {
+                target,
+                added,
+                removed,
+            } => {
+                let fingerprints = |keys: &[KeyData]| {
+                    keys.iter()
+                        .map(|key| key.fingerprint(HashAlg::Sha256).to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                };
+                vec![
+                    ("target", target.to_string()),
+                    ("added", fingerprints(added)),
+                    ("removed", fingerprints(removed)),
+                ]
+            }
+            Event::SignDenied {
+                key,
+                denial,
+                client,
+            } => vec![
+                ("key", key.fingerprint(HashAlg::Sha256).to_string()),
+                ("reason", denial.to_string()),
+                ("client", client.unwrap_or_default().to_owned()),
+                ("nickname", logging::key_nickname(key).unwrap_or_default()),
+            ],
+            Event::CanaryUsed { key, client } => vec![
+                ("key", key.fingerprint(HashAlg::Sha256).to_string()),
+                ("client", client.unwrap_or_default().to_owned()),
+                ("nickname", logging::key_nickname(key).unwrap_or_default()),
+            ],
+            Event::Anomaly {
+                key,
+                anomaly,
+                client,
+            } => vec![
+                ("key", key.fingerprint(HashAlg::Sha256).to_string()),
+                ("reason", anomaly.to_string()),
+                ("client", client.unwrap_or_default().to_owned()),
+                ("nickname", logging::key_nickname(key).unwrap_or_default()),
+            ],
+        }
+    }
+
+    /// The event as a JSON object of strings, besides its Unix time.
+    fn to_json(&self) -> String {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut json = format!("{{\"event\":{},\"time\":{time}", json_string(self.name()));
+        for (name, value) in self.fields() {
+            let _ = write!(json, ",{}:{}", json_string(name), json_string(&value));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// An event to notify of, or a digest of several.
+struct Notification {
+    name: &'static str,
+    fields: Vec<(&'static str, String)>,
+    /// Only made for the webhook.
+    json: Option<String>,
+}
+
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+static DIGEST_WINDOW: OnceLock<Duration> = OnceLock::new();
+/// Notifications held back while a digest window is open, or `None` while
+/// none is.
+static HELD: Mutex<Option<Vec<Notification>>> = Mutex::new(None);
+
+/// Appends events to the file at `path` from now on, one JSON object per
+/// line.
+pub fn open_log(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = LOG_FILE.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Sends notifications that follow another within `window` as one digest
+/// from now on.
+pub fn set_digest_window(window: Duration) {
+    let _ = DIGEST_WINDOW.set(window);
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::KeyUsed {
+                key,
+                target,
+                client,
+            } => {
+                write!(f, "key {} used on target {target}", logging::Key(key))?;
+                if let Some(client) = client {
+                    write!(f, " by {client}")?;
+                }
+                Ok(())
+            }
+            Event::TargetDown { target, error } => {
+                write!(f, "target {target} is unhealthy: {error}")
+            }
+            Event::TargetUp { target } => write!(f, "target {target} is healthy again"),
+            Event::ErrorRate {
+                target,
+                failures,
+                window,
+            } => write!(
+                f,
+                "target {target} failed {failures} times within {}",
+                humantime::format_duration(*window)
+            ),
+            Event::TargetUnlocked { target, duration } => write!(
+                f,
+                "hidden target {target} unlocked for {}",
+                humantime::format_duration(*duration)
+            ),
+            Event::IdentitiesChanged { count } => write!(f, "{count} identities listed"),
+            Event::TargetIdentitiesChanged {
+                target,
+                added,
+                removed,
+            } => {
+                write!(f, "target {target}: ")?;
+                write_keys(f, '+', added)?;
+                f.write_str(", ")?;
+                write_keys(f, '-', removed)
+            }
+            Event::SignDenied {
+                key,
+                denial,
+                client,
+            } => {
+                write!(f, "sign denied for {}", logging::Key(key))?;
+                if let Some(client) = client {
+                    write!(f, " by {client}")?;
+                }
+                write!(f, ": {denial}")
+            }
+            Event::CanaryUsed { key, client } => {
+                write!(f, "canary key {} used", logging::Key(key))?;
+                if let Some(client) = client {
+                    write!(f, " by {client}")?;
+                }
+                write!(f, "; the socket may be abused")
+            }
+            Event::Anomaly {
+                key,
+                anomaly,
+                client,
+            } => {
+                write!(f, "unusual use of key {}", logging::Key(key))?;
+                if let Some(client) = client {
+                    write!(f, " by {client}")?;
+                }
+                write!(f, ": {anomaly}")
+            }
+        }
+    }
+}
+
+/// Writes e.g. `+1 key ed25519 SHA256:...` or `-0`.
+fn write_keys(f: &mut fmt::Formatter<'_>, sign: char, keys: &[KeyData]) -> fmt::Result {
+    write!(f, "{sign}{}", keys.len())?;
+    match keys.len() {
+        0 => return Ok(()),
+        1 => f.write_str(" key")?,
+        _ => f.write_str(" keys")?,
+    }
+    for (i, key) in keys.iter().enumerate() {
+        let algorithm = key.algorithm();
+        let algorithm = algorithm.as_str();
+        let separator = if i == 0 { " " } else { ", " };
+        write!(
+            f,
+            "{separator}{} {}",
+            algorithm.strip_prefix("ssh-").unwrap_or(algorithm),
+            logging::Key(key)
+        )?;
+    }
+    Ok(())
+}
+
+pub(crate) fn record(event: Event) {
+    if let Event::KeyUsed { key, .. } = event {
+        metrics::record_key_use(key);
+    }
+    match event {
+        Event::TargetDown { .. }
+        | Event::ErrorRate { .. }
+        | Event::SignDenied { .. }
+        | Event::Anomaly { .. } => {
+            log::warn!(target: "audit", "{event}")
+        }
+        // An upstream dropping keys, e.g. a YubiKey's after a timeout,
+        // leaves clients unable to sign with them.
+        Event::TargetIdentitiesChanged { removed, .. } if !removed.is_empty() => {
+            log::warn!(target: "audit", "{event}")
+        }
+        Event::CanaryUsed { .. } => log::error!(target: "audit", "{event}"),
+        _ => log::info!(target: "audit", "{event}"),
+    }
+    if let Some(file) = LOG_FILE.get() {
+        let line = event.to_json() + "\n";
+        if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+            log::warn!("Failed to write to the audit log: {e}");
+        }
+    }
+    let webhook = webhook::installed();
+    if events::has_subscribers() {
+        events::publish(&event.to_json());
+    }
+    if events::keeps_recent_signatures()
+        && matches!(
+            event,
+            Event::KeyUsed { .. } | Event::SignDenied { .. } | Event::CanaryUsed { .. }
+        )
+    {
+        events::remember_signature(&event.to_json());
+    }
+    if plugin::wants_events() {
+        plugin::publish(&event.to_json());
+    }
+    if webhook.is_none() && !dbus_enabled() {
+        return;
+    }
+    let notification = Notification {
+        name: event.name(),
+        fields: event.fields(),
+        json: webhook.map(|_| event.to_json()),
+    };
+    match DIGEST_WINDOW.get() {
+        Some(&window) if !matches!(event, Event::CanaryUsed { .. } | Event::ErrorRate { .. }) => {
+            hold(notification, window)
+        }
+        _ => notify(notification),
+    }
+}
+
+/// Notifies of `notification` and opens a digest window, or holds it back
+/// if one is open.
+fn hold(notification: Notification, window: Duration) {
+    let mut held = HELD.lock().unwrap();
+    if let Some(held) = held.as_mut() {
+        held.push(notification);
+        return;
+    }
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        *held = Some(Vec::new());
+        runtime.spawn(async move {
+            tokio::time::sleep(window).await;
+            close_window();
+        });
+    }
+    drop(held);
+    notify(notification);
+}
+
+/// Notifies of what was held back while the digest window was open.
+fn close_window() {
+    let mut held = HELD.lock().unwrap().take().unwrap_or_default();
+    match held.len() {
+        0 => (),
+        1 => notify(held.remove(0)),
+        _ => notify(digest(held)),
+    }
+}
+
+/// One notification of `held`: how many there were, by name, e.g.
+/// "20 key_used, 1 sign_denied", and their JSON objects for the webhook.
+fn digest(held: Vec<Notification>) -> Notification {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for notification in &held {
+        match counts
+            .iter_mut()
+            .find(|(name, _)| *name == notification.name)
+        {
+            Some((_, count)) => *count += 1,
+            None => counts.push((notification.name, 1)),
+        }
+    }
+    let summary = counts
+        .iter()
+        .map(|(name, count)| format!("{count} {name}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let json = webhook::installed().map(|_| {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let events: Vec<_> = held.iter().filter_map(|n| n.json.as_deref()).collect();
+        format!(
+            "{{\"event\":\"digest\",\"time\":{time},\"count\":{},\"summary\":{},\"events\":[{}]}}",
+            json_string(&held.len().to_string()),
+            json_string(&summary),
+            events.join(",")
+        )
+    });
+    Notification {
+        name: "digest",
+        fields: vec![("count", held.len().to_string()), ("summary", summary)],
+        json,
+    }
+}
+
+fn notify(notification: Notification) {
+    #[cfg(unix)]
+    if crate::dbus::enabled() {
+        crate::dbus::emit(notification.name, &notification.fields);
+    }
+    if let (Some(webhook), Some(json)) = (webhook::installed(), notification.json) {
+        webhook.post(notification.name, json);
+    }
+}
+
+fn dbus_enabled() -> bool {
+    #[cfg(unix)]
+    return crate::dbus::enabled();
+    #[cfg(not(unix))]
+    false
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,203 @@
+//! Fault injection, for checking how ssh tooling copes with an agent chain
+//! that misbehaves: requests to targets are delayed, their connections
+//! dropped and their responses made malformed, at random but in an order
+//! fixed by a seed, so that a run can be replayed.
+//!
+//! Enabled with the hidden `--chaos` option, e.g.
+//! `--chaos seed=42,delay=0.2:500ms,drop=0.05,malformed=0.05`, which delays
+//! a fifth of the requests by up to half a second and fails one in twenty
+//! each way.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{ProtoError, Response},
+    ssh_encoding,
+};
+
+/// What is injected, and how often.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chaos {
+    pub seed: u64,
+    /// How often requests are delayed, and by up to how long.
+    pub delay: Option<(f64, Duration)>,
+    /// How often requests fail as if their connection dropped, without
+    /// reaching the target.
+    pub drop: f64,
+    /// How often responses fail to decode, once the target answered.
+    pub malformed: f64,
+}
+
+/// Faults injected into a request.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Faults {
+    pub delay: Option<Duration>,
+    pub fault: Option<Fault>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    Drop,
+    Malformed,
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    /// Parses comma-separated `seed=<n>`, `delay=<probability>:<duration>`,
+    /// `drop=<probability>` and `malformed=<probability>`.  Without a seed,
+    /// one is taken from the clock.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos {
+            seed: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            delay: None,
+            drop: 0.0,
+            malformed: 0.0,
+        };
+        for field in s.split(',').filter(|field| !field.is_empty()) {
+            let (name, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("expected <name>=<value>, found {field:?}"))?;
+            match name {
+                "seed" => {
+                    chaos.seed = value
+                        .parse()
+                        .map_err(|_| format!("invalid seed {value:?}"))?
+                }
+                "delay" => {
+                    let (probability, duration) = value.split_once(':').ok_or_else(|| {
+                        format!("expected delay=<probability>:<duration>, found {value:?}")
+                    })?;
+                    let duration = humantime::parse_duration(duration)
+                        .map_err(|e| format!("invalid delay {duration:?}: {e}"))?;
+                    chaos.delay = Some((self::probability(probability)?, duration));
+                }
+                "drop" => chaos.drop = probability(value)?,
+                "malformed" => chaos.malformed = probability(value)?,
+                _ => return Err(format!("unknown fault {name:?}")),
+            }
+        }
+        if chaos.drop + chaos.malformed > 1.0 {
+            return Err("drop and malformed add up to more than 1".to_owned());
+        }
+        Ok(chaos)
+    }
+}
+
+fn probability(s: &str) -> Result<f64, String> {
+    s.parse()
+        .ok()
+        .filter(|p| (0.0..=1.0).contains(p))
+        .ok_or_else(|| format!("invalid probability {s:?}; use one from 0 to 1"))
+}
+
+impl fmt::Display for Chaos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "seed={}", self.seed)?;
+        if let Some((probability, duration)) = self.delay {
+            write!(
+                f,
+                ",delay={probability}:{}",
+                humantime::format_duration(duration)
+            )?;
+        }
+        write!(f, ",drop={},malformed={}", self.drop, self.malformed)
+    }
+}
+
+impl Chaos {
+    /// The faults injected into each request in turn.
+    pub fn schedule(&self) -> Schedule {
+        Schedule {
+            chaos: self.clone(),
+            state: self.seed,
+        }
+    }
+}
+
+/// Faults drawn from a seeded generator, the same for the same seed.
+pub struct Schedule {
+    chaos: Chaos,
+    state: u64,
+}
+
+impl Schedule {
+    /// splitmix64, uniform in [0, 1).
+    fn draw(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Iterator for Schedule {
+    type Item = Faults;
+
+    fn next(&mut self) -> Option<Faults> {
+        // Always three draws, so that each request takes its own.
+        let (delayed, length, fault) = (self.draw(), self.draw(), self.draw());
+        Some(Faults {
+            delay: self
+                .chaos
+                .delay
+                .filter(|&(probability, _)| delayed < probability)
+                .map(|(_, duration)| duration.mul_f64(length)),
+            fault: if fault < self.chaos.drop {
+                Some(Fault::Drop)
+            } else if fault < self.chaos.drop + self.chaos.malformed {
+                Some(Fault::Malformed)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+static SCHEDULE: OnceLock<Mutex<Schedule>> = OnceLock::new();
+
+/// Injects faults into requests to targets as `chaos` says from now on.
+pub fn install(chaos: &Chaos) {
+    let _ = SCHEDULE.set(Mutex::new(chaos.schedule()));
+}
+
+/// `response` to a request, with the next faults of the schedule injected.
+pub(crate) async fn inject(
+    response: impl Future<Output = Result<Response, AgentError>>,
+) -> Result<Response, AgentError> {
+    let Some(schedule) = SCHEDULE.get() else {
+        return response.await;
+    };
+    let faults = schedule.lock().unwrap().next().unwrap_or_default();
+    if let Some(delay) = faults.delay {
+        log::debug!("Chaos: delaying the request by {delay:?}");
+        tokio::time::sleep(delay).await;
+    }
+    match faults.fault {
+        Some(Fault::Drop) => {
+            log::debug!("Chaos: dropping the connection");
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection dropped by chaos",
+            )
+            .into())
+        }
+        Some(Fault::Malformed) => {
+            response.await?;
+            log::debug!("Chaos: making the response malformed");
+            Err(ProtoError::SshEncoding(ssh_encoding::Error::Length).into())
+        }
+        None => response.await,
+    }
+}
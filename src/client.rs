@@ -0,0 +1,95 @@
+//! Client connections to target agents.
+//!
+//! Like `ssh_agent_lib::client::Client`, but requests are encoded into a
+//! buffer that is scrubbed once written, since forwarded requests can carry
+//! passphrases and private keys.
+
+use std::fmt;
+use std::io;
+
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{Request, Response},
+    ssh_encoding::Encode,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::Decoder;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::codec::{FrameCodec, DEFAULT_MAX_MESSAGE_SIZE};
+
+trait Stream: AsyncRead + AsyncWrite + fmt::Debug + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + fmt::Debug + Send + Sync + Unpin> Stream for T {}
+
+#[derive(Debug)]
+pub struct Client {
+    stream: Box<dyn Stream>,
+    codec: FrameCodec,
+    buffer: BytesMut,
+}
+
+impl Client {
+    pub fn connect(stream: service_binding::Stream) -> io::Result<Self> {
+        let stream: Box<dyn Stream> = match stream {
+            #[cfg(unix)]
+            service_binding::Stream::Unix(stream) => {
+                Box::new(tokio::net::UnixStream::from_std(stream)?)
+            }
+            service_binding::Stream::Tcp(stream) => {
+                Box::new(tokio::net::TcpStream::from_std(stream)?)
+            }
+            #[cfg(windows)]
+            service_binding::Stream::NamedPipe(pipe) => {
+                use tokio::net::windows::named_pipe::ClientOptions;
+                // https://docs.rs/windows-sys/latest/windows_sys/Win32/Foundation/constant.ERROR_PIPE_BUSY.html
+                const ERROR_PIPE_BUSY: i32 = 231;
+                let client = loop {
+                    match ClientOptions::new().open(&pipe) {
+                        Ok(client) => break client,
+                        Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => (),
+                        Err(e) => return Err(e),
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                };
+                Box::new(client)
+            }
+            #[cfg(not(windows))]
+            service_binding::Stream::NamedPipe(_) => {
+                return Err(io::Error::other("Named pipes supported on Windows only"));
+            }
+        };
+        Ok(Self {
+            stream,
+            codec: FrameCodec::new(DEFAULT_MAX_MESSAGE_SIZE),
+            buffer: BytesMut::new(),
+        })
+    }
+
+    pub async fn handle(&mut self, request: Request) -> Result<Response, AgentError> {
+        let mut bytes = Zeroizing::new(Vec::new());
+        let encoded = request.encode_prefixed(&mut *bytes);
+        scrub(request);
+        encoded.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.stream.write_all(&bytes).await?;
+
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.buffer)? {
+                return Ok(frame.decode()?);
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(io::Error::other("server disconnected").into());
+            }
+        }
+    }
+}
+
+/// Clears secrets from a request that its types don't clear on drop
+/// themselves.  Private keys and smartcard PINs already are.
+pub(crate) fn scrub(request: Request) {
+    match request {
+        Request::Lock(mut passphrase) | Request::Unlock(mut passphrase) => passphrase.zeroize(),
+        _ => (),
+    }
+}
@@ -0,0 +1,105 @@
+//! Agent protocol framing with a message size limit.
+//!
+//! Unlike `ssh_agent_lib::codec::Codec`, decoding yields raw frames so that
+//! the length can be checked before a message is buffered and undecodable
+//! messages can be told apart from transport errors.  Frames are scrubbed
+//! when dropped, since they can hold passphrases and private keys.
+
+use std::io;
+use std::mem::size_of;
+
+use ssh_agent_lib::{
+    proto::Response,
+    ssh_encoding::{Decode, Encode},
+};
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+use zeroize::Zeroize;
+
+/// Largest message OpenSSH's agent accepts.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+#[derive(Debug)]
+pub struct FrameCodec {
+    max_message_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_message_size: usize) -> Self {
+        Self { max_message_size }
+    }
+}
+
+/// A complete message without its length prefix.
+pub struct Frame(pub BytesMut);
+
+impl Frame {
+    /// Size of the frame on the wire, including the length prefix.
+    pub fn wire_len(&self) -> usize {
+        size_of::<u32>() + self.0.len()
+    }
+
+    pub fn decode<T: Decode>(&self) -> Result<T, T::Error> {
+        T::decode(&mut &self.0[..])
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        self.0.as_mut().zeroize();
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(header) = src.get(..size_of::<u32>()) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(header.try_into().expect("header is 4 bytes")) as usize;
+        if length > self.max_message_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "message of {length} bytes exceeds the limit of {} bytes",
+                    self.max_message_size
+                ),
+            ));
+        }
+
+        if src.len() < size_of::<u32>() + length {
+            src.reserve(size_of::<u32>() + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(size_of::<u32>());
+        Ok(Some(Frame(src.split_to(length))))
+    }
+}
+
+impl Encoder<Response> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Response, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut bytes = Vec::new();
+        item.encode_prefixed(&mut bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        dst.put(&*bytes);
+        Ok(())
+    }
+}
+
+/// Encodes a raw message, for those `Response` can't represent.
+impl Encoder<&[u8]> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let length =
+            u32::try_from(item.len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        dst.put_u32(length);
+        dst.put(item);
+        Ok(())
+    }
+}
@@ -0,0 +1,2412 @@
+//! Config file loading.
+//!
+//! The config file is TOML, or YAML or JSON with the same structure when its
+//! extension is `.yaml`/`.yml` or `.json`.  Everything in it is optional;
+//! command line options are layered on top.
+//!
+//! ```toml
+//! host = "unix:///run/user/1000/mux.sock"
+//! session_idle_timeout = "10m"
+//! max_sessions = 64
+//! max_message_size = 262144
+//! session_max_requests = 1000
+//! session_max_bytes = 1048576
+//! legacy_v1 = true
+//! max_pipelined_requests = 16
+//! duplicate_keys = "priority"
+//! max_identities = 10
+//! sign_retries = 2
+//! shutdown_timeout = "10s"
+//! redact_logs = true
+//! event_socket = "/run/user/1000/mux-events.sock"
+//! admin_http = "unix:///run/user/1000/mux-admin.sock"
+//! admin_grpc = "/run/user/1000/mux-grpc.sock"
+//! dbus_signals = true
+//! notification_digest = "10s"
+//! usage_file = "/var/lib/ssh-agent-mux/usage"
+//! key_cache = "/var/lib/ssh-agent-mux/keys"
+//! onepassword_agent_toml = "/home/me/.config/1Password/ssh/agent.toml"
+//! audit_log = "/var/log/ssh-agent-mux/audit.log"
+//! allowed_uids = [1000]
+//! allowed_gids = [1000]
+//! route_script = "route.rhai"
+//! plugins = ["notify.wasm"]
+//! canary_keys = ["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOzdqs6GJAGyXn6rqtqMXRD1ff5/7D8A1lZL0xO5ApsT deploy@ci"]
+//! anomalies = "confirm"
+//! approval_cache = "5m"
+//! confirm_prompt = "terminal"
+//!
+//! [targets.yubikey]
+//! binding = "unix:///run/user/1000/yubikey-agent.sock"
+//! max_concurrent_requests = 1
+//! priority = 10
+//! add_key_types = ["sk-ecdsa", "sk-ed25519"]
+//! max_identities = 2
+//! idle_timeout = "5m"
+//! response_timeout = "1m"
+//!
+//! # Only listed while the other targets are unreachable or list no keys
+//! [targets.backup]
+//! binding = "unix:///run/user/1000/backup-agent.sock"
+//! fallback = true
+//!
+//! # Only listed while the others list no keys, or to find an unlisted key
+//! [targets.cloud]
+//! binding = "tcp://agent.example.com:7000"
+//! enumeration = "lazy"
+//!
+//! # Only listed and used for a while after `ssh-agent-mux unlock-target vault`
+//! [targets.vault]
+//! binding = "unix:///run/user/1000/vault-agent.sock"
+//! hidden = true
+//!
+//! # gpg-agent's ssh socket, with its quirks worked around
+//! [targets.gpg]
+//! binding = "unix:///run/user/1000/gnupg/S.gpg-agent.ssh"
+//! flavor = "gpg-agent"
+//! # For pinentry, which a daemonized mux has no terminal or display for
+//! environment = { GPG_TTY = "/dev/tty1" }
+//! forward_environment = ["DISPLAY", "WAYLAND_DISPLAY"]
+//!
+//! # Shorthand for a target with only a binding
+//! [targets]
+//! work = "unix:///run/user/1000/work-agent.sock"
+//! # Every socket in a directory, or those matching a glob, as targets
+//! # named agents/<file name>
+//! agents = "dir:///run/user/1000/agents/*.sock"
+//! # Resolved on every connection
+//! remote = "tcp://agent.example.com:7000"
+//! discovered = "srv://_ssh-agent._tcp.example.com"
+//!
+//! [alerts]
+//! target_failures = 10
+//! window = "1m"
+//!
+//! [log_levels]
+//! routing = "debug"
+//! transport = "warn"
+//!
+//! [add_constraints]
+//! confirm = true
+//! lifetime = "8h"
+//!
+//! # Selected with --profile, on top of the rest
+//! [profiles.work]
+//! host = "unix:///run/user/1000/work-mux.sock"
+//! targets = { work = "unix:///run/user/1000/work-agent.sock" }
+//!
+//! [sandbox]
+//! user = "nobody"
+//! group = "nogroup"
+//! landlock = true
+//! pledge = true
+//! seccomp = "enforce"
+//! chroot = "/var/empty"
+//! capsicum = true
+//!
+//! [pipe_security]
+//! allow_groups = ["BA"]
+//!
+//! [socket_label]
+//! selinux = "system_u:object_r:ssh_agent_mux_sock_t:s0"
+//! smack = "SshAgentMux"
+//!
+//! [webhook]
+//! url = "https://hooks.example.com/ssh-agent-mux"
+//! secret = "hunter2"
+//! retries = 3
+//!
+//! [client_addresses]
+//! "10.0.0.0/8" = "allow"
+//! "10.1.0.0/16" = "confirm"
+//!
+//! [keys."SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E"]
+//! nickname = "work-yubikey"
+//! max_signatures_per_hour = 20
+//! cooldown = "2s"
+//! allowed_host_keys = ["SHA256:+DiY3wvvV6TuJJhbpZisF/zLDA0zPMSvHdkr4UvCOqU"]
+//! require_session_bind = true
+//! allowed_times = ["Mon-Fri 08:00-19:00"]
+//! exec = "/usr/local/bin/approve-signature"
+//!
+//! [routes]
+//! "SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E" = "yubikey"
+//!
+//! [views.containers]
+//! host = "unix:///run/user/1000/mux-containers.sock"
+//! targets = ["yubikey"]
+//! keys = ["SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E"]
+//! match_hosts = ["*.internal.example.com"]
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use service_binding::Binding;
+use ssh_key::{public::KeyData, Algorithm, Fingerprint, PublicKey};
+
+use crate::plugin::Plugin;
+use crate::script::Script;
+
+/// Options that can be set with `SSH_AGENT_MUX_<OPTION>` environment
+/// variables, e.g. `SSH_AGENT_MUX_MAX_IDENTITIES`, besides those with command
+/// line flags, which take their flag's variable.
+const ENV_OPTIONS: [&str; 22] = [
+    "max_message_size",
+    "session_max_requests",
+    "session_max_bytes",
+    "legacy_v1",
+    "max_pipelined_requests",
+    "duplicate_keys",
+    "max_identities",
+    "sign_retries",
+    "shutdown_timeout",
+    "redact_logs",
+    "event_socket",
+    "admin_http",
+    "admin_grpc",
+    "dbus_signals",
+    "notification_digest",
+    "usage_file",
+    "key_cache",
+    "onepassword_agent_toml",
+    "audit_log",
+    "anomalies",
+    "approval_cache",
+    "confirm_prompt",
+];
+
+/// Pipe of the OpenSSH agent service that ships with Windows.
+#[cfg(windows)]
+const WINDOWS_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+/// JSON Schema of the config file format.
+pub const SCHEMA: &str = include_str!("config/schema.json");
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub host: Option<Host>,
+    pub session_idle_timeout: Option<Duration>,
+    pub max_sessions: Option<usize>,
+    pub max_message_size: Option<usize>,
+    pub session_max_requests: Option<u64>,
+    pub session_max_bytes: Option<u64>,
+    pub legacy_v1: bool,
+    pub max_pipelined_requests: Option<usize>,
+    pub duplicate_keys: DuplicateKeys,
+    pub max_identities: Option<usize>,
+    /// Further attempts at a sign request that failed to reach its target,
+    /// before falling back to the next one or failing.
+    pub sign_retries: u32,
+    pub shutdown_timeout: Option<Duration>,
+    /// Log short fingerprints and hashed comments instead of keys and whole
+    /// messages.
+    pub redact_logs: bool,
+    /// Levels of log targets, or areas like `routing`, over `RUST_LOG`'s.
+    pub log_levels: Vec<(String, LevelFilter)>,
+    /// Unix socket streaming events to subscribers as JSON lines.
+    pub event_socket: Option<PathBuf>,
+    /// Unix socket or loopback address of the HTTP admin endpoint.
+    pub admin_http: Option<Binding>,
+    /// Unix socket of the gRPC admin service.
+    pub admin_grpc: Option<PathBuf>,
+    /// Emit events as signals on the D-Bus session bus.
+    pub dbus_signals: bool,
+    /// Send notifications following another within this long as one digest.
+    pub notification_digest: Option<Duration>,
+    pub alerts: AlertsConfig,
+    /// File keeping per-key signature counts across restarts.
+    pub usage_file: Option<PathBuf>,
+    /// Keeps which targets hold which keys across restarts.
+    pub key_cache: Option<PathBuf>,
+    /// 1Password's `agent.toml`, labeling the keys of 1Password targets.
+    pub onepassword_agent_toml: Option<PathBuf>,
+    /// File audit events are appended to as JSON lines.
+    pub audit_log: Option<PathBuf>,
+    /// Unix socket clients must run as one of these users or groups, when
+    /// either is set.  Supplementary groups count on Linux only.
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+    /// What to do with TCP clients by address, when any.
+    pub client_addresses: Vec<AddressRule>,
+    pub targets: Vec<TargetConfig>,
+    /// Directories whose sockets are targets too, after `targets`.
+    pub target_dirs: Vec<TargetDirConfig>,
+    pub keys: Vec<KeyConfig>,
+    pub routes: Vec<RouteConfig>,
+    /// Further host bindings, each serving a subset of the targets and keys.
+    pub views: Vec<ViewConfig>,
+    /// Script deciding where requests are signed, relative to the config
+    /// file's directory.
+    pub route_script: Option<PathBuf>,
+    /// `route_script`, loaded along with the config.
+    pub script: Option<Arc<Script>>,
+    /// WebAssembly plugins, relative to the config file's directory.
+    pub plugins: Vec<PathBuf>,
+    /// `plugins`, loaded along with the config.
+    pub loaded_plugins: Vec<Arc<Plugin>>,
+    /// Decoy identities listed after the others, which no legitimate client
+    /// signs with.
+    pub canary_keys: Vec<PublicKey>,
+    /// What to do with signatures unlike those their key made so far.
+    pub anomalies: Anomalies,
+    /// Signatures confirmed through `SSH_ASKPASS` aren't asked about again
+    /// for this long, for the same key, client and bound hosts.
+    pub approval_cache: Option<Duration>,
+    pub confirm_prompt: ConfirmPrompt,
+    pub add_constraints: AddConstraints,
+    pub sandbox: SandboxConfig,
+    pub socket_label: SocketLabelConfig,
+    pub pipe_security: PipeSecurityConfig,
+    pub webhook: Option<WebhookConfig>,
+}
+
+/// Where the mux serves clients.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Host {
+    Binding(Binding),
+    /// A single session over stdin and stdout, `stdio://`, for a mux spawned
+    /// per client by inetd or systemd socket activation with `Accept=yes`.
+    Stdio,
+}
+
+impl FromStr for Host {
+    type Err = service_binding::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdio://" => Ok(Host::Stdio),
+            _ => s.parse().map(Host::Binding),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Host {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parsed(deserializer, "binding")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetConfig {
+    pub name: String,
+    pub binding: Binding,
+    /// Requests beyond this many in flight to the target are queued.
+    pub max_concurrent_requests: Option<usize>,
+    /// Higher priority targets win keys held by more than one target, with
+    /// `duplicate_keys = "priority"`.
+    pub priority: i64,
+    /// Types of keys added through the mux that the target takes.  Empty
+    /// for any type.
+    pub add_key_types: Vec<KeyType>,
+    /// Identities of the target beyond this many aren't listed.
+    pub max_identities: Option<usize>,
+    /// Sessions close their connection to the target after this long
+    /// without a request, and reopen it for the next.
+    pub idle_timeout: Option<Duration>,
+    /// Requests not answered within this long fail like a broken
+    /// connection.
+    pub response_timeout: Option<Duration>,
+    /// The target's identities are only listed while the other targets are
+    /// unreachable or list none.
+    pub fallback: bool,
+    pub enumeration: Enumeration,
+    /// The target's identities are only listed, and its keys used, while it
+    /// is unlocked.
+    pub hidden: bool,
+    /// Hosts the target's keys may only be used toward, when any.
+    pub destinations: Vec<Destination>,
+    /// For targets by DNS name, connected to instead of `binding`, which is
+    /// empty.
+    pub tcp_name: Option<TcpName>,
+    /// The kind of agent the target is, for working around its quirks.
+    pub flavor: Flavor,
+    /// Environment variables gpg-agent starts pinentry with, passed to it
+    /// before requests that may prompt, over those of `forward_environment`.
+    pub environment: Vec<(String, String)>,
+    /// Variables of the mux's own environment passed like `environment`.
+    pub forward_environment: Vec<String>,
+}
+
+/// A TCP target by DNS name, resolved each time it is connected to so that
+/// DNS-based failover is followed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TcpName {
+    /// `tcp://host:port`.
+    Host { host: String, port: u16 },
+    /// `srv://name`: the hosts and ports of the SRV records of `name`, in
+    /// priority order.
+    Srv(String),
+}
+
+impl fmt::Display for TcpName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpName::Host { host, port } => write!(f, "tcp://{host}:{port}"),
+            TcpName::Srv(name) => write!(f, "srv://{name}"),
+        }
+    }
+}
+
+/// A host keys may be used toward, as a `known_hosts` line without markers:
+/// `<host> <key type> <base64 key>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Destination {
+    pub host: String,
+    pub host_key: KeyData,
+}
+
+impl<'de> Deserialize<'de> for Destination {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parsed(deserializer, "destination")
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, key) = s
+            .trim()
+            .split_once(' ')
+            .ok_or("expected a host name and its host key")?;
+        let key = PublicKey::from_openssh(key.trim()).map_err(|e| e.to_string())?;
+        Ok(Self {
+            host: host.to_owned(),
+            host_key: key.key_data().clone(),
+        })
+    }
+}
+
+/// A target as given on the command line or in `targets`: an agent, or a
+/// directory of them.
+#[derive(Clone, Debug)]
+pub enum TargetSpec {
+    Target(TargetConfig),
+    Dir(TargetDirConfig),
+}
+
+/// The sockets in a directory, `dir:///path`, each a target of its own.  The
+/// last component of the path may be a glob of the sockets' file names,
+/// e.g. `dir:///path/*.sock`.
+#[derive(Clone, Debug)]
+pub struct TargetDirConfig {
+    pub dir: PathBuf,
+    /// File names to match, with `*` and `?` wildcards.
+    pub pattern: Option<String>,
+    /// Settings of the targets found, which are named
+    /// `<name>/<file name>`.  Bound to the directory itself.
+    pub target: TargetConfig,
+}
+
+/// Key types, by the names the config file uses for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyType {
+    Dsa,
+    Ecdsa,
+    Ed25519,
+    Rsa,
+    SkEcdsa,
+    SkEd25519,
+}
+
+/// Which target a key held by more than one target is used from.  Signing
+/// falls back to the others, in the same order, while it is unreachable.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateKeys {
+    /// The first configured target holding the key.
+    #[default]
+    First,
+    /// The highest priority target holding the key, then the first.
+    Priority,
+    /// For signing, the target holding the key that recently signed the
+    /// fastest.  Otherwise like `First`.
+    Latency,
+    /// For signing, all healthy targets holding the key at once, using the
+    /// first signature.  Otherwise like `First`.
+    Race,
+    /// Fail to list identities.
+    Error,
+}
+
+/// What to do with signatures unlike their key's baseline: far more than it
+/// usually makes, toward a host it never signed toward or at an unusual
+/// hour.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Anomalies {
+    #[default]
+    Ignore,
+    /// Log them as audit events.
+    Warn,
+    /// Also require confirmation through `SSH_ASKPASS`.
+    Confirm,
+}
+
+/// When a target's identities are listed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Enumeration {
+    /// Whenever a client lists identities.
+    #[default]
+    Eager,
+    /// Only when the eager targets list none, or to find a key a client
+    /// signs with that they don't hold, e.g. for a slow network-backed agent.
+    Lazy,
+}
+
+/// A kind of agent whose quirks are worked around.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub enum Flavor {
+    /// OpenSSH's agent, or one that behaves like it.
+    #[default]
+    #[serde(rename = "openssh")]
+    OpenSsh,
+    /// gpg-agent's ssh socket, which supports no extensions, refuses keys
+    /// with constraints it doesn't know, and may wait on pinentry for a
+    /// passphrase or confirmation while signing.
+    #[serde(rename = "gpg-agent")]
+    GpgAgent,
+    /// 1Password's SSH agent, whose keys are labeled with their vault and
+    /// item with `onepassword_agent_toml`.
+    #[serde(rename = "1password")]
+    OnePassword,
+}
+
+impl Flavor {
+    /// The flavor of the agent at `binding`, as far as its path tells:
+    /// 1Password's listens on `~/.1password/agent.sock`, or in its group
+    /// container on macOS.
+    fn of(binding: &Binding) -> Self {
+        match binding {
+            Binding::FilePath(path)
+                if path.ends_with(".1password/agent.sock")
+                    || path.ends_with("2BUA8C4S2C.com.1password/t/agent.sock") =>
+            {
+                Self::OnePassword
+            }
+            _ => Self::OpenSsh,
+        }
+    }
+}
+
+/// Where confirmations are asked for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfirmPrompt {
+    /// On the terminal when running in the foreground of one without
+    /// `SSH_ASKPASS` set, otherwise through `SSH_ASKPASS`.
+    #[default]
+    Auto,
+    Askpass,
+    /// With a y/N prompt on the controlling terminal.
+    Terminal,
+}
+
+/// Per-key policy, selected by fingerprint.
+#[derive(Clone, Debug)]
+pub struct KeyConfig {
+    pub fingerprint: Fingerprint,
+    /// Shown along with the key's fingerprint wherever it is logged or
+    /// printed.
+    pub nickname: Option<String>,
+    pub max_signatures_per_hour: Option<u32>,
+    /// Signatures with the key within this long of its last one are denied.
+    pub cooldown: Option<Duration>,
+    /// Host keys sessions must be bound to with `session-bind@openssh.com`
+    /// to sign with the key, when any.
+    pub allowed_host_keys: Vec<Fingerprint>,
+    /// Refuse to sign with the key in sessions that weren't bound to a host
+    /// at all.
+    pub require_session_bind: bool,
+    /// Local times the key may sign at, when any.
+    pub allowed_times: Vec<TimeWindow>,
+    /// Program that must approve each signature with the key.
+    pub exec: Option<PathBuf>,
+}
+
+/// A window of local time on some days of the week, like
+/// `Mon-Fri 08:00-19:00`.  Windows ending before they start run past
+/// midnight, into the next day.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeWindow {
+    /// Bit `n` for day `n` of the week, from Monday.
+    days: u8,
+    /// Minutes into the day.
+    start: u16,
+    end: u16,
+}
+
+impl TimeWindow {
+    const DAYS: [&'static str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+    /// Whether the window holds `minute` of `day` of the week, from Monday
+    /// as 0.
+    pub fn contains(&self, day: u32, minute: u32) -> bool {
+        let on = |day: u32| self.days & (1 << (day % 7)) != 0;
+        let (start, end) = (u32::from(self.start), u32::from(self.end));
+        if start < end {
+            on(day) && (start..end).contains(&minute)
+        } else {
+            (on(day) && minute >= start) || (on(day + 6) && minute < end)
+        }
+    }
+
+    fn day(s: &str) -> Result<u32, String> {
+        Self::DAYS
+            .iter()
+            .position(|day| day.eq_ignore_ascii_case(s))
+            .map(|day| day as u32)
+            .ok_or_else(|| format!("unknown day '{s}'"))
+    }
+
+    fn minute(s: &str) -> Result<u16, String> {
+        let invalid = || format!("invalid time '{s}'");
+        let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+        let hours: u16 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u16 = minutes.parse().map_err(|_| invalid())?;
+        if minutes >= 60 || hours * 60 + minutes > 24 * 60 {
+            return Err(invalid());
+        }
+        Ok(hours * 60 + minutes)
+    }
+}
+
+/// Shown as parsed, e.g. `Mon-Fri 08:00-19:00`, without days for every day.
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.days != 0x7f {
+            let on = |day: usize| self.days & (1 << day) != 0;
+            let mut ranges = Vec::new();
+            let mut day = 0;
+            while day < 7 {
+                if !on(day) {
+                    day += 1;
+                    continue;
+                }
+                let first = day;
+                while day + 1 < 7 && on(day + 1) {
+                    day += 1;
+                }
+                ranges.push(match first == day {
+                    true => Self::DAYS[day].to_owned(),
+                    false => format!("{}-{}", Self::DAYS[first], Self::DAYS[day]),
+                });
+                day += 1;
+            }
+            write!(f, "{} ", ranges.join(","))?;
+        }
+        let time = |minute: u16| format!("{:02}:{:02}", minute / 60, minute % 60);
+        write!(f, "{}-{}", time(self.start), time(self.end))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeWindow {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parsed(deserializer, "time window")
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = match s.trim().rsplit_once(' ') {
+            Some((days, times)) => (Some(days), times),
+            None => (None, s.trim()),
+        };
+        let days = match days {
+            None => 0x7f,
+            Some(days) => {
+                let mut bits = 0;
+                for range in days.split(',') {
+                    let (first, last) = match range.trim().split_once('-') {
+                        Some((first, last)) => (Self::day(first)?, Self::day(last)?),
+                        None => (Self::day(range.trim())?, Self::day(range.trim())?),
+                    };
+                    // Ranges like Sat-Mon wrap around the week.
+                    let mut day = first;
+                    bits |= 1 << day;
+                    while day != last {
+                        day = (day + 1) % 7;
+                        bits |= 1 << day;
+                    }
+                }
+                bits
+            }
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or("expected a time range like 08:00-19:00")?;
+        let (start, end) = (Self::minute(start)?, Self::minute(end)?);
+        if start == end {
+            return Err("empty time range".to_owned());
+        }
+        Ok(Self { days, start, end })
+    }
+}
+
+/// What to do with TCP clients from a network.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AddressRule {
+    pub network: Network,
+    pub action: AddressAction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressAction {
+    Allow,
+    Deny,
+    /// Serve the client, asking through `SSH_ASKPASS` before every
+    /// signature.
+    Confirm,
+}
+
+impl AddressRule {
+    /// The action of the most specific of `rules` matching `address`.  Any
+    /// address is allowed without rules, and none that no rule matches.
+    pub fn action_for(rules: &[AddressRule], address: IpAddr) -> AddressAction {
+        if rules.is_empty() {
+            return AddressAction::Allow;
+        }
+        rules
+            .iter()
+            .filter(|rule| rule.network.contains(address))
+            .max_by_key(|rule| rule.network.prefix_len)
+            .map_or(AddressAction::Deny, |rule| rule.action)
+    }
+}
+
+/// An IP network in CIDR notation, like `10.0.0.0/8`, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of dual-stack sockets show up as mapped addresses.
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            address => address,
+        };
+        let bits = |address: IpAddr| match address {
+            IpAddr::V4(v4) => (u128::from(u32::from(v4)), 32),
+            IpAddr::V6(v6) => (u128::from(v6), 128),
+        };
+        let ((network, len), (address, address_len)) = (bits(self.address), bits(address));
+        if len != address_len {
+            return false;
+        }
+        let shift = u32::from(len - self.prefix_len);
+        network.checked_shr(shift).unwrap_or(0) == address.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parsed(deserializer, "network")
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|e| format!("{e}"))?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|&prefix_len| prefix_len <= max)
+                .ok_or_else(|| format!("invalid prefix length '{prefix_len}'"))?,
+            None => max,
+        };
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+/// A further host binding of the mux, serving only some of its targets and
+/// keys, e.g. a socket forwarded into containers.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ViewConfig {
+    /// The view's key in `views`.
+    #[serde(skip)]
+    pub name: String,
+    #[serde(deserialize_with = "binding_to_serve")]
+    pub host: Binding,
+    /// Names of the targets served, or of directory targets serving all
+    /// their sockets.  Empty for all.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// Keys listed and signed with.  Empty for all.
+    #[serde(default, deserialize_with = "fingerprints")]
+    pub keys: Vec<Fingerprint>,
+    /// Patterns of the host names of destinations the view is for, as in
+    /// `ssh_config`'s `Host`, for `ssh-agent-mux match`.
+    #[serde(default)]
+    pub match_hosts: Vec<String>,
+}
+
+impl ViewConfig {
+    /// Whether the view is for the destination `host`: a pattern of
+    /// `match_hosts` matches it and no negated one, starting with `!`, does.
+    /// Case doesn't matter.
+    pub fn matches_host(&self, host: &str) -> bool {
+        let host: Vec<char> = host.to_lowercase().chars().collect();
+        let matches = |pattern: &str| {
+            let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+            glob_matches(&pattern, &host)
+        };
+        let mut matched = false;
+        for pattern in &self.match_hosts {
+            match pattern.strip_prefix('!') {
+                Some(negated) if matches(negated) => return false,
+                Some(_) => (),
+                None => matched |= matches(pattern),
+            }
+        }
+        matched
+    }
+
+    /// Whether the view serves the target named `name`.
+    pub fn serves_target(&self, name: &str) -> bool {
+        self.targets.is_empty()
+            || self.targets.iter().any(|target| {
+                name == target
+                    || name
+                        .strip_prefix(target.as_str())
+                        .is_some_and(|file| file.starts_with('/'))
+            })
+    }
+}
+
+/// Signs with a key on the named target only, whichever targets list it.
+#[derive(Clone, Debug)]
+pub struct RouteConfig {
+    pub fingerprint: Fingerprint,
+    pub target: String,
+}
+
+/// Error rates notified of once, when reached.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertsConfig {
+    /// Failures of a target within `window` to alert of.
+    #[serde(deserialize_with = "positive")]
+    pub target_failures: Option<usize>,
+    /// A minute, unless set.
+    #[serde(deserialize_with = "window")]
+    pub window: Option<Duration>,
+}
+
+/// Constraints added to every key added through the mux, on top of the
+/// client's own.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AddConstraints {
+    /// Require confirmation for every use of the key.
+    pub confirm: bool,
+    /// Remove the key after this long, at most.
+    #[serde(deserialize_with = "lifetime")]
+    pub lifetime: Option<Duration>,
+}
+
+impl AddConstraints {
+    pub fn is_empty(&self) -> bool {
+        !self.confirm && self.lifetime.is_none()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxConfig {
+    /// User to switch to, by name or ID.
+    pub user: Option<String>,
+    /// Group to switch to, by name or ID.
+    pub group: Option<String>,
+    pub landlock: bool,
+    pub pledge: bool,
+    #[serde(deserialize_with = "seccomp")]
+    pub seccomp: Option<SeccompMode>,
+    /// Directory to confine the process to once privileges are dropped.
+    pub chroot: Option<PathBuf>,
+    /// Enter Capsicum capability mode (FreeBSD).
+    pub capsicum: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeccompMode {
+    /// Kill the process on a disallowed syscall.
+    Enforce,
+    /// Only log disallowed syscalls to the kernel audit log.
+    Log,
+}
+
+/// Security labels of the host socket.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketLabelConfig {
+    /// SELinux context the socket and its file are created with.
+    pub selinux: Option<String>,
+    /// SMACK label of the socket file.
+    pub smack: Option<String>,
+}
+
+/// Who may connect to a named pipe host (Windows).  Without either option,
+/// only the user running the mux may.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipeSecurityConfig {
+    /// SIDs or SDDL aliases of groups that may connect too.
+    pub allow_groups: Vec<String>,
+    /// Security descriptor of the pipe in SDDL, replacing the above.
+    pub sddl: Option<String>,
+}
+
+/// Where audit events are posted.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: HttpUrl,
+    /// Key of the HMAC-SHA256 signature sent with each event.
+    pub secret: Option<String>,
+    /// Further attempts at delivering an event after the first fails.
+    #[serde(default = "WebhookConfig::default_retries")]
+    pub retries: u32,
+}
+
+impl WebhookConfig {
+    fn default_retries() -> u32 {
+        3
+    }
+}
+
+/// An `http://` or `https://` URL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpUrl {
+    pub https: bool,
+    pub host: String,
+    pub port: u16,
+    /// Starts with `/`.
+    pub path: String,
+}
+
+impl<'de> Deserialize<'de> for HttpUrl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parsed(deserializer, "URL")
+    }
+}
+
+impl FromStr for HttpUrl {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (https, rest) = match (s.strip_prefix("https://"), s.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => return Err("only http:// and https:// URLs are supported"),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        // The port follows the last colon, unless it is inside an IPv6
+        // address.
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| "invalid port")?)
+            }
+            _ if https => (authority, 443),
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err("missing host");
+        }
+        Ok(Self {
+            https,
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl HttpUrl {
+    /// The host and port, as in a `Host` header.
+    pub fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.https { "https" } else { "http" };
+        write!(f, "{scheme}://{}{}", self.authority(), self.path)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{}: {1}", .0.display())]
+    Io(PathBuf, #[source] std::io::Error),
+    /// The problems found in the file, in order.
+    #[error("{}", problems(.0, .1))]
+    Invalid(PathBuf, Vec<ParseError>),
+    #[error("{}: no profile '{1}'", .0.display())]
+    UnknownProfile(PathBuf, String),
+    #[error("{}: {1}", .0.display())]
+    Plugin(PathBuf, #[source] crate::plugin::Error),
+    #[error("{}", .0.join("\n"))]
+    Env(Vec<String>),
+    /// Options the seccomp filter would kill the mux for.
+    #[error("{}", .0.join("\n"))]
+    Seccomp(Vec<String>),
+    #[error("sandbox.chroot requires sandbox.user, as root can leave a chroot")]
+    ChrootWithoutUser,
+}
+
+fn problems(path: &Path, errors: &[ParseError]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("{}: {e}", path.display()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A problem with a config file or route script, and the line it is on.
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// The options of a config file, or of one of its profiles, as written:
+/// `None` where left out.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Options {
+    host: Option<Host>,
+    #[serde(default, deserialize_with = "duration")]
+    session_idle_timeout: Option<Duration>,
+    max_sessions: Option<usize>,
+    #[serde(default, deserialize_with = "positive")]
+    max_message_size: Option<usize>,
+    #[serde(default, deserialize_with = "positive")]
+    session_max_requests: Option<u64>,
+    #[serde(default, deserialize_with = "positive")]
+    session_max_bytes: Option<u64>,
+    legacy_v1: Option<bool>,
+    #[serde(default, deserialize_with = "positive")]
+    max_pipelined_requests: Option<usize>,
+    duplicate_keys: Option<DuplicateKeys>,
+    #[serde(default, deserialize_with = "positive")]
+    max_identities: Option<usize>,
+    sign_retries: Option<u32>,
+    #[serde(default, deserialize_with = "duration")]
+    shutdown_timeout: Option<Duration>,
+    redact_logs: Option<bool>,
+    #[serde(default, deserialize_with = "entries")]
+    log_levels: Option<Vec<(String, LevelFilter)>>,
+    event_socket: Option<PathBuf>,
+    #[serde(default, deserialize_with = "local_binding")]
+    admin_http: Option<Binding>,
+    admin_grpc: Option<PathBuf>,
+    dbus_signals: Option<bool>,
+    #[serde(default, deserialize_with = "duration")]
+    notification_digest: Option<Duration>,
+    alerts: Option<AlertsConfig>,
+    usage_file: Option<PathBuf>,
+    key_cache: Option<PathBuf>,
+    onepassword_agent_toml: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    allowed_uids: Option<Vec<u32>>,
+    allowed_gids: Option<Vec<u32>>,
+    #[serde(default, deserialize_with = "address_rules")]
+    client_addresses: Option<Vec<AddressRule>>,
+    #[serde(default, deserialize_with = "targets")]
+    targets: Option<Vec<TargetSpec>>,
+    #[serde(default, deserialize_with = "keys")]
+    keys: Option<Vec<KeyConfig>>,
+    #[serde(default, deserialize_with = "routes")]
+    routes: Option<Vec<RouteConfig>>,
+    #[serde(default, deserialize_with = "views")]
+    views: Option<Vec<ViewConfig>>,
+    route_script: Option<PathBuf>,
+    plugins: Option<Vec<PathBuf>>,
+    #[serde(default, deserialize_with = "public_keys")]
+    canary_keys: Option<Vec<PublicKey>>,
+    anomalies: Option<Anomalies>,
+    #[serde(default, deserialize_with = "duration")]
+    approval_cache: Option<Duration>,
+    confirm_prompt: Option<ConfirmPrompt>,
+    add_constraints: Option<AddConstraints>,
+    sandbox: Option<SandboxConfig>,
+    socket_label: Option<SocketLabelConfig>,
+    pipe_security: Option<PipeSecurityConfig>,
+    webhook: Option<WebhookConfig>,
+    #[serde(default, deserialize_with = "profiles")]
+    profiles: Option<Vec<(String, Options)>>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::load_profile(path, None)
+    }
+
+    /// Loads a config file with the options of `profile`, from its
+    /// `[profiles]` table, on top of those outside of it.
+    pub fn load_profile(path: &Path, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let input =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        let options = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => json_options(&input),
+            Some("yaml" | "yml") => yaml_options(&input),
+            _ => toml_options(&input),
+        };
+        let mut options = options.map_err(|e| ConfigError::Invalid(path.to_owned(), vec![e]))?;
+        let profiles = options.profiles.take();
+        let mut config = Config::default();
+        config.apply(options);
+        if let Some(name) = profile {
+            let (_, profile) = profiles
+                .into_iter()
+                .flatten()
+                .find(|(profile, _)| profile == name)
+                .ok_or_else(|| ConfigError::UnknownProfile(path.to_owned(), name.to_owned()))?;
+            config.apply(profile);
+        }
+        if let Some(script) = &config.route_script {
+            let script = path.parent().unwrap_or(Path::new("")).join(script);
+            let source =
+                std::fs::read_to_string(&script).map_err(|e| ConfigError::Io(script.clone(), e))?;
+            let parsed = Script::parse(&source).map_err(|e| {
+                ConfigError::Invalid(
+                    script.clone(),
+                    vec![ParseError {
+                        line: e.line,
+                        message: e.message,
+                    }],
+                )
+            })?;
+            config.route_script = Some(script);
+            config.script = Some(Arc::new(parsed));
+        }
+        for plugin in &mut config.plugins {
+            *plugin = path.parent().unwrap_or(Path::new("")).join(&plugin);
+            let bytes = std::fs::read(&plugin).map_err(|e| ConfigError::Io(plugin.clone(), e))?;
+            let loaded =
+                Plugin::new(plugin, &bytes).map_err(|e| ConfigError::Plugin(plugin.clone(), e))?;
+            config.loaded_plugins.push(Arc::new(loaded));
+        }
+        Ok(config)
+    }
+
+    /// Sets options from `SSH_AGENT_MUX_*` environment variables, on top of
+    /// the config file.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        for (name, value) in vars {
+            let Some(key) = name
+                .strip_prefix("SSH_AGENT_MUX_")
+                .map(str::to_ascii_lowercase)
+                .filter(|key| ENV_OPTIONS.contains(&key.as_str()))
+            else {
+                continue;
+            };
+            let value = match value.as_str() {
+                "true" => toml::Value::Boolean(true),
+                "false" => toml::Value::Boolean(false),
+                _ => value
+                    .parse()
+                    .map_or(toml::Value::String(value), toml::Value::Integer),
+            };
+            let table = toml::Table::from_iter([(key, value)]);
+            match Options::deserialize(toml::Value::Table(table)) {
+                Ok(options) => self.apply(options),
+                Err(e) => problems.push(format!("{name}: {}", e.message())),
+            }
+        }
+        if !problems.is_empty() {
+            return Err(ConfigError::Env(problems));
+        }
+        Ok(())
+    }
+
+    /// Sets the options given in `options`.  Lists and tables of options,
+    /// like `targets`, replace those already set.
+    fn apply(&mut self, options: Options) {
+        let Options {
+            host,
+            session_idle_timeout,
+            max_sessions,
+            max_message_size,
+            session_max_requests,
+            session_max_bytes,
+            legacy_v1,
+            max_pipelined_requests,
+            duplicate_keys,
+            max_identities,
+            sign_retries,
+            shutdown_timeout,
+            redact_logs,
+            log_levels,
+            event_socket,
+            admin_http,
+            admin_grpc,
+            dbus_signals,
+            notification_digest,
+            alerts,
+            usage_file,
+            key_cache,
+            onepassword_agent_toml,
+            audit_log,
+            allowed_uids,
+            allowed_gids,
+            client_addresses,
+            targets,
+            keys,
+            routes,
+            views,
+            route_script,
+            plugins,
+            canary_keys,
+            anomalies,
+            approval_cache,
+            confirm_prompt,
+            add_constraints,
+            sandbox,
+            socket_label,
+            pipe_security,
+            webhook,
+            profiles: _,
+        } = options;
+        self.host = host.or(self.host.take());
+        self.session_idle_timeout = session_idle_timeout.or(self.session_idle_timeout);
+        self.max_sessions = max_sessions.or(self.max_sessions);
+        self.max_message_size = max_message_size.or(self.max_message_size);
+        self.session_max_requests = session_max_requests.or(self.session_max_requests);
+        self.session_max_bytes = session_max_bytes.or(self.session_max_bytes);
+        set(&mut self.legacy_v1, legacy_v1);
+        self.max_pipelined_requests = max_pipelined_requests.or(self.max_pipelined_requests);
+        set(&mut self.duplicate_keys, duplicate_keys);
+        self.max_identities = max_identities.or(self.max_identities);
+        set(&mut self.sign_retries, sign_retries);
+        self.shutdown_timeout = shutdown_timeout.or(self.shutdown_timeout);
+        set(&mut self.redact_logs, redact_logs);
+        set(&mut self.log_levels, log_levels);
+        self.event_socket = event_socket.or(self.event_socket.take());
+        self.admin_http = admin_http.or(self.admin_http.take());
+        self.admin_grpc = admin_grpc.or(self.admin_grpc.take());
+        set(&mut self.dbus_signals, dbus_signals);
+        self.notification_digest = notification_digest.or(self.notification_digest);
+        set(&mut self.alerts, alerts);
+        self.usage_file = usage_file.or(self.usage_file.take());
+        self.key_cache = key_cache.or(self.key_cache.take());
+        self.onepassword_agent_toml = onepassword_agent_toml.or(self.onepassword_agent_toml.take());
+        self.audit_log = audit_log.or(self.audit_log.take());
+        set(&mut self.allowed_uids, allowed_uids);
+        set(&mut self.allowed_gids, allowed_gids);
+        set(&mut self.client_addresses, client_addresses);
+        if let Some(targets) = targets {
+            self.targets.clear();
+            self.target_dirs.clear();
+            for target in targets {
+                self.add_target(target);
+            }
+        }
+        set(&mut self.keys, keys);
+        set(&mut self.routes, routes);
+        set(&mut self.views, views);
+        self.route_script = route_script.or(self.route_script.take());
+        set(&mut self.plugins, plugins);
+        set(&mut self.canary_keys, canary_keys);
+        set(&mut self.anomalies, anomalies);
+        self.approval_cache = approval_cache.or(self.approval_cache);
+        set(&mut self.confirm_prompt, confirm_prompt);
+        set(&mut self.add_constraints, add_constraints);
+        set(&mut self.sandbox, sandbox);
+        set(&mut self.socket_label, socket_label);
+        set(&mut self.pipe_security, pipe_security);
+        self.webhook = webhook.or(self.webhook.take());
+    }
+
+    pub fn view(&self, name: &str) -> Option<&ViewConfig> {
+        self.views.iter().find(|view| view.name == name)
+    }
+
+    /// Adds a target, or a directory of them, after those already set.
+    pub fn add_target(&mut self, target: TargetSpec) {
+        match target {
+            TargetSpec::Target(target) => self.targets.push(target),
+            TargetSpec::Dir(dir) => self.target_dirs.push(dir),
+        }
+    }
+
+    /// Adds the OpenSSH agent of Windows as target `windows` if no targets
+    /// are set and its service is running.  Whether it was added.
+    #[cfg(windows)]
+    pub fn add_windows_agent(&mut self) -> bool {
+        if !self.targets.is_empty()
+            || !self.target_dirs.is_empty()
+            || !Path::new(WINDOWS_AGENT_PIPE).exists()
+        {
+            return false;
+        }
+        self.targets.push(TargetConfig::new(
+            "windows".to_owned(),
+            Binding::NamedPipe(WINDOWS_AGENT_PIPE.into()),
+        ));
+        true
+    }
+
+    /// Problems with the bindings of the host and targets that would keep
+    /// the mux from serving, or from reaching a target, on this platform,
+    /// each with a suggestion.  Checked before serving.
+    pub fn check_bindings(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Some(Host::Binding(host)) = &self.host {
+            binding_problems("host", host, true, &mut problems);
+            if let Some(target) = self.targets.iter().find(|target| target.binding == *host) {
+                problems.push(format!(
+                    "host: same as target {}, so the mux would proxy to itself; is SSH_AUTH_SOCK already the mux?",
+                    target.name
+                ));
+            }
+        }
+        for (i, view) in self.views.iter().enumerate() {
+            let name = format!("views.{}.host", view.name);
+            binding_problems(&name, &view.host, true, &mut problems);
+            let host = match &self.host {
+                Some(Host::Binding(host)) if *host == view.host => Some("the host"),
+                _ => self.views[..i]
+                    .iter()
+                    .any(|other| other.host == view.host)
+                    .then_some("another view's"),
+            };
+            if let Some(host) = host {
+                problems.push(format!(
+                    "{name}: same as {host}; give each view a socket of its own"
+                ));
+            }
+            if let Some(target) = self
+                .targets
+                .iter()
+                .find(|target| target.binding == view.host)
+            {
+                problems.push(format!(
+                    "{name}: same as target {}, so the mux would proxy to itself",
+                    target.name
+                ));
+            }
+        }
+        for (i, target) in self.targets.iter().enumerate() {
+            let name = format!("targets.{}", target.name);
+            if self.targets[..i]
+                .iter()
+                .any(|other| other.name == target.name)
+            {
+                problems.push(format!("{name}: duplicate target name; rename one of them"));
+            }
+            // Those resolved on connection have no binding yet.
+            if target.tcp_name.is_none() {
+                binding_problems(&name, &target.binding, false, &mut problems);
+            }
+        }
+        for dir in &self.target_dirs {
+            if dir.dir.is_relative() {
+                problems.push(format!(
+                    "targets.{}: {} is a relative path; use an absolute one, e.g. dir://{}",
+                    dir.target.name,
+                    dir.dir.display(),
+                    absolute(&dir.dir).display()
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Problems with a complete configuration, with the command line
+    /// applied, that parsing it doesn't catch.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = self.check_bindings();
+        match &self.host {
+            None => problems.push("no host to bind to".to_owned()),
+            Some(Host::Stdio) => (),
+            Some(Host::Binding(host)) => {
+                if let Binding::FilePath(path) = host {
+                    match path.parent() {
+                        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                            problems.push(format!("host: {} doesn't exist", dir.display()));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+        if let Some(Host::Binding(Binding::Sockets(_))) = &self.host {
+            if !self.allowed_uids.is_empty() || !self.allowed_gids.is_empty() {
+                problems.push(
+                    "allowed_uids, allowed_gids: TCP clients have no UID or GID to allow"
+                        .to_owned(),
+                );
+            }
+        }
+        if !self.client_addresses.is_empty()
+            && !matches!(&self.host, Some(Host::Binding(Binding::Sockets(_))))
+        {
+            problems.push("client_addresses: only TCP clients have addresses".to_owned());
+        }
+        let sandboxed = self.sandbox.landlock
+            || self.sandbox.pledge
+            || self.sandbox.seccomp.is_some()
+            || self.sandbox.capsicum;
+        if sandboxed
+            && self
+                .client_addresses
+                .iter()
+                .any(|rule| rule.action == AddressAction::Confirm)
+        {
+            problems.push(
+                "client_addresses: \"confirm\" runs SSH_ASKPASS, which the sandbox prevents"
+                    .to_owned(),
+            );
+        }
+        if sandboxed && self.anomalies == Anomalies::Confirm {
+            problems.push(
+                "anomalies: \"confirm\" runs SSH_ASKPASS, which the sandbox prevents".to_owned(),
+            );
+        }
+        for key in &self.keys {
+            if let Some(program) = &key.exec {
+                if sandboxed {
+                    problems.push(format!(
+                        "keys.\"{}\".exec: running programs is prevented by the sandbox",
+                        key.fingerprint
+                    ));
+                }
+                if !program.is_file() {
+                    problems.push(format!(
+                        "keys.\"{}\".exec: {} doesn't exist",
+                        key.fingerprint,
+                        program.display()
+                    ));
+                }
+            }
+        }
+        if self.pipe_security.sddl.is_some() && !self.pipe_security.allow_groups.is_empty() {
+            problems.push("pipe_security.allow_groups: ignored with pipe_security.sddl".to_owned());
+        }
+        if let Some(dir) = &self.sandbox.chroot {
+            if !dir.is_dir() {
+                problems.push(format!("sandbox.chroot: {} doesn't exist", dir.display()));
+            }
+        }
+        if let Some(path) = &self.event_socket {
+            match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    problems.push(format!("event_socket: {} doesn't exist", dir.display()));
+                }
+                _ => (),
+            }
+        }
+        if let Some(Binding::FilePath(path)) = &self.admin_http {
+            match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    problems.push(format!("admin_http: {} doesn't exist", dir.display()));
+                }
+                _ => (),
+            }
+        }
+        if let Some(path) = &self.admin_grpc {
+            match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    problems.push(format!("admin_grpc: {} doesn't exist", dir.display()));
+                }
+                _ => (),
+            }
+        }
+        if let Some(path) = &self.usage_file {
+            match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    problems.push(format!("usage_file: {} doesn't exist", dir.display()));
+                }
+                _ => (),
+            }
+        }
+        if let Some(path) = &self.key_cache {
+            match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    problems.push(format!("key_cache: {} doesn't exist", dir.display()));
+                }
+                _ => (),
+            }
+        }
+        if let Some(path) = &self.audit_log {
+            match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    problems.push(format!("audit_log: {} doesn't exist", dir.display()));
+                }
+                _ => (),
+            }
+        }
+        for target in &self.targets {
+            if let Binding::FilePath(path) = &target.binding {
+                if !path.exists() {
+                    problems.push(format!(
+                        "targets.{}: {} doesn't exist",
+                        target.name,
+                        path.display()
+                    ));
+                }
+            }
+        }
+        for target in &self.targets {
+            let environment =
+                !target.environment.is_empty() || !target.forward_environment.is_empty();
+            if environment && target.gpg_agent_socket().is_none() {
+                problems.push(format!(
+                    "targets.{}: environment is only passed to gpg-agent targets at a Unix socket ending in .ssh",
+                    target.name
+                ));
+            }
+        }
+        for dir in &self.target_dirs {
+            if !dir.dir.is_dir() {
+                problems.push(format!(
+                    "targets.{}: {} doesn't exist",
+                    dir.target.name,
+                    dir.dir.display()
+                ));
+            }
+        }
+        for view in &self.views {
+            for name in &view.targets {
+                if !self.targets.iter().any(|target| target.name == *name)
+                    && !self.target_dirs.iter().any(|dir| dir.target.name == *name)
+                {
+                    problems.push(format!("views.{}: unknown target {name}", view.name));
+                }
+            }
+        }
+        for route in &self.routes {
+            // Targets in directories may come and go.
+            if !self
+                .targets
+                .iter()
+                .any(|target| target.name == route.target)
+                && !self
+                    .target_dirs
+                    .iter()
+                    .any(|dir| dir.contains(&route.target))
+            {
+                problems.push(format!(
+                    "routes.\"{}\": unknown target {}",
+                    route.fingerprint, route.target
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Fails configs with a sandbox that can't be applied: a chroot without
+    /// a user to switch to.
+    pub fn check_sandbox(&self) -> Result<(), ConfigError> {
+        match self.sandbox.chroot.is_some() && self.sandbox.user.is_none() {
+            true => Err(ConfigError::ChrootWithoutUser),
+            false => Ok(()),
+        }
+    }
+
+    /// Fails configs the seccomp filter `mode` would kill the mux for when
+    /// enforced: those running programs, which inherit the filter, and those
+    /// with targets found by name unless `resolving` names was allowed when
+    /// the filter was installed.
+    pub fn check_seccomp(
+        &self,
+        mode: Option<SeccompMode>,
+        resolving: bool,
+    ) -> Result<(), ConfigError> {
+        if mode != Some(SeccompMode::Enforce) {
+            return Ok(());
+        }
+        let mut problems = Vec::new();
+        for key in &self.keys {
+            if key.exec.is_some() {
+                problems.push(format!(
+                    "keys.\"{}\".exec: seccomp = \"enforce\" doesn't let the mux run programs",
+                    key.fingerprint
+                ));
+            }
+        }
+        if self.confirm_prompt != ConfirmPrompt::Terminal {
+            if self
+                .client_addresses
+                .iter()
+                .any(|rule| rule.action == AddressAction::Confirm)
+            {
+                problems.push(
+                    "client_addresses: \"confirm\" runs SSH_ASKPASS, which seccomp = \"enforce\" doesn't allow; set confirm_prompt = \"terminal\""
+                        .to_owned(),
+                );
+            }
+            if self.anomalies == Anomalies::Confirm {
+                problems.push(
+                    "anomalies: \"confirm\" runs SSH_ASKPASS, which seccomp = \"enforce\" doesn't allow; set confirm_prompt = \"terminal\""
+                        .to_owned(),
+                );
+            }
+        }
+        if !resolving {
+            for target in self
+                .targets
+                .iter()
+                .filter(|target| target.tcp_name.is_some())
+            {
+                problems.push(format!(
+                    "targets.{}: resolving names wasn't allowed by seccomp when the mux started; restart it",
+                    target.name
+                ));
+            }
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError::Seccomp(problems)),
+        }
+    }
+}
+
+impl TargetConfig {
+    /// A target with default settings.
+    pub fn new(name: String, binding: Binding) -> Self {
+        Self {
+            name,
+            max_concurrent_requests: None,
+            priority: 0,
+            add_key_types: Vec::new(),
+            max_identities: None,
+            idle_timeout: None,
+            response_timeout: None,
+            fallback: false,
+            enumeration: Enumeration::Eager,
+            hidden: false,
+            destinations: Vec::new(),
+            tcp_name: None,
+            flavor: Flavor::of(&binding),
+            environment: Vec::new(),
+            forward_environment: Vec::new(),
+            binding,
+        }
+    }
+
+    /// The environment to pass to gpg-agent: the variables of
+    /// `forward_environment` that the mux has, then `environment`.
+    pub fn passed_environment(&self) -> Vec<(String, String)> {
+        let mut passed: Vec<(String, String)> = self
+            .forward_environment
+            .iter()
+            .filter_map(|name| Some((name.clone(), std::env::var(name).ok()?)))
+            .collect();
+        for (name, value) in &self.environment {
+            passed.retain(|(forwarded, _)| forwarded != name);
+            passed.push((name.clone(), value.clone()));
+        }
+        passed
+    }
+
+    /// gpg-agent's own socket next to its ssh socket, e.g. `S.gpg-agent`
+    /// for `S.gpg-agent.ssh`, for gpg-agent targets passed an environment.
+    pub fn gpg_agent_socket(&self) -> Option<PathBuf> {
+        if self.flavor != Flavor::GpgAgent
+            || self.environment.is_empty() && self.forward_environment.is_empty()
+        {
+            return None;
+        }
+        let Binding::FilePath(path) = &self.binding else {
+            return None;
+        };
+        let path = path.to_str()?.strip_suffix(".ssh")?;
+        Some(PathBuf::from(path))
+    }
+}
+
+/// The options of a target given as a table in `targets`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetOptions {
+    #[serde(deserialize_with = "target_spec")]
+    binding: TargetSpec,
+    #[serde(default, deserialize_with = "positive")]
+    max_concurrent_requests: Option<usize>,
+    #[serde(default)]
+    priority: i64,
+    #[serde(default)]
+    add_key_types: Vec<KeyType>,
+    #[serde(default, deserialize_with = "positive")]
+    max_identities: Option<usize>,
+    #[serde(default, deserialize_with = "duration")]
+    idle_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "duration")]
+    response_timeout: Option<Duration>,
+    #[serde(default)]
+    fallback: bool,
+    #[serde(default)]
+    enumeration: Enumeration,
+    #[serde(default)]
+    hidden: bool,
+    #[serde(default)]
+    destinations: Vec<Destination>,
+    flavor: Option<Flavor>,
+    #[serde(default, deserialize_with = "variables")]
+    environment: Vec<(String, String)>,
+    #[serde(default, deserialize_with = "variable_names")]
+    forward_environment: Vec<String>,
+}
+
+impl TargetOptions {
+    fn into_spec(self) -> TargetSpec {
+        let mut spec = self.binding;
+        let target = spec.target_mut();
+        target.max_concurrent_requests = self.max_concurrent_requests;
+        target.priority = self.priority;
+        target.add_key_types = self.add_key_types;
+        target.max_identities = self.max_identities;
+        target.idle_timeout = self.idle_timeout;
+        target.response_timeout = self.response_timeout;
+        target.fallback = self.fallback;
+        target.enumeration = self.enumeration;
+        target.hidden = self.hidden;
+        target.destinations = self.destinations;
+        if let Some(flavor) = self.flavor {
+            target.flavor = flavor;
+        }
+        target.environment = self.environment;
+        target.forward_environment = self.forward_environment;
+        spec
+    }
+}
+
+/// A target as written in `targets`: its binding, or a table of its options
+/// with one.  Unnamed, until named after its key.
+impl<'de> Deserialize<'de> for TargetSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Target;
+
+        impl<'de> Visitor<'de> for Target {
+            type Value = TargetSpec;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a binding or a table of target options")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                unnamed_target(s)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                TargetOptions::deserialize(de::value::MapAccessDeserializer::new(map))
+                    .map(TargetOptions::into_spec)
+            }
+        }
+
+        deserializer.deserialize_any(Target)
+    }
+}
+
+impl TargetSpec {
+    /// The target, or the settings of those found in the directory.
+    fn target_mut(&mut self) -> &mut TargetConfig {
+        match self {
+            TargetSpec::Target(target) => target,
+            TargetSpec::Dir(dir) => &mut dir.target,
+        }
+    }
+
+    /// A target named `name`, with default settings, bound to `s`.
+    pub fn new(name: String, s: &str) -> Result<Self, service_binding::Error> {
+        if let Some(path) = s.strip_prefix("dir://") {
+            return Ok(Self::Dir(TargetDirConfig::new(name, path.into())));
+        }
+        if s == "default" {
+            return Ok(Self::Target(TargetConfig::new(name, default_agent()?)));
+        }
+        if s == "inherit" {
+            return Ok(Self::Target(TargetConfig::new(name, inherited_agent()?)));
+        }
+        let tcp_name = match (s.strip_prefix("tcp://"), s.strip_prefix("srv://")) {
+            (Some(addr), _) if addr.parse::<SocketAddr>().is_err() => TcpName::host(addr)?,
+            (_, Some(name)) => TcpName::Srv(name.to_owned()),
+            _ => return Ok(Self::Target(TargetConfig::new(name, s.parse()?))),
+        };
+        Ok(Self::Target(TargetConfig {
+            tcp_name: Some(tcp_name),
+            ..TargetConfig::new(name, Binding::Sockets(Vec::new()))
+        }))
+    }
+}
+
+/// The agent of the session: `SSH_AUTH_SOCK`, or on macOS the agent launchd
+/// starts for the user when it isn't set, e.g. for a mux launchd started.
+fn default_agent() -> Result<Binding, service_binding::Error> {
+    #[cfg(target_os = "macos")]
+    if std::env::var_os("SSH_AUTH_SOCK").is_none_or(|path| path.is_empty()) {
+        if let Some(path) = launchd_agent() {
+            return Ok(Binding::FilePath(path));
+        }
+    }
+    inherited_agent()
+}
+
+/// The agent `SSH_AUTH_SOCK` names as the mux starts.
+fn inherited_agent() -> Result<Binding, service_binding::Error> {
+    match std::env::var_os("SSH_AUTH_SOCK") {
+        Some(path) if !path.is_empty() => Ok(Binding::FilePath(path.into())),
+        _ => Err(service_binding::Error::BadAddress(io::Error::new(
+            io::ErrorKind::NotFound,
+            "SSH_AUTH_SOCK isn't set",
+        ))),
+    }
+}
+
+/// The socket of `com.openssh.ssh-agent`, as launchd gives it to the
+/// user's processes, or else as it creates it: the `Listeners` socket of a
+/// `/private/tmp/com.apple.launchd.*` directory the user owns.
+#[cfg(target_os = "macos")]
+fn launchd_agent() -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let output = std::process::Command::new("launchctl")
+        .args(["getenv", "SSH_AUTH_SOCK"])
+        .stderr(std::process::Stdio::null())
+        .output();
+    if let Ok(output) = output {
+        let path = String::from_utf8_lossy(&output.stdout);
+        let path = path.trim();
+        if output.status.success() && !path.is_empty() {
+            return Some(path.into());
+        }
+    }
+    // SAFETY: getuid always succeeds.
+    let uid = unsafe { libc::getuid() };
+    std::fs::read_dir("/private/tmp")
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("com.apple.launchd.")
+        })
+        .map(|entry| entry.path().join("Listeners"))
+        .find(|path| path.metadata().is_ok_and(|metadata| metadata.uid() == uid))
+}
+
+impl FromStr for TargetSpec {
+    type Err = service_binding::Error;
+
+    /// A target named after `s` itself, as on the command line.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s.to_owned(), s)
+    }
+}
+
+impl TcpName {
+    fn host(addr: &str) -> Result<Self, service_binding::Error> {
+        let invalid = || {
+            service_binding::Error::BadAddress(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{addr}' isn't a host and port"),
+            ))
+        };
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(TcpName::Host {
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+impl TargetDirConfig {
+    fn new(name: String, path: PathBuf) -> Self {
+        let pattern = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| name.contains(['*', '?']))
+            .map(str::to_owned);
+        let dir = match pattern {
+            Some(_) => path.parent().unwrap_or(Path::new("/")).to_owned(),
+            None => path,
+        };
+        Self {
+            target: TargetConfig::new(name, Binding::FilePath(dir.clone())),
+            dir,
+            pattern,
+        }
+    }
+
+    /// Whether a target named `name` would be one found in the directory.
+    pub fn contains(&self, name: &str) -> bool {
+        name.strip_prefix(&self.target.name)
+            .and_then(|name| name.strip_prefix('/'))
+            .is_some_and(|file_name| self.matches(file_name))
+    }
+
+    fn matches(&self, file_name: &str) -> bool {
+        self.pattern.as_deref().is_none_or(|pattern| {
+            let pattern: Vec<_> = pattern.chars().collect();
+            let file_name: Vec<_> = file_name.chars().collect();
+            glob_matches(&pattern, &file_name)
+        })
+    }
+
+    /// The targets for the sockets now in the directory, in file name order.
+    pub fn scan(&self) -> io::Result<Vec<TargetConfig>> {
+        let mut file_names = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::FileTypeExt;
+                if !entry.file_type()?.is_socket() {
+                    continue;
+                }
+            }
+            // Target names are strings.
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if self.matches(&file_name) {
+                file_names.push(file_name);
+            }
+        }
+        file_names.sort();
+        Ok(file_names
+            .into_iter()
+            .map(|file_name| TargetConfig {
+                name: format!("{}/{file_name}", self.target.name),
+                binding: Binding::FilePath(self.dir.join(&file_name)),
+                ..self.target.clone()
+            })
+            .collect())
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters
+/// and `?` any single one.
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        Some((&first, rest)) => name.split_first().is_some_and(|(&c, name_rest)| {
+            (first == '?' || first == c) && glob_matches(rest, name_rest)
+        }),
+    }
+}
+
+/// The options of a key in `keys`, which is keyed by its fingerprint.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyOptions {
+    nickname: Option<String>,
+    max_signatures_per_hour: Option<u32>,
+    #[serde(default, deserialize_with = "duration")]
+    cooldown: Option<Duration>,
+    #[serde(default, deserialize_with = "fingerprints")]
+    allowed_host_keys: Vec<Fingerprint>,
+    #[serde(default)]
+    require_session_bind: bool,
+    #[serde(default)]
+    allowed_times: Vec<TimeWindow>,
+    exec: Option<PathBuf>,
+}
+
+impl KeyOptions {
+    fn into_config(self, fingerprint: Fingerprint) -> KeyConfig {
+        KeyConfig {
+            fingerprint,
+            nickname: self.nickname,
+            max_signatures_per_hour: self.max_signatures_per_hour,
+            cooldown: self.cooldown,
+            allowed_host_keys: self.allowed_host_keys,
+            require_session_bind: self.require_session_bind,
+            allowed_times: self.allowed_times,
+            exec: self.exec,
+        }
+    }
+}
+
+impl KeyType {
+    const NAMES: [(&'static str, Self); 6] = [
+        ("dsa", Self::Dsa),
+        ("ecdsa", Self::Ecdsa),
+        ("ed25519", Self::Ed25519),
+        ("rsa", Self::Rsa),
+        ("sk-ecdsa", Self::SkEcdsa),
+        ("sk-ed25519", Self::SkEd25519),
+    ];
+
+    /// The type of keys of `algorithm`, if it is a known one.
+    pub fn of(algorithm: &Algorithm) -> Option<Self> {
+        match algorithm {
+            Algorithm::Dsa => Some(Self::Dsa),
+            Algorithm::Ecdsa { .. } => Some(Self::Ecdsa),
+            Algorithm::Ed25519 => Some(Self::Ed25519),
+            Algorithm::Rsa { .. } => Some(Self::Rsa),
+            Algorithm::SkEcdsaSha2NistP256 => Some(Self::SkEcdsa),
+            Algorithm::SkEd25519 => Some(Self::SkEd25519),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = Self::NAMES
+            .iter()
+            .find(|(_, key_type)| key_type == self)
+            .expect("every key type has a name");
+        f.write_str(name)
+    }
+}
+
+/// Longest Unix socket path, without the terminating NUL.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAX_SOCKET_PATH: usize = 107;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const MAX_SOCKET_PATH: usize = 103;
+
+/// Adds the problems with `binding` of option `name` on this platform to
+/// `problems`, where `host` tells whether it is served on rather than
+/// connected to.
+fn binding_problems(name: &str, binding: &Binding, host: bool, problems: &mut Vec<String>) {
+    match binding {
+        Binding::FilePath(_) if cfg!(windows) => problems.push(format!(
+            "{name}: Unix sockets are only supported on Unix; use npipe:// instead"
+        )),
+        Binding::FilePath(path) => {
+            if path.is_relative() {
+                problems.push(format!(
+                    "{name}: {} is a relative path; use an absolute one, e.g. unix://{}",
+                    path.display(),
+                    absolute(path).display()
+                ));
+            }
+            let length = path.as_os_str().len();
+            if length > MAX_SOCKET_PATH {
+                problems.push(format!(
+                    "{name}: {} is {length} bytes long, beyond the {MAX_SOCKET_PATH} of a Unix socket path; use a shorter one, e.g. in /tmp",
+                    path.display()
+                ));
+            }
+        }
+        Binding::NamedPipe(_) if !cfg!(windows) => problems.push(format!(
+            "{name}: named pipes are only supported on Windows; use unix:// instead"
+        )),
+        Binding::FileDescriptor(_) if cfg!(windows) => problems.push(format!(
+            "{name}: file descriptors are only supported on Unix; use npipe:// instead"
+        )),
+        Binding::FileDescriptor(_) if !host => problems.push(format!(
+            "{name}: fd:// is a socket to serve on, not an agent; use unix:// instead"
+        )),
+        _ => (),
+    }
+}
+
+/// `path` in the current directory, as a suggestion.
+fn absolute(path: &Path) -> PathBuf {
+    std::env::current_dir().unwrap_or_default().join(path)
+}
+
+/// Sets `option` to `value` if it was given.
+fn set<T>(option: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *option = value;
+    }
+}
+
+/// The 1-based line of byte `offset` of `input`.
+fn line_at(input: &str, offset: usize) -> usize {
+    input[..offset.min(input.len())].matches('\n').count() + 1
+}
+
+/// The options of a TOML document.
+fn toml_options(input: &str) -> Result<Options, ParseError> {
+    let line = |e: &toml::de::Error| e.span().map_or(0, |span| line_at(input, span.start));
+    let deserializer = toml::de::Deserializer::parse(input).map_err(|e| ParseError {
+        line: line(&e),
+        message: e.message().to_owned(),
+    })?;
+    serde_path_to_error::deserialize(deserializer).map_err(|e| ParseError {
+        line: line(e.inner()),
+        message: described(e.path(), e.inner().message()),
+    })
+}
+
+/// The options of a YAML document.
+fn yaml_options(input: &str) -> Result<Options, ParseError> {
+    serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(input)).map_err(|e| {
+        let path = e.path().to_string();
+        let message = without_position(e.inner().to_string());
+        // The parser names the mapping an error is in, which is at most the
+        // path to the option.
+        let message = match message.split_once(": ") {
+            Some((prefix, rest)) if path.starts_with(prefix) => rest.to_owned(),
+            _ => message,
+        };
+        ParseError {
+            line: e.inner().location().map_or(0, |location| location.line()),
+            message: described(e.path(), &message),
+        }
+    })
+}
+
+/// The options of a JSON document.
+fn json_options(input: &str) -> Result<Options, ParseError> {
+    let mut deserializer = serde_json::Deserializer::from_str(input);
+    let options = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| ParseError {
+        line: e.inner().line(),
+        message: described(e.path(), &without_position(e.inner().to_string())),
+    })?;
+    deserializer.end().map_err(|e| ParseError {
+        line: e.line(),
+        message: without_position(e.to_string()),
+    })?;
+    Ok(options)
+}
+
+/// An error message of the YAML or JSON parser, without the position it
+/// ends with, which is reported as the line instead.
+fn without_position(message: String) -> String {
+    match message.rfind(" at line ") {
+        Some(i) => message[..i].to_owned(),
+        None => message,
+    }
+}
+
+/// `message` of an error in the option at `path`, naming it as far as it
+/// is known, e.g. not in a key that failed to parse.
+fn described(path: &serde_path_to_error::Path, message: &str) -> String {
+    let path = path.to_string();
+    let known = path.split('?').next().unwrap_or_default();
+    match known.trim_end_matches('.') {
+        "" => message.to_owned(),
+        known => format!("{known}: {message}"),
+    }
+}
+
+/// Deserializes a string with `FromStr`, failing with the string and what
+/// it should have been.
+fn parsed<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D, what: &str) -> Result<T, D::Error>
+where
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse()
+        .map_err(|e| de::Error::custom(format!("invalid {what} '{s}': {e}")))
+}
+
+/// The entries of a table, in the order they were written.
+fn ordered<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Vec<(String, V)>, D::Error> {
+    struct Entries<V>(PhantomData<V>);
+
+    impl<'de, V: Deserialize<'de>> Visitor<'de> for Entries<V> {
+        type Value = Vec<(String, V)>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a table")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut keys = HashSet::new();
+            let mut entries = Vec::new();
+            while let Some(key) = map.next_key::<String>()? {
+                if !keys.insert(key.clone()) {
+                    return Err(de::Error::custom(format!("duplicate key '{key}'")));
+                }
+                entries.push((key, map.next_value()?));
+            }
+            Ok(entries)
+        }
+    }
+
+    deserializer.deserialize_map(Entries(PhantomData))
+}
+
+/// A table of options, like `log_levels`, in order.
+fn entries<'de, D: Deserializer<'de>, V: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<(String, V)>>, D::Error> {
+    ordered(deserializer).map(Some)
+}
+
+fn targets<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<TargetSpec>>, D::Error> {
+    let targets = ordered::<D, TargetSpec>(deserializer)?;
+    Ok(Some(
+        targets
+            .into_iter()
+            .map(|(name, mut target)| {
+                target.target_mut().name = name;
+                target
+            })
+            .collect(),
+    ))
+}
+
+fn keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<KeyConfig>>, D::Error> {
+    ordered::<D, KeyOptions>(deserializer)?
+        .into_iter()
+        .map(|(fingerprint, options)| Ok(options.into_config(fingerprint_key(&fingerprint)?)))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn routes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<RouteConfig>>, D::Error> {
+    ordered::<D, String>(deserializer)?
+        .into_iter()
+        .map(|(fingerprint, target)| {
+            Ok(RouteConfig {
+                fingerprint: fingerprint_key(&fingerprint)?,
+                target,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn views<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<ViewConfig>>, D::Error> {
+    let views = ordered::<D, ViewConfig>(deserializer)?;
+    Ok(Some(
+        views
+            .into_iter()
+            .map(|(name, view)| ViewConfig { name, ..view })
+            .collect(),
+    ))
+}
+
+fn address_rules<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<AddressRule>>, D::Error> {
+    ordered::<D, AddressAction>(deserializer)?
+        .into_iter()
+        .map(|(network, action)| {
+            let network = network
+                .parse()
+                .map_err(|e| de::Error::custom(format!("invalid network '{network}': {e}")))?;
+            Ok(AddressRule { network, action })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn profiles<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<(String, Options)>>, D::Error> {
+    let profiles = ordered::<D, Options>(deserializer)?;
+    if let Some((name, _)) = profiles
+        .iter()
+        .find(|(_, profile)| profile.profiles.is_some())
+    {
+        return Err(de::Error::custom(format!(
+            "profile '{name}' can't have profiles of its own"
+        )));
+    }
+    Ok(Some(profiles))
+}
+
+/// The fingerprint a table entry is keyed by, as in `[keys."SHA256:..."]`.
+fn fingerprint_key<E: de::Error>(key: &str) -> Result<Fingerprint, E> {
+    Fingerprint::from_str(key)
+        .map_err(|e| de::Error::custom(format!("invalid fingerprint '{key}': {e}")))
+}
+
+fn fingerprints<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Fingerprint>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| {
+            Fingerprint::from_str(s)
+                .map_err(|e| de::Error::custom(format!("invalid fingerprint '{s}': {e}")))
+        })
+        .collect()
+}
+
+fn public_keys<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<PublicKey>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| {
+            PublicKey::from_openssh(s.trim())
+                .map_err(|e| de::Error::custom(format!("invalid public key '{s}': {e}")))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s)
+        .map(Some)
+        .map_err(|e| de::Error::custom(format!("invalid duration '{s}': {e}")))
+}
+
+/// A duration longer than zero, like the `window` of `alerts`.
+fn window<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    match duration(deserializer)? {
+        Some(window) if window.is_zero() => Err(de::Error::custom("must be longer than zero")),
+        window => Ok(window),
+    }
+}
+
+/// A key lifetime, which the agent protocol counts in seconds.
+fn lifetime<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    match duration(deserializer)? {
+        Some(lifetime) if lifetime.as_secs() == 0 => {
+            Err(de::Error::custom("must be at least a second"))
+        }
+        lifetime => Ok(lifetime),
+    }
+}
+
+fn positive<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    let value = T::deserialize(deserializer)?;
+    if value == T::default() {
+        return Err(de::Error::custom("must be greater than zero"));
+    }
+    Ok(Some(value))
+}
+
+/// `sandbox.seccomp`: a boolean, or the mode.
+fn seccomp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SeccompMode>, D::Error> {
+    struct Seccomp;
+
+    impl<'de> Visitor<'de> for Seccomp {
+        type Value = Option<SeccompMode>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a boolean, \"enforce\" or \"log\"")
+        }
+
+        fn visit_bool<E: de::Error>(self, enabled: bool) -> Result<Self::Value, E> {
+            Ok(enabled.then_some(SeccompMode::Enforce))
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            match s {
+                "enforce" => Ok(Some(SeccompMode::Enforce)),
+                "log" => Ok(Some(SeccompMode::Log)),
+                _ => Err(de::Error::invalid_value(de::Unexpected::Str(s), &self)),
+            }
+        }
+    }
+
+    deserializer.deserialize_any(Seccomp)
+}
+
+/// A table of environment variables.
+fn variables<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(String, String)>, D::Error> {
+    let variables = ordered::<D, String>(deserializer)?;
+    match variables.iter().find(|(name, _)| !is_variable(name)) {
+        Some((name, _)) => Err(de::Error::custom(format!(
+            "invalid environment variable name {name:?}"
+        ))),
+        None => Ok(variables),
+    }
+}
+
+/// A list of environment variable names.
+fn variable_names<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let names = Vec::<String>::deserialize(deserializer)?;
+    match names.iter().find(|name| !is_variable(name)) {
+        Some(name) => Err(de::Error::custom(format!(
+            "invalid environment variable name {name:?}"
+        ))),
+        None => Ok(names),
+    }
+}
+
+fn is_variable(name: &str) -> bool {
+    !name.is_empty() && !name.contains(['=', '\0'])
+}
+
+/// A target's binding, or a directory of them.  Named after its entry in
+/// `targets` once deserialized.
+fn target_spec<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TargetSpec, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    unnamed_target(&s)
+}
+
+fn unnamed_target<E: de::Error>(s: &str) -> Result<TargetSpec, E> {
+    TargetSpec::new(String::new(), s)
+        .map_err(|e| de::Error::custom(format!("invalid binding '{s}': {e}")))
+}
+
+/// A binding to serve on, unlike `host` not stdio.
+fn binding_to_serve<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Binding, D::Error> {
+    match Host::deserialize(deserializer)? {
+        Host::Binding(binding) => Ok(binding),
+        Host::Stdio => Err(de::Error::custom("can't be stdio://")),
+    }
+}
+
+/// A Unix socket or loopback address, for endpoints without authentication.
+fn local_binding<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Binding>, D::Error> {
+    let binding: Binding = parsed(deserializer, "binding")?;
+    match &binding {
+        Binding::FilePath(_) => Ok(Some(binding)),
+        Binding::Sockets(sockets) if sockets.iter().all(|socket| socket.ip().is_loopback()) => {
+            Ok(Some(binding))
+        }
+        _ => Err(de::Error::custom(
+            "must be a Unix socket or a loopback address",
+        )),
+    }
+}
@@ -0,0 +1,352 @@
+//! Conformance checks of a target agent, for finding which agent of a chain
+//! is the broken link: listing, signing with a key of each type, the
+//! `query` extension, and locking and unlocking.
+//!
+//! Signatures are verified against the keys listed.  Locking uses a random
+//! passphrase and the agent is unlocked again before the checks end, but
+//! signing may ask for confirmation, e.g. of keys added with `ssh-add -c`.
+
+use std::fmt;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use service_binding::Binding;
+use signature::Verifier;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{
+        extension::QueryResponse, signature as flags, Extension, Identity, ProtoError, Request,
+        Response, SignRequest, Unparsed,
+    },
+};
+use ssh_key::{Algorithm, HashAlg};
+
+use crate::client::Client;
+use crate::error::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// Not checked, as an earlier check failed or there was nothing to
+    /// check.
+    Skip,
+}
+
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+/// The checks made of a target, in order.
+#[derive(Clone, Debug, Default)]
+pub struct Report(pub Vec<Check>);
+
+impl Report {
+    pub fn failures(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|check| check.outcome == Outcome::Fail)
+            .count()
+    }
+
+    fn add(&mut self, name: impl Into<String>, outcome: Outcome, detail: impl Into<String>) {
+        self.0.push(Check {
+            name: name.into(),
+            outcome,
+            detail: detail.into(),
+        });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.0 {
+            let outcome = match check.outcome {
+                Outcome::Pass => "PASS",
+                Outcome::Fail => "FAIL",
+                Outcome::Skip => "SKIP",
+            };
+            write!(f, "{outcome}  {}", check.name)?;
+            if !check.detail.is_empty() {
+                write!(f, ": {}", check.detail)?;
+            }
+            writeln!(f)?;
+        }
+        let failures = self.failures();
+        match failures {
+            0 => writeln!(f, "all {} checks passed", self.0.len()),
+            _ => writeln!(f, "{failures} of {} checks failed", self.0.len()),
+        }
+    }
+}
+
+/// A connection to the target, opened again after a transport error.
+struct Connection<'a> {
+    binding: &'a Binding,
+    client: Option<Client>,
+    timeout: Duration,
+}
+
+impl Connection<'_> {
+    async fn request(&mut self, request: Request) -> Result<Response, AgentError> {
+        let mut client = match self.client.take() {
+            Some(client) => client,
+            None => Client::connect(self.binding.clone().try_into()?)?,
+        };
+        let response = tokio::time::timeout(self.timeout, client.handle(request))
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "no answer within {}",
+                        humantime::format_duration(self.timeout)
+                    ),
+                )
+                .into())
+            });
+        if !matches!(response, Err(AgentError::IO(_) | AgentError::Proto(_))) {
+            self.client = Some(client);
+        }
+        response
+    }
+}
+
+/// Checks the agent on `binding`, failing only if it can't be connected to
+/// at all.  Each request fails if not answered within `timeout`.
+pub async fn test_target(binding: &Binding, timeout: Duration) -> Result<Report, Error> {
+    let client = binding
+        .clone()
+        .try_into()
+        .and_then(Client::connect)
+        .map_err(|source| Error::TestTarget {
+            target: binding.clone(),
+            source: source.into(),
+        })?;
+    let mut connection = Connection {
+        binding,
+        client: Some(client),
+        timeout,
+    };
+    let mut report = Report::default();
+    let identities = list(&mut connection, &mut report).await;
+    sign(&mut connection, &mut report, identities.as_deref()).await;
+    query(&mut connection, &mut report).await;
+    lock(&mut connection, &mut report, identities.as_deref()).await;
+    Ok(report)
+}
+
+async fn list(connection: &mut Connection<'_>, report: &mut Report) -> Option<Vec<Identity>> {
+    match connection.request(Request::RequestIdentities).await {
+        Ok(Response::IdentitiesAnswer(identities)) => {
+            report.add(
+                "list identities",
+                Outcome::Pass,
+                format!("{} keys", identities.len()),
+            );
+            Some(identities)
+        }
+        response => {
+            report.add("list identities", Outcome::Fail, unexpected(response));
+            None
+        }
+    }
+}
+
+/// Signs with the first key of each algorithm, RSA keys with each SHA-2
+/// hash.  SHA-1 signatures are deprecated, and not decoded.
+async fn sign(
+    connection: &mut Connection<'_>,
+    report: &mut Report,
+    identities: Option<&[Identity]>,
+) {
+    let Some(identities) = identities else {
+        report.add("sign", Outcome::Skip, "listing failed");
+        return;
+    };
+    if identities.is_empty() {
+        report.add("sign", Outcome::Skip, "no keys to sign with");
+        return;
+    }
+    let mut algorithms: Vec<Algorithm> = Vec::new();
+    for identity in identities {
+        let algorithm = identity.pubkey.algorithm();
+        if algorithms.contains(&algorithm) {
+            continue;
+        }
+        algorithms.push(algorithm.clone());
+        let hashes = match algorithm {
+            Algorithm::Rsa { .. } => vec![
+                (
+                    flags::RSA_SHA2_256,
+                    Algorithm::Rsa {
+                        hash: Some(HashAlg::Sha256),
+                    },
+                ),
+                (
+                    flags::RSA_SHA2_512,
+                    Algorithm::Rsa {
+                        hash: Some(HashAlg::Sha512),
+                    },
+                ),
+            ],
+            algorithm => vec![(0, algorithm)],
+        };
+        for (flags, expected) in hashes {
+            let name = format!("sign with {expected}");
+            let data = challenge();
+            let request = Request::SignRequest(SignRequest {
+                pubkey: identity.pubkey.clone(),
+                data: data.clone(),
+                flags,
+            });
+            match connection.request(request).await {
+                Ok(Response::SignResponse(signature)) if signature.algorithm() != expected => {
+                    report.add(
+                        name,
+                        Outcome::Fail,
+                        format!("signed with {} instead", signature.algorithm()),
+                    )
+                }
+                Ok(Response::SignResponse(signature)) => {
+                    match identity.pubkey.verify(&data, &signature) {
+                        Ok(()) => report.add(name, Outcome::Pass, identity.comment.clone()),
+                        Err(e) => report.add(
+                            name,
+                            Outcome::Fail,
+                            format!("the signature doesn't verify: {e}"),
+                        ),
+                    }
+                }
+                response => report.add(name, Outcome::Fail, unexpected(response)),
+            }
+        }
+    }
+}
+
+/// The `query` extension is optional, so refusing it is as good as
+/// answering it.
+async fn query(connection: &mut Connection<'_>, report: &mut Report) {
+    let request = Request::Extension(Extension {
+        name: "query".to_owned(),
+        details: Unparsed::from(Vec::new()),
+    });
+    match connection.request(request).await {
+        Ok(Response::ExtensionResponse(response)) => {
+            match response.parse_message::<QueryResponse>() {
+                Ok(Some(query)) => report.add(
+                    "query extensions",
+                    Outcome::Pass,
+                    match query.extensions.is_empty() {
+                        true => "none".to_owned(),
+                        false => query.extensions.join(", "),
+                    },
+                ),
+                Ok(None) => report.add(
+                    "query extensions",
+                    Outcome::Fail,
+                    format!("answered as extension {}", response.name),
+                ),
+                Err(e) => report.add("query extensions", Outcome::Fail, e.to_string()),
+            }
+        }
+        Ok(Response::Failure | Response::ExtensionFailure) => {
+            report.add("query extensions", Outcome::Pass, "not supported")
+        }
+        response => report.add("query extensions", Outcome::Fail, unexpected(response)),
+    }
+}
+
+/// Locks the agent, checks that it neither lists nor signs, and unlocks it.
+async fn lock(
+    connection: &mut Connection<'_>,
+    report: &mut Report,
+    identities: Option<&[Identity]>,
+) {
+    let passphrase = format!(
+        "{:x}",
+        u64::from_le_bytes(challenge()[..8].try_into().unwrap())
+    );
+    match connection.request(Request::Lock(passphrase.clone())).await {
+        Ok(Response::Success) => report.add("lock", Outcome::Pass, ""),
+        response => {
+            report.add("lock", Outcome::Fail, unexpected(response));
+            return;
+        }
+    }
+    match connection.request(Request::RequestIdentities).await {
+        Ok(Response::IdentitiesAnswer(identities)) if identities.is_empty() => {
+            report.add("list while locked", Outcome::Pass, "no keys")
+        }
+        Ok(Response::Failure) => report.add("list while locked", Outcome::Pass, "refused"),
+        Ok(Response::IdentitiesAnswer(identities)) => report.add(
+            "list while locked",
+            Outcome::Fail,
+            format!("{} keys listed", identities.len()),
+        ),
+        response => report.add("list while locked", Outcome::Fail, unexpected(response)),
+    }
+    match identities.and_then(|identities| identities.first()) {
+        Some(identity) => {
+            let request = Request::SignRequest(SignRequest {
+                pubkey: identity.pubkey.clone(),
+                data: challenge(),
+                flags: 0,
+            });
+            match connection.request(request).await {
+                Ok(Response::Failure) => report.add("sign while locked", Outcome::Pass, "refused"),
+                Ok(Response::SignResponse(_)) => {
+                    report.add("sign while locked", Outcome::Fail, "signed")
+                }
+                response => report.add("sign while locked", Outcome::Fail, unexpected(response)),
+            }
+        }
+        None => report.add("sign while locked", Outcome::Skip, "no keys to sign with"),
+    }
+    match connection
+        .request(Request::Unlock(passphrase.clone() + "x"))
+        .await
+    {
+        Ok(Response::Failure) => {
+            report.add("unlock with a wrong passphrase", Outcome::Pass, "refused")
+        }
+        Ok(Response::Success) => {
+            report.add("unlock with a wrong passphrase", Outcome::Fail, "unlocked");
+            return;
+        }
+        response => report.add(
+            "unlock with a wrong passphrase",
+            Outcome::Fail,
+            unexpected(response),
+        ),
+    }
+    match connection.request(Request::Unlock(passphrase)).await {
+        Ok(Response::Success) => report.add("unlock", Outcome::Pass, ""),
+        response => report.add("unlock", Outcome::Fail, unexpected(response)),
+    }
+}
+
+/// Data to sign, different each time.
+fn challenge() -> Vec<u8> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut data = nanos.to_le_bytes().to_vec();
+    data.extend_from_slice(b"ssh-agent-mux conformance check");
+    data
+}
+
+/// What is wrong with `response`, which wasn't the one expected.
+fn unexpected(response: Result<Response, AgentError>) -> String {
+    match response {
+        Ok(Response::Failure) => "refused".to_owned(),
+        Ok(Response::ExtensionFailure) => "refused as an extension".to_owned(),
+        Ok(response) => format!("unexpected {}", crate::logging::Message(&response)),
+        Err(AgentError::Proto(ProtoError::UnexpectedResponse)) => "unexpected response".to_owned(),
+        Err(e) => e.to_string(),
+    }
+}
@@ -0,0 +1,133 @@
+//! Signals on the D-Bus session bus, e.g. for desktop indicators of key use.
+//!
+//! Each audit event is emitted as a signal of the [`INTERFACE`] interface on
+//! the [`PATH`] object, named after the event in CamelCase and with its
+//! details as string arguments, e.g. `KeyUsed(key, target, client,
+//! nickname)`.
+
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use tokio::sync::mpsc;
+use zbus::zvariant::StructureBuilder;
+use zbus::Message;
+
+pub const INTERFACE: &str = "io.github.rfdonnelly.SshAgentMux";
+pub const PATH: &str = "/io/github/rfdonnelly/SshAgentMux";
+
+/// Signals queued for the bus before new ones are dropped.
+const BACKLOG: usize = 256;
+
+static SIGNALS: OnceLock<mpsc::Sender<Message>> = OnceLock::new();
+
+/// A connection to the session bus, not yet authenticated.
+pub struct Bus(UnixStream);
+
+impl Bus {
+    /// Connects to the bus of `DBUS_SESSION_BUS_ADDRESS`, or of
+    /// `$XDG_RUNTIME_DIR/bus` without one.
+    pub fn session() -> io::Result<Self> {
+        let path = session_bus_path()?;
+        let stream = UnixStream::connect(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        Ok(Self(stream))
+    }
+}
+
+/// Authenticates on `bus` and emits the signals of audit events on it until
+/// it fails.
+pub async fn emit_signals(bus: Bus) -> zbus::Result<()> {
+    let (sender, mut signals) = mpsc::channel(BACKLOG);
+    if SIGNALS.set(sender).is_err() {
+        return Err(zbus::Error::Failure("already emitting signals".to_owned()));
+    }
+    bus.0.set_nonblocking(true)?;
+    let stream = tokio::net::UnixStream::from_std(bus.0)?;
+    let connection = zbus::connection::Builder::unix_stream(stream)
+        .build()
+        .await?;
+    log::info!("Emitting {INTERFACE} signals on the session bus");
+    while let Some(signal) = signals.recv().await {
+        connection.send(&signal).await?;
+    }
+    Ok(())
+}
+
+/// Queues the signal of an audit event, if signals are emitted.
+pub(crate) fn emit(event: &str, fields: &[(&str, String)]) {
+    let Some(sender) = SIGNALS.get() else {
+        return;
+    };
+    let signal = match signal(event, fields) {
+        Ok(signal) => signal,
+        Err(e) => {
+            log::warn!("Failed to make {event} signal for the session bus: {e}");
+            return;
+        }
+    };
+    if sender.try_send(signal).is_err() {
+        log::warn!("Dropped {event} signal for the session bus");
+    }
+}
+
+/// The signal of an audit event, with the values of its `fields` as
+/// arguments.
+pub fn signal(event: &str, fields: &[(&str, String)]) -> zbus::Result<Message> {
+    let builder = Message::signal(PATH, INTERFACE, camel_case(event))?;
+    if fields.is_empty() {
+        return builder.build(&());
+    }
+    let args = fields
+        .iter()
+        .fold(StructureBuilder::new(), |args, (_, value)| {
+            args.add_field(value.as_str())
+        })
+        .build()?;
+    builder.build(&args)
+}
+
+/// Whether signals are emitted.
+pub(crate) fn enabled() -> bool {
+    SIGNALS.get().is_some()
+}
+
+fn session_bus_path() -> io::Result<PathBuf> {
+    if let Ok(address) = std::env::var("DBUS_SESSION_BUS_ADDRESS") {
+        return address
+            .split(';')
+            .filter_map(|address| address.strip_prefix("unix:"))
+            .flat_map(|params| params.split(','))
+            .find_map(|param| param.strip_prefix("path="))
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("no unix:path= in DBUS_SESSION_BUS_ADDRESS '{address}'"),
+                )
+            });
+    }
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(|dir| PathBuf::from(dir).join("bus"))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "neither DBUS_SESSION_BUS_ADDRESS nor XDG_RUNTIME_DIR is set",
+            )
+        })
+}
+
+/// `key_used` as `KeyUsed`.
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase())
+                .into_iter()
+                .chain(chars)
+        })
+        .collect()
+}
@@ -0,0 +1,223 @@
+//! DNS resolution of TCP targets by name.
+//!
+//! Host names go through the system resolver.  SRV records aren't available
+//! through it, so they are queried from the name servers of
+//! `/etc/resolv.conf` over UDP, with only as much of the protocol as that
+//! takes.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::TcpName;
+
+/// How long each name server may take to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Enough for any answer over UDP with EDNS, which isn't requested.
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Header flags asking for recursion.
+const FLAGS_RD: u16 = 0x0100;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// An SRV record.
+struct Srv {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+/// The addresses of `name`, most preferred first.  Resolved on a blocking
+/// thread, as neither the system resolver nor the SRV queries are async.
+pub async fn resolve(name: &TcpName) -> io::Result<Vec<SocketAddr>> {
+    let name = name.clone();
+    tokio::task::spawn_blocking(move || resolve_blocking(&name))
+        .await
+        .map_err(io::Error::other)?
+}
+
+fn resolve_blocking(name: &TcpName) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = match name {
+        TcpName::Host { host, port } => (host.as_str(), *port).to_socket_addrs()?.collect(),
+        TcpName::Srv(service) => {
+            let mut records = query_srv(service)?;
+            // Heavier records first within a priority, rather than picking
+            // by weight at random.
+            records.sort_by_key(|record| (record.priority, std::cmp::Reverse(record.weight)));
+            let mut addrs = Vec::new();
+            for record in records {
+                match (record.target.as_str(), record.port).to_socket_addrs() {
+                    Ok(found) => addrs.extend(found),
+                    Err(e) => log::warn!("Failed to resolve {} of {name}: {e}", record.target),
+                }
+            }
+            addrs
+        }
+    };
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{name} has no addresses"),
+        ));
+    }
+    log::debug!("Resolved {name} to {addrs:?}");
+    Ok(addrs)
+}
+
+fn name_servers() -> Vec<IpAddr> {
+    let servers: Vec<_> = std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse().ok())
+        .collect();
+    if servers.is_empty() {
+        return vec![Ipv4Addr::LOCALHOST.into()];
+    }
+    servers
+}
+
+/// The SRV records of `name`, asking each name server in turn.
+fn query_srv(name: &str) -> io::Result<Vec<Srv>> {
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u16;
+    let query = srv_query(id, name)?;
+    let mut error = None;
+    for server in name_servers() {
+        match ask(SocketAddr::new(server, 53), &query) {
+            Ok(answer) => return parse_answer(id, &answer, name),
+            Err(e) => {
+                log::debug!("Name server {server} failed to answer for {name}: {e}");
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| io::Error::other("no name servers")))
+}
+
+fn ask(server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.send(query)?;
+    let mut answer = vec![0; MAX_MESSAGE_SIZE];
+    let len = socket.recv(&mut answer)?;
+    answer.truncate(len);
+    Ok(answer)
+}
+
+fn srv_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = Vec::new();
+    for field in [id, FLAGS_RD, 1, 0, 0, 0] {
+        query.extend(field.to_be_bytes());
+    }
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid DNS name '{name}'"),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(TYPE_SRV.to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed DNS answer")
+}
+
+fn u16_at(message: &[u8], at: usize) -> io::Result<u16> {
+    message
+        .get(at..at + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(malformed)
+}
+
+/// The name at `at`, following compression pointers, and where the name
+/// ends in place.
+fn read_name(message: &[u8], mut at: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds pointer loops.
+    for _ in 0..128 {
+        let len = *message.get(at).ok_or_else(malformed)? as usize;
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(at + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(at + 2);
+            at = (u16_at(message, at)? & 0x3fff) as usize;
+            continue;
+        }
+        let label = message.get(at + 1..at + 1 + len).ok_or_else(malformed)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        at += 1 + len;
+    }
+    Err(malformed())
+}
+
+fn parse_answer(id: u16, message: &[u8], name: &str) -> io::Result<Vec<Srv>> {
+    if u16_at(message, 0)? != id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "DNS answer to another query",
+        ));
+    }
+    match u16_at(message, 2)? & 0xf {
+        0 => (),
+        RCODE_NXDOMAIN => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{name} doesn't exist"),
+            ))
+        }
+        rcode => return Err(io::Error::other(format!("DNS error {rcode} for {name}"))),
+    }
+    let questions = u16_at(message, 4)?;
+    let answers = u16_at(message, 6)?;
+    let mut at = 12;
+    for _ in 0..questions {
+        at = read_name(message, at)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        at = read_name(message, at)?.1;
+        let kind = u16_at(message, at)?;
+        let data_len = u16_at(message, at + 8)? as usize;
+        let data = at + 10;
+        at = data + data_len;
+        if at > message.len() {
+            return Err(malformed());
+        }
+        // Answers may also hold the CNAMEs leading to the records.
+        if kind != TYPE_SRV {
+            continue;
+        }
+        let target = read_name(message, data + 6)?.0;
+        // "." means the service isn't available at this name.
+        if target.is_empty() {
+            continue;
+        }
+        records.push(Srv {
+            priority: u16_at(message, data)?,
+            weight: u16_at(message, data + 2)?,
+            port: u16_at(message, data + 4)?,
+            target,
+        });
+    }
+    Ok(records)
+}
@@ -0,0 +1,290 @@
+//! Errors of the mux itself, as opposed to those of the agent protocol.
+
+use std::io;
+use std::path::PathBuf;
+
+use service_binding::Binding;
+use ssh_agent_lib::error::AgentError;
+use ssh_key::Fingerprint;
+
+use crate::config::ConfigError;
+use crate::record;
+use crate::report;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error("no host to bind to; pass --host or set `host` in the config file")]
+    NoHost,
+
+    #[error("failed to bind to {binding:?}: {source}")]
+    Bind {
+        binding: Binding,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to bind the event socket {}: {source}", path.display())]
+    BindEvents {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to bind the admin endpoint to {binding:?}: {source}")]
+    BindAdmin {
+        binding: Binding,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to bind the gRPC admin service to {}: {source}", path.display())]
+    BindAdminGrpc {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to connect to the D-Bus session bus: {0}")]
+    DBus(#[source] io::Error),
+
+    #[error("failed to apply sandbox: {0}")]
+    Sandbox(#[source] io::Error),
+
+    #[error("failed to set up the webhook client: {0}")]
+    Webhook(#[source] reqwest::Error),
+
+    #[error("failed to catch signals: {0}")]
+    Signals(#[source] io::Error),
+
+    #[error("failed to start the async runtime: {0}")]
+    Runtime(#[source] io::Error),
+
+    #[error("failed to serve: {0}")]
+    Serve(#[from] AgentError),
+
+    #[error("failed to connect to target {target}: {source}")]
+    Connect {
+        target: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("no target holds key {0}")]
+    UnknownKey(Fingerprint),
+
+    #[error("key {fingerprint} is held by both {first} and {second}")]
+    DuplicateKey {
+        fingerprint: Fingerprint,
+        first: String,
+        second: String,
+    },
+
+    #[error("key {fingerprint} is routed to target {target}, which isn't connected or is hidden")]
+    RouteUnavailable {
+        fingerprint: Fingerprint,
+        target: String,
+    },
+
+    #[error("no target takes {0} keys")]
+    NoTargetForKeyType(String),
+
+    #[error("health check of {host:?} failed: {source}")]
+    Unhealthy {
+        host: Binding,
+        #[source]
+        source: AgentError,
+    },
+
+    #[error("failed to connect to {target:?}: {source}")]
+    TestTarget {
+        target: Binding,
+        #[source]
+        source: AgentError,
+    },
+
+    #[error("{failed} conformance checks of {target:?} failed")]
+    Nonconformant { target: Binding, failed: usize },
+
+    #[error("{0} targets failed to answer")]
+    Unanswered(usize),
+
+    #[error("failed to replay to {target:?}: {source}")]
+    Replay {
+        target: Binding,
+        #[source]
+        source: AgentError,
+    },
+
+    #[error("failed to open the recording {}: {source}", path.display())]
+    Recording {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid recording {}: {source}", path.display())]
+    InvalidRecording {
+        path: PathBuf,
+        #[source]
+        source: record::ParseError,
+    },
+
+    #[error("invalid bindings:\n  {}", .0.join("\n  "))]
+    Bindings(Vec<String>),
+
+    #[error("a mux serving on stdio can't be reached")]
+    StdioHost,
+
+    #[error("failed to unlock target {target}: {source}")]
+    Unlock {
+        target: String,
+        #[source]
+        source: AgentError,
+    },
+
+    #[error("target {0} isn't hidden")]
+    NotHidden(String),
+
+    #[error("unknown target {0}")]
+    UnknownTarget(String),
+
+    #[error("hidden targets can't be unlocked in sessions bound to a host")]
+    UnlockBound,
+
+    #[error("failed to reach the approvals of the mux: {0}")]
+    Approvals(#[source] AgentError),
+
+    #[error("approvals can't be listed or revoked in sessions bound to a host")]
+    ApprovalsBound,
+
+    #[error("failed to get the status of the mux: {0}")]
+    Status(#[source] AgentError),
+
+    #[error("the status can't be asked for in sessions bound to a host")]
+    StatusBound,
+
+    #[error("failed to create view {name}: {source}")]
+    CreateView {
+        name: String,
+        #[source]
+        source: AgentError,
+    },
+
+    #[error("views can't be created in sessions bound to a host")]
+    CreateViewBound,
+
+    #[error("views can't be created through another view")]
+    CreateViewInView,
+
+    #[error("views of some keys don't add keys, lock, unlock or pass on extensions")]
+    BeyondView,
+
+    #[error("this mux doesn't create views")]
+    NoViewCreation,
+
+    #[error("invalid view name {0:?}; use letters, digits, - and _")]
+    InvalidViewName(String),
+
+    #[error("view {0} is already served")]
+    ViewExists(String),
+
+    #[error("unknown view {0}")]
+    UnknownView(String),
+
+    #[error("no event socket; set `event_socket` in the config file")]
+    NoEventSocket,
+
+    #[error("failed to connect to the event socket {}: {source}", path.display())]
+    EventSocket {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to draw on the terminal: {0}")]
+    Top(#[source] io::Error),
+
+    #[error("no usage file; set `usage_file` in the config file")]
+    NoUsageFile,
+
+    #[error("failed to open the log file {}: {source}", path.display())]
+    LogFile {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to read the key cache {}: {source}", path.display())]
+    KeyCache {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to read the usage file {}: {source}", path.display())]
+    UsageFile {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to read 1Password's agent.toml {}: {source}", path.display())]
+    AgentToml {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid agent.toml {}: {source}", path.display())]
+    InvalidAgentToml {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("failed to list the keys of the targets: {0}")]
+    Export(#[source] AgentError),
+
+    #[error("failed to write the public keys to {}: {source}", path.display())]
+    PublicKeys {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to open the terminal for confirmation prompts: {0}")]
+    Terminal(#[source] io::Error),
+
+    #[error("no audit log; set `audit_log` in the config file")]
+    NoAuditLog,
+
+    #[error("failed to open the audit log {}: {source}", path.display())]
+    AuditLog {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid audit log {}: {source}", path.display())]
+    InvalidAuditLog {
+        path: PathBuf,
+        #[source]
+        source: report::Error,
+    },
+
+    #[error("no targets to route the request to")]
+    NoTargets,
+}
+
+/// Clients only ever see a failure response; the details are logged.
+impl From<Error> for AgentError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Serve(e) => e,
+            e => AgentError::other(e),
+        }
+    }
+}
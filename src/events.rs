@@ -0,0 +1,100 @@
+//! A stream of events for other local tools, e.g. a status bar widget.
+//!
+//! Each client of the event socket is sent the audit events from when it
+//! connected on, one JSON object per line.  Clients that fall behind miss
+//! events rather than holding up the mux.
+//!
+//! The last signatures made and denied are also kept, once asked for, for
+//! the admin endpoint's dashboard.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Events buffered for clients that are behind.
+const BACKLOG: usize = 256;
+
+static EVENTS: OnceLock<broadcast::Sender<Arc<str>>> = OnceLock::new();
+
+/// Signature events kept for [`recent_signatures`].
+const RECENT: usize = 50;
+
+static KEEP_RECENT: AtomicBool = AtomicBool::new(false);
+
+static RECENT_SIGNATURES: Mutex<VecDeque<Arc<str>>> = Mutex::new(VecDeque::new());
+
+fn sender() -> &'static broadcast::Sender<Arc<str>> {
+    EVENTS.get_or_init(|| broadcast::channel(BACKLOG).0)
+}
+
+/// Whether any client is connected to the event socket.
+pub(crate) fn has_subscribers() -> bool {
+    EVENTS
+        .get()
+        .is_some_and(|events| events.receiver_count() > 0)
+}
+
+/// Sends an event, as a JSON object, to the connected clients.
+pub(crate) fn publish(json: &str) {
+    // Fails only without clients.
+    let _ = sender().send(json.into());
+}
+
+/// Keeps the last signature events from now on.
+pub fn keep_recent_signatures() {
+    KEEP_RECENT.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn keeps_recent_signatures() -> bool {
+    KEEP_RECENT.load(Ordering::Relaxed)
+}
+
+/// Keeps a signature event, as a JSON object, dropping the oldest beyond
+/// [`RECENT`].
+pub(crate) fn remember_signature(json: &str) {
+    let mut recent = RECENT_SIGNATURES.lock().unwrap();
+    if recent.len() == RECENT {
+        recent.pop_front();
+    }
+    recent.push_back(json.into());
+}
+
+/// The last signature events kept, as JSON objects, oldest first.
+pub fn recent_signatures() -> Vec<Arc<str>> {
+    RECENT_SIGNATURES.lock().unwrap().iter().cloned().collect()
+}
+
+/// Streams events to clients of `listener` until it fails.
+#[cfg(unix)]
+pub async fn serve(listener: std::os::unix::net::UnixListener) -> std::io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    log::info!("Streaming events; socket = {listener:?}");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(stream_to(stream, sender().subscribe()));
+    }
+}
+
+#[cfg(unix)]
+async fn stream_to(mut stream: tokio::net::UnixStream, mut events: broadcast::Receiver<Arc<str>>) {
+    use tokio::io::AsyncWriteExt;
+
+    loop {
+        let json = match events.recv().await {
+            Ok(json) => json,
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("Event stream client missed {missed} events");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let line = format!("{json}\n");
+        if let Err(e) = stream.write_all(line.as_bytes()).await {
+            log::debug!("Event stream client went away: {e}");
+            return;
+        }
+    }
+}
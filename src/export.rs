@@ -0,0 +1,63 @@
+//! Keys as JSON arrays of objects, one per key, for inventory tooling.
+
+use std::fmt::Write as _;
+
+use ssh_agent_lib::proto::Identity;
+use ssh_key::HashAlg;
+
+use crate::audit::json_string;
+use crate::logging;
+use crate::metrics::KeyUsage;
+
+/// Listed identities, each with the names of the targets holding it, most
+/// preferred first, as objects of their `fingerprint`, `nickname`,
+/// `algorithm`, `comment`, preferred `target` and all their `targets`.
+pub fn identities_json(identities: &[(Identity, Vec<String>)]) -> String {
+    let mut json = String::from("[");
+    for (i, (identity, targets)) in identities.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let targets: Vec<_> = targets.iter().map(|target| json_string(target)).collect();
+        let _ = write!(
+            json,
+            "{{\"fingerprint\":{},\"nickname\":{},\"algorithm\":{},\"comment\":{},\"target\":{},\"targets\":[{}]}}",
+            json_string(&identity.pubkey.fingerprint(HashAlg::Sha256).to_string()),
+            nickname_json(logging::key_nickname(&identity.pubkey)),
+            json_string(identity.pubkey.algorithm().as_str()),
+            json_string(&identity.comment),
+            targets.first().map_or("null", String::as_str),
+            targets.join(",")
+        );
+    }
+    json.push(']');
+    json
+}
+
+/// Key usage by fingerprint, as objects of their `fingerprint`, `nickname`,
+/// number of `signatures` and when they were `last_used` in seconds since
+/// the Unix epoch, `null` for keys that never signed.
+pub fn key_usage_json(usage: &[(String, Option<KeyUsage>)]) -> String {
+    let mut json = String::from("[");
+    for (i, (fingerprint, usage)) in usage.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let (signatures, last_used) = match usage {
+            Some(usage) => (usage.signatures, usage.last_used.to_string()),
+            None => (0, "null".to_owned()),
+        };
+        let _ = write!(
+            json,
+            "{{\"fingerprint\":{},\"nickname\":{},\"signatures\":{signatures},\"last_used\":{last_used}}}",
+            json_string(fingerprint),
+            nickname_json(logging::nickname(fingerprint))
+        );
+    }
+    json.push(']');
+    json
+}
+
+fn nickname_json(nickname: Option<String>) -> String {
+    nickname.map_or_else(|| "null".to_owned(), |nickname| json_string(&nickname))
+}
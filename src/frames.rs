@@ -0,0 +1,188 @@
+//! Dumps of the agent protocol frames of client sessions in hex and ASCII,
+//! for debugging interop problems with unusual clients, e.g. older PuTTY or
+//! Java SSH libraries.
+//!
+//! Each frame is logged at info level under the `ssh_agent_mux::frames`
+//! target, whatever the levels, with its direction, session and message
+//! type.  Private keys, passphrases and PINs are always left out, and key
+//! blobs too unless dumping them, which redacted logs never do.
+
+use std::fmt::{self, Write as _};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::logging;
+
+const OFF: u8 = 0;
+const WITHOUT_KEYS: u8 = 1;
+const WITH_KEYS: u8 = 2;
+
+static DUMP: AtomicU8 = AtomicU8::new(OFF);
+
+/// Dumps the frames of every session from now on, with key blobs if
+/// `keys`.
+pub fn dump_frames(keys: bool) {
+    DUMP.store(
+        if keys { WITH_KEYS } else { WITHOUT_KEYS },
+        Ordering::Relaxed,
+    );
+    logging::show_frames();
+}
+
+pub(crate) fn enabled() -> bool {
+    DUMP.load(Ordering::Relaxed) != OFF
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Direction {
+    Received,
+    Sent,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Received => "received",
+            Direction::Sent => "sent",
+        })
+    }
+}
+
+/// Logs `message`, a frame without its length prefix, of session
+/// `session`.
+pub(crate) fn dump(session: u64, direction: Direction, message: &[u8]) {
+    let keys = DUMP.load(Ordering::Relaxed) == WITH_KEYS && !logging::redact();
+    let mut dump = format!(
+        "Session {session} {direction} {}, {} bytes",
+        message_type(direction, message),
+        message.len()
+    );
+    let mut at = 0;
+    for (range, what) in redactions(direction, message, keys) {
+        hex_lines(&mut dump, message, at..range.start);
+        let _ = write!(
+            dump,
+            "\n  {:04x}  [{} bytes of {what} left out]",
+            range.start,
+            range.len()
+        );
+        at = range.end;
+    }
+    hex_lines(&mut dump, message, at..message.len());
+    log::info!("{dump}");
+}
+
+/// Appends the bytes of `message` in `range`, 16 a line, after their offset.
+fn hex_lines(dump: &mut String, message: &[u8], range: Range<usize>) {
+    let start = range.start;
+    for (i, line) in message[range].chunks(16).enumerate() {
+        let _ = write!(dump, "\n  {:04x} ", start + i * 16);
+        for byte in line {
+            let _ = write!(dump, " {byte:02x}");
+        }
+        let padding = 3 * (16 - line.len());
+        let ascii: String = line
+            .iter()
+            .map(|&byte| match byte {
+                0x20..0x7f => byte as char,
+                _ => '.',
+            })
+            .collect();
+        let _ = write!(dump, "{:padding$}  |{ascii}|", "");
+    }
+}
+
+/// The name of the message's type, and of the extension for extension
+/// requests.
+fn message_type(direction: Direction, message: &[u8]) -> String {
+    let Some(&kind) = message.first() else {
+        return "empty message".to_owned();
+    };
+    let name = match (direction, kind) {
+        (Direction::Received, 1) => "SSH_AGENTC_REQUEST_RSA_IDENTITIES",
+        (Direction::Received, 3) => "SSH_AGENTC_RSA_CHALLENGE",
+        (Direction::Received, 7) => "SSH_AGENTC_ADD_RSA_IDENTITY",
+        (Direction::Received, 8) => "SSH_AGENTC_REMOVE_RSA_IDENTITY",
+        (Direction::Received, 9) => "SSH_AGENTC_REMOVE_ALL_RSA_IDENTITIES",
+        (Direction::Received, 11) => "SSH_AGENTC_REQUEST_IDENTITIES",
+        (Direction::Received, 13) => "SSH_AGENTC_SIGN_REQUEST",
+        (Direction::Received, 17) => "SSH_AGENTC_ADD_IDENTITY",
+        (Direction::Received, 18) => "SSH_AGENTC_REMOVE_IDENTITY",
+        (Direction::Received, 19) => "SSH_AGENTC_REMOVE_ALL_IDENTITIES",
+        (Direction::Received, 20) => "SSH_AGENTC_ADD_SMARTCARD_KEY",
+        (Direction::Received, 21) => "SSH_AGENTC_REMOVE_SMARTCARD_KEY",
+        (Direction::Received, 22) => "SSH_AGENTC_LOCK",
+        (Direction::Received, 23) => "SSH_AGENTC_UNLOCK",
+        (Direction::Received, 24) => "SSH_AGENTC_ADD_RSA_ID_CONSTRAINED",
+        (Direction::Received, 25) => "SSH_AGENTC_ADD_ID_CONSTRAINED",
+        (Direction::Received, 26) => "SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED",
+        (Direction::Received, 27) => {
+            let name = string(message, 1).map(|name| String::from_utf8_lossy(&message[name]));
+            return match name {
+                Some(name) => format!("SSH_AGENTC_EXTENSION {name:?}"),
+                None => "SSH_AGENTC_EXTENSION".to_owned(),
+            };
+        }
+        (Direction::Sent, 2) => "SSH_AGENT_RSA_IDENTITIES_ANSWER",
+        (Direction::Sent, 5) => "SSH_AGENT_FAILURE",
+        (Direction::Sent, 6) => "SSH_AGENT_SUCCESS",
+        (Direction::Sent, 12) => "SSH_AGENT_IDENTITIES_ANSWER",
+        (Direction::Sent, 14) => "SSH_AGENT_SIGN_RESPONSE",
+        (Direction::Sent, 28) => "SSH_AGENT_EXTENSION_FAILURE",
+        (Direction::Sent, 29) => "SSH_AGENT_EXTENSION_RESPONSE",
+        _ => return format!("unknown message type {kind}"),
+    };
+    name.to_owned()
+}
+
+/// The ranges of `message` left out, in order, and what they hold.
+fn redactions(
+    direction: Direction,
+    message: &[u8],
+    keys: bool,
+) -> Vec<(Range<usize>, &'static str)> {
+    let Some(&kind) = message.first() else {
+        return Vec::new();
+    };
+    let body = 1..message.len();
+    match (direction, kind) {
+        (Direction::Received, 7 | 17 | 24 | 25) => vec![(body, "private key")],
+        (Direction::Received, 20 | 21 | 26) => vec![(body, "smartcard key and PIN")],
+        (Direction::Received, 22 | 23) => vec![(body, "passphrase")],
+        (Direction::Received, 13 | 18) if !keys => string(message, 1)
+            .map(|key| vec![(key, "key")])
+            .unwrap_or_default(),
+        (Direction::Sent, 12) if !keys => {
+            let mut redactions = Vec::new();
+            let Some(count) = message.get(1..5) else {
+                return redactions;
+            };
+            let count = u32::from_be_bytes(count.try_into().expect("4 bytes"));
+            let mut at = 5;
+            for _ in 0..count {
+                let Some(key) = string(message, at) else {
+                    break;
+                };
+                let Some(comment) = string(message, key.end) else {
+                    break;
+                };
+                at = comment.end;
+                redactions.push((key, "key"));
+                if logging::redact() && !comment.is_empty() {
+                    redactions.push((comment, "comment"));
+                }
+            }
+            redactions
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The contents of the string of `message` at `at`, after its length.
+fn string(message: &[u8], at: usize) -> Option<Range<usize>> {
+    let len = message.get(at..at + 4)?;
+    let len = u32::from_be_bytes(len.try_into().expect("4 bytes")) as usize;
+    let start = at + 4;
+    let end = start.checked_add(len)?;
+    (end <= message.len()).then_some(start..end)
+}
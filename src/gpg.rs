@@ -0,0 +1,83 @@
+//! Passing pinentry's environment to gpg-agent.  Behind its ssh socket,
+//! gpg-agent starts pinentry with the terminal and display it was started
+//! from, or last told of by `gpg-connect-agent updatestartuptty /bye`, so
+//! a daemonized mux leaves pinentry nowhere to ask and signing fails.
+//!
+//! For gpg-agent targets with an `environment`, the mux does what
+//! `updatestartuptty` does on gpg-agent's own socket before each request
+//! that may prompt: sets each variable with `OPTION putenv` and makes them
+//! those pinentry starts with.  The mux doesn't start targets, so that is
+//! the only agent taking an environment.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use service_binding::{Binding, Stream};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::sandbox;
+
+/// gpg-agent answers these at once unless it is stuck.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Has the gpg-agent at `socket`, its Assuan socket, start pinentry with
+/// `environment` from now on.
+pub(crate) async fn pass_environment(
+    socket: &Path,
+    environment: &[(String, String)],
+) -> io::Result<()> {
+    tokio::time::timeout(TIMEOUT, update_startup_tty(socket, environment))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "gpg-agent didn't answer",
+            ))
+        })
+}
+
+async fn update_startup_tty(socket: &Path, environment: &[(String, String)]) -> io::Result<()> {
+    let stream = match sandbox::connect(Binding::FilePath(socket.to_owned()))? {
+        Stream::Unix(stream) => tokio::net::UnixStream::from_std(stream)?,
+        _ => return Err(io::Error::other("not a Unix socket")),
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    // The greeting.
+    answer(&mut lines).await?;
+    let commands = environment
+        .iter()
+        .map(|(name, value)| format!("OPTION putenv={}={}", escape(name), escape(value)))
+        .chain(["UPDATESTARTUPTTY".to_owned()]);
+    for command in commands {
+        writer.write_all(format!("{command}\n").as_bytes()).await?;
+        answer(&mut lines).await?;
+    }
+    writer.write_all(b"BYE\n").await
+}
+
+/// Reads up to the `OK` ending an answer, failing on `ERR`.
+async fn answer<R: tokio::io::AsyncBufRead + Unpin>(
+    lines: &mut tokio::io::Lines<R>,
+) -> io::Result<()> {
+    loop {
+        let Some(line) = lines.next_line().await? else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+        if line == "OK" || line.starts_with("OK ") {
+            return Ok(());
+        }
+        if line.starts_with("ERR") {
+            return Err(io::Error::other(format!("gpg-agent answered {line:?}")));
+        }
+        // Status and comment lines.
+    }
+}
+
+/// `s` with the characters Assuan lines can't hold percent-escaped.
+fn escape(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
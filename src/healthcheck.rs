@@ -0,0 +1,33 @@
+//! Probing a running mux, e.g. as a container health check.
+
+use std::io;
+use std::time::Duration;
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{ProtoError, Request, Response},
+};
+
+use crate::client::Client;
+use crate::error::Error;
+
+/// Lists the identities of the mux serving on `host`, returning how many it
+/// has.  Fails if that takes longer than `timeout`.
+pub async fn probe(host: &Binding, timeout: Duration) -> Result<usize, Error> {
+    let request = async {
+        let mut client = Client::connect(host.clone().try_into()?)?;
+        match client.handle(Request::RequestIdentities).await? {
+            Response::IdentitiesAnswer(identities) => Ok(identities.len()),
+            Response::Failure => Err(AgentError::Failure),
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    };
+    tokio::time::timeout(timeout, request)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into()))
+        .map_err(|source| Error::Unhealthy {
+            host: host.clone(),
+            source,
+        })
+}
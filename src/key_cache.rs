@@ -0,0 +1,152 @@
+//! Which targets hold which keys, kept across restarts in a cache file, so
+//! that sign requests of clients that don't list identities first are
+//! routed right away, without waiting for every target to list theirs.
+//!
+//! The file holds one key per line: its algorithm and base64 blob, as in
+//! `authorized_keys`, followed by the names of the targets that last listed
+//! it.  Keys are added and updated as targets list them, and a stale entry,
+//! e.g. of a key moved to another target, is corrected by the next listing.
+//! Listings of views, which hold only some of the targets, leave it as is.
+//! The file is written by a thread of its own, so that listings don't wait
+//! for the disk.
+
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+use ssh_key::{public::KeyData, PublicKey};
+
+/// The names of the targets holding each key.
+pub type Entries = Vec<(KeyData, Vec<String>)>;
+
+static KEYS: Mutex<Entries> = Mutex::new(Vec::new());
+
+/// Takes the contents to write the cache file with.
+static WRITER: OnceLock<Sender<String>> = OnceLock::new();
+
+/// Keeps the targets of keys in `path` from now on, starting from what it
+/// holds if it exists.
+pub fn open(path: &Path) -> io::Result<()> {
+    let stored = match read(path) {
+        Ok(stored) => stored,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    *KEYS.lock().unwrap() = stored;
+    let (sender, receiver) = mpsc::channel();
+    if WRITER.set(sender).is_ok() {
+        let path = path.to_owned();
+        thread::Builder::new()
+            .name("key-cache".to_owned())
+            .spawn(move || write_all(&path, receiver))?;
+    }
+    Ok(())
+}
+
+/// Reads the targets of keys kept in `path`.
+pub fn read(path: &Path) -> io::Result<Entries> {
+    let mut keys = Vec::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let key = fields
+            .next()
+            .zip(fields.next())
+            .and_then(|(algorithm, blob)| {
+                PublicKey::from_openssh(&format!("{algorithm} {blob}")).ok()
+            });
+        let Some(key) = key else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: expected a public key", number + 1),
+            ));
+        };
+        keys.push((key.key_data().clone(), fields.map(str::to_owned).collect()));
+    }
+    Ok(keys)
+}
+
+/// The names of the targets that last listed `key`, if there is a cache
+/// file and they did.
+pub(crate) fn targets(key: &KeyData) -> Option<Vec<String>> {
+    WRITER.get()?;
+    KEYS.lock()
+        .unwrap()
+        .iter()
+        .find(|(cached, _)| cached == key)
+        .map(|(_, targets)| targets.clone())
+}
+
+/// Records the names of the targets that just listed each key, writing the
+/// cache file if that changed anything.  Names with whitespace aren't kept.
+pub(crate) fn update(listed: Entries) {
+    let Some(writer) = WRITER.get() else {
+        return;
+    };
+    let mut keys = KEYS.lock().unwrap();
+    let mut changed = false;
+    for (key, mut targets) in listed {
+        targets.retain(|name| !name.contains(char::is_whitespace));
+        match keys.iter_mut().find(|(cached, _)| *cached == key) {
+            Some((_, cached)) if *cached == targets => (),
+            Some((_, cached)) => {
+                *cached = targets;
+                changed = true;
+            }
+            None => {
+                keys.push((key, targets));
+                changed = true;
+            }
+        }
+    }
+    // Sent while holding the lock, so that writes land in order.
+    if changed {
+        let _ = writer.send(contents(&keys));
+    }
+}
+
+/// Writes `path` with the contents received, skipping those replaced by
+/// the time the last write is done.
+fn write_all(path: &Path, receiver: Receiver<String>) {
+    while let Ok(mut contents) = receiver.recv() {
+        while let Ok(newer) = receiver.try_recv() {
+            contents = newer;
+        }
+        if let Err(e) = write(path, &contents) {
+            log::warn!("Failed to write {}: {e}", path.display());
+        }
+    }
+}
+
+/// The contents of a cache file holding `keys`.
+fn contents(keys: &Entries) -> String {
+    let mut contents = String::from("# algorithm key targets...\n");
+    for (key, targets) in keys {
+        let Ok(key) = PublicKey::from(key.clone()).to_openssh() else {
+            continue;
+        };
+        contents += &key;
+        for target in targets {
+            contents += " ";
+            contents += target;
+        }
+        contents += "\n";
+    }
+    contents
+}
+
+/// Replaces `path` with `contents`, through a temporary file so that it is
+/// never left half written.
+fn write(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = fs::File::create(&temporary)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
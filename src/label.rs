@@ -0,0 +1,110 @@
+//! Security labels of the host socket, for SELinux and SMACK.
+//!
+//! The SELinux context is set as the creation context of the binding
+//! thread, so that the socket and its file are created with it rather than
+//! relabeled after.  SMACK has no creation context for files, so the
+//! socket file's label is set right after binding, before the mux accepts
+//! connections.
+
+use std::io;
+
+use service_binding::Binding;
+
+use crate::config::SocketLabelConfig;
+
+/// Calls `bind` to create the host socket for `binding`, with the labels
+/// of `config`.
+pub fn bind<T>(
+    config: &SocketLabelConfig,
+    binding: &Binding,
+    bind: impl FnOnce() -> io::Result<T>,
+) -> io::Result<T> {
+    let listener = match &config.selinux {
+        Some(context) => with_selinux_context(context, bind)?,
+        None => bind()?,
+    };
+    if let Some(label) = &config.smack {
+        match binding {
+            Binding::FilePath(path) => set_smack_label(path, label)?,
+            _ => log::warn!("Only socket files take a SMACK label; not labeling {binding:?}"),
+        }
+    }
+    Ok(listener)
+}
+
+#[cfg(target_os = "linux")]
+fn with_selinux_context<T>(context: &str, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    const ATTRS: [&str; 2] = [
+        "/proc/thread-self/attr/fscreate",
+        "/proc/thread-self/attr/sockcreate",
+    ];
+    for attr in ATTRS {
+        write_attr(attr, context.as_bytes())?;
+    }
+    let result = f();
+    // An empty write resets the creation context to the default.
+    for attr in ATTRS {
+        if let Err(e) = write_attr(attr, b"") {
+            log::warn!("Failed to reset {attr}: {e}");
+        }
+    }
+    let listener = result?;
+    log::info!("Created the host socket with SELinux context {context}");
+    Ok(listener)
+}
+
+#[cfg(target_os = "linux")]
+fn write_attr(attr: &str, value: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::fs::OpenOptions::new().write(true).open(attr)?;
+    // A single write, which may be empty, unlike `write_all`.
+    file.write(value)
+        .map(|_| ())
+        .map_err(|e| io::Error::new(e.kind(), format!("{attr}: {e}")))
+}
+
+#[cfg(target_os = "linux")]
+fn set_smack_label(path: &std::path::Path, label: &str) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: the path and name are NUL-terminated, and the value is valid
+    // for its length.
+    let result = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            c"security.SMACK64".as_ptr(),
+            label.as_ptr().cast(),
+            label.len(),
+            0,
+        )
+    };
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            e.kind(),
+            format!("{}: security.SMACK64: {e}", path.display()),
+        ));
+    }
+    log::info!("Labeled {} with SMACK label {label}", path.display());
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn with_selinux_context<T>(_context: &str, _f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SELinux labels are only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_smack_label(_path: &std::path::Path, _label: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SMACK labels are only supported on Linux",
+    ))
+}
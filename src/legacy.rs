@@ -0,0 +1,32 @@
+//! Answers to SSH protocol 1 agent requests.
+//!
+//! ssh-agent-lib doesn't decode protocol 1 messages, so without this they
+//! all get a generic failure.  Some old clients treat that as fatal where an
+//! empty identity list would do.  Protocol 1 keys are never translated
+//! since the challenge-response they are used with can't be done through a
+//! protocol 2 agent.
+
+const SSH_AGENTC_REQUEST_RSA_IDENTITIES: u8 = 1;
+const SSH_AGENT_RSA_IDENTITIES_ANSWER: u8 = 2;
+const SSH_AGENTC_RSA_CHALLENGE: u8 = 3;
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENT_SUCCESS: u8 = 6;
+const SSH_AGENTC_ADD_RSA_IDENTITY: u8 = 7;
+const SSH_AGENTC_REMOVE_RSA_IDENTITY: u8 = 8;
+const SSH_AGENTC_REMOVE_ALL_RSA_IDENTITIES: u8 = 9;
+const SSH_AGENTC_ADD_RSA_ID_CONSTRAINED: u8 = 24;
+
+/// The reply to `message` if it is a protocol 1 request.
+pub fn reply(message: &[u8]) -> Option<&'static [u8]> {
+    match *message.first()? {
+        // An empty list
+        SSH_AGENTC_REQUEST_RSA_IDENTITIES => Some(&[SSH_AGENT_RSA_IDENTITIES_ANSWER, 0, 0, 0, 0]),
+        // Nothing to remove
+        SSH_AGENTC_REMOVE_ALL_RSA_IDENTITIES => Some(&[SSH_AGENT_SUCCESS]),
+        SSH_AGENTC_RSA_CHALLENGE
+        | SSH_AGENTC_ADD_RSA_IDENTITY
+        | SSH_AGENTC_REMOVE_RSA_IDENTITY
+        | SSH_AGENTC_ADD_RSA_ID_CONSTRAINED => Some(&[SSH_AGENT_FAILURE]),
+        _ => None,
+    }
+}
@@ -0,0 +1,54 @@
+//! An SSH agent that multiplexes other SSH agents.
+
+pub mod admin;
+pub mod alert;
+mod anomaly;
+pub mod approval;
+pub mod approver;
+pub mod askpass;
+pub mod audit;
+pub mod chaos;
+mod client;
+mod codec;
+pub mod config;
+pub mod conformance;
+#[cfg(unix)]
+pub mod dbus;
+mod dns;
+pub mod error;
+pub mod events;
+pub mod export;
+pub mod frames;
+#[cfg(unix)]
+mod gpg;
+pub mod healthcheck;
+pub mod key_cache;
+pub mod label;
+mod legacy;
+pub mod logging;
+pub mod metrics;
+mod mux;
+pub mod onepassword;
+pub mod ping;
+#[cfg(windows)]
+pub mod pipe;
+pub mod plugin;
+mod policy;
+pub mod record;
+pub mod report;
+pub mod sandbox;
+mod script;
+pub mod serve;
+#[cfg(unix)]
+pub mod signal;
+pub mod sshconfig;
+pub mod status;
+#[cfg(unix)]
+pub mod top;
+pub mod unlock;
+mod upstream;
+pub mod view;
+pub mod webhook;
+
+pub use mux::{MuxAgentBind, MuxState, TargetStatus, ViewCreator};
+pub use policy::KeyState;
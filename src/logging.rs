@@ -0,0 +1,559 @@
+//! Logging to stderr, or a log file rotated by size and age, filtered by
+//! `RUST_LOG` like `env_logger`, with debug logging of the mux that can be
+//! switched on and off while running.
+//!
+//! Levels set by `-v`, `-q` and `log_levels` apply on top of `RUST_LOG`,
+//! `log_levels` by log target or by area: `routing` for where requests go,
+//! `transport` for connections and messages to and from clients and
+//! targets, and `policy` for approvals and usage rules.
+//!
+//! On a terminal, records are shown in aligned columns with their level in
+//! color, and requests and responses as summaries with shortened key
+//! fingerprints rather than in full.
+//!
+//! Keys, comments and messages are logged through [`Key`], [`Comment`] and
+//! [`Message`], which redact them when logs are shipped off-host.  Keys are
+//! shown with their nicknames from the config, if any.
+//!
+//! Records logged while handling a client request are prefixed with its
+//! [`RequestId`], so that fan-outs to several targets can be told apart.
+
+mod file;
+
+use std::fmt;
+use std::future::Future;
+use std::io::{self, IsTerminal, Write as _};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use ssh_agent_lib::proto::{
+    AddIdentity, AddIdentityConstrained, Credential, Extension, Request, Response, SignRequest,
+};
+use ssh_key::{public::KeyData, HashAlg, Signature};
+
+use crate::config::KeyConfig;
+use crate::onepassword;
+
+pub use file::Rotation;
+
+/// Whether the mux's own debug logging is on, on top of `RUST_LOG`.
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Whether records are shown for someone watching a terminal.
+static PRETTY: AtomicBool = AtomicBool::new(false);
+
+const CRATE: &str = env!("CARGO_CRATE_NAME");
+
+/// Modules of the mux in each area that levels can be set for, besides log
+/// targets as in `RUST_LOG`.
+const AREAS: [(&str, &[&str]); 3] = [
+    ("routing", &["mux", "script", "plugin"]),
+    (
+        "transport",
+        &[
+            "serve", "upstream", "client", "codec", "legacy", "dns", "pipe",
+        ],
+    ),
+    (
+        "policy",
+        &[
+            "policy", "approval", "approver", "askpass", "anomaly", "unlock",
+        ],
+    ),
+];
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+struct Logger(RwLock<Loggers>);
+
+/// Where records are written.
+#[derive(Clone)]
+enum Output {
+    Stderr,
+    File(Arc<Mutex<file::LogFile>>),
+}
+
+/// Levels set on top of `RUST_LOG`.
+#[derive(Clone, Default)]
+struct Levels {
+    /// Of records without a level of their own, from `-v` or `-q`.
+    default: Option<LevelFilter>,
+    /// By log target or area, from `log_levels`.
+    targets: Vec<(String, LevelFilter)>,
+    /// Whether frame dumps are let through, from `--dump-frames`.
+    frames: bool,
+}
+
+struct Loggers {
+    output: Output,
+    levels: Levels,
+    /// Filtered by `RUST_LOG` and `levels`.
+    normal: env_logger::Logger,
+    /// Filtered by `RUST_LOG` and `levels`, but with the mux's own debug
+    /// records.
+    debug: env_logger::Logger,
+}
+
+impl Loggers {
+    fn new(output: Output, levels: Levels) -> Self {
+        let pretty = PRETTY.load(Ordering::Relaxed);
+        let build = |debug: bool| {
+            let mut builder = env_logger::Builder::from_default_env();
+            builder.target(match &output {
+                Output::Stderr => env_logger::Target::Stderr,
+                Output::File(file) => {
+                    env_logger::Target::Pipe(Box::new(file::Shared(file.clone())))
+                }
+            });
+            if pretty {
+                builder.format(format_pretty);
+            }
+            if let Some(level) = levels.default {
+                builder.filter_level(level);
+            }
+            if debug {
+                builder.filter_module(CRATE, LevelFilter::Debug);
+            }
+            for (name, level) in &levels.targets {
+                for target in area_targets(name) {
+                    // Debug logging of the mux overrides quieter modules.
+                    let level = match debug && target.starts_with(CRATE) {
+                        true => (*level).max(LevelFilter::Debug),
+                        false => *level,
+                    };
+                    builder.filter_module(&target, level);
+                }
+            }
+            if levels.frames {
+                builder.filter_module(&format!("{CRATE}::frames"), LevelFilter::Info);
+            }
+            builder.build()
+        };
+        Self {
+            normal: build(false),
+            debug: build(true),
+            output,
+            levels,
+        }
+    }
+
+    /// Records either logger lets through have to reach the logger.
+    fn max_level(&self) -> LevelFilter {
+        self.normal.filter().max(self.debug.filter())
+    }
+
+    fn current(&self) -> &env_logger::Logger {
+        if debug() {
+            &self.debug
+        } else {
+            &self.normal
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.0.read().unwrap().current().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        let loggers = self.0.read().unwrap();
+        match REQUEST_ID.try_with(|&id| id) {
+            Ok(id) => loggers.current().log(
+                &Record::builder()
+                    .args(format_args!("[{id}] {}", record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            Err(_) => loggers.current().log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().current().flush()
+    }
+}
+
+/// Formats a record as columns of its time, level, module and message.
+fn format_pretty(buf: &mut env_logger::fmt::Formatter, record: &Record<'_>) -> io::Result<()> {
+    let level = buf.default_level_style(record.level());
+    let dim = env_logger::fmt::style::Style::new().dimmed();
+    let target = record.target();
+    let module = target
+        .strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::"))
+        .unwrap_or(target);
+    writeln!(
+        buf,
+        "{dim}{}{dim:#} {level}{:<5}{level:#} {dim}{module:<10}{dim:#} {}",
+        buf.timestamp_seconds(),
+        record.level(),
+        record.args()
+    )
+}
+
+/// The log targets of the modules in area `name`, or else `name` itself.
+fn area_targets(name: &str) -> Vec<String> {
+    match AREAS.iter().find(|(area, _)| *area == name) {
+        Some((_, modules)) => modules
+            .iter()
+            .map(|module| format!("{CRATE}::{module}"))
+            .collect(),
+        None => vec![name.to_owned()],
+    }
+}
+
+/// Installs the logger, writing to stderr.
+pub fn init() {
+    PRETTY.store(io::stderr().is_terminal(), Ordering::Relaxed);
+    let loggers = Loggers::new(Output::Stderr, Levels::default());
+    let max_level = loggers.max_level();
+    let logger = LOGGER.get_or_init(|| Logger(RwLock::new(loggers)));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// Rebuilds the loggers with the output and levels `change` makes.
+fn reconfigure(change: impl FnOnce(&mut Output, &mut Levels)) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let mut loggers = logger.0.write().unwrap();
+    let mut output = loggers.output.clone();
+    let mut levels = loggers.levels.clone();
+    change(&mut output, &mut levels);
+    *loggers = Loggers::new(output, levels);
+    log::set_max_level(loggers.max_level());
+}
+
+/// Writes records to the file at `path` from now on instead, rotated as
+/// `rotation` says.
+pub fn log_to_file(path: &Path, rotation: Rotation) -> io::Result<()> {
+    let file = Arc::new(Mutex::new(file::LogFile::open(path, rotation)?));
+    PRETTY.store(false, Ordering::Relaxed);
+    reconfigure(|output, _| *output = Output::File(file));
+    Ok(())
+}
+
+/// Sets the level of records without one of their own, over `RUST_LOG`'s,
+/// e.g. from `-v`.
+pub fn set_level(level: Option<LevelFilter>) {
+    reconfigure(|_, levels| levels.default = level);
+}
+
+/// Sets the levels of log targets, or areas like `routing`, over
+/// `RUST_LOG`'s.
+pub fn set_levels(targets: &[(String, LevelFilter)]) {
+    reconfigure(|_, levels| levels.targets = targets.to_vec());
+}
+
+/// Lets frame dumps through, whatever the levels.
+pub(crate) fn show_frames() {
+    reconfigure(|_, levels| levels.frames = true);
+}
+
+/// Whether debug logging of the mux is on.
+pub fn debug() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+/// Switches debug logging of the mux on or off.
+pub fn set_debug(debug: bool) {
+    DEBUG.store(debug, Ordering::Relaxed);
+}
+
+/// Identifies a client request in the log records of everything done for
+/// it.
+#[derive(Clone, Copy)]
+pub struct RequestId(u64);
+
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+impl RequestId {
+    /// A new ID, unique for the life of the process.
+    pub fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Calls `f`, prefixing the records it logs with the ID.
+    pub fn in_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        REQUEST_ID.sync_scope(self, f)
+    }
+
+    /// Prefixes the records `future` logs with the ID.
+    pub fn scope<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        REQUEST_ID.scope(self, future)
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "req {}", self.0)
+    }
+}
+
+/// Whether logs leave out public keys, comments and request details.
+static REDACT: AtomicBool = AtomicBool::new(false);
+
+/// Switches redaction of logged keys, comments and messages on or off.
+pub fn set_redact(redact: bool) {
+    REDACT.store(redact, Ordering::Relaxed);
+}
+
+pub(crate) fn redact() -> bool {
+    REDACT.load(Ordering::Relaxed)
+}
+
+/// Whether requests and responses are summarized, when redacting or on a
+/// terminal.
+fn brief() -> bool {
+    redact() || PRETTY.load(Ordering::Relaxed)
+}
+
+/// Nicknames of keys, by fingerprint.
+static NICKNAMES: RwLock<Vec<(ssh_key::Fingerprint, String)>> = RwLock::new(Vec::new());
+
+/// Shows the keys of `keys` with a nickname by it from now on.
+pub fn set_nicknames(keys: &[KeyConfig]) {
+    *NICKNAMES.write().unwrap() = keys
+        .iter()
+        .filter_map(|key| Some((key.fingerprint, key.nickname.clone()?)))
+        .collect();
+}
+
+/// The nickname of `key`, or else its 1Password label, if it has one.
+pub fn key_nickname(key: &KeyData) -> Option<String> {
+    NICKNAMES
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(fingerprint, _)| key.fingerprint(fingerprint.algorithm()) == *fingerprint)
+        .map(|(_, nickname)| nickname.clone())
+        .or_else(|| onepassword::key_label(key))
+}
+
+/// The nickname of the key with `fingerprint`, or else its 1Password
+/// label, if it has one.
+pub fn nickname(fingerprint: &str) -> Option<String> {
+    NICKNAMES
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(known, _)| known.to_string() == fingerprint)
+        .map(|(_, nickname)| nickname.clone())
+        .or_else(|| onepassword::fingerprint_label(fingerprint))
+}
+
+/// Displays a key as its fingerprint, shortened when redacting, after its
+/// nickname.
+pub struct Key<'a>(pub &'a KeyData);
+
+impl fmt::Display for Key<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fingerprint = self.0.fingerprint(HashAlg::Sha256).to_string();
+        show_fingerprint(f, &fingerprint, key_nickname(self.0), redact())
+    }
+}
+
+/// Displays a key in a summarized message, with its fingerprint always
+/// shortened.
+struct BriefKey<'a>(&'a KeyData);
+
+impl fmt::Display for BriefKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fingerprint = self.0.fingerprint(HashAlg::Sha256).to_string();
+        show_fingerprint(f, &fingerprint, key_nickname(self.0), true)
+    }
+}
+
+/// Displays a SHA-256 key fingerprint, shortened when redacting, after the
+/// key's nickname.
+pub struct Fingerprint<'a>(pub &'a str);
+
+impl fmt::Display for Fingerprint<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        show_fingerprint(f, self.0, nickname(self.0), redact())
+    }
+}
+
+fn show_fingerprint(
+    f: &mut fmt::Formatter<'_>,
+    fingerprint: &str,
+    nickname: Option<String>,
+    short: bool,
+) -> fmt::Result {
+    let fingerprint = match fingerprint.get(..15) {
+        // "SHA256:" and 8 characters of base64 tell keys apart.
+        Some(prefix) if short => prefix,
+        _ => fingerprint,
+    };
+    match nickname {
+        Some(nickname) => write!(f, "{nickname} ({fingerprint})"),
+        None => f.write_str(fingerprint),
+    }
+}
+
+/// Displays a key comment, as a short hash when redacting.
+pub struct Comment<'a>(pub &'a str);
+
+impl fmt::Display for Comment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !redact() {
+            return write!(f, "{:?}", self.0);
+        }
+        f.write_str("#")?;
+        for byte in &HashAlg::Sha256.digest(self.0.as_bytes())[..4] {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Displays a request or response for debugging.  When redacting, or on a
+/// terminal, only its type and the shortened fingerprints and comments of
+/// keys in it are shown, the comments as hashes when redacting.  Requests
+/// holding secrets, passphrases, PINs or private keys, are always shown by
+/// their type, and keys added by their comments.
+pub struct Message<'a, T>(pub &'a T);
+
+impl fmt::Display for Message<'_, Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secret = matches!(
+            self.0,
+            Request::AddIdentity(_)
+                | Request::AddIdConstrained(_)
+                | Request::AddSmartcardKey(_)
+                | Request::AddSmartcardKeyConstrained(_)
+                | Request::RemoveSmartcardKey(_)
+                | Request::Lock(_)
+                | Request::Unlock(_)
+        );
+        if !brief() && !secret {
+            return write!(f, "{:?}", self.0);
+        }
+        match self.0 {
+            Request::SignRequest(request) => write!(f, "SignRequest({})", Message(request)),
+            Request::RemoveIdentity(identity) => {
+                write!(f, "RemoveIdentity({})", BriefKey(&identity.pubkey))
+            }
+            Request::AddIdentity(AddIdentity { credential })
+            | Request::AddIdConstrained(AddIdentityConstrained {
+                identity: AddIdentity { credential },
+                ..
+            }) => {
+                let comment = match credential {
+                    Credential::Key { comment, .. } | Credential::Cert { comment, .. } => comment,
+                };
+                write!(f, "{}({})", request_name(self.0), Comment(comment))
+            }
+            Request::Extension(extension) => write!(f, "Extension({})", Message(extension)),
+            request => f.write_str(request_name(request)),
+        }
+    }
+}
+
+impl fmt::Display for Message<'_, Response> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !brief() {
+            return write!(f, "{:?}", self.0);
+        }
+        match self.0 {
+            Response::IdentitiesAnswer(identities) => {
+                f.write_str("IdentitiesAnswer(")?;
+                for (i, identity) in identities.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(
+                        f,
+                        "{} {}",
+                        BriefKey(&identity.pubkey),
+                        Comment(&identity.comment)
+                    )?;
+                }
+                f.write_str(")")
+            }
+            Response::SignResponse(signature) => write!(f, "SignResponse({})", Message(signature)),
+            Response::ExtensionResponse(extension) => {
+                write!(f, "ExtensionResponse({})", Message(extension))
+            }
+            response => f.write_str(response_name(response)),
+        }
+    }
+}
+
+impl fmt::Display for Message<'_, SignRequest> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if redact() {
+            BriefKey(&self.0.pubkey).fmt(f)
+        } else if brief() {
+            write!(
+                f,
+                "{}, {} bytes, flags {:#x}",
+                BriefKey(&self.0.pubkey),
+                self.0.data.len(),
+                self.0.flags
+            )
+        } else {
+            write!(f, "{:?}", self.0)
+        }
+    }
+}
+
+impl fmt::Display for Message<'_, Signature> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if brief() {
+            self.0.algorithm().fmt(f)
+        } else {
+            write!(f, "{:?}", self.0)
+        }
+    }
+}
+
+/// Extensions are shown by name when summarized, as their contents are
+/// opaque.
+impl fmt::Display for Message<'_, Extension> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if brief() {
+            f.write_str(&self.0.name)
+        } else {
+            write!(f, "{:?}", self.0)
+        }
+    }
+}
+
+pub(crate) fn request_name(request: &Request) -> &'static str {
+    match request {
+        Request::RequestIdentities => "RequestIdentities",
+        Request::SignRequest(_) => "SignRequest",
+        Request::AddIdentity(_) => "AddIdentity",
+        Request::RemoveIdentity(_) => "RemoveIdentity",
+        Request::RemoveAllIdentities => "RemoveAllIdentities",
+        Request::AddSmartcardKey(_) => "AddSmartcardKey",
+        Request::RemoveSmartcardKey(_) => "RemoveSmartcardKey",
+        Request::Lock(_) => "Lock",
+        Request::Unlock(_) => "Unlock",
+        Request::AddIdConstrained(_) => "AddIdConstrained",
+        Request::AddSmartcardKeyConstrained(_) => "AddSmartcardKeyConstrained",
+        Request::Extension(_) => "Extension",
+    }
+}
+
+pub(crate) fn response_name(response: &Response) -> &'static str {
+    match response {
+        Response::Failure => "Failure",
+        Response::Success => "Success",
+        Response::IdentitiesAnswer(_) => "IdentitiesAnswer",
+        Response::SignResponse(_) => "SignResponse",
+        Response::ExtensionFailure => "ExtensionFailure",
+        Response::ExtensionResponse(_) => "ExtensionResponse",
+    }
+}
@@ -0,0 +1,115 @@
+//! A log file rotated once it grows too large or old, keeping a few of the
+//! previous ones as `<path>.1`, the newest, to `<path>.<keep>`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// When a log file is rotated, and how many of the previous ones are kept.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    /// Rotated before growing beyond this many bytes.
+    pub max_size: u64,
+    /// Rotated once it was started this long ago, if at all.
+    pub max_age: Option<Duration>,
+    /// Previous files kept, the oldest being removed.
+    pub keep: usize,
+}
+
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    started_at: SystemTime,
+}
+
+impl LogFile {
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path: path.to_owned(),
+            rotation,
+            size: metadata.len(),
+            // Not every filesystem records when a file was created.
+            started_at: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            file,
+        })
+    }
+
+    fn due(&self, len: usize) -> bool {
+        self.size > 0
+            && (self.size + len as u64 > self.rotation.max_size
+                || self.rotation.max_age.is_some_and(|max_age| {
+                    self.started_at.elapsed().unwrap_or_default() >= max_age
+                }))
+    }
+
+    /// Moves each previous file one number up, the current one to `.1`,
+    /// and starts a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| {
+            let mut path = self.path.as_os_str().to_owned();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        match self.rotation.keep {
+            0 => fs::remove_file(&self.path)?,
+            keep => {
+                for n in (1..keep).rev() {
+                    match fs::rename(numbered(n), numbered(n + 1)) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                        _ => (),
+                    }
+                }
+                fs::rename(&self.path, numbered(1))?;
+            }
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) {
+            if let Err(e) = self.rotate() {
+                // Logging it would come back here.
+                eprintln!(
+                    "ssh-agent-mux: failed to rotate {}, writing on: {e}",
+                    self.path.display()
+                );
+            }
+            // Not retried until the file is due again.
+            self.size = 0;
+            self.started_at = SystemTime::now();
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A log file written by several loggers.
+pub struct Shared(pub Arc<Mutex<LogFile>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
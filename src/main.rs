@@ -5,190 +5,1195 @@
 //! SSH_AUTH_SOCK=/tmp/test.sock ssh-add -l
 //! SSH_AUTH_SOCK=/tmp/test.sock ssh <host>
 
-use clap::Parser;
-use futures::future::join_all;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{ArgAction, Parser, Subcommand};
+use log::LevelFilter;
 use service_binding::Binding;
-use ssh_agent_lib::{
-    agent::bind,
-    agent::Agent,
-    agent::Session,
-    async_trait,
-    client::connect,
-    error::AgentError,
-    proto::{Extension, Identity, SignRequest},
-    // proto::{Request, Response},
+use ssh_key::{Fingerprint, PublicKey};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use ssh_agent_mux::chaos::{self, Chaos};
+use ssh_agent_mux::config::{
+    self, Config, ConfigError, Host, SeccompMode, TargetConfig, TargetSpec, ViewConfig,
 };
-use ssh_key::{public::KeyData, Signature};
+use ssh_agent_mux::error::Error;
+use ssh_agent_mux::report::Report;
+use ssh_agent_mux::serve::{serve_stdio, serve_until, ServeOptions};
+#[cfg(unix)]
+use ssh_agent_mux::signal::{self, Signal};
+use ssh_agent_mux::webhook::{self, Webhook};
+use ssh_agent_mux::{
+    admin, alert, approval, askpass, audit, conformance, export, frames, healthcheck, key_cache,
+    label, logging, metrics, onepassword, ping, plugin, record, sandbox, sshconfig, status, unlock,
+    view, MuxAgentBind, MuxState, ViewCreator,
+};
+#[cfg(unix)]
+use ssh_agent_mux::{dbus, events, top};
+
+#[derive(Clone, Debug, Parser)]
+struct Args {
+    /// Target SSH agent to which we will proxy all requests.
+    ///
+    /// Added after any targets from the config file.  `dir:///path` adds
+    /// every socket in a directory, or those matching a glob like
+    /// `dir:///path/*.sock`, re-scanned on SIGHUP as the config is reloaded.  The environment variable
+    /// takes a comma-separated list.
+    #[clap(long="target", num_args=1.., env = "SSH_AGENT_MUX_TARGETS", value_delimiter = ',')]
+    targets: Vec<TargetSpec>,
+
+    /// Source that we will bind to.  Overrides `host` from the config file.
+    /// `stdio://` serves a single session over stdin and stdout.
+    #[clap(long, env = "SSH_AGENT_MUX_HOST")]
+    host: Option<Host>,
+
+    /// Close client sessions after this long without a request, e.g. `10m`.
+    #[clap(long, value_parser = humantime::parse_duration, env = "SSH_AGENT_MUX_SESSION_IDLE_TIMEOUT")]
+    session_idle_timeout: Option<Duration>,
+
+    /// Maximum number of simultaneous client sessions.  Connections beyond
+    /// this are closed immediately.
+    #[clap(long, env = "SSH_AGENT_MUX_MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+
+    /// Switch to this user, by name or ID, once the host socket is bound.
+    #[clap(long, env = "SSH_AGENT_MUX_USER")]
+    user: Option<String>,
+
+    /// Switch to this group, by name or ID, once the host socket is bound.
+    /// Defaults to the user's primary group.
+    #[clap(long, env = "SSH_AGENT_MUX_GROUP")]
+    group: Option<String>,
+
+    /// Confine the mux to this directory, e.g. an empty one, once the
+    /// sockets are bound.  Requires `--user`.  Unix socket targets are
+    /// looked up inside it.
+    #[clap(long, env = "SSH_AGENT_MUX_CHROOT")]
+    chroot: Option<PathBuf>,
+
+    /// Config file: TOML, or YAML or JSON by its extension.
+    #[clap(long, env = "SSH_AGENT_MUX_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Profile of the config file to use, from its `profiles` table.
+    #[clap(long, requires = "config", env = "SSH_AGENT_MUX_PROFILE")]
+    profile: Option<String>,
+
+    /// Log more: info with `-v`, debug with `-vv` and trace with `-vvv`,
+    /// over `RUST_LOG`'s default level.
+    #[clap(short, long, action = ArgAction::Count, conflicts_with = "quiet", env = "SSH_AGENT_MUX_VERBOSE")]
+    verbose: u8,
+
+    /// Log nothing but what `log_levels` in the config file asks for.
+    #[clap(short, long, env = "SSH_AGENT_MUX_QUIET")]
+    quiet: bool,
+
+    /// Log to this file instead of stderr, rotating it as it grows and ages.
+    #[clap(long, env = "SSH_AGENT_MUX_LOG_FILE")]
+    log_file: Option<PathBuf>,
 
-struct IdentityIndex {
-    identity: Identity,
-    target_index: usize,
+    /// Rotate the log file before it grows beyond this size, e.g. `512K`,
+    /// `10M` or `1G`.
+    #[clap(long, default_value = "10M", value_parser = parse_size, env = "SSH_AGENT_MUX_LOG_MAX_SIZE")]
+    log_max_size: u64,
+
+    /// Rotate the log file once it was started this long ago, e.g. `1d`.
+    #[clap(long, value_parser = humantime::parse_duration, env = "SSH_AGENT_MUX_LOG_MAX_AGE")]
+    log_max_age: Option<Duration>,
+
+    /// Number of rotated log files kept, as `<file>.1` to `<file>.<n>`.
+    #[clap(long, default_value_t = 5, env = "SSH_AGENT_MUX_LOG_KEEP")]
+    log_keep: usize,
+
+    /// Inject faults into requests to targets, e.g.
+    /// `seed=42,delay=0.2:500ms,drop=0.05,malformed=0.05`, for testing.
+    #[clap(long, hide = true, env = "SSH_AGENT_MUX_CHAOS")]
+    chaos: Option<Chaos>,
+
+    /// Log every frame of every session in hex and ASCII, without private
+    /// keys, passphrases and PINs, or key blobs but with `=keys`.
+    #[clap(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "redacted", env = "SSH_AGENT_MUX_DUMP_FRAMES")]
+    dump_frames: Option<FrameDump>,
+
+    /// Record the messages of every session to this file, with secrets
+    /// replaced, to replay them with `replay`, e.g. to reproduce a bug.
+    #[clap(long, env = "SSH_AGENT_MUX_RECORD")]
+    record: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    /// Work with the configuration instead of serving.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// List the identities of the mux serving on the host socket, exiting
+    /// with success if it answers.  For container health probes.
+    Healthcheck {
+        /// Fail if the mux takes longer than this to answer.
+        #[clap(long, default_value = "5s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+    },
+    /// Check that the agent on a binding, e.g. a target, answers listing,
+    /// signing with each type of key, the `query` extension and locking as
+    /// agents should, and print a report.
+    TestTarget {
+        /// The agent, e.g. `unix:///run/user/1000/agent.sock`.
+        binding: Binding,
+        /// Fail each check whose request isn't answered within this long.
+        #[clap(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+    },
+    /// Time how long targets take to list their identities, the fastest,
+    /// average and slowest of several requests, for finding the agent making
+    /// SSH slow.
+    Ping {
+        /// Name of the target, or `all`.
+        #[clap(default_value = "all")]
+        target: String,
+        /// Requests made of each target.
+        #[clap(long, default_value_t = 5)]
+        count: u32,
+        /// Fail a target whose request isn't answered within this long.
+        #[clap(long, default_value = "5s", value_parser = humantime::parse_duration)]
+        timeout: Duration,
+    },
+    /// Send the requests of a recording made with `--record` to an agent,
+    /// or through the mux with the configured targets, printing each
+    /// response and whether it differs from the one recorded.
+    Replay {
+        /// The recording.
+        file: PathBuf,
+        /// The agent, e.g. `unix:///run/user/1000/agent.sock`, instead of
+        /// the mux.
+        #[clap(long)]
+        target: Option<Binding>,
+    },
+    /// List how often each key signed and when it last did, from the usage
+    /// file, least recently used first.  Keys of the `keys` table that never
+    /// signed come first.
+    Keys {
+        /// Only list keys that haven't signed for this long.
+        #[clap(long, value_parser = humantime::parse_duration)]
+        unused_for: Option<Duration>,
+        #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Summarize the signatures made and denied by key, target and client,
+    /// from the audit log.  For access reviews.
+    Report {
+        /// Only count events this recent.
+        #[clap(long, default_value = "30d", value_parser = humantime::parse_duration)]
+        since: Duration,
+        #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// List the identities of a hidden target of the mux serving on the host
+    /// socket, and use its keys, for a while.
+    UnlockTarget {
+        /// Name of the target.
+        name: String,
+        /// How long the target stays unlocked.
+        #[clap(long = "for", default_value = "15m", value_parser = humantime::parse_duration)]
+        duration: Duration,
+    },
+    /// List the confirmations the mux serving on the host socket remembers,
+    /// or revoke them.
+    Approvals {
+        /// Revoke those of this key, by fingerprint or nickname, or `all`.
+        #[clap(long, value_name = "KEY")]
+        revoke: Option<String>,
+    },
+    /// Print the status of the mux serving on the host socket: its targets,
+    /// sessions and keys.
+    Status {
+        #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Show the targets of the mux serving on the host socket, their health,
+    /// and the signatures it makes and denies, live, until `q` is pressed.
+    /// Requires `event_socket`.
+    #[cfg(unix)]
+    Top {
+        /// Ask for the status of the targets this often.
+        #[clap(long, default_value = "1s", value_parser = humantime::parse_duration)]
+        refresh: Duration,
+    },
+    /// Work with the views of the mux serving on the host socket.
+    #[command(subcommand)]
+    View(ViewCommand),
+    /// Print the keys the mux lists, from its targets and after its limits,
+    /// e.g. to provision servers with them.
+    Export {
+        #[clap(long, value_enum, default_value_t = ExportFormat::AuthorizedKeys)]
+        format: ExportFormat,
+        /// Precede each key with a comment naming the targets holding it.
+        #[clap(long)]
+        with_targets: bool,
+    },
+    /// Print an ssh_config snippet pointing ssh at the host socket, and at
+    /// each view for the hosts to fill in.
+    GenSshconfig {
+        /// Write the public keys of the mux to this directory, for views
+        /// limiting their keys to name as `IdentityFile`s.
+        #[clap(long)]
+        public_keys: Option<PathBuf>,
+    },
+    /// Exit with success if the view is for the destination host, by its
+    /// `match_hosts`, for `Match exec` rules of ssh_config.  Without a
+    /// view, print the name of the first view for the host.
+    Match {
+        /// The destination's host name, e.g. `%h`.
+        #[clap(long)]
+        host: String,
+        #[clap(long)]
+        view: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum ExportFormat {
+    #[value(name = "authorized_keys")]
+    AuthorizedKeys,
+    Json,
 }
 
-struct KeyIndex {
-    key: KeyData,
-    target_index: usize,
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum FrameDump {
+    Redacted,
+    Keys,
 }
 
-struct MuxAgent {
-    targets: Vec<Box<dyn Session>>,
-    key_target_map: Vec<KeyIndex>,
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
 }
 
-impl MuxAgent {
-    fn new(targets: Vec<Box<dyn Session>>) -> Self {
-        Self {
-            targets,
-            key_target_map: Vec::new(),
+#[derive(Clone, Debug, Subcommand)]
+enum ViewCommand {
+    /// Serve another socket next to the host socket listing and signing
+    /// with only some keys, until the mux exits, and print its path, e.g.
+    /// for a project's direnv config to set `SSH_AUTH_SOCK` to.
+    Create {
+        /// Name of the view, which its socket is named after.
+        name: String,
+        /// Fingerprints of the keys.
+        #[clap(long, num_args = 1.., required = true)]
+        keys: Vec<Fingerprint>,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum ConfigCommand {
+    /// Check the config file and command line together, printing every
+    /// problem found.
+    Check,
+    /// Print a JSON Schema of the config file format, for editors and CI.
+    Schema,
+}
+
+fn main() -> ExitCode {
+    logging::init();
+
+    let mut args = Args::parse();
+    logging::set_level(match (args.quiet, args.verbose) {
+        (true, _) => Some(LevelFilter::Off),
+        (false, 0) => None,
+        (false, 1) => Some(LevelFilter::Info),
+        (false, 2) => Some(LevelFilter::Debug),
+        (false, _) => Some(LevelFilter::Trace),
+    });
+    let result = match args.command.take() {
+        None => run(args),
+        Some(Command::Config(ConfigCommand::Check)) => return check(args),
+        Some(Command::Config(ConfigCommand::Schema)) => {
+            print!("{}", config::SCHEMA);
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::Healthcheck { timeout }) => healthcheck(args, timeout),
+        Some(Command::TestTarget { binding, timeout }) => test_target(&binding, timeout),
+        Some(Command::Ping {
+            target,
+            count,
+            timeout,
+        }) => ping(args, &target, count, timeout),
+        Some(Command::Replay { file, target }) => replay(args, &file, target.as_ref()),
+        Some(Command::Keys { unused_for, format }) => keys(args, unused_for, format),
+        Some(Command::Report { since, format }) => report(args, since, format),
+        Some(Command::UnlockTarget { name, duration }) => unlock_target(args, &name, duration),
+        Some(Command::Approvals { revoke }) => approvals(args, revoke.as_deref()),
+        Some(Command::Status { format }) => status(args, format),
+        #[cfg(unix)]
+        Some(Command::Top { refresh }) => monitor(args, refresh),
+        Some(Command::View(ViewCommand::Create { name, keys })) => create_view(args, &name, &keys),
+        Some(Command::Export {
+            format,
+            with_targets,
+        }) => export(args, format, with_targets),
+        Some(Command::GenSshconfig { public_keys }) => gen_sshconfig(args, public_keys.as_deref()),
+        // Not matching is an answer, not an error, and ssh shows anything
+        // printed to stderr.
+        Some(Command::Match { host, view }) => match match_host(args, &host, view.as_deref()) {
+            Ok(true) => Ok(()),
+            Ok(false) => return ExitCode::FAILURE,
+            Err(e) => Err(e),
+        },
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("ssh-agent-mux: {e}");
+            ExitCode::FAILURE
         }
     }
+}
 
-    fn update_indexes(&mut self, identity_indexes: &[IdentityIndex]) {
-        self.key_target_map = identity_indexes
-            .iter()
-            .map(|identity_index| KeyIndex {
-                target_index: identity_index.target_index,
-                key: identity_index.identity.pubkey.clone(),
-            })
-            .collect();
-    }
-}
-
-#[async_trait]
-impl Session for MuxAgent {
-    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
-        let responses = join_all(
-            self.targets
-                .iter_mut()
-                .map(|target| target.request_identities()),
-        )
-        .await;
-        let responses: Result<Vec<_>, _> = responses.into_iter().collect();
-        let responses = responses?;
-        let identity_indexes: Vec<_> = responses
-            .into_iter()
-            .enumerate()
-            .flat_map(|(target_index, identities)| {
-                identities.into_iter().map(move |identity| IdentityIndex {
-                    identity,
-                    target_index,
-                })
-            })
-            .collect();
-        self.update_indexes(&identity_indexes);
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (number, unit) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size {size:?}; use e.g. 512K, 10M or 1G"))
+}
 
-        let identities = identity_indexes
-            .into_iter()
-            .map(|identity_index| identity_index.identity)
-            .collect();
-        Ok(identities)
+/// Loads the config file, if any, with the command line applied on top.
+fn load_config(args: Args) -> Result<Config, ConfigError> {
+    let mut config = match &args.config {
+        Some(path) => Config::load_profile(path, args.profile.as_deref())?,
+        None => Config::default(),
+    };
+    config.apply_env(std::env::vars())?;
+    for target in args.targets {
+        config.add_target(target);
+    }
+    #[cfg(windows)]
+    if config.add_windows_agent() {
+        log::info!("No targets given; using the Windows OpenSSH agent");
     }
+    config.host = args.host.or(config.host);
+    config.session_idle_timeout = args.session_idle_timeout.or(config.session_idle_timeout);
+    config.max_sessions = args.max_sessions.or(config.max_sessions);
+    config.sandbox.user = args.user.or(config.sandbox.user);
+    config.sandbox.group = args.group.or(config.sandbox.group);
+    config.sandbox.chroot = args.chroot.or(config.sandbox.chroot);
+    config.check_sandbox()?;
+    Ok(config)
+}
 
-    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
-        log::info!("sign request {request:?}");
-        let target_index = self
-            .key_target_map
-            .iter()
-            .find(|key_index| key_index.key == request.pubkey)
-            .unwrap()
-            .target_index;
-        let response = self
-            .targets
-            .get_mut(target_index)
-            .unwrap()
-            .sign(request)
-            .await?;
-        log::info!("sign response {response:?}");
-        Ok(response)
-    }
-
-    async fn extension(&mut self, request: Extension) -> Result<Option<Extension>, AgentError> {
-        log::info!("extension request {request:?}");
-        let response = self
-            .targets
-            .first_mut()
-            .unwrap()
-            .extension(request)
-            .await
-            .unwrap_or(None);
-        log::info!("extension response {response:?}");
-        Ok(response)
-    }
-
-    // async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
-    //     log::info!("handle request {message:?}");
-    //     match message {
-    //         _ => {
-    //             let response = self.targets.first_mut().unwrap().handle(message).await?;
-    //             log::info!("handle response {response:?}");
-    //             Ok(response)
-    //         }
-    //     }
-    // }
-}
-
-struct MuxAgentBind {
-    targets: Vec<Binding>,
+/// Loads the config again, failing what the seccomp filter installed at
+/// startup with `seccomp` and `resolving` would kill the mux for.
+fn reload_config(
+    args: Args,
+    seccomp: Option<SeccompMode>,
+    resolving: bool,
+) -> Result<Config, ConfigError> {
+    let config = load_config(args)?;
+    config.check_seccomp(seccomp, resolving)?;
+    Ok(config)
 }
 
-#[cfg(unix)]
-impl Agent<tokio::net::UnixListener> for MuxAgentBind {
-    fn new_session(&mut self, _socket: &tokio::net::UnixStream) -> impl Session {
-        self.create_new_session()
+fn check(args: Args) -> ExitCode {
+    let problems = match load_config(args) {
+        Ok(config) => config.check(),
+        Err(e) => vec![e.to_string()],
+    };
+    if problems.is_empty() {
+        println!("Configuration OK");
+        return ExitCode::SUCCESS;
     }
+    for problem in problems {
+        println!("{problem}");
+    }
+    ExitCode::FAILURE
 }
 
-impl Agent<tokio::net::TcpListener> for MuxAgentBind {
-    fn new_session(&mut self, _socket: &tokio::net::TcpStream) -> impl Session {
-        self.create_new_session()
+/// The binding the mux of `config` serves on, for admin commands to connect
+/// to.
+fn host_binding(config: &Config) -> Result<Binding, Error> {
+    match config.host.clone().ok_or(Error::NoHost)? {
+        Host::Binding(host) => Ok(host),
+        Host::Stdio => Err(Error::StdioHost),
     }
 }
 
-#[cfg(windows)]
-impl Agent<ssh_agent_lib::agent::NamedPipeListener> for MuxAgentBind {
-    fn new_session(
-        &mut self,
-        _socket: &tokio::net::windows::named_pipe::NamedPipeServer,
-    ) -> impl Session {
-        self.create_new_session()
+/// Runs `future` on a runtime of its own, for commands that talk to a mux or
+/// targets.
+fn block_on<F: std::future::Future>(future: F) -> Result<F::Output, Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::Runtime)?;
+    Ok(runtime.block_on(future))
+}
+
+fn healthcheck(args: Args, timeout: Duration) -> Result<(), Error> {
+    let host = host_binding(&load_config(args)?)?;
+    let identities = block_on(healthcheck::probe(&host, timeout))??;
+    println!("healthy, {identities} identities");
+    Ok(())
+}
+
+fn test_target(binding: &Binding, timeout: Duration) -> Result<(), Error> {
+    let report = block_on(conformance::test_target(binding, timeout))??;
+    print!("{report}");
+    match report.failures() {
+        0 => Ok(()),
+        failed => Err(Error::Nonconformant {
+            target: binding.clone(),
+            failed,
+        }),
+    }
+}
+
+fn ping(args: Args, name: &str, count: u32, timeout: Duration) -> Result<(), Error> {
+    let mut targets = ping::targets(&load_config(args)?);
+    if name != "all" {
+        targets.retain(|target| target.name == name);
+        if targets.is_empty() {
+            return Err(Error::UnknownTarget(name.to_owned()));
+        }
+    }
+    let width = targets
+        .iter()
+        .map(|target| target.name.len())
+        .max()
+        .unwrap_or_default();
+    let failed = block_on(async {
+        let mut failed = 0;
+        for target in &targets {
+            let name = &target.name;
+            match ping::ping(target, count, timeout).await {
+                Ok(latency) => println!(
+                    "{name:<width$}  min {:.1?}, avg {:.1?}, max {:.1?}, {} identities",
+                    latency.min, latency.avg, latency.max, latency.identities
+                ),
+                Err(e) => {
+                    failed += 1;
+                    println!("{name:<width$}  failed: {e}");
+                }
+            }
+        }
+        failed
+    })?;
+    match failed {
+        0 => Ok(()),
+        failed => Err(Error::Unanswered(failed)),
     }
 }
 
-impl MuxAgentBind {
-    fn create_new_session(&mut self) -> impl Session {
-        let targets = self
-            .targets
+fn replay(args: Args, path: &Path, target: Option<&Binding>) -> Result<(), Error> {
+    let recording = std::fs::read_to_string(path).map_err(|source| Error::Recording {
+        path: path.to_owned(),
+        source,
+    })?;
+    let recording =
+        record::Recording::parse(&recording).map_err(|source| Error::InvalidRecording {
+            path: path.to_owned(),
+            source,
+        })?;
+    let replayed = match target {
+        Some(target) => block_on(record::replay(
+            &recording,
+            record::Destination::Agent(target),
+        ))??,
+        None => {
+            let config = load_config(args)?;
+            logging::set_nicknames(&config.keys);
+            let mut mux = MuxAgentBind::new(&config);
+            block_on(record::replay(
+                &recording,
+                record::Destination::Mux(&mut mux),
+            ))??
+        }
+    };
+    for replayed in &replayed {
+        println!("{replayed}");
+    }
+    println!(
+        "{} of {} responses differ from the recording",
+        replayed
             .iter()
-            .map(|target| connect(target.clone().try_into().unwrap()).unwrap())
-            .collect();
-        MuxAgent::new(targets)
+            .filter(|replayed| replayed.differs())
+            .count(),
+        replayed.len()
+    );
+    Ok(())
+}
+
+fn unlock_target(args: Args, name: &str, duration: Duration) -> Result<(), Error> {
+    let host = host_binding(&load_config(args)?)?;
+    block_on(unlock::unlock_target(&host, name, duration))??;
+    println!(
+        "{name} unlocked for {}",
+        humantime::format_duration(duration)
+    );
+    Ok(())
+}
+
+fn approvals(args: Args, revoke: Option<&str>) -> Result<(), Error> {
+    let config = load_config(args)?;
+    logging::set_nicknames(&config.keys);
+    let host = host_binding(&config)?;
+    match revoke {
+        Some(key) => {
+            let key = if key == "all" { "" } else { key };
+            let revoked = block_on(approval::revoke_approvals(&host, key))??;
+            println!("Revoked {} approvals", revoked.len());
+        }
+        None => {
+            let approvals = block_on(approval::list_approvals(&host))??;
+            if approvals.is_empty() {
+                println!("No approvals");
+            }
+            for approval in approvals {
+                println!("{approval}");
+            }
+        }
     }
+    Ok(())
 }
 
-#[derive(Debug, Parser)]
-struct Args {
-    /// Target SSH agent to which we will proxy all requests.
-    #[clap(long="target", num_args=1..)]
-    targets: Vec<Binding>,
+fn status(args: Args, format: OutputFormat) -> Result<(), Error> {
+    let host = host_binding(&load_config(args)?)?;
+    let json = matches!(format, OutputFormat::Json);
+    println!("{}", block_on(status::status(&host, json))??);
+    Ok(())
+}
 
-    /// Source that we will bind to.
-    #[clap(long)]
-    host: Binding,
+#[cfg(unix)]
+fn monitor(args: Args, refresh: Duration) -> Result<(), Error> {
+    let config = load_config(args)?;
+    let host = host_binding(&config)?;
+    let event_socket = config.event_socket.ok_or(Error::NoEventSocket)?;
+    block_on(top::top(&host, &event_socket, refresh))?
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+fn create_view(args: Args, name: &str, keys: &[Fingerprint]) -> Result<(), Error> {
+    let host = host_binding(&load_config(args)?)?;
+    println!("{}", block_on(view::create_view(&host, name, keys))??);
+    Ok(())
+}
 
-    let args = Args::parse();
+/// Labels 1Password keys from the running mux's listing, narrowed down to
+/// those of 1Password targets by the key cache if there is one.  Keys are
+/// left unlabeled, with a warning, if no mux is running on a socket.
+fn learn_labels(config: &Config) -> Result<(), Error> {
+    let cached = match &config.key_cache {
+        Some(path) => match key_cache::read(path) {
+            Ok(cached) => Some(cached),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(source) => {
+                return Err(Error::KeyCache {
+                    path: path.clone(),
+                    source,
+                })
+            }
+        },
+        None => None,
+    };
+    let host = match host_binding(config) {
+        Ok(host) => host,
+        Err(e) => {
+            log::warn!("Not labeling 1Password keys: {e}");
+            return Ok(());
+        }
+    };
+    let targets = ping::targets(config);
+    if let Err(e) = block_on(onepassword::learn_from_mux(
+        &host,
+        &targets,
+        cached.as_ref(),
+    ))? {
+        log::warn!("Failed to list the keys of the mux to label them: {e}");
+    }
+    Ok(())
+}
+
+fn keys(args: Args, unused_for: Option<Duration>, format: OutputFormat) -> Result<(), Error> {
+    let config = load_config(args)?;
+    logging::set_nicknames(&config.keys);
+    if let Some(path) = &config.onepassword_agent_toml {
+        onepassword::set_agent_toml(Some(path))?;
+        learn_labels(&config)?;
+    }
+    let path = config.usage_file.ok_or(Error::NoUsageFile)?;
+    let mut usage: Vec<_> = metrics::read_key_usage(&path)
+        .map_err(|source| Error::UsageFile {
+            path: path.clone(),
+            source,
+        })?
+        .into_iter()
+        .map(|(fingerprint, usage)| (fingerprint, Some(usage)))
+        .collect();
+    for key in &config.keys {
+        let fingerprint = key.fingerprint.to_string();
+        if !usage.iter().any(|(used, _)| *used == fingerprint) {
+            usage.push((fingerprint, None));
+        }
+    }
+    usage.sort_by_key(|(_, usage)| usage.map(|usage| usage.last_used));
+    let now = SystemTime::now();
+    usage.retain(|(_, usage)| {
+        usage.is_none_or(|usage| {
+            let last_used = UNIX_EPOCH + Duration::from_secs(usage.last_used);
+            unused_for.is_none_or(|unused_for| last_used + unused_for <= now)
+        })
+    });
+    if let OutputFormat::Json = format {
+        println!("{}", export::key_usage_json(&usage));
+        return Ok(());
+    }
+    for (fingerprint, usage) in usage {
+        let fingerprint = logging::Fingerprint(&fingerprint);
+        let Some(usage) = usage else {
+            println!("{fingerprint} never used");
+            continue;
+        };
+        println!(
+            "{fingerprint} {} signatures, last used {}",
+            usage.signatures,
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(usage.last_used))
+        );
+    }
+    Ok(())
+}
+
+fn report(args: Args, since: Duration, format: OutputFormat) -> Result<(), Error> {
+    let config = load_config(args)?;
+    logging::set_nicknames(&config.keys);
+    let path = config.audit_log.ok_or(Error::NoAuditLog)?;
+    let log = std::fs::read_to_string(&path).map_err(|source| Error::AuditLog {
+        path: path.clone(),
+        source,
+    })?;
+    let since = SystemTime::now()
+        .checked_sub(since)
+        .unwrap_or(UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let report =
+        Report::new(&log, since).map_err(|source| Error::InvalidAuditLog { path, source })?;
+    match format {
+        OutputFormat::Text => print!("{report}"),
+        OutputFormat::Json => println!("{}", report.to_json()),
+    }
+    Ok(())
+}
+
+fn export(args: Args, format: ExportFormat, with_targets: bool) -> Result<(), Error> {
+    let config = load_config(args)?;
+    logging::set_nicknames(&config.keys);
+    onepassword::set_agent_toml(config.onepassword_agent_toml.as_deref())?;
+    let identities =
+        block_on(MuxAgentBind::new(&config).list_identities())?.map_err(Error::Export)?;
+    match format {
+        ExportFormat::AuthorizedKeys => {
+            for (identity, targets) in identities {
+                if with_targets {
+                    println!("# {}", targets.join(", "));
+                }
+                println!(
+                    "{}",
+                    PublicKey::new(identity.pubkey, identity.comment).to_string()
+                );
+            }
+        }
+        ExportFormat::Json => println!("{}", export::identities_json(&identities)),
+    }
+    Ok(())
+}
+
+fn match_host(args: Args, host: &str, view: Option<&str>) -> Result<bool, Error> {
+    let config = load_config(args)?;
+    let Some(name) = view else {
+        let view = config.views.iter().find(|view| view.matches_host(host));
+        if let Some(view) = view {
+            println!("{}", view.name);
+        }
+        return Ok(view.is_some());
+    };
+    let view = config
+        .views
+        .iter()
+        .find(|view| view.name == name)
+        .ok_or_else(|| Error::UnknownView(name.to_owned()))?;
+    Ok(view.matches_host(host))
+}
 
-    bind(
-        args.host.try_into()?,
-        MuxAgentBind {
-            targets: args.targets,
+fn gen_sshconfig(args: Args, public_keys: Option<&Path>) -> Result<(), Error> {
+    let config = load_config(args)?;
+    let host = host_binding(&config)?;
+    let key_files = match public_keys {
+        Some(dir) => {
+            let identities =
+                block_on(MuxAgentBind::new(&config).list_identities())?.map_err(Error::Export)?;
+            sshconfig::write_public_keys(dir, identities).map_err(|source| Error::PublicKeys {
+                path: dir.to_owned(),
+                source,
+            })?
+        }
+        None => Vec::new(),
+    };
+    print!("{}", sshconfig::generate(&host, &config.views, &key_files));
+    Ok(())
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    if let Some(path) = &args.log_file {
+        let rotation = logging::Rotation {
+            max_size: args.log_max_size,
+            max_age: args.log_max_age,
+            keep: args.log_keep,
+        };
+        logging::log_to_file(path, rotation).map_err(|source| Error::LogFile {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    let config_path = args.config.clone();
+    let log_file = args.log_file.clone();
+    if let Some(chaos) = &args.chaos {
+        log::warn!("Injecting faults into requests to targets: {chaos}");
+        chaos::install(chaos);
+    }
+    if let Some(dump) = args.dump_frames {
+        frames::dump_frames(matches!(dump, FrameDump::Keys));
+    }
+    if let Some(path) = &args.record {
+        record::start(path).map_err(|source| Error::Recording {
+            path: path.clone(),
+            source,
+        })?;
+        log::warn!(
+            "Recording the messages of every session to {}",
+            path.display()
+        );
+    }
+    let reload_args = args.clone();
+    let mut config = load_config(args)?;
+    // What the seccomp filter is set up for, which reloads can't change.
+    let seccomp = config.sandbox.seccomp;
+    let resolving = config
+        .targets
+        .iter()
+        .any(|target| target.tcp_name.is_some())
+        || config.webhook.is_some();
+    config.check_seccomp(seccomp, resolving)?;
+    logging::set_redact(config.redact_logs);
+    logging::set_levels(&config.log_levels);
+    logging::set_nicknames(&config.keys);
+    onepassword::set_agent_toml(config.onepassword_agent_toml.as_deref())?;
+    if let Some(path) = &config.usage_file {
+        metrics::persist_key_usage(path).map_err(|source| Error::UsageFile {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    if let Some(path) = &config.key_cache {
+        key_cache::open(path).map_err(|source| Error::KeyCache {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    if let Some(path) = &config.audit_log {
+        audit::open_log(path).map_err(|source| Error::AuditLog {
+            path: path.clone(),
+            source,
+        })?;
+    }
+    askpass::open_terminal(config.confirm_prompt).map_err(Error::Terminal)?;
+    if let Some(window) = config.notification_digest {
+        audit::set_digest_window(window);
+    }
+    alert::set_thresholds(&config.alerts);
+    if let Some(webhook) = &config.webhook {
+        webhook::install(Webhook::new(webhook).map_err(Error::Webhook)?);
+    }
+    plugin::install(config.loaded_plugins.clone());
+    let problems = config.check_bindings();
+    if !problems.is_empty() {
+        return Err(Error::Bindings(problems));
+    }
+    let host = config.host.take().ok_or(Error::NoHost)?;
+    let options = ServeOptions::from(&config);
+
+    let listener = match &host {
+        Host::Binding(binding) => Some(
+            label::bind(&config.socket_label, binding, || binding.clone().try_into()).map_err(
+                |source| Error::Bind {
+                    binding: binding.clone(),
+                    source,
+                },
+            )?,
+        ),
+        Host::Stdio => None,
+    };
+    let view_listeners = config
+        .views
+        .iter()
+        .map(|view| {
+            let listener = label::bind(&config.socket_label, &view.host, || {
+                view.host.clone().try_into()
+            })
+            .map_err(|source| Error::Bind {
+                binding: view.host.clone(),
+                source,
+            })?;
+            Ok((view.name.clone(), listener))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    // A mux serving stdio is stopped by its client closing the session.
+    #[cfg(unix)]
+    let signals = listener
+        .is_some()
+        .then(|| signal::catch(&Signal::ALL))
+        .transpose()
+        .map_err(Error::Signals)?;
+    #[cfg(unix)]
+    let event_listener = config
+        .event_socket
+        .as_deref()
+        .map(|path| {
+            std::os::unix::net::UnixListener::bind(path).map_err(|source| Error::BindEvents {
+                path: path.to_owned(),
+                source,
+            })
+        })
+        .transpose()?;
+    #[cfg(not(unix))]
+    if config.event_socket.is_some() {
+        log::warn!("The event socket is only supported on Unix; not streaming events");
+    }
+    let admin_listener = config
+        .admin_http
+        .clone()
+        .map(|binding| {
+            service_binding::Listener::try_from(binding.clone())
+                .map_err(|source| Error::BindAdmin { binding, source })
+        })
+        .transpose()?;
+    #[cfg(unix)]
+    let grpc_listener = config
+        .admin_grpc
+        .as_deref()
+        .map(|path| {
+            std::os::unix::net::UnixListener::bind(path).map_err(|source| Error::BindAdminGrpc {
+                path: path.to_owned(),
+                source,
+            })
+        })
+        .transpose()?;
+    #[cfg(not(unix))]
+    if config.admin_grpc.is_some() {
+        log::warn!("The gRPC admin service is only supported on Unix; not serving it");
+    }
+    let admin_sockets: Vec<_> = match &config.admin_http {
+        Some(Binding::FilePath(path)) => Some(path.as_path()),
+        _ => None,
+    }
+    .into_iter()
+    .chain(config.admin_grpc.as_deref())
+    .collect();
+    // Connected before the sandbox is applied, which may hide the bus.
+    #[cfg(unix)]
+    let bus = config
+        .dbus_signals
+        .then(dbus::Bus::session)
+        .transpose()
+        .map_err(Error::DBus)?;
+    #[cfg(not(unix))]
+    if config.dbus_signals {
+        log::warn!("D-Bus signals are only supported on Unix; not emitting them");
+    }
+    // Passed the environment of gpg-agent targets.
+    let gpg_agent_sockets: Vec<_> = config
+        .targets
+        .iter()
+        .filter_map(TargetConfig::gpg_agent_socket)
+        .map(Binding::FilePath)
+        .collect();
+    sandbox::apply(
+        &config.sandbox,
+        &sandbox::Paths {
+            host: match &host {
+                Host::Binding(binding) => Some(binding),
+                Host::Stdio => None,
+            },
+            view_hosts: config.views.iter().map(|view| &view.host).collect(),
+            targets: config
+                .targets
+                .iter()
+                .map(|target| &target.binding)
+                .chain(&gpg_agent_sockets)
+                .collect(),
+            target_dirs: config
+                .target_dirs
+                .iter()
+                .map(|dir| dir.dir.as_path())
+                .collect(),
+            config: config_path.as_deref(),
+            agent_toml: config.onepassword_agent_toml.as_deref(),
+            event_socket: config.event_socket.as_deref(),
+            admin_sockets: admin_sockets.clone(),
+            usage_file: config.usage_file.as_deref(),
+            key_cache: config.key_cache.as_deref(),
+            log_file: log_file.as_deref(),
+            resolves_names: resolving,
         },
     )
-    .await?;
+    .map_err(Error::Sandbox)?;
+
+    let runtime = tokio::runtime::Runtime::new().map_err(Error::Runtime)?;
+    let created_views = Arc::new(Mutex::new(Vec::new()));
+    // Cancelled once the host stops serving, for the views to stop with it.
+    let shutdown = CancellationToken::new();
+    let views = TaskTracker::new();
+    let agent = match &host {
+        Host::Binding(Binding::FilePath(path)) => {
+            MuxAgentBind::new(&config).with_view_creator(view_creator(
+                path,
+                reload_args.clone(),
+                options.clone(),
+                created_views.clone(),
+                shutdown.clone(),
+                views.clone(),
+            ))
+        }
+        _ => MuxAgentBind::new(&config),
+    };
+    #[cfg(unix)]
+    if let Some(event_listener) = event_listener {
+        runtime.spawn(async move {
+            if let Err(e) = events::serve(event_listener).await {
+                log::error!("Failed to stream events: {e}");
+            }
+        });
+    }
+    if let Some(admin_listener) = admin_listener {
+        let reload_args = reload_args.clone();
+        let serving = admin::serve(admin_listener, agent.state(), move || {
+            reload_config(reload_args.clone(), seccomp, resolving)
+        });
+        runtime.spawn(async move {
+            if let Err(e) = serving.await {
+                log::error!("Failed to serve the admin endpoint: {e}");
+            }
+        });
+    }
+    #[cfg(unix)]
+    if let Some(grpc_listener) = grpc_listener {
+        let reload_args = reload_args.clone();
+        let serving = admin::grpc::serve(grpc_listener, agent.state(), move || {
+            reload_config(reload_args.clone(), seccomp, resolving)
+        });
+        runtime.spawn(async move {
+            if let Err(e) = serving.await {
+                log::error!("Failed to serve the gRPC admin service: {e}");
+            }
+        });
+    }
+    for (name, listener) in view_listeners {
+        let view = agent
+            .view(&config, &name)
+            .expect("views are bound from the config");
+        let serving = serve_until(
+            listener,
+            view,
+            options.clone(),
+            shutdown.clone().cancelled_owned(),
+        );
+        views.spawn_on(
+            async move {
+                if let Err(e) = serving.await {
+                    log::error!("Failed to serve view {name}: {e}");
+                }
+            },
+            runtime.handle(),
+        );
+    }
+    #[cfg(unix)]
+    if let Some(bus) = bus {
+        runtime.spawn(async move {
+            if let Err(e) = dbus::emit_signals(bus).await {
+                log::error!("Failed to emit D-Bus signals: {e}");
+            }
+        });
+    }
+    match listener {
+        Some(listener) => {
+            #[cfg(unix)]
+            let signalled = handle_signals(
+                signals.expect("caught when listening"),
+                agent.state(),
+                move || reload_config(reload_args.clone(), seccomp, resolving),
+            );
+            #[cfg(not(unix))]
+            let signalled = futures::future::pending();
+            let stopped = async {
+                signalled.await;
+                shutdown.cancel();
+            };
+            runtime.block_on(serve_until(listener, agent, options, stopped))?;
+            views.close();
+            runtime.block_on(views.wait());
+            if let Host::Binding(Binding::FilePath(path)) = &host {
+                remove_socket(path, &config);
+            }
+        }
+        None => {
+            runtime.block_on(serve_stdio(agent, options))?;
+            shutdown.cancel();
+            views.close();
+            runtime.block_on(views.wait());
+            // Reading stdin blocks a thread that the runtime would otherwise
+            // wait for if the session ended before the client closed it.
+            runtime.shutdown_background();
+        }
+    }
+    #[cfg(unix)]
+    if let Some(path) = &config.event_socket {
+        remove_socket(path, &config);
+    }
+    for path in admin_sockets {
+        remove_socket(path, &config);
+    }
+    for view in &config.views {
+        if let Binding::FilePath(path) = &view.host {
+            remove_socket(path, &config);
+        }
+    }
+    for path in created_views.lock().unwrap().iter() {
+        remove_socket(path, &config);
+    }
 
     Ok(())
 }
+
+/// Serves the views clients create next to the host socket at `host`, named
+/// after it and the view, e.g. `mux-deploy.sock` for `mux.sock`, on `views`
+/// until `shutdown`, adding their paths to `created` for removal on exit.
+fn view_creator(
+    host: &Path,
+    args: Args,
+    options: ServeOptions,
+    created: Arc<Mutex<Vec<PathBuf>>>,
+    shutdown: CancellationToken,
+    views: TaskTracker,
+) -> Arc<ViewCreator> {
+    let host = host.to_owned();
+    Arc::new(move |mux, name, keys| {
+        let mut config = load_config(args.clone())?;
+        let stem = host.file_stem().unwrap_or_default().to_string_lossy();
+        let path = host.with_file_name(format!("{stem}-{name}.sock"));
+        let binding = Binding::FilePath(path.clone());
+        let listener = label::bind(&config.socket_label, &binding, || {
+            binding.clone().try_into()
+        })
+        .map_err(|source| Error::Bind {
+            binding: binding.clone(),
+            source,
+        })?;
+        created.lock().unwrap().push(path.clone());
+        config.views.push(ViewConfig {
+            name: name.to_owned(),
+            host: binding,
+            targets: Vec::new(),
+            keys,
+            match_hosts: Vec::new(),
+        });
+        let view = mux.view(&config, name).expect("added to the config");
+        let serving = serve_until(
+            listener,
+            view,
+            options.clone(),
+            shutdown.clone().cancelled_owned(),
+        );
+        let name = name.to_owned();
+        views.spawn(async move {
+            if let Err(e) = serving.await {
+                log::error!("Failed to serve view {name}: {e}");
+            }
+        });
+        Ok(path.display().to_string())
+    })
+}
+
+/// Removes a socket the mux bound, unless the sandbox took away its path.
+fn remove_socket(path: &Path, config: &Config) {
+    if config.sandbox.chroot.is_some() || config.sandbox.capsicum {
+        log::info!("Leaving {} in place outside the sandbox", path.display());
+        return;
+    }
+    if let Err(e) = std::fs::remove_file(path) {
+        log::warn!("Failed to remove {}: {e}", path.display());
+    }
+}
+
+/// Logs the state on SIGUSR1, toggles debug logging on SIGUSR2 and reloads
+/// the config with `reload` on SIGHUP.  Completes on the first SIGINT or
+/// SIGTERM.
+#[cfg(unix)]
+async fn handle_signals(
+    signals: signal::Signals,
+    state: MuxState,
+    reload: impl Fn() -> Result<Config, ConfigError>,
+) {
+    let mut receiver = match signals.receiver() {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            log::error!("Failed to receive signals: {e}");
+            return futures::future::pending().await;
+        }
+    };
+    loop {
+        match receiver.recv().await {
+            Ok(Signal::User1) => log::info!("State:\n{state}"),
+            // Logged while debug logging is on, so that both show.
+            Ok(Signal::User2) if logging::debug() => {
+                log::info!("Debug logging off");
+                logging::set_debug(false);
+            }
+            Ok(Signal::User2) => {
+                logging::set_debug(true);
+                log::info!("Debug logging on");
+            }
+            Ok(Signal::Hangup) => match reload() {
+                Ok(config) => {
+                    log::info!("Reloading the config");
+                    logging::set_levels(&config.log_levels);
+                    logging::set_nicknames(&config.keys);
+                    if let Err(e) =
+                        onepassword::set_agent_toml(config.onepassword_agent_toml.as_deref())
+                    {
+                        log::error!("Failed to reload 1Password's agent.toml: {e}");
+                    }
+                    state.reload(&config);
+                }
+                Err(e) => log::error!("Failed to reload the config, keeping the current one: {e}"),
+            },
+            Ok(signal) => {
+                log::info!("Received {signal:?}");
+                return;
+            }
+            Err(e) => {
+                log::error!("Failed to receive signals: {e}");
+                return futures::future::pending().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Request counters by message type, and signature counters by key.
+//!
+//! With a usage file, the signature counters are kept across restarts in
+//! it, one key per line: its SHA-256 fingerprint, the number of signatures
+//! made with it and when it was last used, in seconds since the Unix epoch.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ssh_key::{public::KeyData, HashAlg};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counts {
+    pub handled: u64,
+    pub failed: u64,
+}
+
+static REQUESTS: Mutex<BTreeMap<&'static str, Counts>> = Mutex::new(BTreeMap::new());
+
+/// Signatures made with a key.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyUsage {
+    pub signatures: u64,
+    /// When the key last signed, in seconds since the Unix epoch.
+    pub last_used: u64,
+}
+
+/// Usage by key fingerprint.
+static KEYS: Mutex<BTreeMap<String, KeyUsage>> = Mutex::new(BTreeMap::new());
+
+static USAGE_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Counts a handled request of type `kind`.
+pub fn record(kind: &'static str, ok: bool) {
+    let mut requests = REQUESTS.lock().unwrap();
+    let counts = requests.entry(kind).or_default();
+    counts.handled += 1;
+    if !ok {
+        counts.failed += 1;
+    }
+    log::trace!(
+        "{kind} requests: {} handled, {} failed",
+        counts.handled,
+        counts.failed
+    );
+}
+
+/// Counts a signature made with `key`.
+pub(crate) fn record_key_use(key: &KeyData) {
+    let last_used = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut keys = KEYS.lock().unwrap();
+    let usage = keys
+        .entry(key.fingerprint(HashAlg::Sha256).to_string())
+        .or_default();
+    usage.signatures += 1;
+    usage.last_used = last_used;
+    if let Some(path) = USAGE_FILE.get() {
+        // Written while holding the lock, so that writes land in order.
+        if let Err(e) = write_key_usage(path, &keys) {
+            log::warn!("Failed to write {}: {e}", path.display());
+        }
+    }
+}
+
+/// Usage of the keys that signed, by fingerprint.
+pub fn key_usage() -> BTreeMap<String, KeyUsage> {
+    KEYS.lock().unwrap().clone()
+}
+
+/// Keeps key usage in `path` from now on, counting on from what it holds
+/// if it exists.
+pub fn persist_key_usage(path: &Path) -> io::Result<()> {
+    let stored = match read_key_usage(path) {
+        Ok(stored) => stored,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e),
+    };
+    let mut keys = KEYS.lock().unwrap();
+    for (fingerprint, usage) in stored {
+        let counted = keys.entry(fingerprint).or_default();
+        counted.signatures += usage.signatures;
+        counted.last_used = counted.last_used.max(usage.last_used);
+    }
+    let _ = USAGE_FILE.set(path.to_owned());
+    Ok(())
+}
+
+/// Reads the key usage kept in `path`.
+pub fn read_key_usage(path: &Path) -> io::Result<BTreeMap<String, KeyUsage>> {
+    let mut keys = BTreeMap::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let usage = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(fingerprint), Some(signatures), Some(last_used), None) => {
+                signatures.parse().ok().zip(last_used.parse().ok()).map(
+                    |(signatures, last_used)| {
+                        (
+                            fingerprint.to_owned(),
+                            KeyUsage {
+                                signatures,
+                                last_used,
+                            },
+                        )
+                    },
+                )
+            }
+            _ => None,
+        };
+        let Some((fingerprint, usage)) = usage else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "line {}: expected a fingerprint and two numbers",
+                    number + 1
+                ),
+            ));
+        };
+        keys.insert(fingerprint, usage);
+    }
+    Ok(keys)
+}
+
+/// Replaces `path` with `keys`, through a temporary file so that it is
+/// never left half written.
+fn write_key_usage(path: &Path, keys: &BTreeMap<String, KeyUsage>) -> io::Result<()> {
+    let mut contents = String::from("# fingerprint signatures last_used\n");
+    for (fingerprint, usage) in keys {
+        contents += &format!("{fingerprint} {} {}\n", usage.signatures, usage.last_used);
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = fs::File::create(&temporary)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
@@ -0,0 +1,2059 @@
+//! The multiplexing agent: fans requests out to targets and routes the
+//! answers back.
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::future::{join_all, select_ok};
+use serde::Serialize;
+use ssh_agent_lib::{
+    async_trait,
+    error::AgentError,
+    proto::{
+        extension::{
+            DestinationConstraint, HostTuple, KeyConstraintExtension, KeySpec, MessageExtension,
+            RestrictDestination, SessionBind,
+        },
+        AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Credential, Extension,
+        Identity, KeyConstraint, RemoveIdentity, Request, Response, SignRequest, SmartcardKey,
+    },
+    ssh_encoding::Encode,
+};
+use ssh_key::{public::KeyData, Fingerprint, HashAlg, Signature};
+use zeroize::Zeroizing;
+
+use crate::anomaly::{Anomaly, Baseline};
+use crate::approval::{Approval, ApprovalCache, Approvals, ListApprovals, RevokeApprovals};
+use crate::approver::{self, Approver, Program};
+use crate::config::{
+    AddConstraints, AddressAction, AddressRule, Anomalies, Config, Destination, DuplicateKeys,
+    Enumeration, KeyType, RouteConfig, TargetConfig, TargetDirConfig, ViewConfig,
+};
+use crate::error::Error;
+use crate::plugin::Plugin;
+use crate::policy::{self, Denial, Policy};
+use crate::script::{self, Decision, Script};
+#[cfg(unix)]
+use crate::serve::PeerCredentials;
+use crate::serve::{Agent, Handler, Stdio};
+use crate::status::{self, Status};
+use crate::unlock::UnlockTarget;
+use crate::upstream::{self, Target, Upstream};
+use crate::view::{self, CreateView};
+use crate::{askpass, audit, key_cache, logging, metrics};
+
+/// Wait before the first retry of a sign request, doubled for each one after.
+const FIRST_SIGN_BACKOFF: Duration = Duration::from_millis(100);
+
+struct IdentityIndex {
+    identity: Identity,
+    /// Targets holding the key, most preferred first.
+    target_indexes: Vec<usize>,
+}
+
+struct KeyIndex {
+    key: KeyData,
+    /// The targets themselves rather than their positions, which change as
+    /// targets are reloaded while requests listing keys are in flight.
+    targets: Vec<Arc<Target>>,
+}
+
+/// Which targets hold which keys, as of the session last listing them.
+#[derive(Default)]
+struct KeyMap {
+    keys: Vec<KeyIndex>,
+    updated_at: Option<Instant>,
+}
+
+/// The state of a session, which outlives its requests.
+struct SessionState {
+    id: u64,
+    /// Who the client is, for audit events: the user of a Unix socket
+    /// client, or the address of a TCP one.
+    client: Option<String>,
+    /// The session's connections to the targets.
+    upstreams: std::sync::Mutex<Upstreams>,
+    /// Held while connecting to targets that were added, so that concurrent
+    /// requests connect to each once.
+    connecting: tokio::sync::Mutex<()>,
+    key_map: std::sync::Mutex<KeyMap>,
+    /// Host keys the client bound the session to with
+    /// `session-bind@openssh.com`, one per hop.
+    bound_hosts: std::sync::Mutex<Vec<KeyData>>,
+}
+
+/// Connections to the targets of a generation of the [`TargetSet`].
+struct Upstreams {
+    generation: u64,
+    targets: Arc<Vec<Upstream>>,
+}
+
+impl SessionState {
+    fn new(id: u64, client: Option<String>) -> Self {
+        Self {
+            id,
+            client,
+            upstreams: std::sync::Mutex::new(Upstreams {
+                generation: 0,
+                targets: Default::default(),
+            }),
+            connecting: Default::default(),
+            key_map: Default::default(),
+            bound_hosts: Default::default(),
+        }
+    }
+
+    /// The connections to the current targets.  Those to targets that are
+    /// gone are dropped, closing them once requests in flight on them are
+    /// answered, and targets that were added are connected to.
+    async fn upstreams(&self, target_set: &TargetSet) -> Arc<Vec<Upstream>> {
+        let _connecting = self.connecting.lock().await;
+        let (generation, current) = target_set.get_with_generation();
+        let previous = {
+            let upstreams = self.upstreams.lock().unwrap();
+            if upstreams.generation == generation {
+                return upstreams.targets.clone();
+            }
+            upstreams.targets.clone()
+        };
+        // A session carries on without targets that can't be reached, so
+        // that one broken agent doesn't take down the others.
+        let mut targets = Vec::with_capacity(current.len());
+        for target in current {
+            if let Some(upstream) = previous
+                .iter()
+                .find(|upstream| Arc::ptr_eq(&upstream.target, &target))
+            {
+                targets.push(upstream.clone());
+                continue;
+            }
+            match Upstream::connect(target).await {
+                Ok(upstream) => targets.push(upstream),
+                Err(e) => log::error!("{e}"),
+            }
+        }
+        self.remap_keys(&previous, &targets);
+        let mut upstreams = self.upstreams.lock().unwrap();
+        *upstreams = Upstreams {
+            generation,
+            targets: Arc::new(targets),
+        };
+        upstreams.targets.clone()
+    }
+
+    /// Drops the connections to targets that are gone, closing them once
+    /// requests in flight on them are answered.  Targets that were added are
+    /// connected to by the next request.
+    fn drop_gone_upstreams(&self, target_set: &TargetSet) {
+        let current = target_set.get();
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let previous = upstreams.targets.clone();
+        let targets: Vec<_> = previous
+            .iter()
+            .filter(|upstream| {
+                current
+                    .iter()
+                    .any(|target| Arc::ptr_eq(&upstream.target, target))
+            })
+            .cloned()
+            .collect();
+        if targets.len() == previous.len() {
+            return;
+        }
+        self.remap_keys(&previous, &targets);
+        upstreams.targets = Arc::new(targets);
+    }
+
+    /// Moves the key map from the `previous` targets to `targets`.  Keys
+    /// only held by targets that are gone are forgotten, and all are if
+    /// targets were added, as they may hold any of them.
+    fn remap_keys(&self, previous: &[Upstream], targets: &[Upstream]) {
+        let current = |target: &Arc<Target>| {
+            targets
+                .iter()
+                .any(|upstream| Arc::ptr_eq(&upstream.target, target))
+        };
+        let mut key_map = self.key_map.lock().unwrap();
+        if targets.iter().any(|upstream| {
+            !previous
+                .iter()
+                .any(|other| Arc::ptr_eq(&other.target, &upstream.target))
+        }) {
+            *key_map = KeyMap::default();
+            return;
+        }
+        for key_index in &mut key_map.keys {
+            key_index.targets.retain(current);
+        }
+        key_map
+            .keys
+            .retain(|key_index| !key_index.targets.is_empty());
+    }
+}
+
+/// A client session.
+///
+/// Pipelined requests are handled concurrently.  Each target connection
+/// takes one request at a time, in the order they were received, so only
+/// requests for different targets overlap.
+struct Session {
+    state: Arc<SessionState>,
+    shared: Arc<Shared>,
+    /// The address of a TCP client whose signatures need confirmation.
+    confirm_for: Option<IpAddr>,
+}
+
+/// A request of a session, with the targets and settings as they were when
+/// it was received, so that reloading the config doesn't affect requests in
+/// flight.
+struct MuxAgent {
+    targets: Arc<Vec<Upstream>>,
+    state: Arc<SessionState>,
+    settings: Arc<Settings>,
+    shared: Arc<Shared>,
+    confirm_for: Option<IpAddr>,
+}
+
+/// Configuration shared by all sessions, replaced when it is reloaded.
+struct Settings {
+    policy: Policy,
+    duplicate_keys: DuplicateKeys,
+    routes: Vec<RouteConfig>,
+    script: Option<Arc<Script>>,
+    plugins: Vec<Arc<Plugin>>,
+    add_constraints: AddConstraints,
+    max_identities: Option<usize>,
+    /// Further attempts at a sign request that failed to reach its target.
+    sign_retries: u32,
+    client_addresses: Vec<AddressRule>,
+    /// Decoy identities listed after the others.
+    canaries: Vec<Identity>,
+    anomalies: Anomalies,
+    /// How long confirmations are remembered.
+    approval_cache: Option<Duration>,
+    /// Keys listed and signed with, in a view.  Empty for all.
+    view_keys: Vec<Fingerprint>,
+}
+
+impl Settings {
+    fn new(config: &Config, view: Option<&ViewConfig>) -> Self {
+        for route in &config.routes {
+            if !config
+                .targets
+                .iter()
+                .any(|target| target.name == route.target)
+                && !config
+                    .target_dirs
+                    .iter()
+                    .any(|dir| dir.contains(&route.target))
+            {
+                log::warn!(
+                    "Key {} is routed to unknown target {}",
+                    route.fingerprint,
+                    route.target
+                );
+            }
+        }
+        Self {
+            policy: Policy::new(config.keys.clone()),
+            duplicate_keys: config.duplicate_keys,
+            routes: config.routes.clone(),
+            script: config.script.clone(),
+            plugins: config.loaded_plugins.clone(),
+            add_constraints: config.add_constraints.clone(),
+            max_identities: config.max_identities,
+            sign_retries: config.sign_retries,
+            client_addresses: config.client_addresses.clone(),
+            canaries: config
+                .canary_keys
+                .iter()
+                .map(|key| Identity {
+                    pubkey: key.key_data().clone(),
+                    comment: key.comment().to_owned(),
+                })
+                .collect(),
+            anomalies: config.anomalies,
+            approval_cache: config.approval_cache,
+            view_keys: view.map(|view| view.keys.clone()).unwrap_or_default(),
+        }
+    }
+
+    /// Whether `key` is listed and signed with, rather than left out of
+    /// the view.
+    fn serves_key(&self, key: &KeyData) -> bool {
+        self.view_keys.is_empty()
+            || self
+                .view_keys
+                .iter()
+                .any(|fingerprint| key.fingerprint(fingerprint.algorithm()) == *fingerprint)
+    }
+}
+
+/// The targets, settings and state shared by all sessions.
+struct Shared {
+    targets: TargetSet,
+    settings: RwLock<Arc<Settings>>,
+    /// Sessions that were created, for state dumps and reloads.  Ended ones
+    /// are pruned as new ones are added.
+    sessions: std::sync::Mutex<Vec<Weak<SessionState>>>,
+    /// Keys last listed to a client, to tell when they change.
+    listed_keys: std::sync::Mutex<Option<Vec<KeyData>>>,
+    /// How each key was used, to tell anomalies by.
+    baseline: Baseline,
+    approvals: ApprovalCache,
+    /// Asked for the confirmations policy requires.
+    approver: Arc<dyn Approver>,
+    /// The views served along with the whole mux, reloaded with it.
+    views: std::sync::Mutex<Vec<Arc<Shared>>>,
+    /// Serves the views clients create, if they can.
+    view_creator: Option<Arc<ViewCreator>>,
+}
+
+/// Serves a view created by a client, named and of the keys given, with the
+/// agent of the whole mux, answering where, e.g. the path of its socket for
+/// `SSH_AUTH_SOCK`.  The view itself is made with [`MuxAgentBind::view`] of a
+/// config the view is added to.
+pub type ViewCreator =
+    dyn Fn(&MuxAgentBind, &str, Vec<Fingerprint>) -> Result<String, Error> + Send + Sync;
+
+impl Shared {
+    fn new(config: &Config, view: Option<&ViewConfig>, approver: Arc<dyn Approver>) -> Self {
+        Self {
+            targets: TargetSet::new(config, view),
+            settings: RwLock::new(Arc::new(Settings::new(config, view))),
+            sessions: Default::default(),
+            listed_keys: Default::default(),
+            baseline: Default::default(),
+            approvals: Default::default(),
+            approver,
+            views: Default::default(),
+            view_creator: None,
+        }
+    }
+
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// See [`MuxState::reload`].
+    fn reload(&self, config: &Config) {
+        let current_view = self.targets.view();
+        let view = current_view
+            .as_ref()
+            .map(|view| config.view(&view.name).unwrap_or(view));
+        let mut settings = Settings::new(config, view);
+        let mut current = self.settings.write().unwrap();
+        settings.policy.take_signatures(&current.policy);
+        *current = Arc::new(settings);
+        drop(current);
+        self.targets.reconfigure(config, view);
+        self.disconnect_gone_targets();
+        for view in self.views.lock().unwrap().iter() {
+            view.reload(config);
+        }
+    }
+
+    /// Closes every session's connections to targets that are gone, rather
+    /// than waiting for its next request.
+    fn disconnect_gone_targets(&self) {
+        for session in self.live_sessions() {
+            session.drop_gone_upstreams(&self.targets);
+        }
+    }
+
+    fn live_sessions(&self) -> Vec<Arc<SessionState>> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect()
+    }
+}
+
+impl Session {
+    fn new(
+        id: u64,
+        shared: Arc<Shared>,
+        client: Option<String>,
+        confirm_for: Option<IpAddr>,
+    ) -> Self {
+        let state = Arc::new(SessionState::new(id, client));
+        let mut sessions = shared.sessions.lock().unwrap();
+        sessions.retain(|session| session.strong_count() > 0);
+        sessions.push(Arc::downgrade(&state));
+        drop(sessions);
+        Self {
+            state,
+            shared,
+            confirm_for,
+        }
+    }
+}
+
+impl MuxAgent {
+    /// Indexes the identities of all targets, in target order, ordering the
+    /// targets of keys held by more than one according to `duplicate_keys`.
+    fn index_identities(&self, responses: Vec<Vec<Identity>>) -> Result<Vec<IdentityIndex>, Error> {
+        let mut identity_indexes: Vec<IdentityIndex> = Vec::new();
+        for (target_index, identities) in responses.into_iter().enumerate() {
+            for identity in identities {
+                let Some(existing) = identity_indexes
+                    .iter_mut()
+                    .find(|existing| existing.identity.pubkey == identity.pubkey)
+                else {
+                    identity_indexes.push(IdentityIndex {
+                        identity,
+                        target_indexes: vec![target_index],
+                    });
+                    continue;
+                };
+                if existing.target_indexes.contains(&target_index) {
+                    continue;
+                }
+                match self.settings.duplicate_keys {
+                    DuplicateKeys::First | DuplicateKeys::Latency | DuplicateKeys::Race => {
+                        existing.target_indexes.push(target_index)
+                    }
+                    DuplicateKeys::Priority => {
+                        // Ties keep the configured order.
+                        let position = existing
+                            .target_indexes
+                            .iter()
+                            .position(|&other| self.priority(other) < self.priority(target_index))
+                            .unwrap_or(existing.target_indexes.len());
+                        if position == 0 {
+                            existing.identity = identity;
+                        }
+                        existing.target_indexes.insert(position, target_index);
+                    }
+                    DuplicateKeys::Error => {
+                        return Err(Error::DuplicateKey {
+                            fingerprint: identity.pubkey.fingerprint(HashAlg::Sha256),
+                            first: self.targets[existing.target_indexes[0]].name().to_owned(),
+                            second: self.targets[target_index].name().to_owned(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(identity_indexes)
+    }
+
+    fn priority(&self, target_index: usize) -> i64 {
+        self.targets[target_index].target.config.priority
+    }
+
+    fn update_indexes(&self, identity_indexes: &[IdentityIndex]) {
+        *self.state.key_map.lock().unwrap() = KeyMap {
+            keys: identity_indexes
+                .iter()
+                .map(|identity_index| KeyIndex {
+                    targets: identity_index
+                        .target_indexes
+                        .iter()
+                        .filter_map(|&index| self.targets.get(index))
+                        .map(|upstream| upstream.target.clone())
+                        .collect(),
+                    key: identity_index.identity.pubkey.clone(),
+                })
+                .collect(),
+            updated_at: Some(Instant::now()),
+        };
+        // Views list only some targets, which would drop the others.
+        if self.shared.targets.is_view() {
+            return;
+        }
+        key_cache::update(
+            identity_indexes
+                .iter()
+                .map(|identity_index| {
+                    let targets = identity_index
+                        .target_indexes
+                        .iter()
+                        .filter_map(|&target_index| self.targets.get(target_index))
+                        .map(|target| target.name().to_owned())
+                        .collect();
+                    (identity_index.identity.pubkey.clone(), targets)
+                })
+                .collect(),
+        );
+    }
+
+    /// The indexes of the targets the cache file says hold `key`, if any
+    /// of them are among this session's.
+    fn cached_key(&self, key: &KeyData) -> Option<Vec<usize>> {
+        let target_indexes: Vec<_> = key_cache::targets(key)?
+            .iter()
+            .filter_map(|name| self.targets.iter().position(|target| target.name() == name))
+            .collect();
+        (!target_indexes.is_empty()).then_some(target_indexes)
+    }
+
+    /// The indexes of the targets the session last listed holding `key`,
+    /// if any of them are among this request's.
+    fn find_key(&self, key: &KeyData) -> Option<Vec<usize>> {
+        let key_map = self.state.key_map.lock().unwrap();
+        let key_index = key_map
+            .keys
+            .iter()
+            .find(|key_index| key_index.key == *key)?;
+        let target_indexes: Vec<_> = key_index
+            .targets
+            .iter()
+            .filter_map(|target| {
+                self.targets
+                    .iter()
+                    .position(|upstream| Arc::ptr_eq(&upstream.target, target))
+            })
+            .collect();
+        (!target_indexes.is_empty()).then_some(target_indexes)
+    }
+
+    /// The targets holding `key`, as last listed in the session, or else as
+    /// the cache file says, or else as listed now, healthy ones first, each
+    /// most preferred first.  With `duplicate_keys = "latency"`, the fastest are preferred,
+    /// starting with those yet to sign.
+    async fn targets_for(&self, key: &KeyData) -> Result<Vec<&Upstream>, AgentError> {
+        let target_indexes = match self.find_key(key).or_else(|| self.cached_key(key)) {
+            Some(target_indexes) => target_indexes,
+            None => {
+                // Clients like `ssh-add -d` don't list identities first.
+                self.request_identities().await?;
+                match self.find_key(key) {
+                    Some(target_indexes) => target_indexes,
+                    None => self
+                        .find_lazy_key(key)
+                        .await?
+                        .ok_or_else(|| Error::UnknownKey(key.fingerprint(HashAlg::Sha256)))?,
+                }
+            }
+        };
+        let mut targets: Vec<_> = target_indexes
+            .into_iter()
+            .filter_map(|target_index| self.targets.get(target_index))
+            .filter(|target| !target.target.is_hidden())
+            .collect();
+        if targets.is_empty() {
+            // Held only by targets hidden since they were listed.
+            return Err(Error::UnknownKey(key.fingerprint(HashAlg::Sha256)).into());
+        }
+        if let DuplicateKeys::Latency = self.settings.duplicate_keys {
+            targets.sort_by_key(|target| target.target.sign_latency());
+        }
+        targets.sort_by_key(|target| !target.target.is_healthy());
+        Ok(targets)
+    }
+
+    /// Lists the lazy targets as well as the eager ones to find `key`, which
+    /// they didn't list, if there are any.
+    async fn find_lazy_key(&self, key: &KeyData) -> Result<Option<Vec<usize>>, AgentError> {
+        if !self
+            .targets
+            .iter()
+            .any(|target| target.target.config.enumeration == Enumeration::Lazy)
+        {
+            return Ok(None);
+        }
+        let identity_indexes = self.index_identities(self.enumerate(true).await?)?;
+        self.update_indexes(&identity_indexes);
+        Ok(self.find_key(key))
+    }
+
+    /// The preferred target holding `key`.
+    async fn target_for(&self, key: &KeyData) -> Result<&Upstream, AgentError> {
+        Ok(self.targets_for(key).await?[0])
+    }
+
+    /// The target `key` is routed to for signing, if any.
+    fn routed_target(&self, key: &KeyData) -> Result<Option<&Upstream>, Error> {
+        let Some(route) = self
+            .settings
+            .routes
+            .iter()
+            .find(|route| key.fingerprint(route.fingerprint.algorithm()) == route.fingerprint)
+        else {
+            return Ok(None);
+        };
+        self.targets
+            .iter()
+            .find(|target| target.name() == route.target && !target.target.is_hidden())
+            .map(Some)
+            .ok_or_else(|| Error::RouteUnavailable {
+                fingerprint: route.fingerprint,
+                target: route.target.clone(),
+            })
+    }
+
+    /// Replaces `targets` with those the route script picks for `request`,
+    /// if there is a script.
+    fn script_targets<'a>(
+        &'a self,
+        request: &SignRequest,
+        targets: &mut Vec<&'a Upstream>,
+    ) -> Result<(), Denial> {
+        let Some(script) = &self.settings.script else {
+            return Ok(());
+        };
+        let (weekday, minute) = policy::local_time();
+        let bound_hosts = self
+            .state
+            .bound_hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|host_key| {
+                script::Value::String(host_key.fingerprint(HashAlg::Sha256).to_string())
+            })
+            .collect();
+        let fields = vec![
+            (
+                "key",
+                script::Value::String(request.pubkey.fingerprint(HashAlg::Sha256).to_string()),
+            ),
+            (
+                "algorithm",
+                script::Value::String(request.pubkey.algorithm().to_string()),
+            ),
+            ("bound_hosts", script::Value::Array(bound_hosts)),
+            ("flags", script::Value::Int(request.flags.into())),
+            ("weekday", script::Value::Int(weekday.into())),
+            ("hour", script::Value::Int((minute / 60).into())),
+            ("minute", script::Value::Int((minute % 60).into())),
+        ];
+        let names: Vec<_> = targets.iter().map(|target| target.name()).collect();
+        let decision = script
+            .route(script::Value::Map(fields), &names)
+            .unwrap_or_else(|e| {
+                log::warn!("Route script failed: {e}");
+                Decision::Deny
+            });
+        self.decide_targets(decision, Denial::Script, targets)
+    }
+
+    /// Replaces `targets` with those each plugin that routes picks for
+    /// `request`, in turn.
+    fn plugin_targets<'a>(
+        &'a self,
+        request: &SignRequest,
+        targets: &mut Vec<&'a Upstream>,
+    ) -> Result<(), Denial> {
+        for plugin in &self.settings.plugins {
+            let json = self.approver_request(request, targets, &[]).to_json();
+            let decision = plugin.route(&json).unwrap_or_else(|e| {
+                log::warn!("Plugin {} failed: {e}", plugin.path().display());
+                Decision::Deny
+            });
+            self.decide_targets(decision, Denial::Plugin, targets)?;
+        }
+        Ok(())
+    }
+
+    /// Applies `decision` to `targets`, denying with `denial` when it
+    /// denies or picks no or unknown targets.
+    fn decide_targets<'a>(
+        &'a self,
+        decision: Decision,
+        denial: Denial,
+        targets: &mut Vec<&'a Upstream>,
+    ) -> Result<(), Denial> {
+        let names = match decision {
+            Decision::Default => return Ok(()),
+            Decision::Deny => return Err(denial),
+            Decision::Targets(names) => names,
+        };
+        let mut picked = Vec::with_capacity(names.len());
+        for name in names {
+            let Some(target) = self
+                .targets
+                .iter()
+                .find(|target| target.name() == name && !target.target.is_hidden())
+            else {
+                log::warn!("Unknown target {name} picked, denying: {denial}");
+                return Err(denial);
+            };
+            picked.push(target);
+        }
+        if picked.is_empty() {
+            return Err(denial);
+        }
+        *targets = picked;
+        Ok(())
+    }
+
+    /// The target for requests that aren't tied to a key.
+    fn default_target(&self) -> Result<&Upstream, Error> {
+        self.targets.first().ok_or(Error::NoTargets)
+    }
+    async fn request_identities(&self) -> Result<Vec<Identity>, AgentError> {
+        let identity_indexes = self.index_identities(self.enumerate(false).await?)?;
+        self.update_indexes(&identity_indexes);
+        let identities = self.listed_identities(identity_indexes);
+        self.record_listing(&identities);
+        Ok(identities)
+    }
+
+    /// The identities of each target, by target index: those of the eager
+    /// targets, and of the lazy ones too if `lazy`, or else those of the
+    /// lazy ones if the eager ones list none, or else those of the
+    /// `fallback` ones.
+    async fn enumerate(&self, lazy: bool) -> Result<Vec<Vec<Identity>>, AgentError> {
+        let mut responses = self
+            .list_targets(|config| {
+                !config.fallback && (lazy || config.enumeration == Enumeration::Eager)
+            })
+            .await?;
+        if !lazy && responses.iter().all(Vec::is_empty) {
+            responses = self
+                .list_targets(|config| !config.fallback && config.enumeration == Enumeration::Lazy)
+                .await?;
+        }
+        if responses.iter().all(Vec::is_empty) {
+            responses = self.list_targets(|config| config.fallback).await?;
+        }
+        Ok(responses)
+    }
+
+    /// The identities of each target, by target index, listing only those
+    /// `listed` picks.
+    async fn list_targets(
+        &self,
+        listed: impl Fn(&TargetConfig) -> bool,
+    ) -> Result<Vec<Vec<Identity>>, AgentError> {
+        let listed = &listed;
+        let responses = join_all(self.targets.iter().map(|target| async move {
+            if !listed(&target.target.config) || target.target.is_hidden() {
+                return Ok(Vec::new());
+            }
+            let mut identities = target.request_identities().await?;
+            identities.retain(|identity| self.settings.serves_key(&identity.pubkey));
+            Ok(identities)
+        }))
+        .await;
+        responses.into_iter().collect()
+    }
+
+    /// Records an audit event if the listed identities changed.
+    fn record_listing(&self, identities: &[Identity]) {
+        let keys: Vec<_> = identities
+            .iter()
+            .map(|identity| identity.pubkey.clone())
+            .collect();
+        let mut listed_keys = self.shared.listed_keys.lock().unwrap();
+        if listed_keys.as_ref() != Some(&keys) {
+            *listed_keys = Some(keys);
+            drop(listed_keys);
+            audit::record(audit::Event::IdentitiesChanged {
+                count: identities.len(),
+            });
+        }
+    }
+
+    /// The identities to list, leaving out those beyond the per-target and
+    /// overall caps.  Unlisted keys can still be signed with.
+    fn listed_identities(&self, identity_indexes: Vec<IdentityIndex>) -> Vec<Identity> {
+        let mut counts = vec![0; self.targets.len()];
+        let mut listed: Vec<_> = identity_indexes
+            .into_iter()
+            .filter(|identity_index| {
+                let target_index = identity_index.target_indexes[0];
+                counts[target_index] += 1;
+                self.targets[target_index]
+                    .target
+                    .config
+                    .max_identities
+                    .is_none_or(|max| counts[target_index] <= max)
+            })
+            .collect();
+
+        if let Some(max) = self
+            .settings
+            .max_identities
+            .filter(|&max| listed.len() > max)
+        {
+            // Keep the identities of the highest priority targets, in order.
+            let mut by_priority: Vec<_> = (0..listed.len()).collect();
+            by_priority.sort_by_key(|&i| Reverse(self.priority(listed[i].target_indexes[0])));
+            let mut kept = vec![false; listed.len()];
+            for &i in &by_priority[..max] {
+                kept[i] = true;
+            }
+            let mut kept = kept.into_iter();
+            listed.retain(|_| kept.next().unwrap());
+        }
+
+        listed
+            .into_iter()
+            .map(|identity_index| identity_index.identity)
+            .collect()
+    }
+
+    async fn sign(&self, request: SignRequest) -> Result<Signature, AgentError> {
+        log::info!("sign request {}", logging::Message(&request));
+        if self
+            .settings
+            .canaries
+            .iter()
+            .any(|canary| canary.pubkey == request.pubkey)
+        {
+            audit::record(audit::Event::CanaryUsed {
+                key: &request.pubkey,
+                client: self.state.client.as_deref(),
+            });
+            return Err(AgentError::Failure);
+        }
+        if !self.settings.serves_key(&request.pubkey) {
+            return Err(Error::UnknownKey(request.pubkey.fingerprint(HashAlg::Sha256)).into());
+        }
+        let mut targets = match self.routed_target(&request.pubkey)? {
+            Some(target) => vec![target],
+            None => self.targets_for(&request.pubkey).await?,
+        };
+        // Given back unless the signature is made.
+        let reservation = match self
+            .script_targets(&request, &mut targets)
+            .and_then(|()| self.plugin_targets(&request, &mut targets))
+            .and_then(|()| self.check_destinations(&mut targets))
+            .and_then(|()| {
+                let bound_hosts = self.state.bound_hosts.lock().unwrap();
+                self.settings
+                    .policy
+                    .check_sign(&request.pubkey, &bound_hosts)
+            }) {
+            Ok(reservation) => reservation,
+            Err(denial) => {
+                audit::record(audit::Event::SignDenied {
+                    key: &request.pubkey,
+                    denial: &denial,
+                    client: self.state.client.as_deref(),
+                });
+                return Err(AgentError::Failure);
+            }
+        };
+        let anomalies = match self.settings.anomalies {
+            Anomalies::Ignore => Vec::new(),
+            Anomalies::Warn | Anomalies::Confirm => {
+                let bound_hosts = self.state.bound_hosts.lock().unwrap();
+                self.shared.baseline.check(&request.pubkey, &bound_hosts)
+            }
+        };
+        for anomaly in &anomalies {
+            audit::record(audit::Event::Anomaly {
+                key: &request.pubkey,
+                anomaly,
+                client: self.state.client.as_deref(),
+            });
+        }
+        let escalated = self.settings.anomalies == Anomalies::Confirm && !anomalies.is_empty();
+        if (self.confirm_for.is_some() || escalated)
+            && !self
+                .confirm(self.approver_request(&request, &targets, &anomalies))
+                .await
+        {
+            audit::record(audit::Event::SignDenied {
+                key: &request.pubkey,
+                denial: &Denial::NotConfirmed,
+                client: self.state.client.as_deref(),
+            });
+            return Err(AgentError::Failure);
+        }
+        if let Some(program) = self.settings.policy.approver(&request.pubkey) {
+            let approval = self.approver_request(&request, &targets, &[]);
+            if Program(program.to_owned()).decide(&approval).await == approver::Decision::Deny {
+                audit::record(audit::Event::SignDenied {
+                    key: &request.pubkey,
+                    denial: &Denial::NotApproved,
+                    client: self.state.client.as_deref(),
+                });
+                return Err(AgentError::Failure);
+            }
+        }
+        if let DuplicateKeys::Race = self.settings.duplicate_keys {
+            let healthy = targets
+                .iter()
+                .take_while(|target| target.target.is_healthy())
+                .count();
+            if healthy > 1 {
+                match self.race_sign(&targets[..healthy], &request).await {
+                    Err(e) if upstream::is_transport_error(&e) && healthy < targets.len() => {
+                        log::warn!(
+                            "Failed to sign with any healthy target, trying the others: {e}"
+                        );
+                        targets.drain(..healthy);
+                    }
+                    result => return result.inspect(|_| reservation.keep()),
+                }
+            }
+        }
+        let (last, fallbacks) = targets.split_last().expect("a key has a target");
+        for target in fallbacks {
+            log::info!("sign request routed to target {}", target.name());
+            match self.sign_with(target, request.clone()).await {
+                Ok(response) => {
+                    log::info!("sign response {}", logging::Message(&response));
+                    self.record_use(&request.pubkey, target);
+                    reservation.keep();
+                    return Ok(response);
+                }
+                Err(e) if upstream::is_transport_error(&e) => {
+                    log::warn!(
+                        "Failed to sign with target {}, trying the next: {e}",
+                        target.name()
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        log::info!("sign request routed to target {}", last.name());
+        let response = self.sign_with(last, request.clone()).await?;
+        log::info!("sign response {}", logging::Message(&response));
+        self.record_use(&request.pubkey, last);
+        reservation.keep();
+        Ok(response)
+    }
+
+    /// Records a signature made with `key` on `target`, and adds it to the
+    /// key's baseline.
+    fn record_use(&self, key: &KeyData, target: &Upstream) {
+        audit::record(audit::Event::KeyUsed {
+            key,
+            target: target.name(),
+            client: self.state.client.as_deref(),
+        });
+        let bound_hosts = self.state.bound_hosts.lock().unwrap();
+        self.shared.baseline.learn(key, &bound_hosts);
+    }
+
+    /// What approvers are told of `request`, to be signed on `targets`,
+    /// asked about for `anomalies`, if any.
+    fn approver_request(
+        &self,
+        request: &SignRequest,
+        targets: &[&Upstream],
+        anomalies: &[Anomaly],
+    ) -> approver::Request {
+        approver::Request {
+            key: request.pubkey.clone(),
+            targets: targets
+                .iter()
+                .map(|target| target.name().to_owned())
+                .collect(),
+            bound_hosts: self.state.bound_hosts.lock().unwrap().clone(),
+            client: self.state.client.clone(),
+            reasons: anomalies.iter().map(ToString::to_string).collect(),
+            data: request.data.clone(),
+            flags: request.flags,
+        }
+    }
+
+    /// Asks the mux's approver whether to sign `request`, unless it was
+    /// confirmed for the same key, client and bound hosts recently enough.
+    async fn confirm(&self, request: approver::Request) -> bool {
+        let client = request.client.as_deref();
+        if self
+            .shared
+            .approvals
+            .approved(&request.key, client, &request.bound_hosts)
+        {
+            log::info!("sign request confirmed earlier");
+            return true;
+        }
+        if self.shared.approver.decide(&request).await == approver::Decision::Deny {
+            return false;
+        }
+        if let Some(duration) = self.settings.approval_cache {
+            self.shared
+                .approvals
+                .approve(&request.key, client, &request.bound_hosts, duration);
+        }
+        true
+    }
+
+    /// Leaves out the targets whose `destinations` don't include every host
+    /// the session is bound to, failing if none are left.  Unbound sessions
+    /// are taken for local use, as OpenSSH's agent does.
+    fn check_destinations(&self, targets: &mut Vec<&Upstream>) -> Result<(), Denial> {
+        let Some(&first) = targets.first() else {
+            return Ok(());
+        };
+        let bound_hosts = self.state.bound_hosts.lock().unwrap();
+        targets.retain(|target| {
+            bound_hosts
+                .iter()
+                .all(|host_key| allows(&target.target.config.destinations, host_key))
+        });
+        if !targets.is_empty() {
+            return Ok(());
+        }
+        let host_key = bound_hosts
+            .iter()
+            .find(|host_key| !allows(&first.target.config.destinations, host_key))
+            .expect("a bound host isn't allowed");
+        Err(Denial::Destination {
+            host_key: host_key.fingerprint(HashAlg::Sha256),
+        })
+    }
+
+    /// Signs with `target`, retrying up to `sign_retries` times, with
+    /// backoff, while the request fails to reach it.  Refusals aren't
+    /// retried.
+    async fn sign_with(
+        &self,
+        target: &Upstream,
+        request: SignRequest,
+    ) -> Result<Signature, AgentError> {
+        let mut backoff = FIRST_SIGN_BACKOFF;
+        for _ in 0..self.settings.sign_retries {
+            match target.sign(request.clone()).await {
+                Err(e) if upstream::is_transport_error(&e) => {
+                    log::warn!(
+                        "Failed to sign with target {}, retrying in {backoff:?}: {e}",
+                        target.name()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        target.sign(request).await
+    }
+
+    /// Signs with all of `targets` at once, answering with the first
+    /// signature and abandoning the other requests.  Fails with the last
+    /// error if all of them fail.
+    async fn race_sign(
+        &self,
+        targets: &[&Upstream],
+        request: &SignRequest,
+    ) -> Result<Signature, AgentError> {
+        let attempts = targets.iter().map(|&target| {
+            log::info!("sign request raced to target {}", target.name());
+            Box::pin(async move {
+                let response = self.sign_with(target, request.clone()).await?;
+                Ok::<_, AgentError>((response, target))
+            })
+        });
+        let ((response, target), _abandoned) = select_ok(attempts).await?;
+        log::info!(
+            "sign response {} from target {}",
+            logging::Message(&response),
+            target.name()
+        );
+        self.record_use(&request.pubkey, target);
+        Ok(response)
+    }
+
+    /// The first target that takes keys of the type of `credential`.
+    fn add_target(&self, credential: &Credential) -> Result<&Upstream, Error> {
+        let algorithm = match credential {
+            Credential::Key { privkey, .. } => privkey.algorithm().ok(),
+            Credential::Cert { algorithm, .. } => Some(algorithm.clone()),
+        };
+        let key_type = algorithm.as_ref().and_then(KeyType::of);
+        if self.targets.is_empty() {
+            return Err(Error::NoTargets);
+        }
+        self.targets
+            .iter()
+            .find(|target| {
+                let key_types = &target.target.config.add_key_types;
+                key_types.is_empty()
+                    || key_type.is_some_and(|key_type| key_types.contains(&key_type))
+            })
+            .ok_or_else(|| {
+                Error::NoTargetForKeyType(match (key_type, algorithm) {
+                    (Some(key_type), _) => key_type.to_string(),
+                    (None, Some(algorithm)) => algorithm.to_string(),
+                    (None, None) => "unknown".to_owned(),
+                })
+            })
+    }
+
+    /// Fails requests that would reach keys a view of some keys doesn't
+    /// serve, like adding keys to or locking its targets.
+    fn beyond_view(&self) -> Result<(), Error> {
+        match self.settings.view_keys.is_empty() {
+            true => Ok(()),
+            false => Err(Error::BeyondView),
+        }
+    }
+
+    async fn add_identity(&self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.beyond_view()?;
+        let target = self.add_target(&identity.credential)?;
+        if !self.settings.add_constraints.is_empty()
+            || !target.target.restricted_destinations().is_empty()
+        {
+            return self
+                .add_identity_constrained(AddIdentityConstrained {
+                    identity,
+                    constraints: Vec::new(),
+                })
+                .await;
+        }
+        log::info!("add identity routed to target {}", target.name());
+        target.add_identity(identity).await
+    }
+
+    async fn add_identity_constrained(
+        &self,
+        mut identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.beyond_view()?;
+        add_constraints(&self.settings.add_constraints, &mut identity.constraints);
+        let target = self.add_target(&identity.identity.credential)?;
+        add_destinations(
+            target.target.restricted_destinations(),
+            &mut identity.constraints,
+        )?;
+        log::info!(
+            "add constrained identity routed to target {}",
+            target.name()
+        );
+        target.add_identity_constrained(identity).await
+    }
+
+    async fn remove_identity(&self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        if !self.settings.serves_key(&identity.pubkey) {
+            return Err(Error::UnknownKey(identity.pubkey.fingerprint(HashAlg::Sha256)).into());
+        }
+        let target = self.target_for(&identity.pubkey).await?;
+        log::info!("remove identity routed to target {}", target.name());
+        target.remove_identity(identity).await
+    }
+
+    async fn remove_all_identities(&self) -> Result<(), AgentError> {
+        log::info!("remove all identities request");
+        if !self.settings.view_keys.is_empty() {
+            // Only the keys of the view, from each target holding them.
+            let identity_indexes = self.index_identities(self.enumerate(true).await?)?;
+            let responses = join_all(identity_indexes.iter().flat_map(|identity_index| {
+                identity_index.target_indexes.iter().map(|&target_index| {
+                    self.targets[target_index].remove_identity(RemoveIdentity {
+                        pubkey: identity_index.identity.pubkey.clone(),
+                    })
+                })
+            }))
+            .await;
+            return responses.into_iter().collect();
+        }
+        let responses = join_all(
+            self.targets
+                .iter()
+                .map(|target| target.remove_all_identities()),
+        )
+        .await;
+        responses.into_iter().collect()
+    }
+
+    async fn add_smartcard_key(&self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.beyond_view()?;
+        let target = self.default_target()?;
+        if !self.settings.add_constraints.is_empty()
+            || !target.target.restricted_destinations().is_empty()
+        {
+            return self
+                .add_smartcard_key_constrained(AddSmartcardKeyConstrained {
+                    key,
+                    constraints: Vec::new(),
+                })
+                .await;
+        }
+        log::info!("add smartcard key routed to target {}", target.name());
+        target.add_smartcard_key(key).await
+    }
+
+    async fn add_smartcard_key_constrained(
+        &self,
+        mut key: AddSmartcardKeyConstrained,
+    ) -> Result<(), AgentError> {
+        self.beyond_view()?;
+        add_constraints(&self.settings.add_constraints, &mut key.constraints);
+        let target = self.default_target()?;
+        add_destinations(
+            target.target.restricted_destinations(),
+            &mut key.constraints,
+        )?;
+        log::info!(
+            "add constrained smartcard key routed to target {}",
+            target.name()
+        );
+        target.add_smartcard_key_constrained(key).await
+    }
+
+    async fn remove_smartcard_key(&self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.beyond_view()?;
+        let target = self.default_target()?;
+        log::info!("remove smartcard key routed to target {}", target.name());
+        target.remove_smartcard_key(key).await
+    }
+
+    async fn lock(&self, passphrase: String) -> Result<(), AgentError> {
+        let passphrase = Zeroizing::new(passphrase);
+        self.beyond_view()?;
+        log::info!("lock request");
+        // Each target is sent a copy of its own, scrubbed once sent.
+        let responses = join_all(
+            self.targets
+                .iter()
+                .map(|target| target.lock(Zeroizing::new(passphrase.to_string()))),
+        )
+        .await;
+        responses.into_iter().collect()
+    }
+
+    async fn unlock(&self, passphrase: String) -> Result<(), AgentError> {
+        let passphrase = Zeroizing::new(passphrase);
+        self.beyond_view()?;
+        log::info!("unlock request");
+        // Each target is sent a copy of its own, scrubbed once sent.
+        let responses = join_all(
+            self.targets
+                .iter()
+                .map(|target| target.unlock(Zeroizing::new(passphrase.to_string()))),
+        )
+        .await;
+        responses.into_iter().collect()
+    }
+
+    /// Unlocks a hidden target, unless the session is bound to a host, as
+    /// a forwarded agent is.
+    fn unlock_target(&self, unlock: UnlockTarget) -> Result<(), Error> {
+        if !self.state.bound_hosts.lock().unwrap().is_empty() {
+            return Err(Error::UnlockBound);
+        }
+        let target = self
+            .targets
+            .iter()
+            .find(|target| target.name() == unlock.target)
+            .ok_or_else(|| Error::UnknownTarget(unlock.target.clone()))?;
+        unlock_hidden(&target.target, unlock.duration)
+    }
+
+    /// Answers a request named `name` for the approvals, unless the session
+    /// is bound to a host, as a forwarded agent is.
+    fn approvals(
+        &self,
+        name: String,
+        answer: impl FnOnce(&ApprovalCache) -> Vec<Approval>,
+    ) -> Result<Option<Extension>, AgentError> {
+        if !self.state.bound_hosts.lock().unwrap().is_empty() {
+            return Err(Error::ApprovalsBound.into());
+        }
+        let mut details = Vec::new();
+        Approvals(answer(&self.shared.approvals))
+            .encode(&mut details)
+            .map_err(AgentError::other)?;
+        Ok(Some(Extension {
+            name,
+            details: details.into(),
+        }))
+    }
+
+    /// Answers a request named `name` for the status, unless the session is
+    /// bound to a host, as a forwarded agent is.
+    async fn status(&self, name: String, status: Status) -> Result<Option<Extension>, AgentError> {
+        if !self.state.bound_hosts.lock().unwrap().is_empty() {
+            return Err(Error::StatusBound.into());
+        }
+        let state = MuxState {
+            shared: self.shared.clone(),
+        };
+        let answer = match status.format.as_str() {
+            "text" => state.to_string(),
+            "json" => state.status_json(),
+            _ => return Err(AgentError::Failure),
+        };
+        let mut details = Vec::new();
+        answer.encode(&mut details).map_err(AgentError::other)?;
+        Ok(Some(Extension {
+            name,
+            details: details.into(),
+        }))
+    }
+
+    /// Answers a request named `name` to create a view with where it is
+    /// served, unless the session is bound to a host, as a forwarded agent
+    /// is, or is one of a view, whose clients aren't to see other keys.
+    fn create_view(
+        &self,
+        name: String,
+        create: CreateView,
+    ) -> Result<Option<Extension>, AgentError> {
+        if !self.state.bound_hosts.lock().unwrap().is_empty() {
+            return Err(Error::CreateViewBound.into());
+        }
+        if self.shared.targets.view().is_some() {
+            return Err(Error::CreateViewInView.into());
+        }
+        let creator = self
+            .shared
+            .view_creator
+            .as_ref()
+            .ok_or(Error::NoViewCreation)?;
+        if !view::valid_name(&create.name) {
+            return Err(Error::InvalidViewName(create.name).into());
+        }
+        let exists = self.shared.views.lock().unwrap().iter().any(|view| {
+            view.targets
+                .view()
+                .is_some_and(|view| view.name == create.name)
+        });
+        if exists {
+            return Err(Error::ViewExists(create.name).into());
+        }
+        let keys = create
+            .keys
+            .iter()
+            .map(|key| key.parse::<Fingerprint>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(AgentError::other)?;
+        let mux = MuxAgentBind {
+            shared: self.shared.clone(),
+            sessions_created: 0,
+        };
+        let socket = creator(&mux, &create.name, keys)?;
+        log::info!("Created view {}; socket = {socket}", create.name);
+        let mut details = Vec::new();
+        socket.encode(&mut details).map_err(AgentError::other)?;
+        Ok(Some(Extension {
+            name,
+            details: details.into(),
+        }))
+    }
+
+    async fn extension(&self, request: Extension) -> Result<Option<Extension>, AgentError> {
+        log::info!("extension request {}", logging::Message(&request));
+        if let Some(bind) = request.parse_message::<SessionBind>()? {
+            // Binding to a host the client can't prove it reached would get
+            // around `destinations`.
+            bind.verify_signature()?;
+            log::info!(
+                "session bound to host key {}",
+                bind.host_key.fingerprint(HashAlg::Sha256)
+            );
+            self.state.bound_hosts.lock().unwrap().push(bind.host_key);
+        }
+        if let Some(unlock) = request.parse_message::<UnlockTarget>()? {
+            self.unlock_target(unlock)?;
+            return Ok(None);
+        }
+        if let Some(status) = request.parse_message::<Status>()? {
+            return self.status(request.name, status).await;
+        }
+        if let Some(create) = request.parse_message::<CreateView>()? {
+            return self.create_view(request.name, create);
+        }
+        if let Some(ListApprovals) = request.parse_message()? {
+            return self.approvals(request.name, |approvals| approvals.list());
+        }
+        if let Some(revoke) = request.parse_message::<RevokeApprovals>()? {
+            return self.approvals(request.name, |approvals| approvals.revoke(&revoke.key));
+        }
+        // Binding sessions is passed on so that targets can check it.
+        let binding = request.name == SessionBind::NAME;
+        if !binding {
+            self.beyond_view()?;
+        }
+        let response = match self.default_target()?.extension(request).await {
+            // The mux checks bindings itself, for targets that can't.
+            Err(e) if binding => {
+                log::info!("Target refused the session binding: {e}");
+                None
+            }
+            result => result?,
+        };
+        match &response {
+            Some(response) => {
+                log::info!("extension response {}", logging::Message(response))
+            }
+            None => log::info!("extension response None"),
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl Handler for Session {
+    async fn handle(&self, message: Request) -> Result<Response, AgentError> {
+        self.agent().await.handle(message).await
+    }
+}
+
+impl Session {
+    /// The session as of a request received now.
+    async fn agent(&self) -> MuxAgent {
+        MuxAgent {
+            targets: self.state.upstreams(&self.shared.targets).await,
+            state: self.state.clone(),
+            settings: self.shared.settings(),
+            shared: self.shared.clone(),
+            confirm_for: self.confirm_for,
+        }
+    }
+}
+
+impl MuxAgent {
+    /// Routes every request type explicitly, recording each in the logs and
+    /// metrics.
+    async fn handle(&self, message: Request) -> Result<Response, AgentError> {
+        let kind = request_kind(&message);
+        log::debug!("Handling {kind} request");
+        let response = match message {
+            Request::RequestIdentities => self.request_identities().await.map(|mut identities| {
+                identities.extend(self.settings.canaries.iter().cloned());
+                Response::IdentitiesAnswer(identities)
+            }),
+            Request::SignRequest(request) => self.sign(request).await.map(Response::SignResponse),
+            Request::AddIdentity(identity) => self.add_identity(identity).await.map(success),
+            Request::AddIdConstrained(identity) => {
+                self.add_identity_constrained(identity).await.map(success)
+            }
+            Request::RemoveIdentity(identity) => self.remove_identity(identity).await.map(success),
+            Request::RemoveAllIdentities => self.remove_all_identities().await.map(success),
+            Request::AddSmartcardKey(key) => self.add_smartcard_key(key).await.map(success),
+            Request::AddSmartcardKeyConstrained(key) => {
+                self.add_smartcard_key_constrained(key).await.map(success)
+            }
+            Request::RemoveSmartcardKey(key) => self.remove_smartcard_key(key).await.map(success),
+            Request::Lock(passphrase) => self.lock(passphrase).await.map(success),
+            Request::Unlock(passphrase) => self.unlock(passphrase).await.map(success),
+            Request::Extension(extension) => {
+                self.extension(extension)
+                    .await
+                    .map(|response| match response {
+                        Some(response) => Response::ExtensionResponse(response),
+                        None => Response::Success,
+                    })
+            }
+        };
+        if let Err(e) = &response {
+            log::info!("{kind} request failed: {e}");
+        }
+        metrics::record(kind, response.is_ok());
+        response
+    }
+}
+
+fn success(_: ()) -> Response {
+    Response::Success
+}
+
+/// Name of a request's message type for logs and metrics.
+fn request_kind(request: &Request) -> &'static str {
+    match request {
+        Request::RequestIdentities => "request_identities",
+        Request::SignRequest(_) => "sign",
+        Request::AddIdentity(_) => "add_identity",
+        Request::AddIdConstrained(_) => "add_identity_constrained",
+        Request::RemoveIdentity(_) => "remove_identity",
+        Request::RemoveAllIdentities => "remove_all_identities",
+        Request::AddSmartcardKey(_) => "add_smartcard_key",
+        Request::AddSmartcardKeyConstrained(_) => "add_smartcard_key_constrained",
+        Request::RemoveSmartcardKey(_) => "remove_smartcard_key",
+        Request::Lock(_) => "lock",
+        Request::Unlock(_) => "unlock",
+        Request::Extension(_) => "extension",
+    }
+}
+
+/// Adds the configured constraints to those of a key being added, keeping the
+/// shorter of two lifetimes.
+fn add_constraints(config: &AddConstraints, constraints: &mut Vec<KeyConstraint>) {
+    if config.confirm && !constraints.contains(&KeyConstraint::Confirm) {
+        constraints.push(KeyConstraint::Confirm);
+    }
+    if let Some(lifetime) = config.lifetime {
+        let lifetime = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+        match constraints
+            .iter_mut()
+            .find_map(|constraint| match constraint {
+                KeyConstraint::Lifetime(seconds) => Some(seconds),
+                _ => None,
+            }) {
+            Some(seconds) => *seconds = (*seconds).min(lifetime),
+            None => constraints.push(KeyConstraint::Lifetime(lifetime)),
+        }
+    }
+}
+
+/// Whether keys restricted to `destinations` may be used toward the host
+/// with `host_key`.
+fn allows(destinations: &[Destination], host_key: &KeyData) -> bool {
+    destinations.is_empty()
+        || destinations
+            .iter()
+            .any(|destination| destination.host_key == *host_key)
+}
+
+/// Adds OpenSSH's constraint restricting a key to `destinations`, unless
+/// the client restricted it already.
+fn add_destinations(
+    destinations: &[Destination],
+    constraints: &mut Vec<KeyConstraint>,
+) -> Result<(), AgentError> {
+    let restricted = constraints.iter().any(|constraint| {
+        matches!(constraint, KeyConstraint::Extension(extension)
+            if extension.name == RestrictDestination::NAME)
+    });
+    if destinations.is_empty() || restricted {
+        return Ok(());
+    }
+    let anywhere = HostTuple {
+        username: String::new(),
+        hostname: String::new(),
+        keys: Vec::new(),
+    };
+    let restriction = RestrictDestination {
+        constraints: destinations
+            .iter()
+            .map(|destination| DestinationConstraint {
+                from: anywhere.clone(),
+                to: HostTuple {
+                    username: String::new(),
+                    hostname: destination.host.clone(),
+                    keys: vec![KeySpec {
+                        keyblob: destination.host_key.clone(),
+                        is_ca: false,
+                    }],
+                },
+            })
+            .collect(),
+    };
+    constraints.push(KeyConstraint::Extension(Extension::new_key_constraint(
+        restriction,
+    )?));
+    Ok(())
+}
+
+/// The targets sessions connect to.  Those in target directories change
+/// as the directories are scanned again, and all as the config is reloaded.
+struct TargetSet {
+    /// The configured targets, which come first.
+    fixed: RwLock<Vec<Arc<Target>>>,
+    dirs: RwLock<Vec<TargetDirConfig>>,
+    /// The view whose targets these are, if not all of them.
+    view: RwLock<Option<ViewConfig>>,
+    current: RwLock<Vec<Arc<Target>>>,
+    /// Counts changes to `current`, made while holding its lock.
+    generation: AtomicU64,
+}
+
+impl TargetSet {
+    fn new(config: &Config, view: Option<&ViewConfig>) -> Self {
+        let fixed: Vec<_> = config
+            .targets
+            .iter()
+            .map(|target| Arc::new(Target::new(target.clone())))
+            .collect();
+        let set = Self {
+            current: RwLock::new(fixed.clone()),
+            fixed: RwLock::new(fixed),
+            dirs: RwLock::new(config.target_dirs.clone()),
+            view: RwLock::new(view.cloned()),
+            generation: AtomicU64::new(1),
+        };
+        set.scan();
+        set
+    }
+
+    fn get(&self) -> Vec<Arc<Target>> {
+        self.current.read().unwrap().clone()
+    }
+
+    fn get_with_generation(&self) -> (u64, Vec<Arc<Target>>) {
+        let current = self.current.read().unwrap();
+        (self.generation.load(Ordering::Relaxed), current.clone())
+    }
+
+    /// The view whose targets these are, if not all of them.
+    fn view(&self) -> Option<ViewConfig> {
+        self.view.read().unwrap().clone()
+    }
+
+    fn is_view(&self) -> bool {
+        self.view.read().unwrap().is_some()
+    }
+
+    /// Takes the targets of `config`.  Targets whose config didn't change
+    /// keep their state.
+    fn reconfigure(&self, config: &Config, view: Option<&ViewConfig>) {
+        let current = self.get();
+        *self.fixed.write().unwrap() = config
+            .targets
+            .iter()
+            .map(|config| reuse(&current, config.clone()))
+            .collect();
+        *self.dirs.write().unwrap() = config.target_dirs.clone();
+        *self.view.write().unwrap() = view.cloned();
+        self.scan();
+    }
+
+    /// Finds the targets in the directories again.  Targets still there
+    /// keep their state; those of a directory that can't be read are kept
+    /// as they were.
+    fn scan(&self) {
+        let current = self.get();
+        let mut targets = self.fixed.read().unwrap().clone();
+        for dir in self.dirs.read().unwrap().iter() {
+            let found = match dir.scan() {
+                Ok(found) => found,
+                Err(e) => {
+                    log::warn!("Failed to scan {}: {e}", dir.dir.display());
+                    targets.extend(
+                        current
+                            .iter()
+                            .filter(|target| dir.contains(target.name()))
+                            .cloned(),
+                    );
+                    continue;
+                }
+            };
+            targets.extend(found.into_iter().map(|config| reuse(&current, config)));
+        }
+        if let Some(view) = &*self.view.read().unwrap() {
+            targets.retain(|target| view.serves_target(target.name()));
+        }
+        for target in &targets {
+            if !current.iter().any(|kept| Arc::ptr_eq(kept, target)) {
+                log::info!("Found target {}", target.name());
+            }
+        }
+        for target in &current {
+            if !targets.iter().any(|kept| Arc::ptr_eq(kept, target)) {
+                log::info!("Target {} is gone", target.name());
+            }
+        }
+        let mut current = self.current.write().unwrap();
+        if current.len() != targets.len()
+            || current
+                .iter()
+                .zip(&targets)
+                .any(|(target, other)| !Arc::ptr_eq(target, other))
+        {
+            *current = targets;
+            self.generation.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The target of `current` with `config`, or a new one.
+fn reuse(current: &[Arc<Target>], config: TargetConfig) -> Arc<Target> {
+    current
+        .iter()
+        .find(|target| target.config == config)
+        .cloned()
+        .unwrap_or_else(|| Arc::new(Target::new(config)))
+}
+
+/// Creates a [`Session`], with its own target connections, per client
+/// connection.
+pub struct MuxAgentBind {
+    shared: Arc<Shared>,
+    sessions_created: u64,
+}
+
+#[cfg(unix)]
+impl Agent<tokio::net::UnixListener> for MuxAgentBind {
+    fn new_session(&mut self, socket: &tokio::net::UnixStream) -> impl Handler {
+        let client = socket
+            .peer_credentials()
+            .map(|(uid, _)| format!("uid {uid}"));
+        self.sessions_created += 1;
+        Session::new(self.sessions_created, self.shared.clone(), client, None)
+    }
+}
+
+impl Agent<tokio::net::TcpListener> for MuxAgentBind {
+    fn new_session(&mut self, socket: &tokio::net::TcpStream) -> impl Handler {
+        let address = socket.peer_addr().ok().map(|address| address.ip());
+        let client = address.map(|address| address.to_string());
+        let confirm_for = address.filter(|&address| {
+            AddressRule::action_for(&self.shared.settings().client_addresses, address)
+                == AddressAction::Confirm
+        });
+        self.sessions_created += 1;
+        Session::new(
+            self.sessions_created,
+            self.shared.clone(),
+            client,
+            confirm_for,
+        )
+    }
+}
+
+impl Agent<Stdio> for MuxAgentBind {
+    fn new_session(
+        &mut self,
+        _socket: &tokio::io::Join<tokio::io::Stdin, tokio::io::Stdout>,
+    ) -> impl Handler {
+        self.create_new_session()
+    }
+}
+
+#[cfg(windows)]
+impl Agent<crate::pipe::NamedPipeListener> for MuxAgentBind {
+    fn new_session(
+        &mut self,
+        _socket: &tokio::net::windows::named_pipe::NamedPipeServer,
+    ) -> impl Handler {
+        self.create_new_session()
+    }
+}
+
+impl MuxAgentBind {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            shared: Arc::new(Shared::new(config, None, Arc::new(askpass::Prompt))),
+            sessions_created: 0,
+        }
+    }
+
+    /// Serves view `name` of `config`, if there is one: only its targets
+    /// and keys, with the approver of this mux.  It is reloaded along with
+    /// this mux.
+    pub fn view(&self, config: &Config, name: &str) -> Option<Self> {
+        let view = config.view(name)?;
+        let shared = Arc::new(Shared::new(
+            config,
+            Some(view),
+            self.shared.approver.clone(),
+        ));
+        self.shared.views.lock().unwrap().push(shared.clone());
+        Some(Self {
+            shared,
+            sessions_created: 0,
+        })
+    }
+
+    /// Asks `approver` for the confirmations policy requires, e.g. for TCP
+    /// clients with `confirm` addresses, instead of the terminal or
+    /// `SSH_ASKPASS`.  The programs of `exec` policies are still run.
+    pub fn with_approver(mut self, approver: Arc<dyn Approver>) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("approvers are set before serving")
+            .approver = approver;
+        self
+    }
+
+    /// Serves the views clients create, e.g. with `ssh-agent-mux view
+    /// create`, with `creator`.  Without one, they can't.
+    pub fn with_view_creator(mut self, creator: Arc<ViewCreator>) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("view creators are set before serving")
+            .view_creator = Some(creator);
+        self
+    }
+
+    /// A view of the targets and sessions, for dumping their state and
+    /// reloading the config while serving.
+    pub fn state(&self) -> MuxState {
+        MuxState {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// The identities a client would be listed, each with the names of the
+    /// targets holding it, most preferred first.
+    pub async fn list_identities(&mut self) -> Result<Vec<(Identity, Vec<String>)>, AgentError> {
+        identities_with_targets(self.create_new_session()).await
+    }
+
+    /// A session without a client, to replay a recording through.
+    pub(crate) fn replay_session(&mut self) -> Box<dyn Handler> {
+        Box::new(self.create_new_session())
+    }
+
+    fn create_new_session(&mut self) -> Session {
+        self.sessions_created += 1;
+        Session::new(self.sessions_created, self.shared.clone(), None, None)
+    }
+}
+
+async fn identities_with_targets(
+    session: Session,
+) -> Result<Vec<(Identity, Vec<String>)>, AgentError> {
+    let agent = session.agent().await;
+    let identities = agent.request_identities().await?;
+    Ok(identities
+        .into_iter()
+        .map(|identity| {
+            let targets = agent
+                .find_key(&identity.pubkey)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|target_index| agent.targets[target_index].name().to_owned())
+                .collect();
+            (identity, targets)
+        })
+        .collect())
+}
+
+/// Lists and uses the hidden `target` for `duration`.
+fn unlock_hidden(target: &Target, duration: Duration) -> Result<(), Error> {
+    if !target.config.hidden {
+        return Err(Error::NotHidden(target.name().to_owned()));
+    }
+    target.unlock(duration);
+    audit::record(audit::Event::TargetUnlocked {
+        target: target.name(),
+        duration,
+    });
+    Ok(())
+}
+
+/// A target, as the admin endpoints report it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TargetStatus {
+    pub name: String,
+    pub healthy: bool,
+    /// How long ago the target failed, unless it succeeded since.
+    pub failed: Option<Duration>,
+    pub priority: i64,
+    /// Average time the target took to sign, once it has.
+    pub sign_latency: Option<Duration>,
+    pub hidden: bool,
+}
+
+/// The state of the targets and live sessions, displayed as a readable
+/// multi-line report.
+#[derive(Clone)]
+pub struct MuxState {
+    shared: Arc<Shared>,
+}
+
+impl MuxState {
+    /// Scans target directories again.
+    pub fn rescan_targets(&self) {
+        self.shared.targets.scan();
+        self.shared.disconnect_gone_targets();
+    }
+
+    /// Applies the targets and settings of `config`, for requests received
+    /// from now on by every session, new and existing, of the mux and its
+    /// views.  Requests in flight finish as they started, and no client is
+    /// disconnected.  Quotas keep counting the signatures made before.
+    /// Views not in `config`, created by clients or gone from it, keep
+    /// their targets and keys, and new ones aren't served until restarted.
+    pub fn reload(&self, config: &Config) {
+        self.shared.reload(config);
+    }
+
+    /// Like [`MuxAgentBind::list_identities`], in a session of its own
+    /// numbered 0.
+    pub async fn list_identities(&self) -> Result<Vec<(Identity, Vec<String>)>, AgentError> {
+        identities_with_targets(Session::new(0, self.shared.clone(), None, None)).await
+    }
+
+    /// The current targets.
+    pub fn targets(&self) -> Vec<TargetStatus> {
+        self.shared
+            .targets
+            .get()
+            .iter()
+            .map(|target| TargetStatus {
+                name: target.name().to_owned(),
+                healthy: target.is_healthy(),
+                failed: target.failed_at().map(|failed_at| failed_at.elapsed()),
+                priority: target.config.priority,
+                sign_latency: target.sign_latency(),
+                hidden: target.is_hidden(),
+            })
+            .collect()
+    }
+
+    /// The number of live sessions.
+    pub fn sessions(&self) -> usize {
+        self.shared.live_sessions().len()
+    }
+
+    /// The number of keys last listed to a client, unless none listed them
+    /// yet.
+    pub fn keys(&self) -> Option<usize> {
+        self.shared
+            .listed_keys
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Vec::len)
+    }
+
+    /// The keys of the config's `keys` policies, with how much of their
+    /// quotas and cooldowns is taken.
+    pub fn key_policies(&self) -> Vec<policy::KeyState> {
+        self.shared.settings().policy.key_states()
+    }
+
+    /// Lists and uses the hidden target `name` for `duration`.
+    pub fn unlock_target(&self, name: &str, duration: Duration) -> Result<(), Error> {
+        let target = self
+            .shared
+            .targets
+            .get()
+            .into_iter()
+            .find(|target| target.name() == name)
+            .ok_or_else(|| Error::UnknownTarget(name.to_owned()))?;
+        unlock_hidden(&target, duration)
+    }
+
+    /// The status as the JSON document described in [`crate::status`].
+    pub(crate) fn status_json(&self) -> String {
+        let targets = self.target_documents();
+        let status = StatusDocument {
+            schema_version: status::SCHEMA_VERSION,
+            version: env!("CARGO_PKG_VERSION"),
+            healthy_targets: targets.iter().filter(|target| target.healthy).count(),
+            targets,
+            sessions: self.sessions(),
+            keys: self.keys(),
+            keys_used: metrics::key_usage().len(),
+        };
+        serde_json::to_string(&status).expect("the status serializes to JSON")
+    }
+
+    /// The targets as a JSON array of objects of their `name`, whether they
+    /// are `healthy`, how many seconds ago they `failed`, their `priority`,
+    /// average `sign_latency_ms` and whether they are `hidden`, with `null`
+    /// for what is unknown.
+    pub(crate) fn targets_json(&self) -> String {
+        serde_json::to_string(&self.target_documents()).expect("targets serialize to JSON")
+    }
+
+    fn target_documents(&self) -> Vec<TargetDocument> {
+        self.targets()
+            .into_iter()
+            .map(|target| TargetDocument {
+                name: target.name,
+                healthy: target.healthy,
+                failed: target.failed.map(|failed| failed.as_secs()),
+                priority: target.priority,
+                sign_latency_ms: target.sign_latency.map(|latency| latency.as_millis()),
+                hidden: target.hidden,
+            })
+            .collect()
+    }
+}
+
+/// The status, as [`MuxState::status_json`] reports it.
+#[derive(Serialize)]
+struct StatusDocument {
+    schema_version: u32,
+    version: &'static str,
+    targets: Vec<TargetDocument>,
+    healthy_targets: usize,
+    sessions: usize,
+    /// Those last listed to a client, unless none listed them yet.
+    keys: Option<usize>,
+    keys_used: usize,
+}
+
+/// A target, as [`MuxState::targets_json`] reports it.
+#[derive(Serialize)]
+struct TargetDocument {
+    name: String,
+    healthy: bool,
+    failed: Option<u64>,
+    priority: i64,
+    sign_latency_ms: Option<u128>,
+    hidden: bool,
+}
+
+impl fmt::Display for MuxState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Targets:")?;
+        for target in self.shared.targets.get() {
+            match &target.config.tcp_name {
+                Some(name) => write!(f, "  {} ({name}): ", target.name())?,
+                None => write!(f, "  {} ({:?}): ", target.name(), target.config.binding)?,
+            }
+            match target.failed_at() {
+                Some(failed_at) => write!(f, "failed {}s ago", failed_at.elapsed().as_secs())?,
+                None => write!(f, "healthy")?,
+            }
+            write!(f, ", priority {}", target.config.priority)?;
+            if let Some(latency) = target.sign_latency() {
+                write!(f, ", signs in {}ms", latency.as_millis())?;
+            }
+            if let (Some(in_flight), Some(max)) = (
+                target.requests_in_flight(),
+                target.config.max_concurrent_requests,
+            ) {
+                write!(f, ", {in_flight} of {max} requests in flight")?;
+            }
+            match (target.config.hidden, target.is_hidden()) {
+                (true, true) => write!(f, ", hidden")?,
+                (true, false) => write!(f, ", unlocked")?,
+                _ => (),
+            }
+            writeln!(f)?;
+        }
+
+        let sessions = self.shared.live_sessions();
+        write!(f, "Sessions: {}", sessions.len())?;
+        for session in sessions {
+            let upstreams = session.upstreams.lock().unwrap().targets.clone();
+            let names: Vec<_> = upstreams.iter().map(Upstream::name).collect();
+            write!(
+                f,
+                "\n  Session {}, connected to {}: ",
+                session.id,
+                names.join(", ")
+            )?;
+            let bound_hosts = session.bound_hosts.lock().unwrap();
+            if !bound_hosts.is_empty() {
+                let hosts: Vec<_> = bound_hosts
+                    .iter()
+                    .map(|host_key| host_key.fingerprint(HashAlg::Sha256).to_string())
+                    .collect();
+                write!(f, "bound to {}, ", hosts.join(", "))?;
+            }
+            drop(bound_hosts);
+            let key_map = session.key_map.lock().unwrap();
+            let Some(updated_at) = key_map.updated_at else {
+                write!(f, "keys not listed yet")?;
+                continue;
+            };
+            write!(
+                f,
+                "{} keys, listed {}s ago",
+                key_map.keys.len(),
+                updated_at.elapsed().as_secs()
+            )?;
+            for key_index in &key_map.keys {
+                let targets: Vec<_> = key_index
+                    .targets
+                    .iter()
+                    .map(|target| target.name())
+                    .collect();
+                write!(
+                    f,
+                    "\n    {}: {}",
+                    logging::Key(&key_index.key),
+                    targets.join(", ")
+                )?;
+            }
+        }
+
+        let key_usage = metrics::key_usage();
+        write!(f, "\nKeys used: {}", key_usage.len())?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for (fingerprint, usage) in &key_usage {
+            write!(
+                f,
+                "\n  {}: {} signatures, last {}s ago",
+                logging::Fingerprint(fingerprint),
+                usage.signatures,
+                now.saturating_sub(usage.last_used)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+//! Labels for the keys of 1Password's SSH agent: the vault and item each is
+//! stored in, from the `agent.toml` that picks the items the agent offers,
+//! shown like nicknames in logs, `keys`, `export` and notifications.
+//!
+//! The agent lists each key with the title of its item as the comment,
+//! which is matched against the `item` of each `[[ssh-keys]]` entry.  Keys
+//! of entries naming only a vault, or of titles several entries name,
+//! aren't labeled, and nicknames of the `[keys]` table come first.
+
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+use serde::Deserialize;
+use service_binding::Binding;
+use ssh_agent_lib::error::AgentError;
+use ssh_agent_lib::proto::{Identity, Request, Response};
+use ssh_key::{public::KeyData, Fingerprint, HashAlg};
+
+use crate::client::Client;
+use crate::config::{Flavor, TargetConfig};
+use crate::error::Error;
+use crate::key_cache;
+
+/// An item `agent.toml` offers the keys of.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+    /// The item's title.
+    pub item: String,
+    pub vault: Option<String>,
+}
+
+impl Item {
+    fn label(&self) -> String {
+        match &self.vault {
+            Some(vault) => format!("{vault}/{}", self.item),
+            None => self.item.clone(),
+        }
+    }
+}
+
+static ITEMS: RwLock<Vec<Item>> = RwLock::new(Vec::new());
+/// Labels of keys listed so far, by fingerprint.
+static LABELS: RwLock<Vec<(Fingerprint, String)>> = RwLock::new(Vec::new());
+
+/// The parts of `agent.toml` that label keys.
+#[derive(Deserialize)]
+struct AgentToml {
+    #[serde(rename = "ssh-keys", default)]
+    ssh_keys: Vec<SshKeys>,
+}
+
+/// An `[[ssh-keys]]` entry, offering the keys of an item or a vault.
+#[derive(Deserialize)]
+struct SshKeys {
+    item: Option<String>,
+    vault: Option<String>,
+}
+
+/// The items of the `[[ssh-keys]]` entries of `agent_toml` that name one.
+pub fn parse(agent_toml: &str) -> Result<Vec<Item>, toml::de::Error> {
+    let agent_toml: AgentToml = toml::from_str(agent_toml)?;
+    Ok(agent_toml
+        .ssh_keys
+        .into_iter()
+        .filter_map(|entry| {
+            Some(Item {
+                item: entry.item?,
+                vault: entry.vault,
+            })
+        })
+        .collect())
+}
+
+/// Labels keys of 1Password targets from the items of the `agent.toml` at
+/// `path` from now on, or no longer without one.
+pub fn set_agent_toml(path: Option<&Path>) -> Result<(), Error> {
+    let items = match path {
+        Some(path) => {
+            let agent_toml = fs::read_to_string(path).map_err(|source| Error::AgentToml {
+                path: path.to_owned(),
+                source,
+            })?;
+            parse(&agent_toml).map_err(|source| Error::InvalidAgentToml {
+                path: path.to_owned(),
+                source,
+            })?
+        }
+        None => Vec::new(),
+    };
+    *ITEMS.write().unwrap() = items;
+    Ok(())
+}
+
+/// Labels the keys of `identities`, as a 1Password target listed them.
+pub(crate) fn label(identities: &[Identity]) {
+    let items = ITEMS.read().unwrap();
+    if items.is_empty() {
+        return;
+    }
+    let mut labels = LABELS.write().unwrap();
+    for identity in identities {
+        let fingerprint = identity.pubkey.fingerprint(HashAlg::Sha256);
+        labels.retain(|(labeled, _)| *labeled != fingerprint);
+        let mut matching = items.iter().filter(|item| item.item == identity.comment);
+        if let (Some(item), None) = (matching.next(), matching.next()) {
+            labels.push((fingerprint, item.label()));
+        }
+    }
+}
+
+/// The label of `key`, if it has one.
+pub(crate) fn key_label(key: &KeyData) -> Option<String> {
+    let fingerprint = key.fingerprint(HashAlg::Sha256);
+    label_of(|labeled| *labeled == fingerprint)
+}
+
+/// The label of the key with `fingerprint`, if it has one.
+pub(crate) fn fingerprint_label(fingerprint: &str) -> Option<String> {
+    label_of(|labeled| labeled.to_string() == fingerprint)
+}
+
+fn label_of(matches: impl Fn(&Fingerprint) -> bool) -> Option<String> {
+    LABELS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(fingerprint, _)| matches(fingerprint))
+        .map(|(_, label)| label.clone())
+}
+
+/// Labels the keys of 1Password targets from the identities the mux serving
+/// on `host` lists from its index, e.g. for commands that don't list them
+/// otherwise, without asking the targets again.  With the entries of the
+/// key cache, only keys its 1Password targets of `targets` last listed are
+/// labeled.
+pub async fn learn_from_mux(
+    host: &Binding,
+    targets: &[TargetConfig],
+    cached: Option<&key_cache::Entries>,
+) -> Result<(), AgentError> {
+    let mut client = host.clone().try_into().and_then(Client::connect)?;
+    let Response::IdentitiesAnswer(mut identities) =
+        client.handle(Request::RequestIdentities).await?
+    else {
+        return Err(AgentError::Failure);
+    };
+    if let Some(cached) = cached {
+        let onepassword = |name: &String| {
+            targets
+                .iter()
+                .any(|target| target.name == *name && target.flavor == Flavor::OnePassword)
+        };
+        identities.retain(|identity| {
+            cached
+                .iter()
+                .find(|(key, _)| *key == identity.pubkey)
+                .is_some_and(|(_, names)| names.iter().any(onepassword))
+        });
+    }
+    label(&identities);
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Timing how long targets take to list their identities, for finding the
+//! agent making SSH slow.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{ProtoError, Request, Response},
+};
+
+use crate::client::Client;
+use crate::config::{Config, TargetConfig};
+use crate::dns;
+
+/// Round-trip times of listing a target's identities.
+#[derive(Clone, Copy, Debug)]
+pub struct Latency {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    /// Identities the target listed the last time.
+    pub identities: usize,
+}
+
+/// The targets of `config`, with those of its directories as they are now.
+pub fn targets(config: &Config) -> Vec<TargetConfig> {
+    let mut targets = config.targets.clone();
+    for dir in &config.target_dirs {
+        match dir.scan() {
+            Ok(found) => targets.extend(found),
+            Err(e) => log::warn!("Failed to scan {}: {e}", dir.dir.display()),
+        }
+    }
+    targets
+}
+
+/// Lists the identities of `target` `count` times over one connection, which
+/// isn't timed.  Each request fails if not answered within `timeout`.
+pub async fn ping(
+    target: &TargetConfig,
+    count: u32,
+    timeout: Duration,
+) -> Result<Latency, AgentError> {
+    let mut client = match &target.tcp_name {
+        Some(name) => dns::resolve(name).await.map(Binding::Sockets),
+        None => Ok(target.binding.clone()),
+    }
+    .and_then(TryInto::try_into)
+    .and_then(Client::connect)?;
+    let mut latency = Latency {
+        min: Duration::MAX,
+        avg: Duration::ZERO,
+        max: Duration::ZERO,
+        identities: 0,
+    };
+    let mut total = Duration::ZERO;
+    for _ in 0..count.max(1) {
+        let started_at = Instant::now();
+        let response = tokio::time::timeout(timeout, client.handle(Request::RequestIdentities))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into()))?;
+        let elapsed = started_at.elapsed();
+        latency.identities = match response {
+            Response::IdentitiesAnswer(identities) => identities.len(),
+            Response::Failure => return Err(AgentError::Failure),
+            _ => return Err(ProtoError::UnexpectedResponse.into()),
+        };
+        latency.min = latency.min.min(elapsed);
+        latency.max = latency.max.max(elapsed);
+        total += elapsed;
+    }
+    latency.avg = total / count.max(1);
+    Ok(latency)
+}
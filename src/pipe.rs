@@ -0,0 +1,235 @@
+//! Named pipe host with a security descriptor (Windows).
+//!
+//! Every instance of the pipe is created with the descriptor of
+//! [`PipeSecurityConfig`], which by default allows only the user running the
+//! mux to connect, rather than the default DACL that lets every local account
+//! open the pipe for reading.
+
+use std::ffi::{c_void, OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+use ssh_agent_lib::{agent::ListeningSocket, async_trait};
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+
+use crate::config::PipeSecurityConfig;
+
+type Handle = *mut c_void;
+
+const SDDL_REVISION_1: u32 = 1;
+const TOKEN_QUERY: u32 = 0x0008;
+/// `TOKEN_INFORMATION_CLASS::TokenUser`.
+const TOKEN_USER: i32 = 1;
+
+/// `SECURITY_ATTRIBUTES`, only read by Windows.
+#[allow(dead_code)]
+#[repr(C)]
+struct SecurityAttributes {
+    length: u32,
+    security_descriptor: *mut c_void,
+    inherit_handle: i32,
+}
+
+/// The start of `TOKEN_USER`: the SID of its `SID_AND_ATTRIBUTES`.
+#[repr(C)]
+struct TokenUser {
+    sid: *mut c_void,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn ConvertStringSecurityDescriptorToSecurityDescriptorW(
+        string_security_descriptor: *const u16,
+        revision: u32,
+        security_descriptor: *mut *mut c_void,
+        size: *mut u32,
+    ) -> i32;
+    fn ConvertSidToStringSidW(sid: *mut c_void, string_sid: *mut *mut u16) -> i32;
+    fn OpenProcessToken(process: Handle, desired_access: u32, token: *mut Handle) -> i32;
+    fn GetTokenInformation(
+        token: Handle,
+        class: i32,
+        information: *mut c_void,
+        length: u32,
+        return_length: *mut u32,
+    ) -> i32;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCurrentProcess() -> Handle;
+    fn CloseHandle(handle: Handle) -> i32;
+    fn LocalFree(memory: *mut c_void) -> *mut c_void;
+}
+
+fn check(result: i32, what: &str) -> io::Result<()> {
+    if result == 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(e.kind(), format!("{what}: {e}")));
+    }
+    Ok(())
+}
+
+/// A security descriptor allocated by Windows.
+struct SecurityDescriptor(*mut c_void);
+
+// SAFETY: the descriptor is owned and only read after it is created.
+unsafe impl Send for SecurityDescriptor {}
+unsafe impl Sync for SecurityDescriptor {}
+
+impl SecurityDescriptor {
+    fn from_sddl(sddl: &str) -> io::Result<Self> {
+        let wide: Vec<u16> = OsStr::new(sddl).encode_wide().chain([0]).collect();
+        let mut descriptor = std::ptr::null_mut();
+        // SAFETY: `wide` is NUL-terminated and the size is optional.
+        check(
+            unsafe {
+                ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                    wide.as_ptr(),
+                    SDDL_REVISION_1,
+                    &mut descriptor,
+                    std::ptr::null_mut(),
+                )
+            },
+            &format!("security descriptor '{sddl}'"),
+        )?;
+        Ok(Self(descriptor))
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor was allocated with LocalAlloc and is freed once.
+        unsafe { LocalFree(self.0) };
+    }
+}
+
+/// The SDDL of the pipe's security descriptor for `config`.
+fn sddl(config: &PipeSecurityConfig) -> io::Result<String> {
+    if let Some(sddl) = &config.sddl {
+        return Ok(sddl.clone());
+    }
+    // Protected, so that no ACEs are inherited.
+    let mut sddl = format!("D:P(A;;GA;;;{})", current_user_sid()?);
+    for group in &config.allow_groups {
+        sddl.push_str(&format!("(A;;GA;;;{group})"));
+    }
+    Ok(sddl)
+}
+
+/// The SID of the user running the mux, as a string.
+fn current_user_sid() -> io::Result<String> {
+    let mut token = std::ptr::null_mut();
+    // SAFETY: the pseudo handle of the current process needs no closing.
+    check(
+        unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) },
+        "OpenProcessToken",
+    )?;
+    let user = token_user(token);
+    // SAFETY: `token` was opened above and is closed once.
+    unsafe { CloseHandle(token) };
+    let user = user?;
+
+    let mut string_sid = std::ptr::null_mut();
+    // SAFETY: the SID points into `user`, which is alive.
+    check(
+        unsafe {
+            ConvertSidToStringSidW((*user.as_ptr().cast::<TokenUser>()).sid, &mut string_sid)
+        },
+        "ConvertSidToStringSidW",
+    )?;
+    // SAFETY: `string_sid` is NUL-terminated, and freed once after copying.
+    let sid = unsafe {
+        let len = (0..).take_while(|&i| *string_sid.add(i) != 0).count();
+        let sid = OsString::from_wide(std::slice::from_raw_parts(string_sid, len));
+        LocalFree(string_sid.cast());
+        sid
+    };
+    Ok(sid.to_string_lossy().into_owned())
+}
+
+/// The `TOKEN_USER` of `token`, in a buffer aligned for it.
+fn token_user(token: Handle) -> io::Result<Vec<u64>> {
+    let mut length = 0;
+    // SAFETY: querying the length only; this call fails by design.
+    unsafe { GetTokenInformation(token, TOKEN_USER, std::ptr::null_mut(), 0, &mut length) };
+    let mut buffer = vec![0u64; (length as usize).div_ceil(8)];
+    // SAFETY: `buffer` holds at least `length` bytes.
+    check(
+        unsafe {
+            GetTokenInformation(
+                token,
+                TOKEN_USER,
+                buffer.as_mut_ptr().cast(),
+                length,
+                &mut length,
+            )
+        },
+        "GetTokenInformation",
+    )?;
+    Ok(buffer)
+}
+
+/// Like `ssh_agent_lib::agent::NamedPipeListener`, but with a security
+/// descriptor.
+pub struct NamedPipeListener {
+    server: NamedPipeServer,
+    name: OsString,
+    security: SecurityDescriptor,
+}
+
+impl NamedPipeListener {
+    pub fn bind(name: impl Into<OsString>, config: &PipeSecurityConfig) -> io::Result<Self> {
+        let name = name.into();
+        let sddl = sddl(config)?;
+        let security = SecurityDescriptor::from_sddl(&sddl)?;
+        log::debug!("Creating {} with {sddl}", name.to_string_lossy());
+        let server = create(
+            ServerOptions::new().first_pipe_instance(true),
+            &name,
+            &security,
+        )?;
+        Ok(Self {
+            server,
+            name,
+            security,
+        })
+    }
+}
+
+fn create(
+    options: &ServerOptions,
+    name: &OsStr,
+    security: &SecurityDescriptor,
+) -> io::Result<NamedPipeServer> {
+    let mut attributes = SecurityAttributes {
+        length: std::mem::size_of::<SecurityAttributes>() as u32,
+        security_descriptor: security.0,
+        inherit_handle: 0,
+    };
+    // SAFETY: `attributes` is a valid SECURITY_ATTRIBUTES with a valid
+    // descriptor, both alive for the call.
+    unsafe {
+        options.create_with_security_attributes_raw(name, std::ptr::addr_of_mut!(attributes).cast())
+    }
+}
+
+#[async_trait]
+impl ListeningSocket for NamedPipeListener {
+    type Stream = NamedPipeServer;
+
+    async fn accept(&mut self) -> io::Result<Self::Stream> {
+        self.server.connect().await?;
+        let next = create(&ServerOptions::new(), &self.name, &self.security)?;
+        Ok(std::mem::replace(&mut self.server, next))
+    }
+}
+
+impl fmt::Debug for NamedPipeListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("NamedPipeListener")
+            .field(&self.name)
+            .finish()
+    }
+}
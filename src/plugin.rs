@@ -0,0 +1,214 @@
+//! WebAssembly plugins, for routing, policy and notifications shipped
+//! separately from the mux.
+//!
+//! A plugin is a module exporting its `memory` and
+//! `alloc(len: i32) -> i32`, which returns where the mux may write `len`
+//! bytes, along with one or both of:
+//!
+//! - `route(ptr: i32, len: i32) -> i64`, given a sign request as the JSON
+//!   object an `exec` approver gets.  It returns the decision as text at
+//!   `ptr << 32 | len`: empty to leave the targets be, `deny` to deny the
+//!   signature, or the names of the targets to sign with, one per line.
+//! - `event(ptr: i32, len: i32)`, given each audit event as a JSON object.
+//!
+//! Plugins may import `env.log(ptr: i32, len: i32)`, which logs the text at
+//! `ptr`, and nothing else.  Each keeps its memory between calls.
+//!
+//! Plugins run in wasmi's interpreter, and every call is bounded in fuel,
+//! call depth and memory, so that a plugin can neither hang nor exhaust the
+//! mux.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use wasmi::{
+    Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc, WasmParams, WasmResults,
+};
+
+use crate::script::Decision;
+
+/// Memory of a plugin at most: 16 MiB.
+const MAX_MEMORY: usize = 16 << 20;
+/// Fuel one call may use, about one unit per instruction.
+const FUEL: u64 = 10_000_000;
+/// Nested calls within one call.
+const MAX_DEPTH: usize = 256;
+
+static PLUGINS: OnceLock<Vec<Arc<Plugin>>> = OnceLock::new();
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Wasm(#[from] wasmi::Error),
+    #[error("{0}")]
+    Abi(String),
+}
+
+/// An instance with its memory.
+struct Running {
+    store: Store<StoreLimits>,
+    memory: Memory,
+}
+
+pub struct Plugin {
+    path: PathBuf,
+    running: Mutex<Running>,
+    alloc: TypedFunc<i32, i32>,
+    route: Option<TypedFunc<(i32, i32), i64>>,
+    event: Option<TypedFunc<(i32, i32), ()>>,
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+impl Plugin {
+    /// Loads and instantiates the plugin module in `bytes`, read from
+    /// `path`.
+    pub fn new(path: &Path, bytes: &[u8]) -> Result<Self, Error> {
+        let mut config = Config::default();
+        config.consume_fuel(true).set_max_recursion_depth(MAX_DEPTH);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, bytes)?;
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits| limits);
+        let mut linker = Linker::new(&engine);
+        let name = path.display().to_string();
+        linker
+            .func_wrap(
+                "env",
+                "log",
+                move |caller: Caller<'_, StoreLimits>, ptr: i32, len: i32| {
+                    let memory = caller
+                        .get_export("memory")
+                        .and_then(Extern::into_memory)
+                        .ok_or_else(|| wasmi::Error::new("memory isn't exported"))?;
+                    let text = text(memory.data(&caller), ptr as u32, len as u32)
+                        .map_err(|e| wasmi::Error::new(e.to_string()))?;
+                    log::info!("Plugin {name}: {text}");
+                    Ok::<_, wasmi::Error>(())
+                },
+            )
+            .map_err(wasmi::Error::from)?;
+        store.set_fuel(FUEL)?;
+        let instance = linker.instantiate_and_start(&mut store, &module)?;
+
+        let alloc = export(&store, instance, "alloc", "(i32) -> i32")?
+            .ok_or_else(|| Error::Abi("alloc isn't exported".to_owned()))?;
+        let route = export(&store, instance, "route", "(i32, i32) -> i64")?;
+        let event = export(&store, instance, "event", "(i32, i32) -> ()")?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| Error::Abi("memory isn't exported".to_owned()))?;
+        if route.is_none() && event.is_none() {
+            return Err(Error::Abi("exports neither route nor event".to_owned()));
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            running: Mutex::new(Running { store, memory }),
+            alloc,
+            route,
+            event,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Asks the plugin where to sign the request in `json`, if it routes.
+    pub(crate) fn route(&self, json: &str) -> Result<Decision, Error> {
+        let Some(route) = self.route else {
+            return Ok(Decision::Default);
+        };
+        let mut running = self.running.lock().unwrap();
+        let (ptr, len) = self.pass(&mut running, json)?;
+        running.store.set_fuel(FUEL)?;
+        let packed = route.call(&mut running.store, (ptr, len))? as u64;
+        let memory = running.memory.data(&running.store);
+        let decision = text(memory, (packed >> 32) as u32, packed as u32)?;
+        Ok(match decision.trim() {
+            "" => Decision::Default,
+            "deny" => Decision::Deny,
+            names => Decision::Targets(names.lines().map(|name| name.trim().to_owned()).collect()),
+        })
+    }
+
+    /// Tells the plugin of the audit event in `json`, if it wants them.
+    fn event(&self, json: &str) -> Result<(), Error> {
+        let Some(event) = self.event else {
+            return Ok(());
+        };
+        let mut running = self.running.lock().unwrap();
+        let (ptr, len) = self.pass(&mut running, json)?;
+        running.store.set_fuel(FUEL)?;
+        event.call(&mut running.store, (ptr, len))?;
+        Ok(())
+    }
+
+    /// Copies `input` into memory the plugin allocates, returning where.
+    fn pass(&self, running: &mut Running, input: &str) -> Result<(i32, i32), Error> {
+        let len =
+            i32::try_from(input.len()).map_err(|_| Error::Abi("input too long".to_owned()))?;
+        running.store.set_fuel(FUEL)?;
+        let ptr = self.alloc.call(&mut running.store, len)?;
+        let start = ptr as u32 as usize;
+        running
+            .memory
+            .data_mut(&mut running.store)
+            .get_mut(start..start + input.len())
+            .ok_or_else(|| Error::Abi("alloc returned memory out of bounds".to_owned()))?
+            .copy_from_slice(input.as_bytes());
+        Ok((ptr, len))
+    }
+}
+
+/// The function `name` exports, if any, as long as it is of the type
+/// `signature` describes.
+fn export<Params: WasmParams, Results: WasmResults>(
+    store: &Store<StoreLimits>,
+    instance: Instance,
+    name: &str,
+    signature: &str,
+) -> Result<Option<TypedFunc<Params, Results>>, Error> {
+    let Some(function) = instance.get_func(store, name) else {
+        return Ok(None);
+    };
+    function
+        .typed(store)
+        .map(Some)
+        .map_err(|_| Error::Abi(format!("{name} isn't {signature}")))
+}
+
+fn text(memory: &[u8], ptr: u32, len: u32) -> Result<String, Error> {
+    let bytes = memory
+        .get(ptr as usize..ptr as usize + len as usize)
+        .ok_or_else(|| Error::Abi("text out of bounds".to_owned()))?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| Error::Abi("text isn't UTF-8".to_owned()))
+}
+
+/// Sends audit events to `plugins` from now on.
+pub fn install(plugins: Vec<Arc<Plugin>>) {
+    let _ = PLUGINS.set(plugins);
+}
+
+/// Whether any installed plugin wants audit events.
+pub(crate) fn wants_events() -> bool {
+    PLUGINS
+        .get()
+        .is_some_and(|plugins| plugins.iter().any(|plugin| plugin.event.is_some()))
+}
+
+/// Sends the audit event in `json` to the installed plugins.
+pub(crate) fn publish(json: &str) {
+    for plugin in PLUGINS.get().into_iter().flatten() {
+        if let Err(e) = plugin.event(json) {
+            log::warn!("Plugin {} failed: {e}", plugin.path.display());
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Per-key usage policy shared by all sessions.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ssh_key::{public::KeyData, Fingerprint, HashAlg};
+
+use crate::config::KeyConfig;
+
+const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Reason a request was refused by policy.
+#[derive(Debug)]
+pub enum Denial {
+    QuotaExceeded {
+        limit: u32,
+    },
+    /// The key signed less than its cooldown ago.
+    Cooldown {
+        cooldown: Duration,
+    },
+    /// The session is bound to a host the key may not be used toward, by
+    /// its `allowed_host_keys` or the `destinations` of its targets.
+    Destination {
+        host_key: Fingerprint,
+    },
+    /// The session was never bound to a host, and the key requires it.
+    Unbound,
+    OutsideAllowedTimes,
+    /// The client's address or an anomaly requires confirmation, which
+    /// wasn't given.
+    NotConfirmed,
+    /// The key's `exec` program didn't approve.
+    NotApproved,
+    /// The route script denied the signature, or failed.
+    Script,
+    /// A plugin denied the signature, or failed.
+    Plugin,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denial::QuotaExceeded { limit } => {
+                write!(f, "quota of {limit} signatures per hour exceeded")
+            }
+            Denial::Cooldown { cooldown } => write!(
+                f,
+                "less than {} since the key last signed",
+                humantime::format_duration(*cooldown)
+            ),
+            Denial::Destination { host_key } => {
+                write!(f, "host key {host_key} isn't an allowed destination")
+            }
+            Denial::Unbound => f.write_str("session not bound to a host"),
+            Denial::OutsideAllowedTimes => f.write_str("outside the key's allowed times"),
+            Denial::NotConfirmed => f.write_str("not confirmed"),
+            Denial::NotApproved => f.write_str("not approved"),
+            Denial::Script => f.write_str("denied by the route script"),
+            Denial::Plugin => f.write_str("denied by a plugin"),
+        }
+    }
+}
+
+/// A configured key, as [`Policy::key_states`] reports it.
+#[derive(Clone, Debug)]
+pub struct KeyState {
+    pub config: KeyConfig,
+    /// Signatures counted toward the key's quota, if it has one.
+    pub signatures_this_hour: usize,
+    /// When the key last signed, if it has a cooldown.
+    pub last_signed: Option<Instant>,
+}
+
+pub struct Policy {
+    keys: Vec<KeyConfig>,
+    usage: Arc<Mutex<Usage>>,
+}
+
+/// Signatures counted toward quotas and cooldowns, including those being
+/// made.
+#[derive(Default)]
+struct Usage {
+    /// Times of recent signatures for keys with a quota, oldest first.
+    signatures: BTreeMap<Fingerprint, VecDeque<Instant>>,
+    /// Times of the last signatures of keys with a cooldown.
+    last_signatures: BTreeMap<Fingerprint, Instant>,
+}
+
+/// A signature counted toward its key's quota and cooldown while it is
+/// made, so that concurrent requests can't all pass the checks.  Given
+/// back when dropped, unless kept with [`Reservation::keep`].
+#[must_use]
+pub struct Reservation {
+    taken: Option<Taken>,
+}
+
+struct Taken {
+    usage: Arc<Mutex<Usage>>,
+    fingerprint: Fingerprint,
+    at: Instant,
+    quota: bool,
+    /// The key's last signature before this one, if it has a cooldown.
+    cooldown: Option<Option<Instant>>,
+}
+
+impl Reservation {
+    /// Counts the signature for good, once made.
+    pub fn keep(mut self) {
+        self.taken = None;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Some(taken) = self.taken.take() else {
+            return;
+        };
+        let mut usage = taken.usage.lock().unwrap();
+        if taken.quota {
+            if let Some(times) = usage.signatures.get_mut(&taken.fingerprint) {
+                if let Some(index) = times.iter().rposition(|&time| time == taken.at) {
+                    times.remove(index);
+                }
+            }
+        }
+        if let Some(previous) = taken.cooldown {
+            if usage.last_signatures.get(&taken.fingerprint) == Some(&taken.at) {
+                match previous {
+                    Some(time) => usage.last_signatures.insert(taken.fingerprint, time),
+                    None => usage.last_signatures.remove(&taken.fingerprint),
+                };
+            }
+        }
+    }
+}
+
+impl Policy {
+    pub fn new(keys: Vec<KeyConfig>) -> Self {
+        Self {
+            keys,
+            usage: Default::default(),
+        }
+    }
+
+    fn key_config(&self, key: &KeyData) -> Option<&KeyConfig> {
+        self.keys
+            .iter()
+            .find(|config| key.fingerprint(config.fingerprint.algorithm()) == config.fingerprint)
+    }
+
+    /// Counts the signatures `previous` counts toward quotas and
+    /// cooldowns, e.g. those made before the config was reloaded and those
+    /// still being made.
+    pub fn take_signatures(&mut self, previous: &Policy) {
+        self.usage = previous.usage.clone();
+    }
+
+    /// The configured keys, with how much of their quotas and cooldowns is
+    /// taken.
+    pub fn key_states(&self) -> Vec<KeyState> {
+        let now = Instant::now();
+        let usage = self.usage.lock().unwrap();
+        self.keys
+            .iter()
+            .map(|config| KeyState {
+                config: config.clone(),
+                signatures_this_hour: usage.signatures.get(&config.fingerprint).map_or(0, |times| {
+                    times
+                        .iter()
+                        .filter(|&&time| now.duration_since(time) < QUOTA_WINDOW)
+                        .count()
+                }),
+                last_signed: usage.last_signatures.get(&config.fingerprint).copied(),
+            })
+            .collect()
+    }
+
+    /// The program that must approve signatures with `key`, if any.
+    pub fn approver(&self, key: &KeyData) -> Option<&Path> {
+        self.key_config(key)?.exec.as_deref()
+    }
+
+    /// Checks whether `key` may produce a signature now, in a session bound
+    /// to `bound_hosts`, reserving it a place in the key's quota and
+    /// cooldown until it is made or given up.
+    pub fn check_sign(
+        &self,
+        key: &KeyData,
+        bound_hosts: &[KeyData],
+    ) -> Result<Reservation, Denial> {
+        let Some(config) = self.key_config(key) else {
+            return Ok(Reservation { taken: None });
+        };
+
+        if !config.allowed_times.is_empty() {
+            let (day, minute) = local_time();
+            if !config
+                .allowed_times
+                .iter()
+                .any(|window| window.contains(day, minute))
+            {
+                return Err(Denial::OutsideAllowedTimes);
+            }
+        }
+        if config.require_session_bind && bound_hosts.is_empty() {
+            return Err(Denial::Unbound);
+        }
+        // Otherwise unbound sessions are taken for local use, as for
+        // `destinations`.
+        if !config.allowed_host_keys.is_empty() {
+            if let Some(host_key) = bound_hosts.iter().find(|host_key| {
+                !config
+                    .allowed_host_keys
+                    .iter()
+                    .any(|allowed| host_key.fingerprint(allowed.algorithm()) == *allowed)
+            }) {
+                return Err(Denial::Destination {
+                    host_key: host_key.fingerprint(HashAlg::Sha256),
+                });
+            }
+        }
+
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(cooldown) = config.cooldown {
+            if usage
+                .last_signatures
+                .get(&config.fingerprint)
+                .is_some_and(|&time| now.duration_since(time) < cooldown)
+            {
+                return Err(Denial::Cooldown { cooldown });
+            }
+        }
+        if let Some(limit) = config.max_signatures_per_hour {
+            let times = usage.signatures.entry(config.fingerprint).or_default();
+            while times
+                .front()
+                .is_some_and(|&time| now.duration_since(time) >= QUOTA_WINDOW)
+            {
+                times.pop_front();
+            }
+            if times.len() >= limit as usize {
+                return Err(Denial::QuotaExceeded { limit });
+            }
+            times.push_back(now);
+        }
+        let cooldown = config
+            .cooldown
+            .map(|_| usage.last_signatures.insert(config.fingerprint, now));
+
+        Ok(Reservation {
+            taken: Some(Taken {
+                usage: self.usage.clone(),
+                fingerprint: config.fingerprint,
+                at: now,
+                quota: config.max_signatures_per_hour.is_some(),
+                cooldown,
+            }),
+        })
+    }
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn tzset();
+}
+
+/// Loads the local time zone, before the sandbox may hide it.
+pub fn load_time_zone() {
+    // SAFETY: called before the runtime starts any threads.
+    #[cfg(unix)]
+    unsafe {
+        tzset()
+    };
+}
+
+/// The local day of the week, from Monday as 0, and minute of the day.
+/// UTC where the local time zone isn't known.
+pub(crate) fn local_time() -> (u32, u32) {
+    #[cfg(unix)]
+    {
+        // SAFETY: `tm` is only read once `localtime_r` filled it in.
+        let tm = unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm = std::mem::zeroed();
+            if libc::localtime_r(&now, &mut tm).is_null() {
+                None
+            } else {
+                Some(tm)
+            }
+        };
+        if let Some(tm) = tm {
+            return (
+                (tm.tm_wday as u32 + 6) % 7,
+                tm.tm_hour as u32 * 60 + tm.tm_min as u32,
+            );
+        }
+    }
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = seconds / (24 * 60 * 60);
+    // 1970-01-01 was a Thursday.
+    (
+        ((days + 3) % 7) as u32,
+        (seconds % (24 * 60 * 60) / 60) as u32,
+    )
+}
@@ -0,0 +1,336 @@
+//! Recording the agent protocol messages of client sessions, and replaying
+//! them to an agent or through the mux, to make protocol bugs reproducible,
+//! e.g. from a recording attached to an issue report.
+//!
+//! A recording is text, a line per message:
+//! `<milliseconds> <session>.<request> <direction> <hex>`, the direction
+//! being `>` for requests and `<` for responses, numbered in the order
+//! received and followed by a comment naming the message.  `#` starts a
+//! comment.  Secrets are replaced before recording: keys added by a
+//! placeholder Ed25519 key, keeping their comments and constraints, and
+//! passphrases and PINs by `redacted`.  Messages the mux can't decode aren't
+//! recorded.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{AddIdentity, AddIdentityConstrained, Credential, Request, Response, SmartcardKey},
+    ssh_encoding::{Decode, Encode},
+};
+use ssh_key::private::{Ed25519Keypair, KeypairData};
+
+use crate::client::Client;
+use crate::error::Error;
+use crate::logging::{request_name, response_name};
+use crate::serve::Handler;
+use crate::MuxAgentBind;
+
+const HEADER: &str = "# ssh-agent-mux recording";
+
+/// Replaces passphrases and PINs.
+const REDACTED: &str = "redacted";
+
+struct Recorder {
+    file: LineWriter<File>,
+    started_at: Instant,
+}
+
+static RECORDER: OnceLock<Mutex<Recorder>> = OnceLock::new();
+
+/// Records the messages of every session from now on to the file at `path`,
+/// replacing it.
+pub fn start(path: &Path) -> io::Result<()> {
+    let mut file = LineWriter::new(File::create(path)?);
+    writeln!(file, "{HEADER}")?;
+    let recorder = Recorder {
+        file,
+        started_at: Instant::now(),
+    };
+    if RECORDER.set(Mutex::new(recorder)).is_err() {
+        return Err(io::Error::other("already recording"));
+    }
+    Ok(())
+}
+
+/// A client session being recorded.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Session(u64);
+
+impl Session {
+    /// Session `id`, if recording.
+    pub(crate) fn new(id: u64) -> Option<Self> {
+        RECORDER.get()?;
+        Some(Self(id))
+    }
+
+    /// Records the session's request number `n`, without its secrets.
+    pub(crate) fn request(self, n: u64, request: &Request) {
+        let request = redact(request);
+        let mut message = Vec::new();
+        if let Err(e) = request.encode(&mut message) {
+            log::warn!("Failed to record a request: {e}");
+            return;
+        }
+        self.write(n, '>', &message, request_name(&request));
+    }
+
+    /// Records the response to the session's request number `n`.
+    pub(crate) fn response(self, n: u64, response: &Response) {
+        let mut message = Vec::new();
+        if let Err(e) = response.encode(&mut message) {
+            log::warn!("Failed to record a response: {e}");
+            return;
+        }
+        self.write(n, '<', &message, response_name(response));
+    }
+
+    fn write(self, n: u64, direction: char, message: &[u8], name: &str) {
+        let Some(recorder) = RECORDER.get() else {
+            return;
+        };
+        let mut recorder = recorder.lock().unwrap();
+        let elapsed = recorder.started_at.elapsed().as_millis();
+        let mut line = format!("{elapsed} {}.{n} {direction} ", self.0);
+        for byte in message {
+            line += &format!("{byte:02x}");
+        }
+        if let Err(e) = writeln!(recorder.file, "{line}  # {name}") {
+            log::warn!("Failed to record a message: {e}");
+        }
+    }
+}
+
+/// `request` with placeholders for its secrets.
+fn redact(request: &Request) -> Request {
+    let placeholder = |credential: &Credential| {
+        let comment = match credential {
+            Credential::Key { comment, .. } | Credential::Cert { comment, .. } => comment,
+        };
+        Credential::Key {
+            privkey: KeypairData::Ed25519(Ed25519Keypair::from_seed(&[0; 32])),
+            comment: comment.clone(),
+        }
+    };
+    let smartcard_key = |key: &SmartcardKey| SmartcardKey {
+        id: key.id.clone(),
+        pin: REDACTED.to_owned().into(),
+    };
+    match request {
+        Request::AddIdentity(AddIdentity { credential }) => Request::AddIdentity(AddIdentity {
+            credential: placeholder(credential),
+        }),
+        Request::AddIdConstrained(AddIdentityConstrained {
+            identity: AddIdentity { credential },
+            constraints,
+        }) => Request::AddIdConstrained(AddIdentityConstrained {
+            identity: AddIdentity {
+                credential: placeholder(credential),
+            },
+            constraints: constraints.clone(),
+        }),
+        Request::AddSmartcardKey(key) => Request::AddSmartcardKey(smartcard_key(key)),
+        Request::RemoveSmartcardKey(key) => Request::RemoveSmartcardKey(smartcard_key(key)),
+        Request::AddSmartcardKeyConstrained(constrained) => {
+            let mut constrained = constrained.clone();
+            constrained.key = smartcard_key(&constrained.key);
+            Request::AddSmartcardKeyConstrained(constrained)
+        }
+        Request::Lock(_) => Request::Lock(REDACTED.to_owned()),
+        Request::Unlock(_) => Request::Unlock(REDACTED.to_owned()),
+        request => request.clone(),
+    }
+}
+
+/// A request of a recording, and the response recorded, if any.
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    /// Of the session, from 1.
+    pub n: u64,
+    pub request: Request,
+    pub response: Option<Response>,
+}
+
+/// The sessions of a recording, by number, with their requests in the order
+/// received.
+#[derive(Clone, Debug, Default)]
+pub struct Recording(pub BTreeMap<u64, Vec<Exchange>>);
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Recording {
+    pub fn parse(recording: &str) -> Result<Self, ParseError> {
+        /// The request and response numbered alike, and the line of the
+        /// response.
+        type Messages = (Option<Request>, Option<Response>, usize);
+        let mut sessions: BTreeMap<u64, BTreeMap<u64, Messages>> = BTreeMap::new();
+        for (i, line) in recording.lines().enumerate() {
+            let error = |message: String| ParseError {
+                line: i + 1,
+                message,
+            };
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let [_, number, direction, hex] = line.split_whitespace().collect::<Vec<_>>()[..]
+            else {
+                return Err(error(
+                    "expected a time, message number, direction and message".to_owned(),
+                ));
+            };
+            let (session, n) = number
+                .split_once('.')
+                .and_then(|(session, n)| Some((session.parse().ok()?, n.parse().ok()?)))
+                .ok_or_else(|| error(format!("invalid message number {number:?}")))?;
+            let message = decode_hex(hex).ok_or_else(|| error("invalid hex".to_owned()))?;
+            let messages = sessions.entry(session).or_default().entry(n).or_default();
+            match direction {
+                ">" => {
+                    let request = Request::decode(&mut &message[..])
+                        .map_err(|e| error(format!("invalid request: {e}")))?;
+                    messages.0 = Some(request);
+                }
+                "<" => {
+                    let response = Response::decode(&mut &message[..])
+                        .map_err(|e| error(format!("invalid response: {e}")))?;
+                    messages.1 = Some(response);
+                    messages.2 = i + 1;
+                }
+                _ => return Err(error(format!("invalid direction {direction:?}"))),
+            }
+        }
+        let mut recording = Recording::default();
+        for (session, messages) in sessions {
+            let mut exchanges = Vec::new();
+            for (n, (request, response, line)) in messages {
+                let request = request.ok_or_else(|| ParseError {
+                    line,
+                    message: format!("response {session}.{n} has no request"),
+                })?;
+                exchanges.push(Exchange {
+                    n,
+                    request,
+                    response,
+                });
+            }
+            recording.0.insert(session, exchanges);
+        }
+        Ok(recording)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // An odd digit out is `None` too.
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Where a recording is replayed.
+pub enum Destination<'a> {
+    /// An agent, e.g. a target, connected to once per session.
+    Agent(&'a Binding),
+    /// The mux, with a session of its own per session.
+    Mux(&'a mut MuxAgentBind),
+}
+
+/// A request replayed.
+#[derive(Debug)]
+pub struct Replayed {
+    pub session: u64,
+    pub n: u64,
+    pub request: Request,
+    pub recorded: Option<Response>,
+    pub response: Result<Response, AgentError>,
+}
+
+impl Replayed {
+    /// Whether the response differs from the one recorded, if any.
+    pub fn differs(&self) -> bool {
+        match (&self.recorded, &self.response) {
+            (Some(recorded), Ok(response)) => recorded != response,
+            (Some(_), Err(_)) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+impl fmt::Display for Replayed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{} {}: ",
+            self.session,
+            self.n,
+            request_name(&self.request)
+        )?;
+        match &self.response {
+            Ok(response) => f.write_str(response_name(response))?,
+            Err(e) => write!(f, "error: {e}")?,
+        }
+        match &self.recorded {
+            Some(recorded) if self.differs() => {
+                write!(f, ", unlike the recorded {}", response_name(recorded))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+enum Peer {
+    Agent(Client),
+    Mux(Box<dyn Handler>),
+}
+
+/// Sends the requests of each session of `recording` in turn to
+/// `destination`, each once the previous one is answered.
+pub async fn replay(
+    recording: &Recording,
+    mut destination: Destination<'_>,
+) -> Result<Vec<Replayed>, Error> {
+    let mut replayed = Vec::new();
+    for (&session, exchanges) in &recording.0 {
+        let mut peer = match &mut destination {
+            Destination::Agent(binding) => Peer::Agent(
+                (*binding)
+                    .clone()
+                    .try_into()
+                    .and_then(Client::connect)
+                    .map_err(|source| Error::Replay {
+                        target: (*binding).clone(),
+                        source: source.into(),
+                    })?,
+            ),
+            Destination::Mux(mux) => Peer::Mux(mux.replay_session()),
+        };
+        for exchange in exchanges {
+            let request = exchange.request.clone();
+            let response = match &mut peer {
+                Peer::Agent(client) => client.handle(request).await,
+                Peer::Mux(session) => session.handle(request).await,
+            };
+            replayed.push(Replayed {
+                session,
+                n: exchange.n,
+                request: exchange.request.clone(),
+                recorded: exchange.response.clone(),
+                response,
+            });
+        }
+    }
+    Ok(replayed)
+}
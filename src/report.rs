@@ -0,0 +1,198 @@
+//! Usage reports from the audit log, for periodic access reviews.
+//!
+//! Signatures made and denied, including attempts with canary keys, are
+//! summed up by key, by target and by client, as text or as a JSON object.
+//! Other events are left out.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::audit::json_string;
+use crate::logging;
+
+/// Clients of events that don't name one, e.g. those of a mux serving
+/// stdio.
+const UNKNOWN_CLIENT: &str = "unknown";
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+/// The fields of an audit event that reports sum up.
+#[derive(Deserialize)]
+struct Event {
+    event: Option<String>,
+    time: Option<u64>,
+    key: Option<String>,
+    client: Option<String>,
+    target: Option<String>,
+}
+
+/// Signatures of a key, target or client.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub signatures: u64,
+    pub denied: u64,
+    /// When it last signed or was denied, in seconds since the Unix epoch.
+    pub last_seen: u64,
+}
+
+impl Usage {
+    fn add(&mut self, denied: bool, time: u64) {
+        if denied {
+            self.denied += 1;
+        } else {
+            self.signatures += 1;
+        }
+        self.last_seen = self.last_seen.max(time);
+    }
+
+    fn to_json(self) -> String {
+        format!(
+            "{{\"signatures\":{},\"denied\":{},\"last_seen\":{}}}",
+            self.signatures, self.denied, self.last_seen
+        )
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} signatures", self.signatures)?;
+        if self.denied > 0 {
+            write!(f, ", {} denied", self.denied)?;
+        }
+        write!(f, ", last {}", rfc3339(self.last_seen))
+    }
+}
+
+/// Usage since a point in time.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// In seconds since the Unix epoch.
+    pub since: u64,
+    pub total: Usage,
+    /// By SHA-256 fingerprint.
+    pub keys: BTreeMap<String, Usage>,
+    /// Denied signatures have no target, so only count toward the keys and
+    /// clients.
+    pub targets: BTreeMap<String, Usage>,
+    /// By user of Unix socket clients, e.g. `uid 1000`, or address of TCP
+    /// ones.
+    pub clients: BTreeMap<String, Usage>,
+}
+
+impl Report {
+    /// Sums up the events of the audit log `log` at or after `since`, in
+    /// seconds since the Unix epoch.
+    pub fn new(log: &str, since: u64) -> Result<Self, Error> {
+        let mut report = Report {
+            since,
+            ..Default::default()
+        };
+        for (i, line) in log.lines().enumerate() {
+            let error = |message: String| Error {
+                line: i + 1,
+                message,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: Event = serde_json::from_str(line).map_err(|e| error(e.to_string()))?;
+            let denied = match event.event.as_deref() {
+                Some("key_used") => false,
+                Some("sign_denied" | "canary_used") => true,
+                Some(_) => continue,
+                None => return Err(error("not an audit event".to_owned())),
+            };
+            let time = event
+                .time
+                .ok_or_else(|| error("'time' is missing".to_owned()))?;
+            if time < since {
+                continue;
+            }
+            let key = event
+                .key
+                .ok_or_else(|| error("'key' is missing".to_owned()))?;
+            let client = event
+                .client
+                .as_deref()
+                .filter(|client| !client.is_empty())
+                .unwrap_or(UNKNOWN_CLIENT);
+            report.total.add(denied, time);
+            add(&mut report.keys, &key, denied, time);
+            add(&mut report.clients, client, denied, time);
+            if let Some(target) = &event.target {
+                add(&mut report.targets, target, denied, time);
+            }
+        }
+        Ok(report)
+    }
+
+    /// The report as a JSON object: the totals and `since`, along with
+    /// `keys`, `targets` and `clients` objects of the usage of each.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"since\":{},\"signatures\":{},\"denied\":{}",
+            self.since, self.total.signatures, self.total.denied
+        );
+        for (name, usages) in self.sections() {
+            let _ = write!(json, ",{}:{{", json_string(name));
+            for (i, (id, usage)) in usages.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                let _ = write!(json, "{}:{}", json_string(id), usage.to_json());
+            }
+            json.push('}');
+        }
+        json.push('}');
+        json
+    }
+
+    fn sections(&self) -> [(&'static str, &BTreeMap<String, Usage>); 3] {
+        [
+            ("keys", &self.keys),
+            ("targets", &self.targets),
+            ("clients", &self.clients),
+        ]
+    }
+}
+
+fn add(usages: &mut BTreeMap<String, Usage>, id: &str, denied: bool, time: u64) {
+    usages.entry(id.to_owned()).or_default().add(denied, time);
+}
+
+fn rfc3339(time: u64) -> humantime::Rfc3339Timestamp {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(time))
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Since {}: {} signatures, {} denied",
+            rfc3339(self.since),
+            self.total.signatures,
+            self.total.denied
+        )?;
+        for (name, usages) in self.sections() {
+            writeln!(f, "\n{}{}:", name[..1].to_uppercase(), &name[1..])?;
+            if usages.is_empty() {
+                writeln!(f, "  none")?;
+            }
+            for (id, usage) in usages {
+                match name {
+                    "keys" => writeln!(f, "  {}: {usage}", logging::Fingerprint(id))?,
+                    _ => writeln!(f, "  {id}: {usage}")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+//! Optional confinement applied once the host socket is bound.
+//!
+//! This runs before the async runtime starts so that confinement which only
+//! applies to the calling thread, like Landlock, covers every thread.
+
+#[cfg(target_os = "freebsd")]
+mod capsicum;
+#[cfg(target_os = "linux")]
+mod landlock;
+#[cfg(target_os = "openbsd")]
+mod pledge;
+#[cfg(unix)]
+mod privileges;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp;
+
+use std::io;
+use std::path::Path;
+
+use service_binding::Binding;
+
+use crate::config::SandboxConfig;
+
+/// What the mux still needs to reach once confined.
+pub struct Paths<'a> {
+    /// `None` when serving over stdio.
+    pub host: Option<&'a Binding>,
+    /// The hosts of views.
+    pub view_hosts: Vec<&'a Binding>,
+    /// Only needed by unveil and Capsicum; Landlock doesn't govern connecting
+    /// to sockets.
+    #[cfg_attr(
+        not(any(target_os = "openbsd", target_os = "freebsd")),
+        allow(dead_code)
+    )]
+    pub targets: Vec<&'a Binding>,
+    /// Directories of Unix socket targets, which may come and go.
+    pub target_dirs: Vec<&'a Path>,
+    pub config: Option<&'a Path>,
+    /// 1Password's `agent.toml`, read again with the config.
+    pub agent_toml: Option<&'a Path>,
+    pub event_socket: Option<&'a Path>,
+    /// The sockets of the HTTP and gRPC admin endpoints.
+    pub admin_sockets: Vec<&'a Path>,
+    pub usage_file: Option<&'a Path>,
+    pub key_cache: Option<&'a Path>,
+    pub log_file: Option<&'a Path>,
+    /// Whether targets or the webhook are found by name, through DNS.
+    pub resolves_names: bool,
+}
+
+/// What the mux was set up to do beyond serving clients, for the seccomp
+/// filter to allow the syscalls it takes.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(
+    not(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    )),
+    allow(dead_code)
+)]
+struct SeccompUses {
+    /// Replacing files, e.g. the key cache, and rotating the log file.
+    write_files: bool,
+    /// Binding the views clients create next to a host socket file.
+    create_views: bool,
+    /// Looking up targets and the webhook found by name.
+    resolve_names: bool,
+}
+
+impl SeccompUses {
+    fn of(paths: &Paths) -> Self {
+        Self {
+            write_files: paths.usage_file.is_some()
+                || paths.key_cache.is_some()
+                || paths.log_file.is_some(),
+            create_views: matches!(paths.host, Some(Binding::FilePath(_))),
+            resolve_names: paths.resolves_names,
+        }
+    }
+}
+
+pub fn apply(config: &SandboxConfig, paths: &Paths) -> io::Result<()> {
+    if config.chroot.is_some() && config.user.is_none() {
+        // Root can leave a chroot.
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a chroot requires switching to another user",
+        ));
+    }
+    // Time-window policies need the local time zone, which may be out of
+    // reach once confined.
+    crate::policy::load_time_zone();
+    // Dropping privileges needs syscalls and files that the other
+    // confinement removes, so it comes first.
+    if config.user.is_some() || config.group.is_some() {
+        drop_privileges(config, paths)?;
+    }
+    // Files are still replaced by the handles opened before the chroot.
+    let seccomp_uses = SeccompUses::of(paths);
+    // Nothing outside the chroot is reachable by path anymore.
+    let chrooted_paths;
+    let paths = match config.chroot {
+        Some(_) => {
+            chrooted_paths = Paths {
+                host: None,
+                view_hosts: Vec::new(),
+                targets: paths.targets.clone(),
+                target_dirs: paths.target_dirs.clone(),
+                config: None,
+                agent_toml: None,
+                event_socket: None,
+                admin_sockets: Vec::new(),
+                usage_file: None,
+                key_cache: None,
+                log_file: None,
+                resolves_names: paths.resolves_names,
+            };
+            &chrooted_paths
+        }
+        None => paths,
+    };
+    if config.landlock {
+        restrict_filesystem(paths)?;
+    }
+    if config.pledge {
+        pledge(paths)?;
+    }
+    if let Some(mode) = config.seccomp {
+        install_seccomp(mode, seccomp_uses)?;
+        log::info!("Installed seccomp filter ({mode:?})");
+    }
+    if config.capsicum {
+        enter_capability_mode(paths)?;
+    }
+    Ok(())
+}
+
+/// Connects to a target, through what was opened for it beforehand when
+/// the sandbox took away paths.
+pub(crate) fn connect(binding: Binding) -> io::Result<service_binding::Stream> {
+    #[cfg(target_os = "freebsd")]
+    if let Binding::FilePath(path) = &binding {
+        if let Some(stream) = capsicum::connect(path) {
+            return stream.map(service_binding::Stream::Unix);
+        }
+    }
+    binding.try_into()
+}
+
+#[cfg(unix)]
+fn drop_privileges(config: &SandboxConfig, paths: &Paths) -> io::Result<()> {
+    let user = config
+        .user
+        .as_deref()
+        .map(privileges::lookup_user)
+        .transpose()?;
+    let gid = config
+        .group
+        .as_deref()
+        .map(privileges::lookup_group)
+        .transpose()?;
+
+    for host in paths.host.iter().chain(&paths.view_hosts) {
+        if let Binding::FilePath(path) = host {
+            privileges::chown(
+                path,
+                user.as_ref().map(|user| user.uid),
+                gid.or(user.as_ref().and_then(|user| user.gid)),
+            )?;
+        }
+    }
+    privileges::drop(user.as_ref(), gid, config.chroot.as_deref())?;
+
+    // SAFETY: getuid and getgid always succeed.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    log::info!("Dropped privileges to uid {uid}, gid {gid}");
+    if let Some(dir) = &config.chroot {
+        log::info!("Confined to {}", dir.display());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_config: &SandboxConfig, _paths: &Paths) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "dropping privileges is only supported on Unix",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn restrict_filesystem(paths: &Paths) -> io::Result<()> {
+    let mut rules = landlock::Rules {
+        read_files: paths.config.into_iter().chain(paths.agent_toml).collect(),
+        read_dirs: paths.target_dirs.clone(),
+        socket_dirs: Vec::new(),
+        write_dirs: paths
+            .usage_file
+            .into_iter()
+            .chain(paths.key_cache)
+            .chain(paths.log_file)
+            .filter_map(Path::parent)
+            .collect(),
+    };
+    for host in paths.host.iter().chain(&paths.view_hosts) {
+        if let Binding::FilePath(path) = host {
+            rules.socket_dirs.extend(path.parent());
+        }
+    }
+    rules
+        .socket_dirs
+        .extend(paths.event_socket.and_then(Path::parent));
+    rules
+        .socket_dirs
+        .extend(paths.admin_sockets.iter().filter_map(|path| path.parent()));
+
+    if landlock::restrict(&rules)? {
+        log::info!("Restricted filesystem access with Landlock");
+    } else {
+        log::warn!("Landlock is not available; filesystem access is not restricted");
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restrict_filesystem(_paths: &Paths) -> io::Result<()> {
+    log::warn!("Landlock is only available on Linux; filesystem access is not restricted");
+    Ok(())
+}
+
+#[cfg(target_os = "openbsd")]
+fn pledge(paths: &Paths) -> io::Result<()> {
+    pledge::restrict(paths)?;
+    log::info!("Pledged and unveiled");
+    Ok(())
+}
+
+#[cfg(not(target_os = "openbsd"))]
+fn pledge(_paths: &Paths) -> io::Result<()> {
+    log::warn!("pledge and unveil are only available on OpenBSD; the process is not restricted");
+    Ok(())
+}
+
+#[cfg(target_os = "freebsd")]
+fn enter_capability_mode(paths: &Paths) -> io::Result<()> {
+    capsicum::enter(paths)?;
+    log::info!("Entered Capsicum capability mode");
+    Ok(())
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn enter_capability_mode(_paths: &Paths) -> io::Result<()> {
+    log::warn!("Capsicum is only available on FreeBSD; the process is not restricted");
+    Ok(())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use seccomp::install as install_seccomp;
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn install_seccomp(_mode: crate::config::SeccompMode, _uses: SeccompUses) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "seccomp is only supported on Linux x86_64 and aarch64",
+    ))
+}
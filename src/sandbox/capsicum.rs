@@ -0,0 +1,161 @@
+//! FreeBSD Capsicum capability mode.
+//!
+//! Capability mode takes away every global namespace, so the directories of
+//! Unix socket targets are opened beforehand and targets are connected to
+//! with connectat(2) relative to them.  TCP targets can't be connected to at
+//! all.
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use service_binding::Binding;
+
+use super::Paths;
+
+extern "C" {
+    fn connectat(
+        fd: libc::c_int,
+        s: libc::c_int,
+        name: *const libc::sockaddr,
+        namelen: libc::socklen_t,
+    ) -> libc::c_int;
+}
+
+/// Directories of Unix socket targets, opened before entering capability
+/// mode.
+static TARGET_DIRS: OnceLock<Vec<(PathBuf, OwnedFd)>> = OnceLock::new();
+
+fn check(result: libc::c_int, what: &str) -> io::Result<libc::c_int> {
+    if result < 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(e.kind(), format!("{what}: {e}")));
+    }
+    Ok(result)
+}
+
+pub fn enter(paths: &Paths) -> io::Result<()> {
+    if !paths.target_dirs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "target directories can't be scanned in capability mode",
+        ));
+    }
+    if paths.usage_file.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the usage file can't be written in capability mode",
+        ));
+    }
+    if paths.key_cache.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the key cache can't be written in capability mode",
+        ));
+    }
+    if paths.log_file.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the log file can't be rotated in capability mode",
+        ));
+    }
+    let mut dirs: Vec<(PathBuf, OwnedFd)> = Vec::new();
+    for target in &paths.targets {
+        let Binding::FilePath(path) = target else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "only Unix socket targets can be reached in capability mode, not {target:?}"
+                ),
+            ));
+        };
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        if dirs.iter().any(|(opened, _)| opened == dir) {
+            continue;
+        }
+        let c_dir = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: `c_dir` is a valid NUL-terminated string.
+        let fd = check(
+            unsafe {
+                libc::open(
+                    c_dir.as_ptr(),
+                    libc::O_DIRECTORY | libc::O_CLOEXEC | libc::O_RDONLY,
+                )
+            },
+            &dir.display().to_string(),
+        )?;
+        // SAFETY: `fd` was just opened and is owned by nothing else.
+        dirs.push((dir.to_owned(), unsafe { OwnedFd::from_raw_fd(fd) }));
+    }
+    let _ = TARGET_DIRS.set(dirs);
+
+    // SAFETY: cap_enter takes no arguments.
+    check(unsafe { libc::cap_enter() }, "cap_enter")?;
+    Ok(())
+}
+
+/// Connects to the Unix socket at `path` through its opened directory, or
+/// `None` outside capability mode.
+pub fn connect(path: &Path) -> Option<io::Result<UnixStream>> {
+    let dirs = TARGET_DIRS.get()?;
+    Some(connect_in(dirs, path))
+}
+
+fn connect_in(dirs: &[(PathBuf, OwnedFd)], path: &Path) -> io::Result<UnixStream> {
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let (_, dir_fd) = dirs
+        .iter()
+        .find(|(opened, _)| opened == dir)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} wasn't opened before entering capability mode",
+                    dir.display()
+                ),
+            )
+        })?;
+    let name = path.file_name().unwrap_or_default().as_bytes();
+
+    // SAFETY: `sockaddr_un` is plain old data; all-zero is a valid value.
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    if name.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: socket name too long", path.display()),
+        ));
+    }
+    for (dst, &src) in addr.sun_path.iter_mut().zip(name) {
+        *dst = src as libc::c_char;
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    addr.sun_len = std::mem::size_of::<libc::sockaddr_un>() as u8;
+
+    // SAFETY: socket takes no pointers.
+    let socket = check(
+        unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) },
+        "socket",
+    )?;
+    // SAFETY: `socket` was just created and is owned by nothing else.
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+    // SAFETY: `addr` is a valid `sockaddr_un` of the given size.
+    check(
+        unsafe {
+            connectat(
+                dir_fd.as_raw_fd(),
+                socket.as_raw_fd(),
+                std::ptr::addr_of!(addr).cast(),
+                std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+            )
+        },
+        &path.display().to_string(),
+    )?;
+    let stream = UnixStream::from(socket);
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
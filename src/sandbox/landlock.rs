@@ -0,0 +1,174 @@
+//! Landlock filesystem confinement.
+//!
+//! Once applied, the process can only read the config file, list target
+//! directories, create or remove the host socket and replace the usage
+//! file.  Connecting to Unix sockets is not governed by
+//! Landlock's filesystem rights, so targets stay reachable.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const CREATE_RULESET_VERSION: u32 = 1 << 0;
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+/// `LANDLOCK_ACCESS_FS_REFER`, added in ABI 2.
+const ACCESS_FS_REFER: u64 = 1 << 13;
+/// `LANDLOCK_ACCESS_FS_TRUNCATE`, added in ABI 3.
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+/// `LANDLOCK_ACCESS_FS_IOCTL_DEV`, added in ABI 5.
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: libc::c_int,
+}
+
+/// Filesystem access the process keeps.
+pub struct Rules<'a> {
+    /// Files that may be read.
+    pub read_files: Vec<&'a Path>,
+    /// Directories that may be listed.
+    pub read_dirs: Vec<&'a Path>,
+    /// Directories in which sockets may be created and removed.
+    pub socket_dirs: Vec<&'a Path>,
+    /// Directories in which files may be created, written and replaced.
+    pub write_dirs: Vec<&'a Path>,
+}
+
+/// Returns the Landlock ABI version, or `None` if Landlock is unavailable.
+fn abi_version() -> Option<i64> {
+    // SAFETY: querying the version takes no pointers.
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    (version > 0).then_some(version)
+}
+
+fn handled_access(abi: i64) -> u64 {
+    // All rights of ABI 1.
+    let mut access = (1 << 13) - 1;
+    if abi >= 2 {
+        access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_FS_TRUNCATE;
+    }
+    if abi >= 5 {
+        access |= ACCESS_FS_IOCTL_DEV;
+    }
+    access
+}
+
+fn open_path(path: &Path) -> io::Result<libc::c_int> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_path` is a valid NUL-terminated string.
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+fn add_rule(ruleset: libc::c_int, path: &Path, allowed_access: u64) -> io::Result<()> {
+    let parent_fd = open_path(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("landlock: {}: {e}", path.display())))?;
+    let attr = PathBeneathAttr {
+        allowed_access,
+        parent_fd,
+    };
+    // SAFETY: `attr` is a valid path-beneath rule that outlives the call.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset,
+            RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr,
+            0,
+        )
+    };
+    // SAFETY: `parent_fd` was opened above and is not used afterwards.
+    unsafe { libc::close(parent_fd) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Restricts the calling thread, and threads it spawns afterwards, to
+/// `rules`.  Returns `false` if the kernel doesn't support Landlock.
+pub fn restrict(rules: &Rules) -> io::Result<bool> {
+    let Some(abi) = abi_version() else {
+        return Ok(false);
+    };
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled_access(abi),
+    };
+    // SAFETY: `attr` is a valid ruleset attribute of the given size.
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = ruleset as libc::c_int;
+
+    let result = (|| {
+        for path in &rules.read_files {
+            add_rule(ruleset, path, ACCESS_FS_READ_FILE)?;
+        }
+        for path in &rules.read_dirs {
+            add_rule(ruleset, path, ACCESS_FS_READ_DIR)?;
+        }
+        for path in &rules.socket_dirs {
+            add_rule(ruleset, path, ACCESS_FS_MAKE_SOCK | ACCESS_FS_REMOVE_FILE)?;
+        }
+        for path in &rules.write_dirs {
+            let access = ACCESS_FS_WRITE_FILE
+                | ACCESS_FS_MAKE_REG
+                | ACCESS_FS_REMOVE_FILE
+                | ACCESS_FS_TRUNCATE;
+            add_rule(ruleset, path, access & handled_access(abi))?;
+        }
+
+        // SAFETY: prctl and landlock_restrict_self take no pointers here.
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    })();
+
+    // SAFETY: the ruleset fd is not used after this point.
+    unsafe { libc::close(ruleset) };
+    result.map(|()| true)
+}
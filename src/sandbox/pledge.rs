@@ -0,0 +1,86 @@
+//! OpenBSD pledge(2) and unveil(2).
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use service_binding::Binding;
+
+use super::Paths;
+
+/// `unix` and `inet` cover serving clients and connecting to targets, `rpath`
+/// re-reading the config and `cpath` removing the host and event sockets.
+const PROMISES: &str = "stdio unix inet rpath cpath";
+/// With a usage file or key cache, which are written and replaced.
+const WRITE_PROMISES: &str = "stdio unix inet rpath wpath cpath";
+
+fn c_string(s: &[u8]) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn unveil(path: &Path, permissions: &str) -> io::Result<()> {
+    let c_path = c_string(path.as_os_str().as_bytes())?;
+    let c_permissions = c_string(permissions.as_bytes())?;
+    // SAFETY: both arguments are valid NUL-terminated strings.
+    if unsafe { libc::unveil(c_path.as_ptr(), c_permissions.as_ptr()) } != 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            e.kind(),
+            format!("unveil: {}: {e}", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+pub fn restrict(paths: &Paths) -> io::Result<()> {
+    for file in paths.config.into_iter().chain(paths.agent_toml) {
+        unveil(file, "r")?;
+    }
+    for host in paths.host.iter().chain(&paths.view_hosts) {
+        if let Binding::FilePath(path) = host {
+            if let Some(dir) = path.parent() {
+                unveil(dir, "c")?;
+            }
+        }
+    }
+    if let Some(dir) = paths.event_socket.and_then(Path::parent) {
+        unveil(dir, "c")?;
+    }
+    for dir in paths.admin_sockets.iter().filter_map(|path| path.parent()) {
+        unveil(dir, "c")?;
+    }
+    // Connecting to a Unix socket needs write permission on its path.
+    for target in &paths.targets {
+        if let Binding::FilePath(path) = target {
+            unveil(path, "rw")?;
+        }
+    }
+    for dir in &paths.target_dirs {
+        unveil(dir, "rw")?;
+    }
+    let mut promises = PROMISES;
+    for dir in paths
+        .usage_file
+        .into_iter()
+        .chain(paths.key_cache)
+        .chain(paths.log_file)
+        .filter_map(Path::parent)
+    {
+        unveil(dir, "rwc")?;
+        promises = WRITE_PROMISES;
+    }
+
+    let promises = c_string(promises.as_bytes())?;
+    // SAFETY: null arguments lock unveil and leave execpromises unchanged;
+    // `promises` is a valid NUL-terminated string.
+    unsafe {
+        if libc::unveil(std::ptr::null(), std::ptr::null()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::pledge(promises.as_ptr(), std::ptr::null()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,183 @@
+//! Dropping root privileges after the host socket is bound, optionally into
+//! a chroot.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+fn c_string(s: &str) -> io::Result<CString> {
+    CString::new(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no such {kind} '{name}'"))
+}
+
+/// Buffer size for the reentrant passwd/group lookups.
+const LOOKUP_BUFFER_SIZE: usize = 16 * 1024;
+
+pub struct User {
+    pub uid: libc::uid_t,
+    /// Primary group, unless the user has no passwd entry.
+    pub gid: Option<libc::gid_t>,
+    /// Set when the user has a passwd entry, for initializing supplementary
+    /// groups.
+    name: Option<CString>,
+}
+
+/// Looks up a user by name or numeric ID.  Numeric IDs without a passwd
+/// entry, as are common in containers, are accepted as is.
+pub fn lookup_user(user: &str) -> io::Result<User> {
+    let c_user = c_string(user)?;
+    // SAFETY: `passwd` is plain old data; all-zero is a valid value.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+
+    let found = if let Ok(uid) = user.parse::<libc::uid_t>() {
+        // SAFETY: all pointers reference live, correctly sized buffers.
+        unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        }
+    } else {
+        // SAFETY: as above; `c_user` is NUL-terminated.
+        unsafe {
+            libc::getpwnam_r(
+                c_user.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        }
+    };
+    if found != 0 {
+        return Err(io::Error::from_raw_os_error(found));
+    }
+    if result.is_null() {
+        return match user.parse() {
+            Ok(uid) => Ok(User {
+                uid,
+                gid: None,
+                name: None,
+            }),
+            Err(_) => Err(not_found("user", user)),
+        };
+    }
+
+    // SAFETY: on success `pw_name` points at a NUL-terminated string in
+    // `buffer`.
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) }.to_owned();
+    Ok(User {
+        uid: passwd.pw_uid,
+        gid: Some(passwd.pw_gid),
+        name: Some(name),
+    })
+}
+
+/// Looks up a group by name or numeric ID.
+pub fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let c_group = c_string(group)?;
+    // SAFETY: `group` is plain old data; all-zero is a valid value.
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    // SAFETY: all pointers reference live, correctly sized buffers.
+    let found = unsafe {
+        libc::getgrnam_r(
+            c_group.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if found != 0 {
+        return Err(io::Error::from_raw_os_error(found));
+    }
+    if result.is_null() {
+        return Err(not_found("group", group));
+    }
+    Ok(entry.gr_gid)
+}
+
+fn check(result: libc::c_int, what: &str) -> io::Result<()> {
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(e.kind(), format!("{what}: {e}")));
+    }
+    Ok(())
+}
+
+/// Gives the host socket to the user the process is about to become, so it
+/// can still remove it.
+pub fn chown(path: &Path, uid: Option<libc::uid_t>, gid: Option<libc::gid_t>) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // -1 leaves the owner or group unchanged.
+    let uid = uid.unwrap_or(libc::uid_t::MAX);
+    let gid = gid.unwrap_or(libc::gid_t::MAX);
+    // SAFETY: `c_path` is a valid NUL-terminated string.
+    check(unsafe { libc::chown(c_path.as_ptr(), uid, gid) }, "chown")
+}
+
+/// Switches to `user` and/or `gid` permanently, confined to `root` if set.
+/// `gid` defaults to the user's primary group.
+pub fn drop(user: Option<&User>, gid: Option<libc::gid_t>, root: Option<&Path>) -> io::Result<()> {
+    let gid = gid.or(user.and_then(|user| user.gid));
+    if gid.is_none() && user.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a group is required for a user without a passwd entry",
+        ));
+    }
+
+    if let Some(gid) = gid {
+        // SAFETY: the group list and user name outlive the calls.
+        unsafe {
+            match user.and_then(|user| user.name.as_ref()) {
+                #[cfg(not(target_os = "macos"))]
+                Some(name) => check(libc::initgroups(name.as_ptr(), gid), "initgroups")?,
+                #[cfg(target_os = "macos")]
+                Some(name) => check(libc::initgroups(name.as_ptr(), gid as _), "initgroups")?,
+                None => check(libc::setgroups(1, &gid), "setgroups")?,
+            }
+            check(libc::setgid(gid), "setgid")?;
+        }
+    }
+    // After initgroups, which reads the group database, and before setuid,
+    // which gives up the right to chroot.
+    if let Some(root) = root {
+        let c_root = CString::new(root.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: both paths are valid NUL-terminated strings.
+        unsafe {
+            check(libc::chroot(c_root.as_ptr()), "chroot")?;
+            check(libc::chdir(c"/".as_ptr()), "chdir")?;
+        }
+    }
+    if let Some(user) = user {
+        // SAFETY: setuid takes no pointers.
+        unsafe {
+            check(libc::setuid(user.uid), "setuid")?;
+            // Make sure root can't be regained.
+            if user.uid != 0 && libc::setuid(0) == 0 {
+                return Err(io::Error::other(
+                    "privileges could be regained after setuid",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
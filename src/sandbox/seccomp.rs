@@ -0,0 +1,223 @@
+//! seccomp-bpf syscall filter.
+//!
+//! The filter allows the syscalls needed to serve clients after startup:
+//! socket I/O, accepting clients and connecting to targets, the tokio
+//! runtime and memory management, and those of the features set up, like
+//! replacing the key cache or resolving targets by name.  Programs run by
+//! the mux would inherit the filter, so configs running any are refused.  Anything else kills the process, or is only
+//! logged by the kernel in [`SeccompMode::Log`].
+
+use std::io;
+
+use libc::{
+    sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W,
+};
+
+use super::SeccompUses;
+use crate::config::SeccompMode;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscall numbers at or above this are x32 ABI syscalls on x86_64.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Offsets into `struct seccomp_data`.
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+const ALLOWED: &[libc::c_long] = &[
+    // I/O
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_close,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_openat,
+    libc::SYS_unlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    // Sockets
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_sendto,
+    libc::SYS_sendmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    // Event loop
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    // The wakeup socket of tokio's signal driver, which zbus enables.
+    libc::SYS_socketpair,
+    // Threads and synchronization
+    libc::SYS_futex,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_tgkill,
+    libc::SYS_prctl,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_restart_syscall,
+    // Signals
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    // Time and randomness
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    libc::SYS_prlimit64,
+    // Legacy variants only present on x86_64
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+];
+
+/// For [`SeccompUses::write_files`].
+const WRITE_FILES: &[libc::c_long] = &[
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+];
+
+/// For [`SeccompUses::create_views`].
+const CREATE_VIEWS: &[libc::c_long] = &[
+    libc::SYS_bind,
+    libc::SYS_listen,
+    // Labeling their socket files for SMACK.
+    libc::SYS_setxattr,
+];
+
+/// For [`SeccompUses::resolve_names`], as glibc's resolver does it.
+const RESOLVE_NAMES: &[libc::c_long] = &[
+    libc::SYS_uname,
+    libc::SYS_sendmmsg,
+    // The UDP sockets of SRV lookups.
+    libc::SYS_bind,
+];
+
+fn statement(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn filter(mode: SeccompMode, uses: SeccompUses) -> Vec<sock_filter> {
+    let deny = match mode {
+        SeccompMode::Enforce => libc::SECCOMP_RET_KILL_PROCESS,
+        SeccompMode::Log => libc::SECCOMP_RET_LOG,
+    };
+
+    let mut program = vec![
+        // Refuse syscalls made through another ABI.
+        statement(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+        jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+        statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+    ];
+    let mut allowed = ALLOWED.to_vec();
+    if uses.write_files {
+        allowed.extend(WRITE_FILES);
+    }
+    if uses.create_views {
+        allowed.extend(CREATE_VIEWS);
+    }
+    if uses.resolve_names {
+        allowed.extend(RESOLVE_NAMES);
+    }
+    for nr in allowed {
+        program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+        program.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    program.push(statement(BPF_RET | BPF_K, deny));
+    program
+}
+
+/// Installs the filter on every thread of the process.
+pub(super) fn install(mode: SeccompMode, uses: SeccompUses) -> io::Result<()> {
+    let mut program = filter(mode, uses);
+    let prog = sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+
+    // SAFETY: prctl and seccomp only read the arguments passed here, and
+    // `prog` points at a live filter for the duration of the call.
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &prog as *const sock_fprog,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
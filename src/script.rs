@@ -0,0 +1,167 @@
+//! Routing and policy scripts.
+//!
+//! A `route_script` defines `fn route(request, targets)`, which is run for
+//! each sign request with its details and the names of the targets holding
+//! its key, in order of preference.  It returns `()` or `true` to sign as
+//! usual, `false` to deny the signature, or the name of a target, or an array
+//! of them, to sign on those targets only, in order.
+//!
+//! Scripts are [Rhai](https://rhai.rs), without modules or `eval`.  They can
+//! reach nothing but their arguments, `print` and `debug` log, and each run
+//! is bounded in operations, call depth and the size of strings and arrays.
+
+use rhai::{Dynamic, Engine, EvalAltResult, ParseError, Position, Scope, AST};
+
+/// Operations in one run before it is stopped.
+const MAX_OPERATIONS: u64 = 100_000;
+/// Nested function calls before a run is stopped.
+const MAX_DEPTH: usize = 32;
+/// Bytes of a string, and values of an array or map, at most.
+const MAX_STRING: usize = 64 * 1024;
+const MAX_ARRAY: usize = 10_000;
+
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, Error> {
+    Err(Error {
+        line,
+        message: message.into(),
+    })
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Self {
+            line: line(e.1),
+            message: e.0.to_string(),
+        }
+    }
+}
+
+impl From<Box<EvalAltResult>> for Error {
+    fn from(e: Box<EvalAltResult>) -> Self {
+        Self {
+            line: line(e.position()),
+            message: e.unwrap_inner().to_string(),
+        }
+    }
+}
+
+/// The line at `position`, or the first for errors without one.
+fn line(position: Position) -> usize {
+    position.line().unwrap_or(1)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    Array(Vec<Value>),
+    /// For arguments with fields like `request.key`.
+    Map(Vec<(&'static str, Value)>),
+}
+
+impl Value {
+    fn into_dynamic(self) -> Dynamic {
+        match self {
+            Value::Unit => Dynamic::UNIT,
+            Value::Bool(b) => b.into(),
+            Value::Int(n) => n.into(),
+            Value::String(s) => s.into(),
+            Value::Array(values) => values
+                .into_iter()
+                .map(Value::into_dynamic)
+                .collect::<Vec<_>>()
+                .into(),
+            Value::Map(fields) => fields
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into_dynamic()))
+                .collect::<rhai::Map>()
+                .into(),
+        }
+    }
+}
+
+/// What `route` decided for a request.
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    /// Sign on the targets holding the key, as without a script.
+    Default,
+    Deny,
+    /// Sign on these targets only, in order.
+    Targets(Vec<String>),
+}
+
+#[derive(Debug)]
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    /// Parses a script, which must define `route(request, targets)`.
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_DEPTH)
+            .set_max_string_size(MAX_STRING)
+            .set_max_array_size(MAX_ARRAY)
+            .set_max_map_size(MAX_ARRAY)
+            .disable_symbol("eval")
+            .on_print(|text| log::info!("Route script: {text}"))
+            .on_debug(|text, _, position| log::debug!("Route script, {position}: {text}"));
+        let ast = engine.compile(source)?;
+        let routes: Vec<_> = ast.iter_functions().filter(|f| f.name == "route").collect();
+        if routes.is_empty() {
+            return error(1, "no 'route' function");
+        }
+        if !routes.iter().any(|route| route.params.len() == 2) {
+            return error(1, "'route' must take (request, targets)");
+        }
+        Ok(Self { engine, ast })
+    }
+
+    /// Runs `route` for a request with the names of the targets holding its
+    /// key.
+    pub fn route(&self, request: Value, targets: &[&str]) -> Result<Decision, Error> {
+        let targets: Vec<Dynamic> = targets.iter().map(|name| name.to_string().into()).collect();
+        let request = request.into_dynamic();
+        let decision: Dynamic =
+            self.engine
+                .call_fn(&mut Scope::new(), &self.ast, "route", (request, targets))?;
+        if decision.is_unit() {
+            return Ok(Decision::Default);
+        }
+        if let Some(sign) = decision.clone().try_cast::<bool>() {
+            return Ok(if sign {
+                Decision::Default
+            } else {
+                Decision::Deny
+            });
+        }
+        if decision.is_string() {
+            return Ok(Decision::Targets(vec![decision.into_string().unwrap()]));
+        }
+        let type_name = decision.type_name();
+        let Some(names) = decision.try_cast::<rhai::Array>() else {
+            return error(1, format!("'route' returned a {type_name}"));
+        };
+        names
+            .into_iter()
+            .map(|name| {
+                let type_name = name.type_name();
+                name.into_string()
+                    .or_else(|_| error(1, format!("'route' returned a {type_name} target")))
+            })
+            .collect::<Result<_, _>>()
+            .map(Decision::Targets)
+    }
+}
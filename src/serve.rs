@@ -0,0 +1,615 @@
+//! Accept loop and per-connection request handling.
+//!
+//! This replaces `ssh_agent_lib::agent::bind` so that the mux controls the
+//! lifetime of client connections, and can have several requests of one
+//! connection in flight.
+
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::stream::FuturesOrdered;
+use futures::{FutureExt, SinkExt, StreamExt, TryStreamExt};
+use ssh_agent_lib::{
+    agent::ListeningSocket,
+    async_trait,
+    error::AgentError,
+    proto::{ProtoError, Request, Response},
+    ssh_encoding::Encode,
+};
+use tokio::io::{Join, Stdin, Stdout};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+
+use crate::codec::{Frame, FrameCodec, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::config::{AddressAction, AddressRule, Config, PipeSecurityConfig};
+use crate::frames::{self, Direction};
+#[cfg(windows)]
+use crate::pipe::NamedPipeListener;
+use crate::{legacy, logging, metrics, record};
+
+#[cfg(unix)]
+type PlatformSpecificListener = UnixListener;
+
+#[cfg(windows)]
+type PlatformSpecificListener = NamedPipeListener;
+
+/// Requests of one session that are handled at the same time by default.
+pub const DEFAULT_MAX_PIPELINED_REQUESTS: usize = 16;
+
+/// How long requests in flight are waited for on shutdown by default.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Like `ssh_agent_lib::agent::Agent`, but for [`Handler`] sessions.
+pub trait Agent<S: ListeningSocket>: Send {
+    /// Creates the session for a new client connection.
+    fn new_session(&mut self, socket: &S::Stream) -> impl Handler;
+}
+
+/// Client connections whose peer's credentials can be checked.
+pub trait PeerCredentials {
+    /// The UID and GID of the peer, if the connection has them.
+    fn peer_credentials(&self) -> Option<(u32, u32)>;
+
+    /// The supplementary groups of the peer, where the platform tells them.
+    fn peer_groups(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    /// The IP address of the peer, if the connection has one.
+    fn peer_address(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+#[cfg(unix)]
+impl PeerCredentials for tokio::net::UnixStream {
+    fn peer_credentials(&self) -> Option<(u32, u32)> {
+        self.peer_cred().ok().map(|cred| (cred.uid(), cred.gid()))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn peer_groups(&self) -> Vec<u32> {
+        use std::os::fd::AsRawFd;
+        let size = std::mem::size_of::<libc::gid_t>();
+        let mut groups: Vec<libc::gid_t> = vec![0; 16];
+        loop {
+            let mut len = (groups.len() * size) as libc::socklen_t;
+            // SAFETY: `groups` holds `len` bytes.
+            let result = unsafe {
+                libc::getsockopt(
+                    self.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_PEERGROUPS,
+                    groups.as_mut_ptr().cast(),
+                    &mut len,
+                )
+            };
+            let needed = len as usize / size;
+            match result {
+                0 => {
+                    groups.truncate(needed);
+                    return groups;
+                }
+                // Answered with the size needed.
+                _ if std::io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE)
+                    && needed > groups.len() =>
+                {
+                    groups.resize(needed, 0)
+                }
+                _ => return Vec::new(),
+            }
+        }
+    }
+}
+
+impl PeerCredentials for tokio::net::TcpStream {
+    fn peer_credentials(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    fn peer_address(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|address| address.ip())
+    }
+}
+
+#[cfg(windows)]
+impl PeerCredentials for tokio::net::windows::named_pipe::NamedPipeServer {
+    fn peer_credentials(&self) -> Option<(u32, u32)> {
+        None
+    }
+}
+
+/// A client session.  Unlike `ssh_agent_lib::agent::Session`, requests are
+/// handled through a shared reference, so that several can be in flight.
+#[async_trait]
+pub trait Handler: Send + Sync + 'static {
+    async fn handle(&self, request: Request) -> Result<Response, AgentError>;
+}
+
+#[derive(Clone, Debug)]
+pub struct ServeOptions {
+    /// Close client sessions that send no request for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Maximum number of simultaneous client sessions.
+    pub max_sessions: Option<usize>,
+    /// Sessions sending a larger message are closed.
+    pub max_message_size: usize,
+    /// Close sessions after this many requests.
+    pub session_max_requests: Option<u64>,
+    /// Close sessions after receiving this many bytes.
+    pub session_max_bytes: Option<u64>,
+    /// Answer protocol 1 requests like an agent without protocol 1 keys.
+    pub legacy_v1: bool,
+    /// Requests of one session handled at the same time.  Responses are
+    /// always sent in request order.
+    pub max_pipelined_requests: usize,
+    /// On shutdown, sessions with requests in flight after this long are
+    /// closed anyway.
+    pub shutdown_timeout: Duration,
+    /// Clients must run as one of these users or groups, when either is
+    /// set.  Clients of sockets without credentials, like TCP, never do.
+    /// Supplementary groups count on Linux, and only the primary group
+    /// elsewhere.
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+    /// Which TCP clients may connect, by address.
+    pub client_addresses: Vec<AddressRule>,
+    /// Who may connect to a named pipe.
+    pub pipe_security: PipeSecurityConfig,
+}
+
+impl ServeOptions {
+    /// Whether a client with `credentials` and supplementary `groups` may
+    /// connect.
+    fn allows_peer(&self, credentials: Option<(u32, u32)>, groups: &[u32]) -> bool {
+        if self.allowed_uids.is_empty() && self.allowed_gids.is_empty() {
+            return true;
+        }
+        credentials.is_some_and(|(uid, gid)| {
+            self.allowed_uids.contains(&uid)
+                || std::iter::once(&gid)
+                    .chain(groups)
+                    .any(|gid| self.allowed_gids.contains(gid))
+        })
+    }
+}
+
+impl From<&Config> for ServeOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            idle_timeout: config.session_idle_timeout,
+            max_sessions: config.max_sessions,
+            max_message_size: config.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+            session_max_requests: config.session_max_requests,
+            session_max_bytes: config.session_max_bytes,
+            legacy_v1: config.legacy_v1,
+            max_pipelined_requests: config
+                .max_pipelined_requests
+                .unwrap_or(DEFAULT_MAX_PIPELINED_REQUESTS),
+            shutdown_timeout: config.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            allowed_uids: config.allowed_uids.clone(),
+            allowed_gids: config.allowed_gids.clone(),
+            client_addresses: config.client_addresses.clone(),
+            pipe_security: config.pipe_security.clone(),
+        }
+    }
+}
+
+/// Caps the number of simultaneous sessions.
+///
+/// Connections beyond the cap are closed immediately, before any upstream
+/// connection is made, so existing sessions are never disturbed by a storm of
+/// new ones.
+struct SessionLimit {
+    max_sessions: usize,
+    permits: Arc<Semaphore>,
+    /// Connections rejected since the cap was last hit.
+    rejected: u64,
+}
+
+impl SessionLimit {
+    fn new(max_sessions: usize) -> Self {
+        Self {
+            max_sessions,
+            permits: Arc::new(Semaphore::new(max_sessions)),
+            rejected: 0,
+        }
+    }
+
+    fn try_acquire(&mut self) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => {
+                if self.rejected > 0 {
+                    log::info!(
+                        "Accepting sessions again; rejected {} while at the limit of {}",
+                        self.rejected,
+                        self.max_sessions
+                    );
+                    self.rejected = 0;
+                }
+                Some(permit)
+            }
+            Err(_) => {
+                if self.rejected == 0 {
+                    log::warn!(
+                        "Session limit of {} reached; rejecting new connections",
+                        self.max_sessions
+                    );
+                }
+                self.rejected += 1;
+                None
+            }
+        }
+    }
+}
+
+pub async fn serve<A>(
+    listener: service_binding::Listener,
+    agent: A,
+    options: ServeOptions,
+) -> Result<(), AgentError>
+where
+    A: Agent<PlatformSpecificListener> + Agent<TcpListener>,
+{
+    serve_until(listener, agent, options, futures::future::pending()).await
+}
+
+/// Like [`serve`], but once `shutdown` completes stops accepting
+/// connections and returns when the sessions have answered the requests they
+/// have in flight, or after [`ServeOptions::shutdown_timeout`].
+pub async fn serve_until<A>(
+    listener: service_binding::Listener,
+    agent: A,
+    options: ServeOptions,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), AgentError>
+where
+    A: Agent<PlatformSpecificListener> + Agent<TcpListener>,
+{
+    match listener {
+        #[cfg(unix)]
+        service_binding::Listener::Unix(listener) => {
+            listen(UnixListener::from_std(listener)?, agent, options, shutdown).await
+        }
+        service_binding::Listener::Tcp(listener) => {
+            listen(TcpListener::from_std(listener)?, agent, options, shutdown).await
+        }
+        #[cfg(windows)]
+        service_binding::Listener::NamedPipe(pipe) => {
+            let listener = NamedPipeListener::bind(pipe, &options.pipe_security)?;
+            listen(listener, agent, options, shutdown).await
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(AgentError::IO(std::io::Error::other(
+            "Unsupported type of a listener.",
+        ))),
+    }
+}
+
+async fn listen<S>(
+    mut socket: S,
+    mut agent: impl Agent<S>,
+    options: ServeOptions,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), AgentError>
+where
+    S: ListeningSocket + fmt::Debug + Send,
+    S::Stream: PeerCredentials,
+{
+    log::info!("Listening; socket = {:?}", socket);
+    let mut limit = options.max_sessions.map(SessionLimit::new);
+    let draining = CancellationToken::new();
+    let mut sessions = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = socket.accept() => match accepted {
+                Ok(socket) => {
+                    let credentials = socket.peer_credentials();
+                    if !options.allows_peer(credentials, &socket.peer_groups()) {
+                        match credentials {
+                            Some((uid, gid)) => log::warn!(
+                                "Rejecting connection from uid {uid}, gid {gid}, which isn't allowed"
+                            ),
+                            None => log::warn!(
+                                "Rejecting connection without peer credentials to check"
+                            ),
+                        }
+                        continue;
+                    }
+                    if let Some(address) = socket.peer_address() {
+                        if AddressRule::action_for(&options.client_addresses, address)
+                            == AddressAction::Deny
+                        {
+                            log::warn!("Rejecting connection from {address}, which isn't allowed");
+                            continue;
+                        }
+                    }
+                    let permit = match &mut limit {
+                        Some(limit) => match limit.try_acquire() {
+                            Some(permit) => Some(permit),
+                            None => continue,
+                        },
+                        None => None,
+                    };
+                    let session = agent.new_session(&socket);
+                    let options = options.clone();
+                    let draining = draining.clone();
+                    sessions.spawn(async move {
+                        let _permit = permit;
+                        let adapter =
+                            Framed::new(socket, FrameCodec::new(options.max_message_size));
+                        if let Err(e) =
+                            handle_socket::<S>(session, adapter, &options, &draining).await
+                        {
+                            log::error!("Agent protocol error: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    log::error!("Failed to accept socket: {:?}", e);
+                    return Err(AgentError::IO(e));
+                }
+            },
+            // Reaps finished sessions.
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => (),
+            () = &mut shutdown => break,
+        }
+    }
+
+    drop(socket);
+    log::info!("Shutting down; draining {} sessions", sessions.len());
+    draining.cancel();
+    let drained = tokio::time::timeout(options.shutdown_timeout, async {
+        while sessions.join_next().await.is_some() {}
+    });
+    if drained.await.is_err() {
+        log::warn!(
+            "Closing {} sessions still busy after {:?}",
+            sessions.len(),
+            options.shutdown_timeout
+        );
+        sessions.shutdown().await;
+    }
+    Ok(())
+}
+
+/// Stdin and stdout as the one connection of a mux spawned per client, as
+/// by inetd.
+#[derive(Debug, Default)]
+pub struct Stdio {
+    accepted: bool,
+}
+
+#[async_trait]
+impl ListeningSocket for Stdio {
+    type Stream = Join<Stdin, Stdout>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::Stream> {
+        if std::mem::replace(&mut self.accepted, true) {
+            return Err(std::io::Error::other("stdio carries a single session"));
+        }
+        Ok(tokio::io::join(tokio::io::stdin(), tokio::io::stdout()))
+    }
+}
+
+/// Serves the one session on stdin and stdout, returning once it ends.
+/// Session limits don't apply.
+pub async fn serve_stdio(
+    mut agent: impl Agent<Stdio>,
+    options: ServeOptions,
+) -> Result<(), AgentError> {
+    let mut stdio = Stdio::default();
+    let socket = stdio.accept().await?;
+    log::info!("Serving a session on stdio");
+    let session = agent.new_session(&socket);
+    let adapter = Framed::new(socket, FrameCodec::new(options.max_message_size));
+    handle_socket::<Stdio>(session, adapter, &options, &CancellationToken::new()).await
+}
+
+/// A response, or a raw message for those `Response` can't represent.
+enum Reply {
+    Response(Response),
+    Raw(&'static [u8]),
+}
+
+/// What to do with a received frame.
+enum Action<'a> {
+    Reply(BoxFuture<'a, Reply>),
+    Close,
+}
+
+async fn handle_socket<S>(
+    session: impl Handler,
+    mut adapter: Framed<S::Stream, FrameCodec>,
+    options: &ServeOptions,
+    draining: &CancellationToken,
+) -> Result<(), AgentError>
+where
+    S: ListeningSocket + fmt::Debug + Send,
+{
+    let mut budget = Budget::default();
+    let id = next_session_id();
+    let recording = record::Session::new(id);
+    let mut in_flight = FuturesOrdered::new();
+    let mut eof = false;
+    loop {
+        if eof && in_flight.is_empty() {
+            // Reached EOF of the stream (client disconnected) and answered
+            // everything, we can close the socket and exit the handler.
+            // Dropping the session releases its upstream connections.
+            return Ok(());
+        }
+        let reading = !eof && in_flight.len() < options.max_pipelined_requests;
+        // Sessions with requests in flight aren't idle.
+        let idle_timeout = options.idle_timeout.filter(|_| in_flight.is_empty());
+
+        tokio::select! {
+            biased;
+            Some(reply) = in_flight.next(), if !in_flight.is_empty() => {
+                if frames::enabled() {
+                    dump_reply(id, &reply);
+                }
+                match reply {
+                    Reply::Response(response) => adapter.send(response).await?,
+                    Reply::Raw(message) => adapter.send(message).await?,
+                }
+            }
+            // Like at EOF, requests in flight are still answered.
+            () = draining.cancelled(), if !eof => eof = true,
+            incoming_frame = next_frame(&mut adapter, idle_timeout), if reading => {
+                let Some(incoming_frame) = incoming_frame? else {
+                    eof = true;
+                    continue;
+                };
+                if frames::enabled() {
+                    frames::dump(id, Direction::Received, &incoming_frame.0);
+                }
+                let id = logging::RequestId::next();
+                match id.in_scope(|| {
+                    receive(&session, incoming_frame, &mut budget, options, recording)
+                }) {
+                    Action::Reply(reply) => in_flight.push_back(id.scope(reply)),
+                    Action::Close => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Numbers client sessions, from 1, in recordings and frame dumps.
+fn next_session_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+fn dump_reply(session: u64, reply: &Reply) {
+    match reply {
+        Reply::Response(response) => {
+            let mut message = Vec::new();
+            if response.encode(&mut message).is_ok() {
+                frames::dump(session, Direction::Sent, &message);
+            }
+        }
+        Reply::Raw(message) => frames::dump(session, Direction::Sent, message),
+    }
+}
+
+/// Reads the next frame, or `None` at EOF.  Closes the session by reporting
+/// EOF once it is idle for `idle_timeout`.
+async fn next_frame<T>(
+    adapter: &mut Framed<T, FrameCodec>,
+    idle_timeout: Option<Duration>,
+) -> Result<Option<Frame>, std::io::Error>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    match idle_timeout {
+        Some(idle_timeout) => match tokio::time::timeout(idle_timeout, adapter.try_next()).await {
+            Ok(incoming_frame) => incoming_frame,
+            Err(_) => {
+                log::info!("Closing session idle for {idle_timeout:?}");
+                Ok(None)
+            }
+        },
+        None => adapter.try_next().await,
+    }
+}
+
+/// Requests and bytes received by a session so far.
+#[derive(Default)]
+struct Budget {
+    requests: u64,
+    bytes: u64,
+}
+
+/// Accounts for a received frame and starts handling it.
+fn receive<'a>(
+    session: &'a impl Handler,
+    incoming_frame: Frame,
+    budget: &mut Budget,
+    options: &ServeOptions,
+    recording: Option<record::Session>,
+) -> Action<'a> {
+    budget.requests += 1;
+    budget.bytes += incoming_frame.wire_len() as u64;
+    if let Some(limit) = options
+        .session_max_requests
+        .filter(|&l| budget.requests > l)
+    {
+        log::warn!("Closing session that exceeded its budget of {limit} requests");
+        return Action::Close;
+    }
+    if let Some(limit) = options.session_max_bytes.filter(|&l| budget.bytes > l) {
+        log::warn!("Closing session that exceeded its budget of {limit} bytes");
+        return Action::Close;
+    }
+
+    if options.legacy_v1 {
+        if let Some(reply) = legacy::reply(&incoming_frame.0) {
+            log::debug!("Answering protocol 1 message type {}", incoming_frame.0[0]);
+            metrics::record("legacy_v1", true);
+            return Action::Reply(futures::future::ready(Reply::Raw(reply)).boxed());
+        }
+    }
+
+    let incoming_message = match incoming_frame.decode::<Request>() {
+        Ok(message) => message,
+        Err(ProtoError::UnsupportedCommand { command }) => {
+            log::info!("Unsupported message type {command}");
+            metrics::record("unsupported", false);
+            return Action::Reply(
+                futures::future::ready(Reply::Response(Response::Failure)).boxed(),
+            );
+        }
+        Err(e) => {
+            // Like OpenSSH's agent, don't try to recover from malformed
+            // input.
+            log::warn!("Closing session that sent a malformed message: {e}");
+            return Action::Close;
+        }
+    };
+
+    let n = budget.requests;
+    if let Some(recording) = recording {
+        recording.request(n, &incoming_message);
+    }
+    Action::Reply(
+        async move {
+            log::debug!("Request: {}", logging::Message(&incoming_message));
+            let response = match session.handle(incoming_message).await {
+                Ok(message) => message,
+                Err(AgentError::ExtensionFailure) => {
+                    log::error!("Extension failure handling message");
+                    Response::ExtensionFailure
+                }
+                // Refused by policy, whose denials are logged with their
+                // reason, or by a target, rather than failed.
+                Err(AgentError::Failure) => {
+                    log::info!("Request refused");
+                    Response::Failure
+                }
+                Err(e) => {
+                    log::error!("Error handling message: {:?}", e);
+                    Response::Failure
+                }
+            };
+            log::debug!("Response: {}", logging::Message(&response));
+            if let Some(recording) = recording {
+                recording.response(n, &response);
+            }
+            Reply::Response(response)
+        }
+        .boxed(),
+    )
+}
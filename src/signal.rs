@@ -0,0 +1,116 @@
+//! Catching signals without blocking them, through a self-pipe.
+//!
+//! The handler only writes the signal number to a socket, which is
+//! async-signal-safe; the runtime reads it from the other end.
+
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use tokio::io::AsyncReadExt;
+
+/// The sending end of the self-pipe, once signals are caught.
+static SENDER: AtomicI32 = AtomicI32::new(-1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    Interrupt,
+    Terminate,
+    User1,
+    User2,
+    Hangup,
+}
+
+impl Signal {
+    pub const ALL: [Signal; 5] = [
+        Signal::Interrupt,
+        Signal::Terminate,
+        Signal::User1,
+        Signal::User2,
+        Signal::Hangup,
+    ];
+
+    fn number(self) -> libc::c_int {
+        match self {
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Terminate => libc::SIGTERM,
+            Signal::User1 => libc::SIGUSR1,
+            Signal::User2 => libc::SIGUSR2,
+            Signal::Hangup => libc::SIGHUP,
+        }
+    }
+
+    fn from_number(number: libc::c_int) -> Option<Self> {
+        Signal::ALL
+            .into_iter()
+            .find(|signal| signal.number() == number)
+    }
+}
+
+/// Signals caught since [`catch`], to be received once the runtime runs.
+pub struct Signals(UnixStream);
+
+/// Receives caught signals on the runtime.
+pub struct Receiver(tokio::net::UnixStream);
+
+/// Replaces the default action of `signals` with queueing them for a
+/// [`Receiver`].  Only meant to be called once, before the sandbox is
+/// applied.
+pub fn catch(signals: &[Signal]) -> io::Result<Signals> {
+    let (sender, receiver) = UnixStream::pair()?;
+    // A full buffer drops the signal rather than blocking the handler; the
+    // receiver has plenty to act on by then.
+    sender.set_nonblocking(true)?;
+    receiver.set_nonblocking(true)?;
+    SENDER.store(sender.as_raw_fd(), Ordering::Relaxed);
+    // The handler writes to the sender for the rest of the process.
+    std::mem::forget(sender);
+
+    for signal in signals {
+        // SAFETY: the action is fully initialized and the handler is
+        // async-signal-safe.
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal.number(), &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(Signals(receiver))
+}
+
+extern "C" fn handle(number: libc::c_int) {
+    let byte = number as u8;
+    // SAFETY: write is async-signal-safe, and the byte outlives the call.
+    unsafe {
+        libc::write(
+            SENDER.load(Ordering::Relaxed),
+            (&byte as *const u8).cast(),
+            1,
+        );
+    }
+}
+
+impl Signals {
+    /// Starts receiving the caught signals.  Must be called on the runtime.
+    pub fn receiver(self) -> io::Result<Receiver> {
+        tokio::net::UnixStream::from_std(self.0).map(Receiver)
+    }
+}
+
+impl Receiver {
+    /// Waits for the next caught signal.
+    pub async fn recv(&mut self) -> io::Result<Signal> {
+        loop {
+            let number = self.0.read_u8().await?;
+            if let Some(signal) = Signal::from_number(number.into()) {
+                return Ok(signal);
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! `ssh_config` snippets pointing ssh at the sockets of the mux: the host
+//! for every host, and each view for its `match_hosts`, or else for the
+//! hosts left to fill in, with the public keys of its keys as
+//! `IdentityFile`s so that ssh only offers those.
+//!
+//! `ssh_config` takes backslashes as escapes, so named pipes are written
+//! with forward slashes, e.g. `//./pipe/ssh-agent-mux`, which Windows
+//! takes too.  `%` is doubled as it starts tokens.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use service_binding::Binding;
+use ssh_agent_lib::proto::Identity;
+use ssh_key::{HashAlg, PublicKey};
+
+use crate::config::ViewConfig;
+
+const UNREACHABLE: &str = "not served on a Unix socket or named pipe, which ssh needs.";
+
+/// A key of the mux written out as a public key file.
+#[derive(Clone, Debug)]
+pub struct KeyFile {
+    pub identity: Identity,
+    /// Names of the targets holding the key.
+    pub targets: Vec<String>,
+    pub path: PathBuf,
+}
+
+/// Writes the public key of each of `identities`, with the targets holding
+/// it, to `dir`, named after its fingerprint.
+pub fn write_public_keys(
+    dir: &Path,
+    identities: Vec<(Identity, Vec<String>)>,
+) -> io::Result<Vec<KeyFile>> {
+    fs::create_dir_all(dir)?;
+    identities
+        .into_iter()
+        .map(|(identity, targets)| {
+            let fingerprint = identity.pubkey.fingerprint(HashAlg::Sha256).to_string();
+            // URL-safe base64, without the algorithm.
+            let name: String = fingerprint["SHA256:".len()..]
+                .chars()
+                .map(|c| match c {
+                    '/' => '_',
+                    '+' => '-',
+                    c => c,
+                })
+                .collect();
+            let path = dir.join(format!("{name}.pub"));
+            let key = PublicKey::new(identity.pubkey.clone(), identity.comment.clone());
+            fs::write(&path, key.to_string() + "\n")?;
+            Ok(KeyFile {
+                identity,
+                targets,
+                path,
+            })
+        })
+        .collect()
+}
+
+/// The snippet for the mux serving on `host` with `views`, giving views
+/// the `IdentityFile`s of theirs of `key_files` when they limit their keys.
+pub fn generate(host: &Binding, views: &[ViewConfig], key_files: &[KeyFile]) -> String {
+    let mut snippet = String::new();
+    for view in views {
+        let patterns = match view.match_hosts.is_empty() {
+            true => {
+                let _ = writeln!(
+                    snippet,
+                    "# The {} view: put the hosts to use it for in place of `{}`.",
+                    view.name, view.name
+                );
+                view.name.clone()
+            }
+            false => {
+                let _ = writeln!(snippet, "# The {} view.", view.name);
+                view.match_hosts.join(" ")
+            }
+        };
+        let Some(agent) = agent_path(&view.host) else {
+            let _ = writeln!(snippet, "# The view is {UNREACHABLE}\n");
+            continue;
+        };
+        let _ = writeln!(snippet, "Host {patterns}");
+        let _ = writeln!(snippet, "    IdentityAgent {}", quote(&agent));
+        if !view.keys.is_empty() || !view.targets.is_empty() {
+            let files: Vec<_> = key_files.iter().filter(|file| serves(view, file)).collect();
+            for file in &files {
+                let path = file.path.to_string_lossy();
+                let _ = writeln!(snippet, "    IdentityFile {}", quote(&path));
+            }
+            if !files.is_empty() {
+                let _ = writeln!(snippet, "    IdentitiesOnly yes");
+            }
+        }
+        snippet.push('\n');
+    }
+    match agent_path(host) {
+        Some(agent) => {
+            let _ = writeln!(snippet, "Host *\n    IdentityAgent {}", quote(&agent));
+        }
+        None => {
+            let _ = writeln!(snippet, "# The host is {UNREACHABLE}");
+        }
+    }
+    snippet
+}
+
+/// Whether `view` lists and signs with the key of `file`.
+fn serves(view: &ViewConfig, file: &KeyFile) -> bool {
+    let key = &file.identity.pubkey;
+    (view.keys.is_empty()
+        || view
+            .keys
+            .iter()
+            .any(|fingerprint| key.fingerprint(fingerprint.algorithm()) == *fingerprint))
+        && file.targets.iter().any(|target| view.serves_target(target))
+}
+
+/// How `ssh_config` names the agent at `binding`, if ssh can reach it.
+pub fn agent_path(binding: &Binding) -> Option<String> {
+    match binding {
+        Binding::FilePath(path) => Some(path.to_string_lossy().into_owned()),
+        Binding::NamedPipe(name) => Some(name.to_string_lossy().replace('\\', "/")),
+        _ => None,
+    }
+}
+
+/// `arg` as one argument of an `ssh_config` line.
+fn quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if arg.contains(char::is_whitespace) || arg.contains(['"', '\'', '#']) {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
@@ -0,0 +1,82 @@
+//! The status of a running mux, through an extension of the agent protocol
+//! the mux answers itself: the report logged on SIGUSR1, or a JSON document
+//! for monitoring scripts.
+//!
+//! The JSON document is an object of:
+//!
+//! - `schema_version`: [`SCHEMA_VERSION`], raised whenever a field changes
+//!   meaning or goes away, but not when one is added;
+//! - `version`: the version of the mux;
+//! - `targets`: an array of objects of each target's `name`, whether it is
+//!   `healthy`, how many seconds ago it `failed`, its `priority`, average
+//!   `sign_latency_ms` and whether it is `hidden`, with `null` for what is
+//!   unknown;
+//! - `healthy_targets`, `sessions`, `keys` last listed to a client, or
+//!   `null` before any were, and `keys_used` since the mux started, or the
+//!   usage file was created.
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{extension::MessageExtension, Extension, ProtoError, Request, Response},
+    ssh_encoding::{self, Decode, Encode, Reader, Writer},
+};
+
+use crate::client::Client;
+use crate::error::Error;
+
+/// Version of the JSON document's fields.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const STATUS: &str = "status@rfdonnelly.github.io";
+
+/// `status@rfdonnelly.github.io`: asks for the status, as `text` or `json`,
+/// answered with it as a string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub format: String,
+}
+
+impl MessageExtension for Status {
+    const NAME: &'static str = STATUS;
+}
+
+impl Decode for Status {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            format: String::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for Status {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        self.format.encoded_len()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        self.format.encode(writer)
+    }
+}
+
+/// The status of the mux serving on `host`, as the JSON document if `json`,
+/// or else as text.
+pub async fn status(host: &Binding, json: bool) -> Result<String, Error> {
+    let request = async {
+        let mut client = Client::connect(host.clone().try_into()?)?;
+        let extension = Extension::new_message(Status {
+            format: if json { "json" } else { "text" }.to_owned(),
+        })?;
+        match client.handle(Request::Extension(extension)).await? {
+            Response::ExtensionResponse(response) if response.name == STATUS => Ok(response
+                .details
+                .parse::<String>()
+                .map_err(ProtoError::from)?),
+            Response::Failure | Response::ExtensionFailure => Err(AgentError::Failure),
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    };
+    request.await.map_err(Error::Status)
+}
@@ -0,0 +1,349 @@
+//! A live view of a running mux in the terminal: its targets and their
+//! health, from its status, and the signatures made and denied, by key,
+//! from its event socket since the view started.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table};
+use ratatui::Frame;
+use service_binding::Binding;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::UnixStream;
+
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::report::Usage;
+use crate::status;
+
+/// Signature events kept for display.
+const RECENT: usize = 100;
+
+/// A target, as the status describes it.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Target {
+    pub name: String,
+    pub healthy: bool,
+    /// Seconds since it failed.
+    pub failed: Option<u64>,
+    pub priority: i64,
+    pub sign_latency_ms: Option<u64>,
+    pub hidden: bool,
+}
+
+/// A signature made or denied, from a `key_used`, `sign_denied` or
+/// `canary_used` event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    /// In seconds since the Unix epoch.
+    pub time: u64,
+    pub event: String,
+    /// The key's nickname, or its shortened fingerprint.
+    pub key: String,
+    /// The target that signed, or why the signature was denied.
+    pub outcome: String,
+    pub client: String,
+}
+
+/// What the view shows.
+#[derive(Clone, Debug, Default)]
+pub struct Monitor {
+    pub version: String,
+    pub targets: Vec<Target>,
+    pub sessions: Option<u64>,
+    pub keys: Option<u64>,
+    /// Why the status couldn't be had the last time it was asked for.
+    pub problem: Option<String>,
+    /// Newest first.
+    pub signatures: VecDeque<Signature>,
+    /// By [`Signature::key`].
+    pub usage: BTreeMap<String, Usage>,
+    /// Whether the event stream ended, e.g. as the mux exited.
+    pub disconnected: bool,
+}
+
+impl Monitor {
+    /// Takes in the status, as the JSON document described in
+    /// [`crate::status`].
+    pub fn set_status(&mut self, json: &str) {
+        match serde_json::from_str::<Status>(json) {
+            Ok(status) => {
+                self.version = status.version;
+                self.targets = status.targets;
+                self.sessions = status.sessions;
+                self.keys = status.keys;
+                self.problem = None;
+            }
+            Err(e) => self.problem = Some(format!("invalid status: {e}")),
+        }
+    }
+
+    /// Takes in an event, as a JSON object, ignoring those other than
+    /// signatures made and denied.
+    pub fn add_event(&mut self, json: &str) {
+        let Ok(event) = serde_json::from_str::<SignatureEvent>(json) else {
+            return;
+        };
+        let denied = match event.event.as_str() {
+            "key_used" => false,
+            "sign_denied" | "canary_used" => true,
+            _ => return,
+        };
+        let time = event.time.max(0) as u64;
+        let key = match event.nickname.as_str() {
+            "" => event.key.get(..15).unwrap_or(&event.key),
+            nickname => nickname,
+        }
+        .to_owned();
+        let usage = self.usage.entry(key.clone()).or_default();
+        match denied {
+            true => usage.denied += 1,
+            false => usage.signatures += 1,
+        }
+        usage.last_seen = usage.last_seen.max(time);
+        if self.signatures.len() == RECENT {
+            self.signatures.pop_back();
+        }
+        let outcome = match event.event.as_str() {
+            "key_used" => event.target,
+            "canary_used" => "canary key".to_owned(),
+            _ => event.reason,
+        };
+        self.signatures.push_front(Signature {
+            time,
+            event: event.event,
+            key,
+            outcome,
+            client: event.client,
+        });
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [header, targets, rest] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(self.targets.len() as u16 + 3),
+            Constraint::Fill(1),
+        ])
+        .areas(frame.area());
+        let [signatures, usage] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(rest);
+        frame.render_widget(self.header(), header);
+        frame.render_widget(self.target_table(), targets);
+        frame.render_widget(self.signature_table(), signatures);
+        frame.render_widget(self.usage_table(), usage);
+    }
+
+    fn header(&self) -> Paragraph<'_> {
+        let unknown = |count: Option<u64>| count.map_or("?".to_owned(), |count| count.to_string());
+        let mut spans = vec![
+            Span::styled(
+                format!("ssh-agent-mux {}", self.version),
+                Style::new().add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(format!(
+                "  {}/{} targets healthy, {} sessions, {} keys",
+                self.targets.iter().filter(|target| target.healthy).count(),
+                self.targets.len(),
+                unknown(self.sessions),
+                unknown(self.keys),
+            )),
+        ];
+        if let Some(problem) = &self.problem {
+            spans.push(Span::styled(
+                format!("  {problem}"),
+                Style::new().fg(Color::Red),
+            ));
+        }
+        spans.push(Span::styled(
+            "  q to quit",
+            Style::new().fg(Color::DarkGray),
+        ));
+        Paragraph::new(Line::from(spans))
+    }
+
+    fn target_table(&self) -> Table<'_> {
+        let rows = self.targets.iter().map(|target| {
+            let (health, color) = match (target.healthy, target.failed) {
+                (true, _) => ("healthy".to_owned(), Color::Green),
+                (false, Some(failed)) => (format!("failed {failed}s ago"), Color::Red),
+                (false, None) => ("unhealthy".to_owned(), Color::Red),
+            };
+            Row::new([
+                Span::raw(target.name.clone()),
+                Span::styled(health, Style::new().fg(color)),
+                Span::raw(target.priority.to_string()),
+                Span::raw(
+                    target
+                        .sign_latency_ms
+                        .map_or(String::new(), |latency| format!("{latency}ms")),
+                ),
+                Span::raw(if target.hidden { "hidden" } else { "" }),
+            ])
+        });
+        Table::new(
+            rows,
+            [
+                Constraint::Fill(2),
+                Constraint::Length(18),
+                Constraint::Length(8),
+                Constraint::Length(10),
+                Constraint::Length(6),
+            ],
+        )
+        .header(heading(["Target", "Health", "Priority", "Signs in", ""]))
+        .block(Block::bordered().title(" Targets "))
+    }
+
+    fn signature_table(&self) -> Table<'_> {
+        let rows = self.signatures.iter().map(|signature| {
+            let color = match signature.event.as_str() {
+                "key_used" => Color::Reset,
+                _ => Color::Red,
+            };
+            Row::new([
+                time_of_day(signature.time),
+                signature.key.clone(),
+                signature.outcome.clone(),
+                signature.client.clone(),
+            ])
+            .style(Style::new().fg(color))
+        });
+        let title = match self.disconnected {
+            true => " Signatures (event stream ended) ",
+            false => " Signatures ",
+        };
+        Table::new(
+            rows,
+            [
+                Constraint::Length(8),
+                Constraint::Fill(2),
+                Constraint::Fill(2),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(heading(["Time", "Key", "Outcome", "Client"]))
+        .block(Block::bordered().title(title))
+    }
+
+    fn usage_table(&self) -> Table<'_> {
+        let mut usage: Vec<_> = self.usage.iter().collect();
+        usage.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.signatures + usage.denied));
+        let rows = usage.into_iter().map(|(key, usage)| {
+            Row::new([
+                key.clone(),
+                usage.signatures.to_string(),
+                usage.denied.to_string(),
+                time_of_day(usage.last_seen),
+            ])
+        });
+        Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(6),
+                Constraint::Length(6),
+                Constraint::Length(8),
+            ],
+        )
+        .header(heading(["Key", "Signed", "Denied", "Last"]))
+        .block(Block::bordered().title(" Keys "))
+    }
+}
+
+fn heading<const N: usize>(names: [&'static str; N]) -> Row<'static> {
+    Row::new(names).style(Style::new().add_modifier(Modifier::BOLD))
+}
+
+/// `HH:MM:SS` in UTC.
+fn time_of_day(time: u64) -> String {
+    let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(time));
+    time.to_string()[11..19].to_owned()
+}
+
+/// The parts of the status the view shows.
+#[derive(Deserialize)]
+struct Status {
+    #[serde(default)]
+    version: String,
+    targets: Vec<Target>,
+    sessions: Option<u64>,
+    keys: Option<u64>,
+}
+
+/// The fields of an event the view shows signatures from, empty where left
+/// out.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct SignatureEvent {
+    event: String,
+    time: i64,
+    key: String,
+    nickname: String,
+    target: String,
+    reason: String,
+    client: String,
+}
+
+/// Shows the mux serving on `host`, streaming events on `event_socket`,
+/// asking for its status every `refresh`, until `q` is pressed.
+pub async fn top(host: &Binding, event_socket: &Path, refresh: Duration) -> Result<(), Error> {
+    let events = UnixStream::connect(event_socket)
+        .await
+        .map_err(|source| Error::EventSocket {
+            path: event_socket.to_owned(),
+            source,
+        })?;
+    let mut terminal = ratatui::try_init().map_err(Error::Top)?;
+    // Reading keys blocks.
+    let (quit, mut quitting) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || loop {
+        let Ok(event) = event::read() else {
+            return;
+        };
+        let redraw = match event {
+            Event::Key(key) if key.kind == KeyEventKind::Press => {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                !(ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc))
+            }
+            _ => true,
+        };
+        if quit.send(redraw).is_err() || !redraw {
+            return;
+        }
+    });
+    let mut events = BufReader::new(events).lines();
+    let mut refreshes = tokio::time::interval(refresh);
+    let mut monitor = Monitor::default();
+    let result = loop {
+        tokio::select! {
+            _ = refreshes.tick() => {
+                match tokio::time::timeout(refresh, status::status(host, true)).await {
+                    Ok(Ok(json)) => monitor.set_status(&json),
+                    Ok(Err(e)) => monitor.problem = Some(e.to_string()),
+                    Err(_) => monitor.problem = Some("the mux didn't answer".to_owned()),
+                }
+            }
+            line = events.next_line(), if !monitor.disconnected => match line {
+                Ok(Some(line)) => monitor.add_event(&line),
+                Ok(None) | Err(_) => monitor.disconnected = true,
+            },
+            redraw = quitting.recv() => if redraw != Some(true) {
+                break Ok(());
+            },
+        }
+        if let Err(e) = terminal.draw(|frame| monitor.draw(frame)) {
+            break Err(Error::Top(e));
+        }
+    };
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,69 @@
+//! Unlocking hidden targets of a running mux, through an extension of the
+//! agent protocol the mux answers itself.
+
+use std::time::Duration;
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{extension::MessageExtension, Extension, ProtoError, Request, Response},
+    ssh_encoding::{self, CheckedSum, Decode, Encode, Reader, Writer},
+};
+
+use crate::client::Client;
+use crate::error::Error;
+
+/// `unlock-target@rfdonnelly.github.io`: lists and uses a hidden target for
+/// a while.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnlockTarget {
+    pub target: String,
+    pub duration: Duration,
+}
+
+impl MessageExtension for UnlockTarget {
+    const NAME: &'static str = "unlock-target@rfdonnelly.github.io";
+}
+
+impl Decode for UnlockTarget {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            target: String::decode(reader)?,
+            duration: Duration::from_secs(u32::decode(reader)?.into()),
+        })
+    }
+}
+
+impl Encode for UnlockTarget {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        [self.target.encoded_len()?, 0u32.encoded_len()?].checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        self.target.encode(writer)?;
+        let seconds = self.duration.as_secs().try_into().unwrap_or(u32::MAX);
+        seconds.encode(writer)
+    }
+}
+
+/// Unlocks hidden `target` of the mux serving on `host` for `duration`.
+pub async fn unlock_target(host: &Binding, target: &str, duration: Duration) -> Result<(), Error> {
+    let request = async {
+        let mut client = Client::connect(host.clone().try_into()?)?;
+        let extension = Extension::new_message(UnlockTarget {
+            target: target.to_owned(),
+            duration,
+        })?;
+        match client.handle(Request::Extension(extension)).await? {
+            Response::Success => Ok(()),
+            Response::Failure | Response::ExtensionFailure => Err(AgentError::Failure),
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    };
+    request.await.map_err(|source| Error::Unlock {
+        target: target.to_owned(),
+        source,
+    })
+}
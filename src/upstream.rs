@@ -0,0 +1,482 @@
+//! Target agents and the connections sessions hold to them.
+
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{
+        AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Extension, Identity,
+        ProtoError, RemoveIdentity, Request, Response, SignRequest, SmartcardKey,
+    },
+};
+use ssh_key::{public::KeyData, Signature};
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+use zeroize::Zeroizing;
+
+use crate::alert::FailureRate;
+use crate::client::{self, Client};
+use crate::config::{Destination, Flavor, TargetConfig};
+use crate::error::Error;
+use crate::{audit, chaos, dns, logging, onepassword, sandbox};
+
+/// How long a target that failed is avoided for.
+const UNHEALTHY_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A configured target agent, shared by all sessions.
+pub struct Target {
+    pub config: TargetConfig,
+    /// Limits requests in flight to the target across all sessions.
+    concurrency: Option<Semaphore>,
+    /// When the target last failed at the transport level, until it
+    /// succeeds again.
+    failed_at: std::sync::Mutex<Option<Instant>>,
+    failure_rate: FailureRate,
+    /// Moving average of how long the target took to sign, once it has.
+    sign_latency: std::sync::Mutex<Option<Duration>>,
+    /// Until when a hidden target is listed and used.
+    unlocked_until: std::sync::Mutex<Option<Instant>>,
+    /// The keys the target listed the last time, once it has.
+    identities: std::sync::Mutex<Option<Vec<KeyData>>>,
+}
+
+impl Target {
+    pub fn new(config: TargetConfig) -> Self {
+        Self {
+            concurrency: config.max_concurrent_requests.map(Semaphore::new),
+            config,
+            failed_at: Default::default(),
+            failure_rate: Default::default(),
+            sign_latency: Default::default(),
+            unlocked_until: Default::default(),
+            identities: Default::default(),
+        }
+    }
+
+    /// Lists and uses the target, if hidden, for `duration` from now.
+    pub fn unlock(&self, duration: Duration) {
+        *self.unlocked_until.lock().unwrap() = Some(Instant::now() + duration);
+    }
+
+    /// Whether the target is hidden and not unlocked.
+    pub fn is_hidden(&self) -> bool {
+        self.config.hidden
+            && self
+                .unlocked_until
+                .lock()
+                .unwrap()
+                .is_none_or(|until| Instant::now() >= until)
+    }
+
+    pub fn sign_latency(&self) -> Option<Duration> {
+        *self.sign_latency.lock().unwrap()
+    }
+
+    fn record_sign_latency(&self, latency: Duration) {
+        let mut average = self.sign_latency.lock().unwrap();
+        // Weighs recent signatures most, so a target that got slow loses
+        // traffic after a few of them.
+        *average = Some(match *average {
+            Some(average) => (average * 3 + latency) / 4,
+            None => latency,
+        });
+    }
+
+    /// When the target failed, if it hasn't succeeded since.
+    pub fn failed_at(&self) -> Option<Instant> {
+        *self.failed_at.lock().unwrap()
+    }
+
+    /// Requests in flight to the target, if they are limited.
+    pub fn requests_in_flight(&self) -> Option<usize> {
+        let semaphore = self.concurrency.as_ref()?;
+        Some(self.config.max_concurrent_requests? - semaphore.available_permits())
+    }
+
+    /// Whether the target is to be preferred over others holding the same
+    /// key.  Targets that failed are retried after a backoff.
+    pub fn is_healthy(&self) -> bool {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .is_none_or(|failed_at| failed_at.elapsed() >= UNHEALTHY_BACKOFF)
+    }
+
+    fn record_success(&self) {
+        if self.failed_at.lock().unwrap().take().is_some() {
+            audit::record(audit::Event::TargetUp {
+                target: self.name(),
+            });
+        }
+    }
+
+    fn record_failure(&self, e: &dyn fmt::Display) {
+        self.failure_rate.failed(self.name());
+        if self
+            .failed_at
+            .lock()
+            .unwrap()
+            .replace(Instant::now())
+            .is_none()
+        {
+            audit::record(audit::Event::TargetDown {
+                target: self.name(),
+                error: e,
+            });
+        }
+    }
+
+    /// Records an audit event if the target listed keys unlike the time
+    /// before.
+    fn record_identities(&self, identities: &[Identity]) {
+        let keys: Vec<_> = identities
+            .iter()
+            .map(|identity| identity.pubkey.clone())
+            .collect();
+        let Some(listed) = self.identities.lock().unwrap().replace(keys.clone()) else {
+            return;
+        };
+        let added: Vec<_> = keys
+            .iter()
+            .filter(|key| !listed.contains(key))
+            .cloned()
+            .collect();
+        let removed: Vec<_> = listed
+            .into_iter()
+            .filter(|key| !keys.contains(key))
+            .collect();
+        if !added.is_empty() || !removed.is_empty() {
+            audit::record(audit::Event::TargetIdentitiesChanged {
+                target: self.name(),
+                added: &added,
+                removed: &removed,
+            });
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// The hosts keys added to the target are restricted to.  None for
+    /// gpg-agent, which refuses keys with the constraint, so that the mux
+    /// only enforces them when signing.
+    pub fn restricted_destinations(&self) -> &[Destination] {
+        match self.config.flavor {
+            Flavor::GpgAgent => &[],
+            _ => &self.config.destinations,
+        }
+    }
+
+    /// Waits for a free request slot on the target, if it is limited.
+    async fn permit(&self) -> Option<SemaphorePermit<'_>> {
+        let semaphore = self.concurrency.as_ref()?;
+        if let Ok(permit) = semaphore.try_acquire() {
+            return Some(permit);
+        }
+        log::debug!("Queueing request for busy target {}", self.name());
+        // The semaphore is never closed.
+        semaphore.acquire().await.ok()
+    }
+}
+
+/// A session's connection to a target agent.
+///
+/// The connection takes one request at a time, in the order they were
+/// made.  With an `idle_timeout`, it is closed once idle for that long and
+/// reopened for the next request.
+#[derive(Clone)]
+pub struct Upstream {
+    pub target: Arc<Target>,
+    connection: Arc<Mutex<Connection>>,
+}
+
+struct Connection {
+    /// `None` once closed for being idle or broken, or while a request is in
+    /// flight, so that abandoning the request, e.g. when another target
+    /// signed first, closes the connection rather than leaving its answer
+    /// unread.
+    client: Option<Client>,
+    /// When the last request was answered.
+    used_at: Instant,
+}
+
+impl Upstream {
+    pub async fn connect(target: Arc<Target>) -> Result<Self, Error> {
+        let client = open(&target).await.map_err(|source| Error::Connect {
+            target: target.name().to_owned(),
+            source,
+        })?;
+        let connection = Arc::new(Mutex::new(Connection {
+            client: Some(client),
+            used_at: Instant::now(),
+        }));
+        if let Some(timeout) = target.config.idle_timeout {
+            tokio::spawn(close_when_idle(
+                Arc::downgrade(&connection),
+                timeout,
+                target.clone(),
+            ));
+        }
+        Ok(Self { target, connection })
+    }
+
+    pub fn name(&self) -> &str {
+        self.target.name()
+    }
+
+    async fn handle(&self, request: Request) -> Result<Response, AgentError> {
+        let mut connection = self.connection.lock().await;
+        let _permit = self.target.permit().await;
+        let (mut client, reused) = match connection.client.take() {
+            Some(client) => (client, true),
+            None => {
+                log::debug!("Reopening the connection to target {}", self.name());
+                match open(&self.target).await {
+                    Ok(client) => (client, false),
+                    Err(e) => {
+                        client::scrub(request);
+                        return Err(e.into());
+                    }
+                }
+            }
+        };
+        log::debug!(
+            "Forwarding to target {}: {}",
+            self.name(),
+            logging::Message(&request)
+        );
+        // Listing and signing can be sent again without harm.
+        let replay = (reused
+            && matches!(
+                request,
+                Request::RequestIdentities | Request::SignRequest(_)
+            ))
+        .then(|| request.clone());
+        #[cfg(unix)]
+        if matches!(
+            request,
+            Request::SignRequest(_) | Request::AddIdentity(_) | Request::AddIdConstrained(_)
+        ) {
+            self.pass_environment().await;
+        }
+        let mut result = self.exchange(&mut client, request).await;
+        if let (Some(request), Err(e)) = (replay, &result) {
+            if is_transport_error(e) {
+                // Most likely a connection that died while idle, e.g. over a
+                // sleep, rather than a target that is down.
+                log::info!(
+                    "Connection to target {} is dead, reconnecting to send the request again: {e}",
+                    self.name()
+                );
+                client = open(&self.target).await?;
+                result = self.exchange(&mut client, request).await;
+            }
+        }
+        // A broken connection is reopened for the next request instead.
+        if !matches!(&result, Err(e) if is_transport_error(e)) {
+            connection.client = Some(client);
+        }
+        connection.used_at = Instant::now();
+        match &result {
+            Ok(response) => {
+                log::debug!(
+                    "Target {} answered: {}",
+                    self.name(),
+                    logging::Message(response)
+                );
+                self.target.record_success();
+            }
+            Err(e) if is_transport_error(e) => self.target.record_failure(e),
+            Err(_) => (),
+        }
+        result
+    }
+
+    /// Passes the target's environment to gpg-agent for pinentry, which the
+    /// request may start.  Failing to only warns, as it may not.
+    #[cfg(unix)]
+    async fn pass_environment(&self) {
+        let Some(socket) = self.target.config.gpg_agent_socket() else {
+            return;
+        };
+        let environment = self.target.config.passed_environment();
+        if let Err(e) = crate::gpg::pass_environment(&socket, &environment).await {
+            log::warn!(
+                "Failed to pass the environment to gpg-agent of target {}: {e}",
+                self.name()
+            );
+        }
+    }
+
+    /// Sends `request` on `client`, failing like a broken connection if it
+    /// isn't answered within the target's `response_timeout`, unless
+    /// gpg-agent is signing, which may wait on pinentry.
+    async fn exchange(
+        &self,
+        client: &mut Client,
+        request: Request,
+    ) -> Result<Response, AgentError> {
+        let timeout = match (self.target.config.flavor, &request) {
+            (Flavor::GpgAgent, Request::SignRequest(_)) => None,
+            _ => self.target.config.response_timeout,
+        };
+        let response = chaos::inject(client.handle(request));
+        let Some(timeout) = timeout else {
+            return response.await;
+        };
+        tokio::time::timeout(timeout, response)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no answer within {}", humantime::format_duration(timeout)),
+                )
+                .into())
+            })
+    }
+
+    /// Sends a request that is answered with success or failure.
+    async fn handle_status(&self, request: Request) -> Result<(), AgentError> {
+        match self.handle(request).await? {
+            Response::Success => Ok(()),
+            Response::Failure => Err(AgentError::Failure),
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    }
+
+    pub async fn request_identities(&self) -> Result<Vec<Identity>, AgentError> {
+        match self.handle(Request::RequestIdentities).await? {
+            Response::IdentitiesAnswer(identities) => {
+                self.target.record_identities(&identities);
+                if self.target.config.flavor == Flavor::OnePassword {
+                    onepassword::label(&identities);
+                }
+                Ok(identities)
+            }
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    }
+
+    pub async fn sign(&self, request: SignRequest) -> Result<Signature, AgentError> {
+        let start = Instant::now();
+        match self.handle(Request::SignRequest(request)).await? {
+            Response::SignResponse(signature) => {
+                self.target.record_sign_latency(start.elapsed());
+                Ok(signature)
+            }
+            // A refusal, e.g. a denied confirmation, rather than a broken
+            // target.
+            Response::Failure => Err(AgentError::Failure),
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    }
+
+    pub async fn add_identity(&self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.handle_status(Request::AddIdentity(identity)).await
+    }
+
+    pub async fn add_identity_constrained(
+        &self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.handle_status(Request::AddIdConstrained(identity))
+            .await
+    }
+
+    pub async fn remove_identity(&self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        self.handle_status(Request::RemoveIdentity(identity)).await
+    }
+
+    pub async fn remove_all_identities(&self) -> Result<(), AgentError> {
+        self.handle_status(Request::RemoveAllIdentities).await
+    }
+
+    pub async fn add_smartcard_key(&self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.handle_status(Request::AddSmartcardKey(key)).await
+    }
+
+    pub async fn add_smartcard_key_constrained(
+        &self,
+        key: AddSmartcardKeyConstrained,
+    ) -> Result<(), AgentError> {
+        self.handle_status(Request::AddSmartcardKeyConstrained(key))
+            .await
+    }
+
+    pub async fn remove_smartcard_key(&self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.handle_status(Request::RemoveSmartcardKey(key)).await
+    }
+
+    /// Moves `passphrase` into the request, which is scrubbed once sent.
+    pub async fn lock(&self, mut passphrase: Zeroizing<String>) -> Result<(), AgentError> {
+        self.handle_status(Request::Lock(std::mem::take(&mut passphrase)))
+            .await
+    }
+
+    /// Moves `passphrase` into the request, which is scrubbed once sent.
+    pub async fn unlock(&self, mut passphrase: Zeroizing<String>) -> Result<(), AgentError> {
+        self.handle_status(Request::Unlock(std::mem::take(&mut passphrase)))
+            .await
+    }
+
+    pub async fn extension(&self, request: Extension) -> Result<Option<Extension>, AgentError> {
+        // gpg-agent answers them with a plain failure.
+        if self.target.config.flavor == Flavor::GpgAgent {
+            return Err(AgentError::ExtensionFailure);
+        }
+        match self.handle(Request::Extension(request)).await? {
+            Response::Success => Ok(None),
+            Response::ExtensionResponse(response) => Ok(Some(response)),
+            Response::ExtensionFailure => Err(AgentError::ExtensionFailure),
+            Response::Failure => Err(AgentError::Failure),
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    }
+}
+
+/// Connects to `target`, resolving it again if it is named by DNS.
+async fn open(target: &Target) -> io::Result<Client> {
+    match &target.config.tcp_name {
+        Some(name) => dns::resolve(name).await.map(Binding::Sockets),
+        None => Ok(target.config.binding.clone()),
+    }
+    .and_then(sandbox::connect)
+    .and_then(Client::connect)
+    .inspect_err(|e| target.record_failure(e))
+}
+
+/// Closes `connection` whenever it has been idle for `timeout`, until the
+/// session drops it.
+async fn close_when_idle(
+    connection: Weak<Mutex<Connection>>,
+    timeout: Duration,
+    target: Arc<Target>,
+) {
+    let mut idle_at = Instant::now() + timeout;
+    loop {
+        tokio::time::sleep_until(idle_at.into()).await;
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        let mut connection = connection.lock().await;
+        idle_at = connection.used_at + timeout;
+        if idle_at > Instant::now() {
+            continue;
+        }
+        if connection.client.take().is_some() {
+            log::debug!("Closed the idle connection to target {}", target.name());
+        }
+        // Nothing to close until the connection is reopened.
+        idle_at = Instant::now() + timeout;
+    }
+}
+
+/// Whether an error means the target is unreachable or broken, rather than
+/// that it refused the request.
+pub fn is_transport_error(e: &AgentError) -> bool {
+    matches!(e, AgentError::IO(_) | AgentError::Proto(_))
+}
@@ -0,0 +1,91 @@
+//! Views created while the mux is serving, through an extension of the agent
+//! protocol the mux answers itself, e.g. a socket with only a project's keys
+//! for its direnv config to point `SSH_AUTH_SOCK` at.
+//!
+//! Such a view is served next to the host socket until the mux exits, and
+//! reloaded along with it like the views of the config file.
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{extension::MessageExtension, Extension, ProtoError, Request, Response},
+    ssh_encoding::{self, CheckedSum, Decode, Encode, Reader, Writer},
+};
+use ssh_key::Fingerprint;
+
+use crate::client::Client;
+use crate::error::Error;
+
+const CREATE_VIEW: &str = "create-view@rfdonnelly.github.io";
+
+/// `create-view@rfdonnelly.github.io`: serves a view of the keys with these
+/// fingerprints, answered with where it is served, e.g. the path of its
+/// socket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CreateView {
+    pub name: String,
+    pub keys: Vec<String>,
+}
+
+impl MessageExtension for CreateView {
+    const NAME: &'static str = CREATE_VIEW;
+}
+
+impl Decode for CreateView {
+    type Error = ProtoError;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, Self::Error> {
+        Ok(Self {
+            name: String::decode(reader)?,
+            keys: Vec::decode(reader)?,
+        })
+    }
+}
+
+impl Encode for CreateView {
+    fn encoded_len(&self) -> ssh_encoding::Result<usize> {
+        [self.name.encoded_len()?, self.keys.encoded_len()?].checked_sum()
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> ssh_encoding::Result<()> {
+        self.name.encode(writer)?;
+        self.keys.encode(writer)
+    }
+}
+
+/// Whether `name` can name a created view, and its socket: letters, digits,
+/// `-` and `_`.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Has the mux serving on `host` serve view `name` of `keys`, answering
+/// where, e.g. the path of its socket.
+pub async fn create_view(
+    host: &Binding,
+    name: &str,
+    keys: &[Fingerprint],
+) -> Result<String, Error> {
+    let request = async {
+        let mut client = Client::connect(host.clone().try_into()?)?;
+        let extension = Extension::new_message(CreateView {
+            name: name.to_owned(),
+            keys: keys.iter().map(Fingerprint::to_string).collect(),
+        })?;
+        match client.handle(Request::Extension(extension)).await? {
+            Response::ExtensionResponse(response) if response.name == CREATE_VIEW => Ok(response
+                .details
+                .parse::<String>()
+                .map_err(ProtoError::from)?),
+            Response::Failure | Response::ExtensionFailure => Err(AgentError::Failure),
+            _ => Err(ProtoError::UnexpectedResponse.into()),
+        }
+    };
+    request.await.map_err(|source| Error::CreateView {
+        name: name.to_owned(),
+        source,
+    })
+}
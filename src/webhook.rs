@@ -0,0 +1,117 @@
+//! Delivery of audit events to a webhook.
+//!
+//! Events are posted as JSON objects over HTTP or HTTPS, each from its own
+//! task so that a slow or unreachable endpoint never holds up a request.
+//! With a secret, the HMAC-SHA256 of the body is sent in the
+//! `X-Signature-256` header as `sha256=<hex>`.
+
+use std::fmt::Write as _;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use sha2::Sha256;
+
+use crate::config::{HttpUrl, WebhookConfig};
+
+/// How long one attempt at posting an event may take.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before the first retry, doubled for each one after.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+
+static WEBHOOK: OnceLock<Webhook> = OnceLock::new();
+
+pub struct Webhook {
+    url: HttpUrl,
+    /// Set up front, with the system's CA certificates, as the sandbox may
+    /// keep the mux from reading them later.  The host is resolved on every
+    /// delivery, so that it may move.
+    client: reqwest::Client,
+    secret: Option<Vec<u8>>,
+    retries: u32,
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(ATTEMPT_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            url: config.url.clone(),
+            client,
+            secret: config
+                .secret
+                .as_ref()
+                .map(|secret| secret.as_bytes().to_vec()),
+            retries: config.retries,
+        })
+    }
+
+    /// Posts an event in the background, retrying with backoff.
+    pub fn post(&'static self, event: &str, body: String) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            log::warn!("Not posting {event} event outside the async runtime");
+            return;
+        };
+        runtime.spawn(self.deliver(body));
+    }
+
+    async fn deliver(&self, body: String) {
+        let mut backoff = FIRST_BACKOFF;
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            match self.send(&body).await {
+                Ok(()) => return,
+                Err(e) => log::warn!("Failed to post audit event to {}: {e}", self.url),
+            }
+        }
+        log::error!(
+            "Gave up posting audit event to {} after {} attempts",
+            self.url,
+            self.retries + 1
+        );
+    }
+
+    async fn send(&self, body: &str) -> Result<(), reqwest::Error> {
+        let mut request = self
+            .client
+            .post(self.url.to_string())
+            .header(CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            request = request.header("X-Signature-256", format!("sha256={}", sign(secret, body)));
+        }
+        request
+            .body(body.to_owned())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Posts audit events to `webhook` from now on.
+pub fn install(webhook: Webhook) {
+    let _ = WEBHOOK.set(webhook);
+}
+
+/// The installed webhook, if any.
+pub(crate) fn installed() -> Option<&'static Webhook> {
+    WEBHOOK.get()
+}
+
+/// Hex-encoded HMAC-SHA256 of `body`.
+fn sign(secret: &[u8], body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
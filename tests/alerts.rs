@@ -0,0 +1,43 @@
+//! End-to-end tests of error rate alerts, in their own process as the
+//! thresholds and the audit log are set for the whole process.
+
+mod common;
+
+use ssh_agent_lib::agent::Session;
+use ssh_agent_mux::alert;
+use ssh_agent_mux::audit;
+use ssh_agent_mux::config::AlertsConfig;
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+#[tokio::test]
+async fn alerts_once_of_targets_failing_often() {
+    let dir = TestDir::new();
+    let log = dir.path("audit.log");
+    audit::open_log(&log).unwrap();
+    alert::set_thresholds(&AlertsConfig {
+        target_failures: Some(3),
+        window: None,
+    });
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    // Never spawned, so every connection to it fails.
+    let mock2 = MockAgent::new(2);
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    // Each session connects to the targets anew.
+    for _ in 0..6 {
+        connect(&mux).await.request_identities().await.unwrap();
+    }
+
+    let alerts: Vec<_> = std::fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .filter(|line| line.contains("\"event\":\"error_rate\""))
+        .map(str::to_owned)
+        .collect();
+    assert_eq!(alerts.len(), 1, "{alerts:?}");
+    assert!(alerts[0].contains("\"target\":\"mock2\""), "{}", alerts[0]);
+    assert!(alerts[0].contains("\"failures\":\"3\""), "{}", alerts[0]);
+    assert!(alerts[0].contains("\"window\":\"60\""), "{}", alerts[0]);
+}
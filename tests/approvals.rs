@@ -0,0 +1,141 @@
+//! End-to-end tests of confirmations, in their own process as
+//! `SSH_ASKPASS` is set for the whole process.
+
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use ssh_agent_lib::{agent::Session, async_trait, client::Client, proto::SignRequest};
+use ssh_agent_mux::approver::{self, Approver, Decision};
+use ssh_agent_mux::MuxAgentBind;
+use ssh_agent_mux::{approval, askpass};
+use ssh_key::HashAlg;
+use tokio::net::TcpStream;
+
+use common::{key, spawn_tcp_mux, spawn_tcp_mux_with, MockAgent, TestDir};
+
+fn sign_request(n: u8) -> SignRequest {
+    SignRequest {
+        pubkey: key(n),
+        data: b"data".to_vec(),
+        flags: 0,
+    }
+}
+
+/// Makes `SSH_ASKPASS` approve everything, appending each question to the
+/// returned file.
+fn install_askpass(dir: &TestDir) -> PathBuf {
+    let asked = dir.path("asked");
+    let askpass = dir.path("askpass");
+    std::fs::write(
+        &askpass,
+        format!("#!/bin/sh\necho \"$1\" >> {}\n", asked.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&askpass, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::env::set_var("SSH_ASKPASS", &askpass);
+    asked
+}
+
+/// Denies everything, keeping the questions asked.
+#[derive(Default)]
+struct Denier {
+    asked: Mutex<Vec<approver::Request>>,
+}
+
+#[async_trait]
+impl Approver for Denier {
+    async fn decide(&self, request: &approver::Request) -> Decision {
+        self.asked.lock().unwrap().push(request.clone());
+        Decision::Deny
+    }
+}
+
+#[tokio::test]
+async fn asks_once_per_key_and_client_until_revoked() {
+    let dir = TestDir::new();
+    let asked = install_askpass(&dir);
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_tcp_mux(
+        &dir,
+        &[&mock],
+        "approval_cache = \"5m\"\n[client_addresses]\n\"127.0.0.0/8\" = \"confirm\"",
+    );
+    let host = format!("tcp://{mux}").parse().unwrap();
+    let request = sign_request(1);
+    let times_asked = || {
+        std::fs::read_to_string(&asked)
+            .unwrap_or_default()
+            .lines()
+            .count()
+    };
+
+    let mut client = Client::new(TcpStream::connect(mux).await.unwrap());
+    client.sign(request.clone()).await.unwrap();
+    client.sign(request.clone()).await.unwrap();
+    assert_eq!(times_asked(), 1);
+
+    let approvals = approval::list_approvals(&host).await.unwrap();
+    assert_eq!(approvals.len(), 1);
+    assert_eq!(
+        approvals[0].key,
+        key(1).fingerprint(HashAlg::Sha256).to_string()
+    );
+    assert_eq!(approvals[0].client, "127.0.0.1");
+    assert!(approvals[0].hosts.is_empty());
+
+    let revoked = approval::revoke_approvals(&host, "").await.unwrap();
+    assert_eq!(revoked.len(), 1);
+    client.sign(request).await.unwrap();
+    assert_eq!(times_asked(), 2);
+}
+
+#[tokio::test]
+async fn asks_the_approver_given_by_the_embedder() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(2), "two");
+    mock.spawn(&dir);
+    let denier = Arc::new(Denier::default());
+    let approver = denier.clone();
+    let mux = spawn_tcp_mux_with(
+        &dir,
+        &[&mock],
+        "[client_addresses]\n\"127.0.0.0/8\" = \"confirm\"",
+        |config| MuxAgentBind::new(config).with_approver(approver),
+    );
+
+    let mut client = Client::new(TcpStream::connect(mux).await.unwrap());
+    let result = client.sign(sign_request(2)).await;
+
+    assert!(result.is_err());
+    let questions = denier.asked.lock().unwrap();
+    assert_eq!(questions.len(), 1);
+    assert_eq!(questions[0].key, key(2));
+    assert_eq!(questions[0].client.as_deref(), Some("127.0.0.1"));
+    assert_eq!(questions[0].targets, ["mock1"]);
+}
+
+#[test]
+fn asks_on_the_terminal_with_a_y_n_prompt() {
+    let ask = |input: &str| {
+        let mut output = Vec::new();
+        let answer = askpass::ask(input.as_bytes(), &mut output, "Allow use of key work?").unwrap();
+        (answer, String::from_utf8(output).unwrap())
+    };
+
+    assert_eq!(
+        ask("y\n"),
+        (
+            true,
+            "\nssh-agent-mux: Allow use of key work? [y/N] ".to_owned()
+        )
+    );
+    assert!(ask(" YES \n").0);
+    assert!(!ask("n\n").0);
+    assert!(!ask("\n").0);
+    // The terminal closed before an answer.
+    assert!(!ask("").0);
+}
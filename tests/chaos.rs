@@ -0,0 +1,53 @@
+//! Tests of fault injection, in their own process as the schedule is
+//! installed for the whole process.
+
+mod common;
+
+use ssh_agent_lib::{agent::Session, proto::SignRequest};
+use ssh_agent_mux::chaos::{self, Chaos, Fault};
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+#[test]
+fn schedules_the_same_faults_for_the_same_seed() {
+    let chaos: Chaos = "seed=42,delay=0.5:1s,drop=0.25,malformed=0.25"
+        .parse()
+        .unwrap();
+    assert_eq!(chaos.to_string().parse::<Chaos>().unwrap(), chaos);
+
+    let faults: Vec<_> = chaos.schedule().take(100).collect();
+    assert_eq!(faults, chaos.schedule().take(100).collect::<Vec<_>>());
+    let count = |fault| faults.iter().filter(|f| f.fault == Some(fault)).count();
+    assert!((10..40).contains(&count(Fault::Drop)));
+    assert!((10..40).contains(&count(Fault::Malformed)));
+    assert!(faults
+        .iter()
+        .filter_map(|f| f.delay)
+        .all(|delay| delay.as_secs_f64() < 1.0));
+
+    let other: Chaos = "seed=43,drop=0.5".parse().unwrap();
+    assert_ne!(faults, other.schedule().take(100).collect::<Vec<_>>());
+    assert!("drop=0.75,malformed=0.5".parse::<Chaos>().is_err());
+    assert!("drop=2".parse::<Chaos>().is_err());
+}
+
+#[tokio::test]
+async fn drops_requests_to_targets() {
+    chaos::install(&"seed=1,drop=1".parse().unwrap());
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "");
+
+    let mut client = connect(&mux).await;
+    let result = client
+        .sign(SignRequest {
+            pubkey: key(1),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .await;
+
+    assert!(result.is_err());
+    assert!(mock.requests().is_empty());
+}
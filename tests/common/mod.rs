@@ -0,0 +1,381 @@
+//! Mock target agents and a mux to run in front of them.
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use service_binding::Listener;
+use ssh_agent_lib::{
+    agent::{listen, Session},
+    async_trait,
+    client::Client,
+    error::AgentError,
+    proto::{Identity, Request, Response},
+};
+use ssh_agent_mux::config::{Config, Host};
+use ssh_agent_mux::serve::{serve_until, ServeOptions};
+use ssh_agent_mux::{MuxAgentBind, MuxState};
+use ssh_key::{public::Ed25519PublicKey, public::KeyData, Algorithm, Signature};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+
+/// A public key that is unique per `n`.  The mux never checks that keys or
+/// signatures are valid, so there's no need for real ones.
+pub fn key(n: u8) -> KeyData {
+    KeyData::Ed25519(Ed25519PublicKey([n; 32]))
+}
+
+/// A directory for sockets and configs, removed when dropped.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "ssh-agent-mux-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    identities: Vec<Identity>,
+    delay: Duration,
+    failing: bool,
+    crashed: bool,
+    crash_next: bool,
+    hang_next: bool,
+    requests: Vec<Request>,
+}
+
+/// A scriptable target agent.
+///
+/// Signatures are made of the mock's ID byte so tests can tell which mock
+/// signed.
+#[derive(Clone)]
+pub struct MockAgent {
+    id: u8,
+    state: Arc<Mutex<MockState>>,
+    /// Extra lines for the mock's table in the mux config.
+    target_options: String,
+}
+
+impl MockAgent {
+    pub fn new(id: u8) -> Self {
+        Self {
+            id,
+            state: Default::default(),
+            target_options: String::new(),
+        }
+    }
+
+    pub fn with_target_options(mut self, options: &str) -> Self {
+        self.target_options = options.to_owned();
+        self
+    }
+
+    pub fn with_key(self, key: KeyData, comment: &str) -> Self {
+        self.state.lock().unwrap().identities.push(Identity {
+            pubkey: key,
+            comment: comment.to_owned(),
+        });
+        self
+    }
+
+    /// Lists only `key` from now on, like an agent whose keys were swapped.
+    pub fn set_key(&self, key: KeyData, comment: &str) {
+        self.state.lock().unwrap().identities = vec![Identity {
+            pubkey: key,
+            comment: comment.to_owned(),
+        }];
+    }
+
+    /// Delays every answer by `delay`.
+    pub fn set_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+
+    /// Answers every request with a failure while set.
+    pub fn set_failing(&self, failing: bool) {
+        self.state.lock().unwrap().failing = failing;
+    }
+
+    /// Closes connections on every request instead of answering while set,
+    /// like an agent that crashed.
+    pub fn set_crashed(&self, crashed: bool) {
+        self.state.lock().unwrap().crashed = crashed;
+    }
+
+    /// Closes the connection on the next request instead of answering, like
+    /// a connection the agent dropped while it was idle.
+    pub fn crash_next(&self) {
+        self.state.lock().unwrap().crash_next = true;
+    }
+
+    /// Never answers the next request, like a connection left half-open by
+    /// a sleep.
+    pub fn hang_next(&self) {
+        self.state.lock().unwrap().hang_next = true;
+    }
+
+    /// Requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn signature(&self) -> Signature {
+        Signature::new(Algorithm::Ed25519, vec![self.id; 64]).unwrap()
+    }
+
+    pub fn socket(&self, dir: &TestDir) -> PathBuf {
+        dir.path(&format!("mock{}.sock", self.id))
+    }
+
+    /// Serves the mock on its socket in `dir`.
+    pub fn spawn(&self, dir: &TestDir) {
+        let listener = UnixListener::bind(self.socket(dir)).unwrap();
+        tokio::spawn(listen(listener, self.clone()));
+    }
+
+    /// Serves the mock over TCP on the loopback interface, returning the
+    /// port.
+    pub async fn spawn_tcp(&self) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(listen(listener, self.clone()));
+        port
+    }
+}
+
+#[async_trait]
+impl Session for MockAgent {
+    async fn handle(&mut self, message: Request) -> Result<Response, AgentError> {
+        let (delay, failing, crashed, hung) = {
+            let mut state = self.state.lock().unwrap();
+            state.requests.push(message.clone());
+            let crashed = state.crashed || std::mem::take(&mut state.crash_next);
+            let hung = std::mem::take(&mut state.hang_next);
+            (state.delay, state.failing, crashed, hung)
+        };
+        if hung {
+            return futures::future::pending().await;
+        }
+        if crashed {
+            // Ends the connection's task, dropping the connection, without
+            // the noise of a panic.
+            std::panic::resume_unwind(Box::new("crashed"));
+        }
+        // Even a zero sleep waits for the next timer tick.
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        if failing {
+            return Err(AgentError::Failure);
+        }
+        Ok(match message {
+            Request::RequestIdentities => {
+                Response::IdentitiesAnswer(self.state.lock().unwrap().identities.clone())
+            }
+            Request::SignRequest(_) => Response::SignResponse(self.signature()),
+            _ => Response::Success,
+        })
+    }
+}
+
+/// Starts a mux in front of `targets`, named `mock<id>`, with `config`
+/// prepended to its config file.  Returns the mux's socket.
+pub fn spawn_mux(dir: &TestDir, targets: &[&MockAgent], config: &str) -> PathBuf {
+    spawn_mux_until(dir, targets, config, futures::future::pending()).socket
+}
+
+pub struct Mux {
+    pub socket: PathBuf,
+    pub state: MuxState,
+    pub serving: JoinHandle<Result<(), AgentError>>,
+}
+
+/// Like [`spawn_mux`], but shuts the mux down once `shutdown` completes.
+pub fn spawn_mux_until(
+    dir: &TestDir,
+    targets: &[&MockAgent],
+    config: &str,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Mux {
+    let socket = dir.path("mux.sock");
+    let config = load_config(
+        dir,
+        targets,
+        &format!("host = \"unix://{}\"\n{config}", socket.display()),
+    );
+    let Some(Host::Binding(host)) = config.host.clone() else {
+        panic!("serving on stdio");
+    };
+    let listener = host.try_into().unwrap();
+    let options = ServeOptions::from(&config);
+    let agent = MuxAgentBind::new(&config);
+    let state = agent.state();
+    let serving = tokio::spawn(serve_until(listener, agent, options, shutdown));
+    Mux {
+        socket,
+        state,
+        serving,
+    }
+}
+
+/// Like [`spawn_mux`], but serving on a TCP port of the loopback interface,
+/// returning its address.
+pub fn spawn_tcp_mux(dir: &TestDir, targets: &[&MockAgent], config: &str) -> SocketAddr {
+    spawn_tcp_mux_with(dir, targets, config, MuxAgentBind::new)
+}
+
+/// Like [`spawn_tcp_mux`], but with the mux `bind` makes of the config.
+pub fn spawn_tcp_mux_with(
+    dir: &TestDir,
+    targets: &[&MockAgent],
+    config: &str,
+    bind: impl FnOnce(&Config) -> MuxAgentBind,
+) -> SocketAddr {
+    let config = load_config(
+        dir,
+        targets,
+        &format!("host = \"tcp://127.0.0.1:0\"\n{config}"),
+    );
+    let Some(Host::Binding(host)) = config.host.clone() else {
+        panic!("serving on stdio");
+    };
+    let listener: Listener = host.try_into().unwrap();
+    let Listener::Tcp(tcp) = &listener else {
+        panic!("not serving on TCP");
+    };
+    let address = tcp.local_addr().unwrap();
+    let options = ServeOptions::from(&config);
+    tokio::spawn(serve_until(
+        listener,
+        bind(&config),
+        options,
+        futures::future::pending(),
+    ));
+    address
+}
+
+/// Loads `config` with `targets`, named `mock<id>`, added.
+pub fn load_config(dir: &TestDir, targets: &[&MockAgent], config: &str) -> Config {
+    let mut contents = format!("{config}\n");
+    for mock in targets {
+        contents += &format!(
+            "[targets.mock{}]\nbinding = \"unix://{}\"\n{}\n",
+            mock.id,
+            mock.socket(dir).display(),
+            mock.target_options
+        );
+    }
+    let config_path = dir.path("mux.toml");
+    std::fs::write(&config_path, contents).unwrap();
+    Config::load(&config_path).unwrap()
+}
+
+pub async fn connect(socket: &Path) -> Client<UnixStream> {
+    Client::new(UnixStream::connect(socket).await.unwrap())
+}
+
+/// A WebAssembly plugin deciding odd-numbered sign requests as `odd` and the
+/// others as `even`, assembled by hand.
+pub fn route_plugin(odd: &str, even: &str) -> Vec<u8> {
+    fn leb(mut value: u64, out: &mut Vec<u8>) {
+        loop {
+            let byte = value as u8 & 0x7f;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+    fn sleb(mut value: i64, out: &mut Vec<u8>) {
+        loop {
+            let byte = value as u8 & 0x7f;
+            value >>= 7;
+            if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+    fn section(id: u8, contents: &[u8], out: &mut Vec<u8>) {
+        out.push(id);
+        leb(contents.len() as u64, out);
+        out.extend_from_slice(contents);
+    }
+    // Decisions are kept at these addresses.
+    let (odd_at, even_at) = (16, 256);
+    let decide = |at: i64, text: &str| {
+        let mut code = vec![0x42];
+        sleb(at << 32 | text.len() as i64, &mut code);
+        code
+    };
+    // alloc: always the same buffer.
+    let alloc = [0x00, 0x41, 0x80, 0x08, 0x0b];
+    // route: counts calls in global 0, deciding by whether the count is odd.
+    let mut route = vec![
+        0x00, 0x23, 0x00, 0x41, 0x01, 0x6a, 0x24, 0x00, 0x23, 0x00, 0x41, 0x01, 0x71, 0x04, 0x7e,
+    ];
+    route.extend(decide(odd_at, odd));
+    route.push(0x05);
+    route.extend(decide(even_at, even));
+    route.extend([0x0b, 0x0b]);
+    let mut code = vec![2];
+    for body in [&alloc[..], &route] {
+        leb(body.len() as u64, &mut code);
+        code.extend_from_slice(body);
+    }
+    let mut data = vec![2];
+    for (at, text) in [(odd_at, odd), (even_at, even)] {
+        data.push(0x00);
+        data.push(0x41);
+        sleb(at, &mut data);
+        data.push(0x0b);
+        leb(text.len() as u64, &mut data);
+        data.extend_from_slice(text.as_bytes());
+    }
+
+    let mut module = b"\0asm\x01\0\0\0".to_vec();
+    section(
+        1,
+        &[2, 0x60, 1, 0x7f, 1, 0x7f, 0x60, 2, 0x7f, 0x7f, 1, 0x7e],
+        &mut module,
+    );
+    section(3, &[2, 0, 1], &mut module);
+    section(5, &[1, 0, 1], &mut module);
+    section(6, &[1, 0x7f, 1, 0x41, 0, 0x0b], &mut module);
+    let mut exports = vec![3];
+    for (name, kind, index) in [("memory", 2, 0), ("alloc", 0, 0), ("route", 0, 1)] {
+        exports.push(name.len() as u8);
+        exports.extend_from_slice(name.as_bytes());
+        exports.extend([kind, index]);
+    }
+    section(7, &exports, &mut module);
+    section(10, &code, &mut module);
+    section(11, &data, &mut module);
+    module
+}
@@ -0,0 +1,891 @@
+//! Tests of config file loading and checking.
+
+mod common;
+
+use service_binding::Binding;
+use ssh_agent_mux::config::{self, Config, ConfigError, Flavor, HttpUrl, TargetSpec, TimeWindow};
+
+use common::{route_plugin, TestDir};
+
+#[test]
+fn reports_invalid_entries_with_their_option_and_line() {
+    let dir = TestDir::new();
+    let errors = |toml: &str| load_errors(&dir, "mux.toml", toml);
+
+    assert_eq!(
+        errors("max_sessions = 1\n[targets.a]\npriority = \"high\"\n"),
+        [(
+            3,
+            "targets.a.priority: invalid type: string \"high\", expected i64".to_owned()
+        )]
+    );
+    assert_eq!(
+        errors("[targets.a]\npriority = 1\n"),
+        [(1, "targets.a: missing field `binding`".to_owned())]
+    );
+    assert_eq!(
+        errors("[alerts]\nwindow = \"0s\"\n"),
+        [(2, "alerts.window: must be longer than zero".to_owned())]
+    );
+    assert_eq!(
+        errors("[keys.\"SHA256:x\"]\nnickname = \"work\"\n"),
+        [(
+            1,
+            "keys: invalid fingerprint 'SHA256:x': Base64 encoding error: invalid Base64 encoding"
+                .to_owned()
+        )]
+    );
+    let unknown = errors("max_sessions = 1\nunknown = 1\n");
+    assert_eq!(unknown[0].0, 2);
+    assert!(
+        unknown[0]
+            .1
+            .starts_with("unknown: unknown field `unknown`, expected one of `host`"),
+        "{unknown:?}"
+    );
+    assert_eq!(
+        errors("max_sessions = 1\nmax_sessions = 2\n"),
+        [(2, "duplicate key".to_owned())]
+    );
+}
+
+#[test]
+fn checks_the_complete_configuration() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    let target = dir.path("missing.sock");
+    std::fs::write(
+        &path,
+        format!(
+            "host = \"unix://{0}\"\n[targets]\na = \"unix://{0}\"\n",
+            target.display()
+        ),
+    )
+    .unwrap();
+
+    let problems = Config::load(&path).unwrap().check();
+
+    assert_eq!(
+        problems,
+        [
+            "host: same as target a, so the mux would proxy to itself; is SSH_AUTH_SOCK already the mux?".to_owned(),
+            format!("targets.a: {} doesn't exist", target.display()),
+        ]
+    );
+}
+
+#[test]
+fn schema_covers_every_option() {
+    let options = [
+        ("host", "\"unix:///tmp/mux.sock\""),
+        ("session_idle_timeout", "\"10m\""),
+        ("max_sessions", "64"),
+        ("max_message_size", "262144"),
+        ("session_max_requests", "1000"),
+        ("session_max_bytes", "1048576"),
+        ("legacy_v1", "true"),
+        ("max_pipelined_requests", "16"),
+        ("duplicate_keys", "\"priority\""),
+        ("max_identities", "10"),
+        ("sign_retries", "2"),
+        ("shutdown_timeout", "\"10s\""),
+        ("redact_logs", "true"),
+        ("log_levels", "{ routing = \"debug\", audit = \"info\" }"),
+        ("event_socket", "\"/tmp/mux-events.sock\""),
+        ("admin_http", "\"tcp://127.0.0.1:7070\""),
+        ("admin_grpc", "\"/tmp/mux-grpc.sock\""),
+        ("dbus_signals", "true"),
+        ("notification_digest", "\"10s\""),
+        ("alerts", "{ target_failures = 10, window = \"1m\" }"),
+        ("usage_file", "\"/tmp/mux-usage\""),
+        ("key_cache", "\"/tmp/mux-keys\""),
+        ("onepassword_agent_toml", "\"/tmp/agent.toml\""),
+        ("audit_log", "\"/tmp/mux-audit.log\""),
+        ("allowed_uids", "[1000]"),
+        ("allowed_gids", "[1000]"),
+        ("client_addresses", "{ \"10.0.0.0/8\" = \"confirm\" }"),
+        (
+            "canary_keys",
+            "[\"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOzdqs6GJAGyXn6rqtqMXRD1ff5/7D8A1lZL0xO5ApsT\"]",
+        ),
+        ("anomalies", "\"confirm\""),
+        ("approval_cache", "\"5m\""),
+        ("confirm_prompt", "\"askpass\""),
+        ("add_constraints", "{ confirm = true, lifetime = \"8h\" }"),
+        (
+            "sandbox",
+            "{ user = \"nobody\", group = \"nogroup\", landlock = true, pledge = true, seccomp = \"log\", chroot = \"/var/empty\", capsicum = true }",
+        ),
+        (
+            "socket_label",
+            "{ selinux = \"system_u:object_r:tmp_t:s0\", smack = \"_\" }",
+        ),
+        ("pipe_security", "{ allow_groups = [\"BA\"] }"),
+        (
+            "webhook",
+            "{ url = \"http://127.0.0.1:9000/hook\", secret = \"s\", retries = 1 }",
+        ),
+        (
+            "targets",
+            "{ a = { binding = \"unix:///tmp/a.sock\", max_concurrent_requests = 1, priority = 1, add_key_types = [\"rsa\"], max_identities = 1, idle_timeout = \"5m\", response_timeout = \"1m\", fallback = true, enumeration = \"lazy\", hidden = true, destinations = [\"host.example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOzdqs6GJAGyXn6rqtqMXRD1ff5/7D8A1lZL0xO5ApsT\"], flavor = \"gpg-agent\", environment = { GPG_TTY = \"/dev/tty1\" }, forward_environment = [\"DISPLAY\"] } }",
+        ),
+        (
+            "keys",
+            "{ \"SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E\" = { nickname = \"work\", max_signatures_per_hour = 1, cooldown = \"2s\", allowed_host_keys = [\"SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E\"], require_session_bind = true, allowed_times = [\"Mon-Fri 08:00-19:00\"], exec = \"/bin/true\" } }",
+        ),
+        ("profiles", "{ work = { max_sessions = 1 } }"),
+        (
+            "routes",
+            "{ \"SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E\" = \"a\" }",
+        ),
+        (
+            "views",
+            "{ deploy = { host = \"unix:///tmp/deploy.sock\", targets = [\"a\"], keys = [\"SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E\"], match_hosts = [\"*.example.com\"] } }",
+        ),
+        ("route_script", "\"route.rhai\""),
+        ("plugins", "[\"route.wasm\"]"),
+    ];
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    let contents: String = options
+        .iter()
+        .map(|(key, value)| format!("{key} = {value}\n"))
+        .collect();
+    std::fs::write(&path, contents).unwrap();
+    std::fs::write(dir.path("route.rhai"), "fn route(request, targets) {}").unwrap();
+    std::fs::write(dir.path("route.wasm"), route_plugin("", "")).unwrap();
+    Config::load(&path).unwrap();
+
+    let names = options.iter().map(|(key, _)| *key).chain([
+        "confirm",
+        "lifetime",
+        "user",
+        "group",
+        "landlock",
+        "pledge",
+        "seccomp",
+        "chroot",
+        "capsicum",
+        "binding",
+        "max_concurrent_requests",
+        "priority",
+        "add_key_types",
+        "idle_timeout",
+        "fallback",
+        "hidden",
+        "destinations",
+        "nickname",
+        "max_signatures_per_hour",
+        "cooldown",
+        "allowed_host_keys",
+        "require_session_bind",
+        "allowed_times",
+        "exec",
+        "selinux",
+        "smack",
+        "allow_groups",
+        "sddl",
+        "url",
+        "secret",
+        "retries",
+    ]);
+    for name in names {
+        assert!(
+            config::SCHEMA.contains(&format!("\"{name}\": {{")),
+            "{name} is missing from the schema"
+        );
+    }
+}
+
+#[test]
+fn loads_yaml_and_json_like_toml() {
+    let toml = r#"
+host = "unix:///tmp/mux.sock"
+session_idle_timeout = "10m"
+legacy_v1 = true
+
+[targets]
+a = "unix:///tmp/a.sock"
+
+[targets.b]
+binding = "unix:///tmp/b.sock"
+priority = -1
+add_key_types = ["rsa", "ed25519"]
+
+[keys."SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E"]
+max_signatures_per_hour = 20
+"#;
+    let yaml = r#"
+---
+# Comments are skipped
+host: unix:///tmp/mux.sock
+session_idle_timeout: '10m'
+legacy_v1: true  # so are trailing ones
+targets:
+  a: unix:///tmp/a.sock
+  b:
+    binding: "unix:///tmp/b.sock"
+    priority: -1
+    add_key_types:
+    - rsa
+    - ed25519
+keys:
+  "SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E": {max_signatures_per_hour: 20}
+"#;
+    let json = r#"{
+  "host": "unix:///tmp/mux.sock",
+  "session_idle_timeout": "10m",
+  "legacy_v1": true,
+  "targets": {
+    "a": "unix:///tmp/a.sock",
+    "b": {"binding": "unix:///tmp/b.sock", "priority": -1, "add_key_types": ["rsa", "ed25519"]}
+  },
+  "keys": {
+    "SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E": {"max_signatures_per_hour": 20}
+  }
+}"#;
+    let dir = TestDir::new();
+    let load = |name: &str, contents: &str| {
+        let path = dir.path(name);
+        std::fs::write(&path, contents).unwrap();
+        format!("{:?}", Config::load(&path).unwrap())
+    };
+
+    let expected = load("mux.toml", toml);
+    assert_eq!(load("mux.yaml", yaml), expected);
+    assert_eq!(load("mux.json", json), expected);
+}
+
+#[test]
+fn reports_yaml_errors_with_their_line() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.yml");
+    std::fs::write(&path, "targets:\n  a: unix:///tmp/a.sock\n   b: x\n").unwrap();
+
+    let Err(ConfigError::Invalid(_, errors)) = Config::load(&path) else {
+        panic!("config loaded");
+    };
+    assert_eq!(errors[0].line, 3);
+}
+
+/// The config in `contents`, loaded from a file named `name`, as debugged.
+fn loaded(dir: &TestDir, name: &str, contents: &str) -> String {
+    let path = dir.path(name);
+    std::fs::write(&path, contents).unwrap();
+    format!("{:?}", Config::load(&path).unwrap())
+}
+
+/// The lines and messages of the errors of the config in `contents`,
+/// loaded from a file named `name`.
+fn load_errors(dir: &TestDir, name: &str, contents: &str) -> Vec<(usize, String)> {
+    let path = dir.path(name);
+    std::fs::write(&path, contents).unwrap();
+    let Err(ConfigError::Invalid(_, errors)) = Config::load(&path) else {
+        panic!("config loaded: {contents}");
+    };
+    errors.into_iter().map(|e| (e.line, e.message)).collect()
+}
+
+#[test]
+fn parses_yaml_quoting_and_escapes() {
+    let toml = r#"
+key_cache = "tab\there \"quoted\" back\\slash \u00e9 / # kept"
+usage_file = "it's \\n # kept"
+audit_log = "plain#kept:colon"
+event_socket = "123"
+admin_grpc = "inf"
+"#;
+    let yaml = r#"
+key_cache: "tab\there \"quoted\" back\\slash \u00e9 \/ # kept"  # dropped
+usage_file: 'it''s \n # kept'
+audit_log: plain#kept:colon # dropped
+"event_socket": "123"
+'admin_grpc': inf
+"#;
+    let dir = TestDir::new();
+
+    assert_eq!(
+        loaded(&dir, "mux.yaml", yaml),
+        loaded(&dir, "mux.toml", toml)
+    );
+}
+
+#[test]
+fn parses_yaml_nesting() {
+    let toml = r#"
+[targets.a]
+binding = "unix:///tmp/a.sock"
+add_key_types = ["rsa", "ed25519"]
+
+[targets.b]
+binding = "unix:///tmp/b.sock"
+priority = 2
+add_key_types = ["ecdsa"]
+
+[keys."SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E"]
+nickname = "work"
+allowed_times = ["Mon-Fri 08:00-19:00", "Sat 10:00-12:00"]
+
+[alerts]
+target_failures = 3
+"#;
+    let yaml = r#"
+targets:
+    a:
+        binding: unix:///tmp/a.sock
+        add_key_types:
+          - rsa
+          - "ed25519"
+    b: {binding: 'unix:///tmp/b.sock', priority: 2, add_key_types: [ecdsa]}
+keys:
+  "SHA256:2ETgWaKcVbLcLzA8YTfMY/dh9pNhGHGq/HwIFuJyn+E":
+    nickname: work
+    allowed_times:
+    - Mon-Fri 08:00-19:00
+    - "Sat 10:00-12:00"
+alerts:
+  target_failures: 3
+"#;
+    let dir = TestDir::new();
+
+    assert_eq!(
+        loaded(&dir, "mux.yaml", yaml),
+        loaded(&dir, "mux.toml", toml)
+    );
+}
+
+#[test]
+fn parses_yaml_nulls_anchors_and_block_scalars() {
+    let toml = r#"
+usage_file = "/tmp/usage"
+
+[targets.a]
+binding = "unix:///tmp/a.sock"
+priority = 2
+
+[targets.b]
+binding = "unix:///tmp/b.sock"
+priority = 2
+"#;
+    let yaml = r#"
+key_cache: ~
+audit_log:
+usage_file: >-
+  /tmp/usage
+targets:
+  a: {binding: unix:///tmp/a.sock, priority: &priority 2}
+  b:
+    binding: !!str unix:///tmp/b.sock
+    priority: *priority
+"#;
+    let dir = TestDir::new();
+
+    assert_eq!(
+        loaded(&dir, "mux.yaml", yaml),
+        loaded(&dir, "mux.toml", toml)
+    );
+}
+
+#[test]
+fn reports_yaml_errors_with_their_position() {
+    let dir = TestDir::new();
+    let errors = |yaml: &str| load_errors(&dir, "mux.yaml", yaml);
+
+    assert_eq!(
+        errors("targets:\n\ta: unix:///tmp/a.sock\n"),
+        [(
+            2,
+            "targets: found character that cannot start any token".to_owned()
+        )]
+    );
+    assert_eq!(
+        errors("key_cache: /tmp/keys\n  usage_file: /tmp/usage\n"),
+        [(
+            2,
+            "mapping values are not allowed in this context".to_owned()
+        )]
+    );
+    assert_eq!(
+        errors("key_cache: \"\\q\"\n"),
+        [(
+            1,
+            "key_cache: found unknown escape character at line 1 column 13, while parsing a quoted scalar"
+                .to_owned()
+        )]
+    );
+    assert_eq!(
+        errors("key_cache: /tmp/keys\nkey_cache: /tmp/keys\n"),
+        [(1, "duplicate field `key_cache`".to_owned())]
+    );
+    assert_eq!(
+        errors("targets:\n  b: {binding: unix:///tmp/b.sock priority: 2}\n"),
+        [(
+            2,
+            "targets.b: did not find expected ',' or '}' at line 2 column 43, while parsing a flow mapping"
+                .to_owned()
+        )]
+    );
+    // Errors in the options themselves are reported at the option.
+    assert_eq!(
+        errors("targets:\n  a:\n    binding: unix:///tmp/a.sock\n    priority: high\n"),
+        [(
+            4,
+            "targets.a.priority: invalid type: string \"high\", expected i64".to_owned()
+        )]
+    );
+    assert_eq!(
+        errors("alerts:\n  window: 0s\n"),
+        [(2, "alerts.window: must be longer than zero".to_owned())]
+    );
+}
+
+#[test]
+fn parses_json_escapes_and_nesting() {
+    let toml = r#"
+key_cache = "tab\there \"quoted\" back\\slash / \u00e9 😀 \u0008\u000c\r\n"
+
+[targets.a]
+binding = "unix:///tmp/a.sock"
+priority = -2
+add_key_types = ["rsa", "ed25519"]
+
+[alerts]
+target_failures = 3
+"#;
+    let json = r#"{
+  "key_cache": "tab\there \"quoted\" back\\slash \/ \u00e9 \ud83d\ude00 \b\f\r\n",
+  "targets": {"a": {
+    "binding": "unix:///tmp/a.sock",
+    "priority": -2,
+    "add_key_types": [
+      "rsa",
+      "ed25519"
+    ]
+  }},
+  "alerts": {"target_failures": 3}
+}"#;
+    let dir = TestDir::new();
+
+    assert_eq!(
+        loaded(&dir, "mux.json", json),
+        loaded(&dir, "mux.toml", toml)
+    );
+}
+
+#[test]
+fn reports_json_errors_with_their_position() {
+    let dir = TestDir::new();
+    let errors = |json: &str| load_errors(&dir, "mux.json", json);
+
+    assert_eq!(
+        errors("{\n  \"key_cache\": \"/tmp/keys\",\n}"),
+        [(3, "trailing comma".to_owned())]
+    );
+    assert_eq!(
+        errors("{\"key_cache\": \"\\q\"}"),
+        [(1, "key_cache: invalid escape".to_owned())]
+    );
+    assert_eq!(
+        errors("{\n\"key_cache\": \"a\",\n\"key_cache\": \"b\"\n}"),
+        [(3, "duplicate field `key_cache`".to_owned())]
+    );
+    assert_eq!(errors("{}\n{}"), [(2, "trailing characters".to_owned())]);
+    // Errors in the options themselves are reported at the option.
+    assert_eq!(
+        errors("{\n  \"targets\": {\n    \"a\": {\"binding\": \"unix:///tmp/a.sock\",\n    \"priority\": \"high\"}}}"),
+        [(
+            4,
+            "targets.a.priority: invalid type: string \"high\", expected i64".to_owned()
+        )]
+    );
+    assert_eq!(
+        errors("{\"max_message_size\": 0}"),
+        [(1, "max_message_size: must be greater than zero".to_owned())]
+    );
+}
+
+#[test]
+fn reports_route_script_errors_with_their_line() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    std::fs::write(&path, "route_script = \"route.rhai\"\n").unwrap();
+    std::fs::write(
+        dir.path("route.rhai"),
+        "fn route(request, targets) {\n    let target = targets[0]\n    target\n}\n",
+    )
+    .unwrap();
+
+    let Err(ConfigError::Invalid(script, errors)) = Config::load(&path) else {
+        panic!("config loaded");
+    };
+    assert_eq!(script, dir.path("route.rhai"));
+    assert_eq!(errors[0].line, 3);
+    assert_eq!(
+        errors[0].message,
+        "Expecting ';' to terminate this statement"
+    );
+}
+
+#[test]
+fn reports_plugins_that_fail_to_load() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    std::fs::write(&path, "plugins = [\"route.wasm\"]\n").unwrap();
+    std::fs::write(dir.path("route.wasm"), "fn route(request, targets) {}").unwrap();
+
+    let Err(e @ ConfigError::Plugin(..)) = Config::load(&path) else {
+        panic!("config loaded");
+    };
+    let expected = format!(
+        "{}: magic header not detected",
+        dir.path("route.wasm").display()
+    );
+    assert!(e.to_string().starts_with(&expected), "{e}");
+}
+
+#[test]
+fn applies_the_selected_profile_on_top() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    std::fs::write(
+        &path,
+        r#"
+host = "unix:///tmp/mux.sock"
+max_sessions = 8
+
+[targets]
+personal = "unix:///tmp/personal.sock"
+
+[profiles.work]
+host = "unix:///tmp/work-mux.sock"
+targets = { work = "unix:///tmp/work.sock" }
+"#,
+    )
+    .unwrap();
+
+    let base = Config::load(&path).unwrap();
+    let work = Config::load_profile(&path, Some("work")).unwrap();
+    let missing = Config::load_profile(&path, Some("play"));
+
+    assert_eq!(
+        format!("{:?}", base.host),
+        "Some(Binding(FilePath(\"/tmp/mux.sock\")))"
+    );
+    assert_eq!(
+        format!("{:?}", work.host),
+        "Some(Binding(FilePath(\"/tmp/work-mux.sock\")))"
+    );
+    assert_eq!(work.max_sessions, Some(8));
+    let names: Vec<_> = work
+        .targets
+        .iter()
+        .map(|target| target.name.as_str())
+        .collect();
+    assert_eq!(names, ["work"]);
+    assert!(matches!(missing, Err(ConfigError::UnknownProfile(..))));
+}
+
+#[test]
+fn sets_options_from_the_environment() {
+    let mut config = Config::default();
+    config
+        .apply_env([
+            ("SSH_AGENT_MUX_MAX_IDENTITIES".to_owned(), "5".to_owned()),
+            ("SSH_AGENT_MUX_LEGACY_V1".to_owned(), "true".to_owned()),
+            ("HOME".to_owned(), "/root".to_owned()),
+        ])
+        .unwrap();
+    let invalid = config.apply_env([("SSH_AGENT_MUX_MAX_IDENTITIES".to_owned(), "x".to_owned())]);
+
+    assert_eq!(config.max_identities, Some(5));
+    assert!(config.legacy_v1);
+    assert_eq!(
+        invalid.unwrap_err().to_string(),
+        "SSH_AGENT_MUX_MAX_IDENTITIES: invalid type: string \"x\", expected usize"
+    );
+}
+
+#[test]
+fn parses_webhook_urls() {
+    let url: HttpUrl = "http://[::1]:9000/hook".parse().unwrap();
+    assert_eq!(
+        (url.host.as_str(), url.port, url.path.as_str()),
+        ("::1", 9000, "/hook")
+    );
+    assert_eq!(url.to_string(), "http://[::1]:9000/hook");
+
+    let url: HttpUrl = "http://relay.local".parse().unwrap();
+    assert_eq!(url.to_string(), "http://relay.local:80/");
+
+    let url: HttpUrl = "https://hooks.example.com/ssh-agent-mux".parse().unwrap();
+    assert!(url.https);
+    assert_eq!(
+        url.to_string(),
+        "https://hooks.example.com:443/ssh-agent-mux"
+    );
+
+    assert!("ftp://relay.local/".parse::<HttpUrl>().is_err());
+}
+
+#[test]
+fn parses_time_windows() {
+    let weekdays: TimeWindow = "Mon-Fri 08:00-19:00".parse().unwrap();
+    assert!(weekdays.contains(0, 8 * 60));
+    assert!(!weekdays.contains(4, 19 * 60));
+    assert!(!weekdays.contains(5, 12 * 60));
+
+    // Past midnight, into the next day, and around the end of the week.
+    let nights: TimeWindow = "Sat-Sun 22:00-06:00".parse().unwrap();
+    assert!(nights.contains(6, 23 * 60));
+    assert!(nights.contains(0, 5 * 60));
+    assert!(!nights.contains(5, 5 * 60));
+
+    let every_day: TimeWindow = "00:00-24:00".parse().unwrap();
+    assert!(every_day.contains(3, 24 * 60 - 1));
+
+    assert!("Mon-Fri 08:00".parse::<TimeWindow>().is_err());
+    assert!("Someday 08:00-19:00".parse::<TimeWindow>().is_err());
+    assert!("08:00-08:00".parse::<TimeWindow>().is_err());
+}
+
+#[test]
+fn scans_target_directories_for_matching_sockets() {
+    let dir = TestDir::new();
+    let _b = std::os::unix::net::UnixListener::bind(dir.path("b.sock")).unwrap();
+    let _a = std::os::unix::net::UnixListener::bind(dir.path("a.sock")).unwrap();
+    let _other = std::os::unix::net::UnixListener::bind(dir.path("a.socket")).unwrap();
+    std::fs::write(dir.path("c.sock"), "not a socket").unwrap();
+
+    let spec = format!("dir://{}", dir.path("*.sock").display());
+    let TargetSpec::Dir(target_dir) = spec.parse().unwrap() else {
+        panic!("{spec} isn't a directory");
+    };
+    let targets = target_dir.scan().unwrap();
+
+    let names: Vec<_> = targets.iter().map(|target| target.name.as_str()).collect();
+    assert_eq!(names, [format!("{spec}/a.sock"), format!("{spec}/b.sock")]);
+    assert!(target_dir.contains(&format!("{spec}/d.sock")));
+    assert!(!target_dir.contains(&format!("{spec}/d.socket")));
+}
+
+#[test]
+fn parses_tcp_targets_by_name_without_resolving_them() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    std::fs::write(
+        &path,
+        r#"
+[targets]
+addr = "tcp://127.0.0.1:7000"
+host = "tcp://agent.invalid:7000"
+srv = "srv://_ssh-agent._tcp.example.com"
+"#,
+    )
+    .unwrap();
+
+    let config = Config::load(&path).unwrap();
+
+    let names: Vec<_> = config
+        .targets
+        .iter()
+        .map(|target| target.tcp_name.as_ref().map(ToString::to_string))
+        .collect();
+    assert_eq!(
+        names,
+        [
+            None,
+            Some("tcp://agent.invalid:7000".to_owned()),
+            Some("srv://_ssh-agent._tcp.example.com".to_owned())
+        ]
+    );
+    assert!("tcp://agent.invalid".parse::<TargetSpec>().is_err());
+}
+
+#[test]
+fn detects_1password_targets_by_their_socket() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    std::fs::write(
+        &path,
+        r#"
+[targets]
+linux = "unix:///home/me/.1password/agent.sock"
+macos = "unix:///Users/me/Library/Group Containers/2BUA8C4S2C.com.1password/t/agent.sock"
+other = "unix:///home/me/.ssh/agent.sock"
+gpg = { binding = "unix:///home/me/.1password/agent.sock", flavor = "gpg-agent" }
+"#,
+    )
+    .unwrap();
+
+    let config = Config::load(&path).unwrap();
+
+    let flavors: Vec<_> = config.targets.iter().map(|target| target.flavor).collect();
+    assert_eq!(
+        flavors,
+        [
+            Flavor::OnePassword,
+            Flavor::OnePassword,
+            Flavor::OpenSsh,
+            Flavor::GpgAgent
+        ]
+    );
+}
+
+#[test]
+fn only_passes_the_environment_to_gpg_agent() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    std::fs::write(
+        &path,
+        r#"
+host = "unix:///tmp/mux.sock"
+[targets.gpg]
+binding = "unix:///run/user/1000/gnupg/S.gpg-agent.ssh"
+flavor = "gpg-agent"
+environment = { GPG_TTY = "/dev/tty1" }
+forward_environment = ["GPG_TTY", "SSH_AGENT_MUX_UNSET"]
+[targets.other]
+binding = "unix:///run/user/1000/agent.sock"
+environment = { GPG_TTY = "/dev/tty1" }
+"#,
+    )
+    .unwrap();
+
+    let config = Config::load(&path).unwrap();
+
+    let gpg = &config.targets[0];
+    assert_eq!(
+        gpg.gpg_agent_socket(),
+        Some("/run/user/1000/gnupg/S.gpg-agent".into())
+    );
+    assert_eq!(
+        gpg.passed_environment(),
+        [("GPG_TTY".to_owned(), "/dev/tty1".to_owned())]
+    );
+    assert_eq!(config.targets[1].gpg_agent_socket(), None);
+    assert!(config.check().contains(
+        &"targets.other: environment is only passed to gpg-agent targets at a Unix socket ending in .ssh"
+            .to_owned()
+    ));
+}
+
+#[test]
+fn matches_views_to_destination_hosts() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    std::fs::write(
+        &path,
+        r#"
+[views.work]
+host = "unix:///tmp/work.sock"
+match_hosts = ["*.example.com", "!secret.example.com", "GitHub.com"]
+
+[views.all]
+host = "unix:///tmp/all.sock"
+"#,
+    )
+    .unwrap();
+
+    let config = Config::load(&path).unwrap();
+
+    let work = &config.views[0];
+    assert!(work.matches_host("build.example.com"));
+    assert!(work.matches_host("github.com"));
+    assert!(work.matches_host("BUILD.example.COM"));
+    assert!(!work.matches_host("secret.example.com"));
+    assert!(!work.matches_host("example.com"));
+    assert!(!work.matches_host("gitlab.com"));
+    assert!(!config.views[1].matches_host("github.com"));
+}
+
+#[test]
+fn resolves_the_default_and_inherit_targets_to_the_session_agent() {
+    std::env::set_var("SSH_AUTH_SOCK", "/tmp/session-agent.sock");
+
+    for name in ["default", "inherit"] {
+        let TargetSpec::Target(target) = name.parse().unwrap() else {
+            panic!("not a single target");
+        };
+        assert_eq!(target.name, name);
+        assert_eq!(
+            target.binding,
+            Binding::FilePath("/tmp/session-agent.sock".into())
+        );
+    }
+}
+
+#[test]
+fn reports_every_invalid_binding_with_a_suggestion() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    let long = format!("/tmp/{}.sock", "a".repeat(120));
+    std::fs::write(
+        &path,
+        format!(
+            "host = \"unix://mux.sock\"\n[targets]\npipe = \"npipe://openssh-ssh-agent\"\nlong = \"unix://{long}\"\n"
+        ),
+    )
+    .unwrap();
+    let mut config = Config::load(&path).unwrap();
+    config.add_target(TargetSpec::new("pipe".to_owned(), "unix:///tmp/agent.sock").unwrap());
+
+    let problems = config.check_bindings();
+
+    let cwd = std::env::current_dir().unwrap();
+    assert_eq!(
+        problems,
+        [
+            format!(
+                "host: mux.sock is a relative path; use an absolute one, e.g. unix://{}",
+                cwd.join("mux.sock").display()
+            ),
+            "targets.pipe: named pipes are only supported on Windows; use unix:// instead"
+                .to_owned(),
+            format!(
+                "targets.long: {long} is 130 bytes long, beyond the 107 of a Unix socket path; use a shorter one, e.g. in /tmp"
+            ),
+            "targets.pipe: duplicate target name; rename one of them".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn requires_a_user_to_switch_to_for_a_chroot() {
+    let mut config = Config::default();
+    config.sandbox.chroot = Some("/var/empty".into());
+    assert!(matches!(
+        config.check_sandbox(),
+        Err(ConfigError::ChrootWithoutUser)
+    ));
+
+    config.sandbox.user = Some("nobody".to_owned());
+    assert!(config.check_sandbox().is_ok());
+}
+
+#[test]
+fn refuses_a_chroot_without_a_user_on_the_command_line() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    let host = format!("host = \"unix://{}\"\n", dir.path("mux.sock").display());
+    std::fs::write(&path, &host).unwrap();
+    let check = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+            .arg("--config")
+            .arg(&path)
+            .args(args)
+            .args(["config", "check"])
+            .env_remove("SSH_AGENT_MUX_USER")
+            .env_remove("SSH_AGENT_MUX_CHROOT")
+            .output()
+            .unwrap()
+    };
+
+    let output = check(&["--chroot", "/"]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("sandbox.chroot requires sandbox.user")
+    );
+
+    std::fs::write(&path, format!("{host}[sandbox]\nuser = \"nobody\"\n")).unwrap();
+    assert!(check(&["--chroot", "/"]).status.success());
+}
@@ -0,0 +1,49 @@
+//! Tests of the encoding of the D-Bus signals of audit events.
+
+#![cfg(unix)]
+
+use ssh_agent_mux::dbus::{self, INTERFACE, PATH};
+use zbus::message::Type;
+
+#[test]
+fn encodes_events_as_signals_with_their_fields_as_arguments() {
+    let signal = dbus::signal(
+        "key_used",
+        &[
+            ("key", "SHA256:2ETgWaKcVbLcLzA8YTfMY".to_owned()),
+            ("target", "yubikey".to_owned()),
+            ("client", "uid 1000".to_owned()),
+            ("nickname", String::new()),
+        ],
+    )
+    .unwrap();
+
+    let header = signal.header();
+    assert_eq!(header.message_type(), Type::Signal);
+    assert_eq!(header.path().unwrap().as_str(), PATH);
+    assert_eq!(header.interface().unwrap().as_str(), INTERFACE);
+    assert_eq!(header.member().unwrap().as_str(), "KeyUsed");
+    // As the signature is sent, of the arguments rather than a structure.
+    assert_eq!(header.signature().to_string_no_parens(), "ssss");
+    let args: (String, String, String, String) = signal.body().deserialize().unwrap();
+    assert_eq!(
+        args,
+        (
+            "SHA256:2ETgWaKcVbLcLzA8YTfMY".to_owned(),
+            "yubikey".to_owned(),
+            "uid 1000".to_owned(),
+            String::new()
+        )
+    );
+}
+
+#[test]
+fn encodes_events_without_fields_as_signals_without_arguments() {
+    let signal = dbus::signal("identities_changed", &[]).unwrap();
+
+    assert_eq!(
+        signal.header().member().unwrap().as_str(),
+        "IdentitiesChanged"
+    );
+    assert_eq!(signal.header().signature().to_string_no_parens(), "");
+}
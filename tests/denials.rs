@@ -0,0 +1,71 @@
+//! Tests of how policy denials are logged and answered, in their own
+//! process as the logger is installed for the whole process.
+
+mod common;
+
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use ssh_agent_lib::agent::Session;
+use ssh_agent_lib::proto::{Request, Response, SignRequest};
+use ssh_key::HashAlg;
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+/// Keeps the level and message of every record.
+struct Capture(Mutex<Vec<(Level, String)>>);
+
+impl Log for Capture {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.0
+            .lock()
+            .unwrap()
+            .push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+#[tokio::test]
+async fn answers_denials_with_failure_and_logs_them_as_warnings() {
+    log::set_logger(&CAPTURE).unwrap();
+    log::set_max_level(LevelFilter::Info);
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let config = format!(
+        "[keys.\"{}\"]\nrequire_session_bind = true\n",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    let response = client
+        .handle(Request::SignRequest(SignRequest {
+            pubkey: key(1),
+            data: b"data".to_vec(),
+            flags: 0,
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(response, Response::Failure);
+    let records = CAPTURE.0.lock().unwrap().clone();
+    assert!(
+        records.iter().any(|(level, message)| *level == Level::Warn
+            && message.starts_with("sign denied for ")
+            && message.ends_with(": session not bound to a host")),
+        "{records:?}"
+    );
+    assert!(
+        records.iter().all(|(level, _)| *level > Level::Error),
+        "{records:?}"
+    );
+}
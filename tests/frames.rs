@@ -0,0 +1,58 @@
+//! Tests of frame dumps, in their own process as the logger is installed
+//! and dumping switched on for the whole process.
+
+mod common;
+
+use log::LevelFilter;
+use ssh_agent_lib::{agent::Session, proto::SignRequest};
+use ssh_agent_mux::{frames, logging};
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+#[tokio::test]
+async fn dumps_frames_without_keys_or_passphrases() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.log");
+    logging::init();
+    logging::log_to_file(
+        &path,
+        logging::Rotation {
+            max_size: 1 << 20,
+            max_age: None,
+            keep: 0,
+        },
+    )
+    .unwrap();
+    logging::set_level(Some(LevelFilter::Off));
+    frames::dump_frames(false);
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "");
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    client
+        .sign(SignRequest {
+            pubkey: key(1),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .await
+        .unwrap();
+    let _ = client.lock("hunter2".to_owned()).await;
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    for dumped in [
+        "received SSH_AGENTC_REQUEST_IDENTITIES, 1 bytes",
+        "sent SSH_AGENT_IDENTITIES_ANSWER",
+        "received SSH_AGENTC_SIGN_REQUEST",
+        "sent SSH_AGENT_SIGN_RESPONSE",
+        "0009  [51 bytes of key left out]",
+        "003c  00 00 00 03 6f 6e 65                             |....one|",
+        "0005  [51 bytes of key left out]",
+        "0001  [11 bytes of passphrase left out]",
+    ] {
+        assert!(log.contains(dumped), "{dumped:?} isn't in {log}");
+    }
+    assert!(!log.contains("hunter"), "{log}");
+}
@@ -0,0 +1,51 @@
+//! Tests of allowing clients by their supplementary groups, in their own
+//! process as the groups are set for the whole process.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use ssh_agent_lib::agent::Session;
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+/// A supplementary group of the process other than its primary group,
+/// added if need be and allowed.
+fn supplementary_group() -> Option<libc::gid_t> {
+    // SAFETY: getgid always succeeds.
+    let gid = unsafe { libc::getgid() };
+    let mut groups = vec![0; 256];
+    // SAFETY: `groups` holds as many as asked for.
+    let count = unsafe { libc::getgroups(groups.len() as i32, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    if let Some(group) = groups.iter().find(|group| **group != gid) {
+        return Some(*group);
+    }
+    let added = gid + 4242;
+    groups.push(added);
+    // SAFETY: `groups` holds its length of groups.
+    let result = unsafe { libc::setgroups(groups.len(), groups.as_ptr()) };
+    (result == 0).then_some(added)
+}
+
+#[tokio::test]
+async fn serves_clients_in_allowed_supplementary_groups() {
+    let Some(group) = supplementary_group() else {
+        eprintln!("No supplementary group to test with");
+        return;
+    };
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    // SAFETY: getuid always succeeds.
+    let uid = unsafe { libc::getuid() };
+    let mux = spawn_mux(
+        &dir,
+        &[&mock],
+        &format!("allowed_uids = [{}]\nallowed_gids = [{group}]", uid + 1),
+    );
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+}
@@ -0,0 +1,196 @@
+//! Tests of the gRPC admin service, through its generated client.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use hyper_util::rt::TokioIo;
+use ssh_agent_lib::agent::Session;
+use ssh_agent_lib::proto::SignRequest;
+use ssh_agent_mux::admin::grpc::{self, proto};
+use ssh_agent_mux::config::Config;
+use ssh_key::HashAlg;
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint};
+use tonic::Code;
+
+use common::{connect, key, spawn_mux_until, MockAgent, TestDir};
+
+type Client = proto::admin_client::AdminClient<Channel>;
+
+/// Serves the service for `mux`, reloading `mux.toml` of `dir`, and
+/// connects to it.
+async fn serve(dir: &TestDir, mux: &common::Mux) -> Client {
+    let socket = dir.path("grpc.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+    let config_path = dir.path("mux.toml");
+    tokio::spawn(grpc::serve(listener, mux.state.clone(), move || {
+        Config::load(&config_path)
+    }));
+    proto::admin_client::AdminClient::new(channel(socket).await)
+}
+
+async fn channel(socket: PathBuf) -> Channel {
+    // The URI is required, but unused by the connector.
+    Endpoint::from_static("http://localhost")
+        .connect_with_connector(tower::service_fn(move |_| {
+            let socket = socket.clone();
+            async move { Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(socket).await?)) }
+        }))
+        .await
+        .unwrap()
+}
+
+fn append(path: &Path, config: &str) {
+    let current = std::fs::read_to_string(path).unwrap();
+    std::fs::write(path, format!("{current}{config}")).unwrap();
+}
+
+#[tokio::test]
+async fn reports_the_status_targets_and_keys() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock1], "", futures::future::pending());
+    let mut client = serve(&dir, &mux).await;
+
+    let before = client
+        .get_status(proto::GetStatusRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    let keys = client
+        .list_keys(proto::ListKeysRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .keys;
+    let status = client
+        .get_status(proto::GetStatusRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(status.healthy_targets, 1);
+    // Counted once listed.
+    assert_eq!(before.keys, None);
+    assert_eq!(status.keys, Some(1));
+    assert_eq!(
+        status.targets,
+        [proto::Target {
+            name: "mock1".to_owned(),
+            healthy: true,
+            failed_seconds_ago: None,
+            priority: 0,
+            sign_latency_ms: None,
+            hidden: false,
+        }]
+    );
+    assert_eq!(keys.len(), 1);
+    assert_eq!(
+        keys[0].fingerprint,
+        key(1).fingerprint(HashAlg::Sha256).to_string()
+    );
+    assert!(keys[0].public_key.starts_with("ssh-ed25519 "), "{keys:?}");
+    assert!(keys[0].public_key.ends_with(" one"), "{keys:?}");
+    assert_eq!(keys[0].comment, "one");
+    assert_eq!(keys[0].targets, ["mock1"]);
+}
+
+#[tokio::test]
+async fn reloads_the_config() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock1], "", futures::future::pending());
+    let mut client = serve(&dir, &mux).await;
+
+    append(&dir.path("mux.toml"), "priority = 5\n");
+    client.reload(proto::ReloadRequest {}).await.unwrap();
+    let targets = client
+        .list_targets(proto::ListTargetsRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .targets;
+    assert_eq!(targets[0].priority, 5);
+
+    append(&dir.path("mux.toml"), "priority = \"high\"\n");
+    let error = client.reload(proto::ReloadRequest {}).await.unwrap_err();
+    assert_eq!(error.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn unlocks_hidden_targets() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(2), "two")
+        .with_target_options("hidden = true");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock1, &mock2], "", futures::future::pending());
+    let mut client = serve(&dir, &mux).await;
+    let unlock = |target: &str| proto::UnlockTargetRequest {
+        target: target.to_owned(),
+        duration_seconds: 60,
+    };
+
+    let error = client.unlock_target(unlock("mock3")).await.unwrap_err();
+    assert_eq!(error.code(), Code::NotFound);
+    let error = client.unlock_target(unlock("mock1")).await.unwrap_err();
+    assert_eq!(error.code(), Code::FailedPrecondition);
+    client.unlock_target(unlock("mock2")).await.unwrap();
+
+    let mut agent = connect(&mux.socket).await;
+    assert_eq!(agent.request_identities().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn reports_key_policies_with_their_usage() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let fingerprint = key(1).fingerprint(HashAlg::Sha256).to_string();
+    let config = format!(
+        "[keys.\"{fingerprint}\"]\nnickname = \"work\"\nmax_signatures_per_hour = 10\ncooldown = \"1s\"\nallowed_times = [\"Mon-Fri,Sun 08:00-19:00\"]\n"
+    );
+    let mux = spawn_mux_until(&dir, &[&mock1], &config, futures::future::pending());
+    let mut client = serve(&dir, &mux).await;
+    let mut agent = connect(&mux.socket).await;
+    // Outside the allowed times or not, a signature is made or denied.
+    let signed = agent
+        .sign(SignRequest {
+            pubkey: key(1),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .await
+        .is_ok();
+
+    let policies = client
+        .list_key_policies(proto::ListKeyPoliciesRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .policies;
+
+    assert_eq!(
+        policies,
+        [proto::KeyPolicy {
+            fingerprint,
+            nickname: Some("work".to_owned()),
+            max_signatures_per_hour: Some(10),
+            signatures_this_hour: signed.into(),
+            cooldown_seconds: Some(1),
+            last_signed_seconds_ago: signed.then_some(0),
+            allowed_host_keys: Vec::new(),
+            require_session_bind: false,
+            allowed_times: vec!["Mon-Fri,Sun 08:00-19:00".to_owned()],
+            exec: None,
+        }]
+    );
+}
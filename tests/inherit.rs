@@ -0,0 +1,86 @@
+//! Tests of the `inherit` target, on muxes run as processes of their own,
+//! as it is the agent of the environment they start in.
+
+mod common;
+
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+
+use common::{connect, key, MockAgent, TestDir};
+
+/// Runs the mux on `host` in front of `--target inherit` with
+/// `SSH_AUTH_SOCK` set to `agent`, or unset.
+fn command(dir: &TestDir, host: &str, agent: Option<&std::path::Path>) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"));
+    command
+        .arg("--host")
+        .arg(format!("unix://{}", dir.path(host).display()))
+        .args(["--target", "inherit"])
+        .env_remove("SSH_AGENT_MUX_TARGETS")
+        .env_remove("SSH_AGENT_MUX_CONFIG")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    match agent {
+        Some(agent) => command.env("SSH_AUTH_SOCK", agent),
+        None => command.env_remove("SSH_AUTH_SOCK"),
+    };
+    command
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[tokio::test]
+async fn proxies_to_the_agent_of_the_environment() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mut mux = command(&dir, "mux.sock", Some(&mock1.socket(&dir)))
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let socket = dir.path("mux.sock");
+    while !socket.exists() {
+        assert!(mux.try_wait().unwrap().is_none(), "the mux exited");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let identities = connect(&socket).await.request_identities().await;
+    let _ = mux.kill();
+    let _ = mux.wait();
+
+    assert_eq!(identities.unwrap()[0].comment, "one");
+}
+
+#[test]
+fn refuses_to_inherit_the_mux_itself() {
+    let dir = TestDir::new();
+
+    let output = command(&dir, "mux.sock", Some(&dir.path("mux.sock")))
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("so the mux would proxy to itself"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn refuses_to_inherit_without_ssh_auth_sock() {
+    let dir = TestDir::new();
+
+    let output = command(&dir, "mux.sock", None).output().unwrap();
+
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("SSH_AUTH_SOCK isn't set"),
+        "{}",
+        stderr(&output)
+    );
+}
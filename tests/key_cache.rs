@@ -0,0 +1,76 @@
+//! End-to-end tests of the key cache, in their own process as the cache file
+//! is kept for the whole process.
+
+mod common;
+
+use std::time::Duration;
+
+use ssh_agent_lib::{agent::Session, proto::SignRequest};
+use ssh_agent_mux::key_cache;
+use ssh_agent_mux::MuxAgentBind;
+use ssh_key::PublicKey;
+
+use common::{connect, key, load_config, spawn_mux, MockAgent, TestDir};
+
+#[tokio::test]
+async fn routes_signatures_to_cached_targets_before_listing() {
+    let dir = TestDir::new();
+    let path = dir.path("keys");
+    let cached = PublicKey::from(key(2)).to_openssh().unwrap();
+    std::fs::write(&path, format!("{cached} mock2\n")).unwrap();
+    key_cache::open(&path).unwrap();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(2), "two");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let mut client = connect(&mux).await;
+    client
+        .sign(SignRequest {
+            pubkey: key(2),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .await
+        .unwrap();
+
+    assert!(mock1.requests().is_empty());
+    assert_eq!(mock2.requests().len(), 1);
+
+    client.request_identities().await.unwrap();
+    let expected = [
+        (key(2), vec!["mock2".to_owned()]),
+        (key(1), vec!["mock1".to_owned()]),
+    ];
+    // Written in the background.
+    for _ in 0..50 {
+        if key_cache::read(&path).unwrap() == expected {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(key_cache::read(&path).unwrap(), expected);
+
+    // Listings that change nothing don't write it again.
+    std::fs::remove_file(&path).unwrap();
+    client.request_identities().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!path.exists());
+
+    // Nor do listings of views, which hold only some of the targets.
+    let mock3 = MockAgent::new(3).with_key(key(2), "two");
+    mock3.spawn(&dir);
+    let config = load_config(
+        &dir,
+        &[&mock1, &mock2, &mock3],
+        &format!(
+            "[views.deploy]\nhost = \"unix://{}\"\ntargets = [\"mock3\"]",
+            dir.path("deploy.sock").display()
+        ),
+    );
+    let mut view = MuxAgentBind::new(&config).view(&config, "deploy").unwrap();
+    assert_eq!(view.list_identities().await.unwrap().len(), 1);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!path.exists());
+}
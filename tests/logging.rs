@@ -0,0 +1,43 @@
+//! Tests of the log file, in their own process as the logger is installed
+//! for the whole process.
+
+mod common;
+
+use ssh_agent_mux::logging;
+
+use common::TestDir;
+
+#[test]
+fn rotates_the_log_file_as_it_grows() {
+    let dir = TestDir::new();
+    let path = dir.path("mux.log");
+    logging::init();
+    logging::log_to_file(
+        &path,
+        logging::Rotation {
+            max_size: 200,
+            max_age: None,
+            keep: 2,
+        },
+    )
+    .unwrap();
+
+    for n in 0..20 {
+        log::error!("record {n} of a log long enough to rotate it");
+    }
+
+    let read = |name: &str| std::fs::read_to_string(dir.path(name)).unwrap();
+    let current = read("mux.log");
+    assert!(current.len() <= 200, "{current}");
+    assert!(current.contains("record 19 "), "{current}");
+    // The newest rotated file comes right before the current one.
+    let kept = [read("mux.log.2"), read("mux.log.1"), current].concat();
+    let numbers: Vec<usize> = kept
+        .lines()
+        .map(|line| line.split("record ").nth(1).unwrap())
+        .map(|rest| rest.split(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(numbers, (20 - numbers.len()..20).collect::<Vec<_>>());
+    assert!(numbers.len() < 20);
+    assert!(!dir.path("mux.log.3").exists());
+}
@@ -0,0 +1,2182 @@
+//! End-to-end tests of the mux against mock target agents.
+
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{SinkExt, StreamExt};
+use ratatui::{backend::TestBackend, Terminal};
+use service_binding::Binding;
+use ssh_agent_lib::{
+    agent::Session,
+    client::Client,
+    codec::Codec,
+    proto::{
+        extension::{RestrictDestination, SessionBind},
+        AddIdentity, AddIdentityConstrained, Credential, Extension, KeyConstraint, RemoveIdentity,
+        Request, Response, SignRequest,
+    },
+};
+use ssh_agent_mux::config::{Config, ViewConfig};
+use ssh_agent_mux::serve::{serve_until, ServeOptions};
+use ssh_agent_mux::{
+    admin, conformance, events, export, healthcheck, logging, ping, sshconfig, status, top, unlock,
+    view, MuxAgentBind, ViewCreator,
+};
+use ssh_key::private::{Ed25519Keypair, KeypairData};
+use ssh_key::{public::KeyData, HashAlg, PublicKey};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_util::codec::Framed;
+
+use common::{
+    connect, key, load_config, route_plugin, spawn_mux, spawn_mux_until, spawn_tcp_mux,
+    spawn_tcp_mux_with, MockAgent, Mux, TestDir,
+};
+
+fn sign_request(n: u8) -> SignRequest {
+    SignRequest {
+        pubkey: key(n),
+        data: b"data".to_vec(),
+        flags: 0,
+    }
+}
+
+fn add_identity(n: u8) -> AddIdentity {
+    AddIdentity {
+        credential: Credential::Key {
+            privkey: KeypairData::Ed25519(Ed25519Keypair::from_seed(&[n; 32])),
+            comment: format!("key {n}"),
+        },
+    }
+}
+
+/// The host key of a host, unique per `host`.
+fn host_key(host: u8) -> KeyData {
+    KeyData::Ed25519(Ed25519Keypair::from_seed(&[host; 32]).public)
+}
+
+/// Binds a session to the host with [`host_key`]`(host)`, as an OpenSSH
+/// client does after key exchange.
+fn session_bind(host: u8) -> Extension {
+    let session_id = vec![host; 32];
+    let keypair = Ed25519Keypair::from_seed(&[host; 32]);
+    let signature = signature::Signer::try_sign(&keypair, &session_id).unwrap();
+    Extension::new_message(SessionBind {
+        host_key: host_key(host),
+        session_id,
+        signature,
+        is_forwarding: false,
+    })
+    .unwrap()
+}
+
+/// The `known_hosts` line of the host of [`host_key`]`(host)`.
+fn known_host(host: u8) -> String {
+    let host_key = PublicKey::new(host_key(host), "");
+    format!("host{host}.example.com {}", host_key.to_openssh().unwrap())
+}
+
+#[tokio::test]
+async fn lists_identities_of_all_targets_in_order() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(2), "two")
+        .with_key(key(3), "three");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    let comments: Vec<_> = identities.iter().map(|i| i.comment.as_str()).collect();
+    assert_eq!(comments, ["one", "two", "three"]);
+}
+
+#[tokio::test]
+async fn lists_fallback_targets_only_while_the_others_list_no_keys() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(2), "two")
+        .with_target_options("fallback = true");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].comment, "one");
+    assert!(mock2.requests().is_empty());
+
+    let dir = TestDir::new();
+    let mock3 = MockAgent::new(3);
+    mock3.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock3, &mock2], "");
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].comment, "two");
+}
+
+#[tokio::test]
+async fn lists_fallback_targets_while_the_others_are_unreachable() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(2), "two")
+        .with_target_options("fallback = true");
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].comment, "two");
+}
+
+#[tokio::test]
+async fn lists_lazy_targets_only_when_needed() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(2), "two")
+        .with_target_options("enumeration = \"lazy\"");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+    let mut client = connect(&mux).await;
+
+    let identities = client.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].comment, "one");
+    assert!(mock2.requests().is_empty());
+
+    client.sign(sign_request(2)).await.unwrap();
+    assert_eq!(mock2.requests().len(), 2);
+
+    let dir = TestDir::new();
+    let mock3 = MockAgent::new(3);
+    mock3.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock3, &mock2], "");
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].comment, "two");
+}
+
+#[tokio::test]
+async fn sends_requests_again_on_dead_connections() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1)
+        .with_key(key(1), "one")
+        .with_target_options("response_timeout = \"100ms\"");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "");
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+
+    mock.crash_next();
+    client.sign(sign_request(1)).await.unwrap();
+    mock.hang_next();
+    let identities = client.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(mock.requests().len(), 5);
+}
+
+#[tokio::test]
+async fn routes_sign_to_the_target_holding_the_key() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(2), "two");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let mut client = connect(&mux).await;
+    let signature = client.sign(sign_request(2)).await.unwrap();
+
+    assert_eq!(signature, mock2.signature());
+    assert!(!mock1
+        .requests()
+        .iter()
+        .any(|r| matches!(r, Request::SignRequest(_))));
+}
+
+#[tokio::test]
+async fn fails_to_sign_with_unknown_key() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "");
+
+    assert!(connect(&mux).await.sign(sign_request(9)).await.is_err());
+}
+
+#[tokio::test]
+async fn locks_and_unlocks_all_targets() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1);
+    let mock2 = MockAgent::new(2);
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let mut client = connect(&mux).await;
+    client.lock("secret".to_owned()).await.unwrap();
+    client.unlock("secret".to_owned()).await.unwrap();
+
+    for mock in [&mock1, &mock2] {
+        assert_eq!(
+            mock.requests(),
+            [
+                Request::Lock("secret".to_owned()),
+                Request::Unlock("secret".to_owned())
+            ]
+        );
+    }
+}
+
+#[tokio::test]
+async fn serves_the_remaining_targets_when_one_is_unreachable() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let missing = MockAgent::new(2);
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &missing], "");
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+}
+
+#[tokio::test]
+async fn waits_for_slow_targets() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(2), "two");
+    mock2.set_delay(Duration::from_millis(200));
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 2);
+}
+
+#[tokio::test]
+async fn fails_requests_when_a_target_fails() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "");
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    mock1.set_failing(true);
+
+    assert!(client.sign(sign_request(1)).await.is_err());
+}
+
+#[tokio::test]
+async fn fails_extensions_the_target_refuses() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "");
+    mock1.set_failing(true);
+
+    let mut stream = UnixStream::connect(&mux).await.unwrap();
+    let name = b"other@example.com";
+    let extension = [&[27][..], &(name.len() as u32).to_be_bytes(), name].concat();
+    assert_eq!(exchange(&mut stream, &extension).await, [5]);
+    // The mux binds sessions itself.
+    let mut client = connect(&mux).await;
+    client.extension(session_bind(1)).await.unwrap();
+}
+
+#[tokio::test]
+async fn denies_signatures_beyond_the_hourly_quota() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let config = format!(
+        "[keys.\"{}\"]\nmax_signatures_per_hour = 1\n",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    client.sign(sign_request(1)).await.unwrap();
+
+    assert!(client.sign(sign_request(1)).await.is_err());
+}
+
+#[tokio::test]
+async fn denies_signatures_within_the_cooldown_of_the_last_one() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let config = format!(
+        "[keys.\"{}\"]\ncooldown = \"1s\"\n",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    let mut client = connect(&mux).await;
+    client.sign(sign_request(1)).await.unwrap();
+    assert!(client.sign(sign_request(1)).await.is_err());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    client.sign(sign_request(1)).await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_only_signatures_made_toward_the_quota() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let config = format!(
+        "[keys.\"{}\"]\nmax_signatures_per_hour = 1\n",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    mock1.set_failing(true);
+    assert!(client.sign(sign_request(1)).await.is_err());
+    mock1.set_failing(false);
+
+    client.sign(sign_request(1)).await.unwrap();
+    assert!(client.sign(sign_request(1)).await.is_err());
+}
+
+/// How many of `n` clients signing with key 1 at once through `mux` get a
+/// signature.
+async fn concurrent_signatures(mux: &std::path::Path, n: usize) -> usize {
+    let mut clients = Vec::new();
+    for _ in 0..n {
+        clients.push(connect(mux).await);
+    }
+    let signing = clients.into_iter().map(|mut client| {
+        tokio::spawn(async move { client.sign(sign_request(1)).await.is_ok() })
+    });
+    futures::future::join_all(signing)
+        .await
+        .into_iter()
+        .filter(|signed| *signed.as_ref().unwrap())
+        .count()
+}
+
+#[tokio::test]
+async fn counts_concurrent_signatures_toward_the_quota_before_they_are_made() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    mock1.set_delay(Duration::from_millis(200));
+    let config = format!(
+        "[keys.\"{}\"]\nmax_signatures_per_hour = 1\n",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    assert_eq!(concurrent_signatures(&mux, 8).await, 1);
+}
+
+#[tokio::test]
+async fn starts_the_cooldown_of_concurrent_signatures_before_they_are_made() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    mock1.set_delay(Duration::from_millis(200));
+    let config = format!(
+        "[keys.\"{}\"]\ncooldown = \"1h\"\n",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    assert_eq!(concurrent_signatures(&mux, 8).await, 1);
+}
+
+#[tokio::test]
+async fn queues_requests_over_the_limit_of_a_target_in_order() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "one")
+        .with_target_options("max_concurrent_requests = 1");
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "");
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        let mut client = connect(&mux).await;
+        client.request_identities().await.unwrap();
+        clients.push(client);
+    }
+    mock1.set_delay(Duration::from_millis(100));
+
+    let started = std::time::Instant::now();
+    let mut signing = Vec::new();
+    for (n, mut client) in clients.into_iter().enumerate() {
+        signing.push(tokio::spawn(async move {
+            let request = SignRequest {
+                data: vec![n as u8],
+                ..sign_request(1)
+            };
+            let signature = client.sign(request).await;
+            (signature, started.elapsed())
+        }));
+        // Each is queued before the next is sent.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let signed = futures::future::join_all(signing).await;
+
+    let mut finished = Vec::new();
+    for signed in signed {
+        let (signature, elapsed) = signed.unwrap();
+        assert_eq!(signature.unwrap(), mock1.signature());
+        finished.push(elapsed);
+    }
+    // One at a time, so each took the previous ones' delays too.
+    for (n, elapsed) in finished.iter().enumerate() {
+        assert!(
+            *elapsed >= Duration::from_millis(100) * (n as u32 + 1),
+            "{finished:?}"
+        );
+    }
+    let sent: Vec<_> = mock1
+        .requests()
+        .into_iter()
+        .filter_map(|request| match request {
+            Request::SignRequest(request) => Some(request.data),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(sent, [[0], [1], [2]]);
+}
+
+#[tokio::test]
+async fn keeps_the_cooldown_of_signatures_made_across_a_reload() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    mock1.set_delay(Duration::from_millis(200));
+    let config = format!(
+        "[keys.\"{}\"]\ncooldown = \"1h\"\n",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux_until(&dir, &[&mock1], &config, futures::future::pending());
+
+    let mut client = connect(&mux.socket).await;
+    let signing = tokio::spawn(async move { client.sign(sign_request(1)).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    mux.state.reload(&load_config(&dir, &[&mock1], &config));
+    signing.await.unwrap().unwrap();
+
+    let mut client = connect(&mux.socket).await;
+    assert!(client.sign(sign_request(1)).await.is_err());
+}
+
+#[tokio::test]
+async fn starts_the_cooldown_only_once_signatures_are_approved() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let approved = dir.path("approved");
+    let approver = dir.path("approver");
+    std::fs::write(
+        &approver,
+        format!("#!/bin/sh\ntest -e {}\n", approved.display()),
+    )
+    .unwrap();
+    std::fs::set_permissions(&approver, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let config = format!(
+        "[keys.\"{}\"]\ncooldown = \"1h\"\nexec = \"{}\"\n",
+        key(1).fingerprint(HashAlg::Sha256),
+        approver.display()
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    let mut client = connect(&mux).await;
+    assert!(client.sign(sign_request(1)).await.is_err());
+    std::fs::write(&approved, "").unwrap();
+
+    client.sign(sign_request(1)).await.unwrap();
+    assert!(client.sign(sign_request(1)).await.is_err());
+}
+
+#[tokio::test]
+async fn denies_signatures_for_hosts_not_allowed_for_the_key() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let config = format!(
+        "[keys.\"{}\"]\nallowed_host_keys = [\"{}\"]\n",
+        key(1).fingerprint(HashAlg::Sha256),
+        host_key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    let mut client = connect(&mux).await;
+    client.extension(session_bind(1)).await.unwrap();
+    client.sign(sign_request(1)).await.unwrap();
+    // A forwarded socket is bound again on the next hop.
+    client.extension(session_bind(2)).await.unwrap();
+
+    assert!(client.sign(sign_request(1)).await.is_err());
+}
+
+#[tokio::test]
+async fn denies_signatures_in_unbound_sessions_when_required() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let config = format!(
+        "[keys.\"{}\"]\nrequire_session_bind = true\n",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1], &config);
+
+    let mut client = connect(&mux).await;
+    assert!(client.sign(sign_request(1)).await.is_err());
+    client.extension(session_bind(1)).await.unwrap();
+
+    client.sign(sign_request(1)).await.unwrap();
+}
+
+#[tokio::test]
+async fn signs_only_with_the_approval_of_exec_programs() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(2), "two");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let approver = dir.path("approver");
+    std::fs::write(
+        &approver,
+        "#!/bin/sh\n\
+         test \"$SSH_AGENT_MUX_TARGETS\" = mock1 && grep -q \"\\\"key\\\":\\\"$SSH_AGENT_MUX_KEY\\\"\"\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&approver, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let mut config = String::new();
+    for n in [1, 2] {
+        config += &format!(
+            "[keys.\"{}\"]\nexec = \"{}\"\n",
+            key(n).fingerprint(HashAlg::Sha256),
+            approver.display()
+        );
+    }
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], &config);
+
+    let mut client = connect(&mux).await;
+    client.sign(sign_request(1)).await.unwrap();
+    assert!(client.sign(sign_request(2)).await.is_err());
+}
+
+#[tokio::test]
+async fn routes_signatures_with_the_route_script() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "one")
+        .with_key(key(2), "two");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(1), "also one")
+        .with_key(key(3), "three");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let script = format!(
+        "fn route(request, targets) {{
+            if request.key == \"{}\" {{
+                return false;
+            }}
+            // The second target holding the key, if any
+            if targets.len() > 1 {{ targets[1] }}
+        }}",
+        key(3).fingerprint(HashAlg::Sha256)
+    );
+    std::fs::write(dir.path("route.rhai"), script).unwrap();
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "route_script = \"route.rhai\"");
+
+    let mut client = connect(&mux).await;
+    let signature = client.sign(sign_request(1)).await.unwrap();
+    assert_eq!(signature, mock2.signature());
+    let signature = client.sign(sign_request(2)).await.unwrap();
+    assert_eq!(signature, mock1.signature());
+    assert!(client.sign(sign_request(3)).await.is_err());
+}
+
+#[tokio::test]
+async fn routes_signatures_with_plugins() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(1), "also one");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    std::fs::write(dir.path("route.wasm"), route_plugin("mock2", "deny")).unwrap();
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "plugins = [\"route.wasm\"]");
+
+    let mut client = connect(&mux).await;
+    let signature = client.sign(sign_request(1)).await.unwrap();
+    assert_eq!(signature, mock2.signature());
+    assert!(client.sign(sign_request(1)).await.is_err());
+    let signature = client.sign(sign_request(1)).await.unwrap();
+    assert_eq!(signature, mock2.signature());
+}
+
+#[tokio::test]
+async fn handles_pipelined_requests_concurrently_and_answers_in_order() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(2), "two");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+    let stream = UnixStream::connect(&mux).await.unwrap();
+    let mut framed = Framed::new(stream, Codec::<Response, Request>::default());
+    framed.send(Request::RequestIdentities).await.unwrap();
+    framed.next().await.unwrap().unwrap();
+    // The second answer is ready first.
+    mock1.set_delay(Duration::from_millis(300));
+    mock2.set_delay(Duration::from_millis(200));
+
+    let start = Instant::now();
+    framed
+        .feed(Request::SignRequest(sign_request(1)))
+        .await
+        .unwrap();
+    framed
+        .feed(Request::SignRequest(sign_request(2)))
+        .await
+        .unwrap();
+    framed.flush().await.unwrap();
+    let first = framed.next().await.unwrap().unwrap();
+    let second = framed.next().await.unwrap().unwrap();
+
+    assert!(start.elapsed() < Duration::from_millis(450));
+    assert_eq!(first, Response::SignResponse(mock1.signature()));
+    assert_eq!(second, Response::SignResponse(mock2.signature()));
+}
+
+#[tokio::test]
+async fn uses_duplicate_keys_from_the_first_target_by_default() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(1), "also one")
+        .with_target_options("priority = 10");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let mut client = connect(&mux).await;
+    let identities = client.request_identities().await.unwrap();
+    let signature = client.sign(sign_request(1)).await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].comment, "one");
+    assert_eq!(signature, mock1.signature());
+}
+
+#[tokio::test]
+async fn uses_duplicate_keys_from_the_highest_priority_target() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(1), "also one")
+        .with_target_options("priority = 10");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "duplicate_keys = \"priority\"");
+
+    let mut client = connect(&mux).await;
+    let identities = client.request_identities().await.unwrap();
+    let signature = client.sign(sign_request(1)).await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].comment, "also one");
+    assert_eq!(signature, mock2.signature());
+}
+
+#[tokio::test]
+async fn serves_tcp_clients_by_address() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let allowed = spawn_tcp_mux(
+        &dir,
+        &[&mock],
+        "[client_addresses]\n\"127.0.0.0/8\" = \"allow\"",
+    );
+    let denied = spawn_tcp_mux(
+        &dir,
+        &[&mock],
+        "[client_addresses]\n\"127.0.0.0/8\" = \"allow\"\n\"127.0.0.1\" = \"deny\"",
+    );
+    let unlisted = spawn_tcp_mux(
+        &dir,
+        &[&mock],
+        "[client_addresses]\n\"10.0.0.0/8\" = \"allow\"",
+    );
+
+    let mut client = Client::new(TcpStream::connect(allowed).await.unwrap());
+    assert_eq!(client.request_identities().await.unwrap().len(), 1);
+    for mux in [denied, unlisted] {
+        let mut client = Client::new(TcpStream::connect(mux).await.unwrap());
+        assert!(client.request_identities().await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn fails_to_list_duplicate_keys_when_configured() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(1), "also one");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "duplicate_keys = \"error\"");
+
+    assert!(connect(&mux).await.request_identities().await.is_err());
+}
+
+#[tokio::test]
+async fn falls_back_to_lower_priority_targets_while_one_is_unreachable() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(1), "also one")
+        .with_target_options("priority = 10");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "duplicate_keys = \"priority\"");
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    mock2.set_crashed(true);
+    let signature = client.sign(sign_request(1)).await.unwrap();
+
+    assert_eq!(signature, mock1.signature());
+    assert!(matches!(
+        mock2.requests().last(),
+        Some(Request::SignRequest(_))
+    ));
+}
+
+#[tokio::test]
+async fn retries_sign_requests_that_fail_to_reach_the_target() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "sign_retries = 2");
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    mock.set_crashed(true);
+    let recovering = mock.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        recovering.set_crashed(false);
+    });
+    let signature = client.sign(sign_request(1)).await.unwrap();
+
+    assert_eq!(signature, mock.signature());
+    let signs = mock
+        .requests()
+        .into_iter()
+        .filter(|r| matches!(r, Request::SignRequest(_)))
+        .count();
+    // Sent again at once on a new connection, then retried after a backoff.
+    assert_eq!(signs, 3);
+
+    // Refusals aren't retried.
+    mock.set_failing(true);
+    assert!(client.sign(sign_request(1)).await.is_err());
+    let signs = mock
+        .requests()
+        .into_iter()
+        .filter(|r| matches!(r, Request::SignRequest(_)))
+        .count();
+    assert_eq!(signs, 4);
+}
+
+#[tokio::test]
+async fn signs_duplicate_keys_with_the_fastest_target() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(1), "also one");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    mock1.set_delay(Duration::from_millis(100));
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "duplicate_keys = \"latency\"");
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    // Each target signs once before latencies are compared.
+    let first = client.sign(sign_request(1)).await.unwrap();
+    let second = client.sign(sign_request(1)).await.unwrap();
+    let third = client.sign(sign_request(1)).await.unwrap();
+
+    assert_eq!(first, mock1.signature());
+    assert_eq!(second, mock2.signature());
+    assert_eq!(third, mock2.signature());
+}
+
+#[tokio::test]
+async fn races_duplicate_keys_across_targets() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(1), "also one");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "duplicate_keys = \"race\"");
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    mock1.set_delay(Duration::from_millis(500));
+    let started = Instant::now();
+    let signature = client.sign(sign_request(1)).await.unwrap();
+
+    assert_eq!(signature, mock2.signature());
+    assert!(started.elapsed() < Duration::from_millis(400));
+    // The abandoned request's answer isn't taken for the next one's.
+    mock1.set_delay(Duration::ZERO);
+    assert_eq!(client.request_identities().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn signs_routed_keys_with_the_configured_target() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2);
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let routes = format!(
+        "[routes]\n\"{}\" = \"mock2\"",
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], &routes);
+
+    let signature = connect(&mux).await.sign(sign_request(1)).await.unwrap();
+
+    assert_eq!(signature, mock2.signature());
+    assert!(mock1.requests().is_empty());
+}
+
+#[tokio::test]
+async fn adds_keys_to_the_first_target_taking_their_type() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_target_options("add_key_types = [\"rsa\"]");
+    let mock2 = MockAgent::new(2).with_target_options("add_key_types = [\"ed25519\"]");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    connect(&mux)
+        .await
+        .add_identity(add_identity(1))
+        .await
+        .unwrap();
+
+    assert!(mock1.requests().is_empty());
+    assert!(matches!(
+        mock2.requests().as_slice(),
+        [Request::AddIdentity(_)]
+    ));
+}
+
+#[tokio::test]
+async fn restricts_keys_of_targets_to_their_destinations() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "one")
+        .with_target_options(&format!("destinations = [\"{}\"]", known_host(1)));
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "");
+
+    let mut client = connect(&mux).await;
+    client.extension(session_bind(2)).await.unwrap();
+    assert!(client.sign(sign_request(1)).await.is_err());
+
+    let mut client = connect(&mux).await;
+    client.extension(session_bind(1)).await.unwrap();
+    assert_eq!(
+        client.sign(sign_request(1)).await.unwrap(),
+        mock1.signature()
+    );
+
+    // Unbound sessions are local use.
+    let mut client = connect(&mux).await;
+    client.sign(sign_request(1)).await.unwrap();
+    client.add_identity(add_identity(2)).await.unwrap();
+    let Some(Request::AddIdConstrained(identity)) = mock1.requests().pop() else {
+        panic!("expected a constrained key");
+    };
+    let [KeyConstraint::Extension(extension)] = &identity.constraints[..] else {
+        panic!("expected a destination constraint");
+    };
+    let restriction = extension
+        .parse_key_constraint::<RestrictDestination>()
+        .unwrap()
+        .unwrap();
+    assert_eq!(restriction.constraints.len(), 1);
+    assert_eq!(restriction.constraints[0].to.hostname, "host1.example.com");
+}
+
+#[tokio::test]
+async fn works_around_the_quirks_of_gpg_agent() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "one")
+        .with_target_options(&format!(
+            "flavor = \"gpg-agent\"\nresponse_timeout = \"100ms\"\ndestinations = [\"{}\"]",
+            known_host(1)
+        ));
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "");
+
+    let mut client = connect(&mux).await;
+    client.extension(session_bind(1)).await.unwrap();
+    client.request_identities().await.unwrap();
+    // Pinentry asking.
+    mock1.set_delay(Duration::from_millis(300));
+    assert_eq!(
+        client.sign(sign_request(1)).await.unwrap(),
+        mock1.signature()
+    );
+    mock1.set_delay(Duration::ZERO);
+    client.add_identity(add_identity(2)).await.unwrap();
+
+    assert!(matches!(
+        mock1.requests().as_slice(),
+        [
+            Request::RequestIdentities,
+            Request::SignRequest(_),
+            Request::AddIdentity(_)
+        ]
+    ));
+}
+
+#[tokio::test]
+async fn passes_the_environment_to_gpg_agent_for_pinentry() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let ssh_socket = dir.path("S.gpg-agent.ssh");
+    std::os::unix::fs::symlink(mock1.socket(&dir), &ssh_socket).unwrap();
+    // gpg-agent's own socket, recording the commands it is sent.
+    let commands = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let listener = tokio::net::UnixListener::bind(dir.path("S.gpg-agent")).unwrap();
+    tokio::spawn({
+        let commands = commands.clone();
+        async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                writer.write_all(b"OK Pleased to meet you\n").await.unwrap();
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if line == "BYE" {
+                        break;
+                    }
+                    commands.lock().unwrap().push(line);
+                    writer.write_all(b"OK\n").await.unwrap();
+                }
+            }
+        }
+    });
+    let mux = spawn_mux(
+        &dir,
+        &[],
+        &format!(
+            "[targets.gpg]\nbinding = \"unix://{}\"\nflavor = \"gpg-agent\"\n\
+             environment = {{ GPG_TTY = \"/dev/tty1\", DISPLAY = \":1\" }}\n\
+             forward_environment = [\"PATH\", \"SSH_AGENT_MUX_UNSET\"]",
+            ssh_socket.display()
+        ),
+    );
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    assert!(commands.lock().unwrap().is_empty());
+    assert_eq!(
+        client.sign(sign_request(1)).await.unwrap(),
+        mock1.signature()
+    );
+
+    assert_eq!(
+        *commands.lock().unwrap(),
+        [
+            format!("OPTION putenv=PATH={}", std::env::var("PATH").unwrap()),
+            "OPTION putenv=GPG_TTY=/dev/tty1".to_owned(),
+            "OPTION putenv=DISPLAY=:1".to_owned(),
+            "UPDATESTARTUPTTY".to_owned(),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn adds_configured_constraints_to_added_keys() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1);
+    mock1.spawn(&dir);
+    let mux = spawn_mux(
+        &dir,
+        &[&mock1],
+        "[add_constraints]\nconfirm = true\nlifetime = \"1h\"",
+    );
+
+    let mut client = connect(&mux).await;
+    client.add_identity(add_identity(1)).await.unwrap();
+    client
+        .add_identity_constrained(AddIdentityConstrained {
+            identity: add_identity(2),
+            constraints: vec![KeyConstraint::Lifetime(60)],
+        })
+        .await
+        .unwrap();
+
+    let constraints: Vec<_> = mock1
+        .requests()
+        .into_iter()
+        .map(|request| match request {
+            Request::AddIdConstrained(identity) => identity.constraints,
+            request => panic!("unexpected request {request:?}"),
+        })
+        .collect();
+    assert_eq!(
+        constraints,
+        [
+            vec![KeyConstraint::Confirm, KeyConstraint::Lifetime(3600)],
+            vec![KeyConstraint::Lifetime(60), KeyConstraint::Confirm],
+        ]
+    );
+}
+
+#[tokio::test]
+async fn caps_listed_identities_per_target_and_overall() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "one")
+        .with_key(key(2), "two");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(3), "three")
+        .with_key(key(4), "four")
+        .with_key(key(5), "five")
+        .with_target_options("priority = 10\nmax_identities = 2");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "max_identities = 3");
+
+    let mut client = connect(&mux).await;
+    let identities = client.request_identities().await.unwrap();
+    // Unlisted keys are still routed.
+    let signature = client.sign(sign_request(5)).await.unwrap();
+
+    let comments: Vec<_> = identities
+        .iter()
+        .map(|identity| identity.comment.as_str())
+        .collect();
+    assert_eq!(comments, ["one", "three", "four"]);
+    assert_eq!(signature, mock2.signature());
+}
+
+#[tokio::test]
+async fn lists_identities_with_their_targets_for_export() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "one")
+        .with_key(key(2), "two");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(2), "two")
+        .with_key(key(3), "three");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let config = load_config(&dir, &[&mock1, &mock2], "max_identities = 2");
+
+    let identities = MuxAgentBind::new(&config).list_identities().await.unwrap();
+
+    let listed: Vec<_> = identities
+        .iter()
+        .map(|(identity, targets)| (identity.comment.as_str(), targets.join(",")))
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            ("one", "mock1".to_owned()),
+            ("two", "mock1,mock2".to_owned())
+        ]
+    );
+    let exported: serde_json::Value =
+        serde_json::from_str(&export::identities_json(&identities)).unwrap();
+    assert_eq!(
+        exported,
+        serde_json::json!([
+            {
+                "fingerprint": key(1).fingerprint(HashAlg::Sha256).to_string(),
+                "nickname": null,
+                "algorithm": "ssh-ed25519",
+                "comment": "one",
+                "target": "mock1",
+                "targets": ["mock1"],
+            },
+            {
+                "fingerprint": key(2).fingerprint(HashAlg::Sha256).to_string(),
+                "nickname": null,
+                "algorithm": "ssh-ed25519",
+                "comment": "two",
+                "target": "mock1",
+                "targets": ["mock1", "mock2"],
+            },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn health_checks_pass_while_the_mux_answers() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "");
+    let timeout = Duration::from_secs(1);
+
+    let healthy = healthcheck::probe(&Binding::FilePath(mux), timeout).await;
+    let missing = healthcheck::probe(&Binding::FilePath(dir.path("missing.sock")), timeout).await;
+
+    assert_eq!(healthy.unwrap(), 1);
+    assert!(missing.is_err());
+}
+
+#[tokio::test]
+async fn reports_how_targets_break_the_protocol() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+
+    let report = conformance::test_target(
+        &Binding::FilePath(mock1.socket(&dir)),
+        Duration::from_secs(1),
+    )
+    .await
+    .unwrap();
+
+    // The mock's signatures are only its ID, and it answers everything else
+    // with success.
+    let checks: Vec<_> = report
+        .0
+        .iter()
+        .map(|check| (check.name.as_str(), check.outcome))
+        .collect();
+    use conformance::Outcome::{Fail, Pass};
+    assert_eq!(
+        checks,
+        [
+            ("list identities", Pass),
+            ("sign with ssh-ed25519", Fail),
+            ("query extensions", Fail),
+            ("lock", Pass),
+            ("list while locked", Fail),
+            ("sign while locked", Fail),
+            ("unlock with a wrong passphrase", Fail),
+        ]
+    );
+    assert_eq!(report.failures(), 5);
+    assert!(conformance::test_target(
+        &Binding::FilePath(dir.path("missing.sock")),
+        Duration::from_secs(1)
+    )
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn times_listing_the_identities_of_targets() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.set_delay(Duration::from_millis(50));
+    mock1.spawn(&dir);
+    let config = load_config(
+        &dir,
+        &[&mock1],
+        &format!(
+            "[targets.missing]\nbinding = \"unix://{}\"",
+            dir.path("missing.sock").display()
+        ),
+    );
+    let targets = ping::targets(&config);
+    let target = |name: &str| targets.iter().find(|target| target.name == name).unwrap();
+
+    let latency = ping::ping(target("mock1"), 3, Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert!(latency.min >= Duration::from_millis(50));
+    assert!(latency.min <= latency.avg && latency.avg <= latency.max);
+    assert_eq!(latency.identities, 1);
+    assert!(ping::ping(target("mock1"), 1, Duration::from_millis(10))
+        .await
+        .is_err());
+    assert!(ping::ping(target("missing"), 1, Duration::from_secs(1))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn answers_requests_in_flight_when_shutting_down() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.set_delay(Duration::from_millis(300));
+    mock1.spawn(&dir);
+    let (shutdown, shut_down) = tokio::sync::oneshot::channel();
+    let Mux {
+        socket: mux,
+        serving,
+        ..
+    } = spawn_mux_until(&dir, &[&mock1], "", async {
+        shut_down.await.unwrap();
+    });
+    let mut client = connect(&mux).await;
+    let _idle = connect(&mux).await;
+
+    let signing = tokio::spawn(async move { client.sign(sign_request(1)).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    shutdown.send(()).unwrap();
+    let signature = signing.await.unwrap();
+    let served = tokio::time::timeout(Duration::from_secs(1), serving).await;
+
+    assert_eq!(signature.unwrap(), mock1.signature());
+    served.unwrap().unwrap().unwrap();
+    assert!(UnixStream::connect(&mux).await.is_err());
+}
+
+#[tokio::test]
+async fn reports_the_state_of_targets_and_sessions() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock1, &mock2], "", futures::future::pending());
+    let mut client = connect(&mux.socket).await;
+    let _idle = connect(&mux.socket).await;
+
+    client.request_identities().await.unwrap();
+    let state = mux.state.to_string();
+
+    let fingerprint = key(1).fingerprint(HashAlg::Sha256);
+    assert!(state.contains("mock1 ("), "{state}");
+    assert!(state.contains("mock2 ("), "{state}");
+    assert!(state.contains(": failed 0s ago"), "{state}");
+    assert!(state.contains("Sessions: 2"), "{state}");
+    assert!(
+        state.contains(&format!("1 keys, listed 0s ago\n    {fingerprint}: mock1")),
+        "{state}"
+    );
+    assert!(state.contains("keys not listed yet"), "{state}");
+}
+
+#[tokio::test]
+async fn reopens_connections_closed_for_being_idle() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "one")
+        .with_target_options("idle_timeout = \"100ms\"");
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "");
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+
+    // Only a new connection reaches the agent restarted in its place.
+    std::fs::remove_file(mock1.socket(&dir)).unwrap();
+    let restarted = MockAgent::new(1).with_key(key(2), "two");
+    restarted.spawn(&dir);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let identities = client.request_identities().await.unwrap();
+
+    assert_eq!(identities[0].comment, "two");
+}
+
+#[tokio::test(start_paused = true)]
+async fn closes_client_sessions_idle_for_the_timeout() {
+    // Keeps the clock from jumping ahead while waiting on sockets, so that
+    // only advancing it moves it.
+    tokio::spawn(async {
+        loop {
+            tokio::task::yield_now().await;
+        }
+    });
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1], "session_idle_timeout = \"1m\"");
+    let mut idle = connect(&mux).await;
+    let mut active = connect(&mux).await;
+
+    // Each request starts the timeout over.
+    for _ in 0..4 {
+        tokio::time::advance(Duration::from_secs(40)).await;
+        active.request_identities().await.unwrap();
+    }
+
+    assert!(idle.request_identities().await.is_err());
+    assert_eq!(active.request_identities().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn resolves_tcp_targets_by_name_when_connecting() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let port = mock1.spawn_tcp().await;
+    let config = format!("targets = {{ remote = \"tcp://localhost:{port}\" }}");
+    let mux = spawn_mux_until(&dir, &[], &config, futures::future::pending());
+
+    let identities = connect(&mux.socket)
+        .await
+        .request_identities()
+        .await
+        .unwrap();
+
+    assert_eq!(identities[0].comment, "one");
+    let state = mux.state.to_string();
+    assert!(
+        state.contains(&format!("remote (tcp://localhost:{port})")),
+        "{state}"
+    );
+}
+
+#[tokio::test]
+async fn serves_the_agents_found_in_target_directories() {
+    let dir = TestDir::new();
+    let agents = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(2), "two");
+    mock1.spawn(&agents);
+    let config = format!(
+        "targets = {{ agents = \"dir://{}\" }}",
+        agents.path("").display()
+    );
+    let mux = spawn_mux_until(&dir, &[], &config, futures::future::pending());
+
+    let identities = connect(&mux.socket)
+        .await
+        .request_identities()
+        .await
+        .unwrap();
+    assert_eq!(identities.len(), 1);
+
+    mock2.spawn(&agents);
+    mux.state.rescan_targets();
+    let identities = connect(&mux.socket)
+        .await
+        .request_identities()
+        .await
+        .unwrap();
+    let comments: Vec<_> = identities.iter().map(|i| i.comment.as_str()).collect();
+    assert_eq!(comments, ["one", "two"]);
+    assert!(mux.state.to_string().contains("agents/mock2.sock ("));
+}
+
+#[tokio::test]
+async fn lists_and_uses_hidden_targets_only_while_unlocked() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(2), "two")
+        .with_target_options("hidden = true");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+    let host = Binding::FilePath(mux.clone());
+    let mut client = connect(&mux).await;
+
+    let hidden = client.request_identities().await.unwrap();
+    let hidden_sign = client.sign(sign_request(2)).await;
+    let not_hidden = unlock::unlock_target(&host, "mock1", Duration::from_secs(1)).await;
+    unlock::unlock_target(&host, "mock2", Duration::from_secs(1))
+        .await
+        .unwrap();
+    let unlocked = client.request_identities().await.unwrap();
+    let signature = client.sign(sign_request(2)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let relocked_sign = client.sign(sign_request(2)).await;
+
+    assert_eq!(hidden.len(), 1);
+    assert!(hidden_sign.is_err());
+    assert!(not_hidden.is_err());
+    assert_eq!(unlocked.len(), 2);
+    assert_eq!(signature, mock2.signature());
+    assert!(relocked_sign.is_err());
+}
+
+#[tokio::test]
+async fn lists_canary_keys_but_never_signs_with_them() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let canary = PublicKey::new(key(45), "deploy@ci").to_openssh().unwrap();
+    let mux = spawn_mux(&dir, &[&mock1], &format!("canary_keys = [\"{canary}\"]"));
+    let mut client = connect(&mux).await;
+
+    let identities = client.request_identities().await.unwrap();
+    let result = client.sign(sign_request(45)).await;
+
+    let comments: Vec<_> = identities
+        .iter()
+        .map(|identity| identity.comment.as_str())
+        .collect();
+    assert_eq!(comments, ["one", "deploy@ci"]);
+    assert!(result.is_err());
+    assert!(!mock1
+        .requests()
+        .iter()
+        .any(|request| matches!(request, Request::SignRequest(_))));
+}
+
+#[tokio::test]
+async fn warns_of_keys_used_toward_new_hosts() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(46), "forty-six");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "anomalies = \"warn\"");
+    let event_socket = dir.path("events.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&event_socket).unwrap();
+    tokio::spawn(events::serve(listener));
+    let subscriber = UnixStream::connect(&event_socket).await.unwrap();
+    // Let the mux subscribe the client before there are events.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = connect(&mux).await;
+    client.extension(session_bind(1)).await.unwrap();
+    for _ in 0..10 {
+        client.sign(sign_request(46)).await.unwrap();
+    }
+    let mut client = connect(&mux).await;
+    client.extension(session_bind(2)).await.unwrap();
+    client.sign(sign_request(46)).await.unwrap();
+
+    // Other tests' events are streamed too.
+    let fingerprint = key(46).fingerprint(HashAlg::Sha256);
+    let mut lines = BufReader::new(subscriber).lines();
+    let line = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let line = lines.next_line().await.unwrap().unwrap();
+            if line.starts_with("{\"event\":\"anomaly\"") && line.contains(&fingerprint.to_string())
+            {
+                return line;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(
+        line.contains(&format!(
+            ",\"reason\":\"first use toward host key {}\",",
+            host_key(2).fingerprint(HashAlg::Sha256)
+        )),
+        "{line}"
+    );
+}
+
+#[tokio::test]
+async fn shows_keys_by_their_nicknames() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(42), "forty-two");
+    mock1.spawn(&dir);
+    let fingerprint = key(42).fingerprint(HashAlg::Sha256);
+    let config = format!("[keys.\"{fingerprint}\"]\nnickname = \"work\"");
+    logging::set_nicknames(&load_config(&dir, &[&mock1], &config).keys);
+    let mux = spawn_mux_until(&dir, &[&mock1], &config, futures::future::pending());
+
+    connect(&mux.socket)
+        .await
+        .request_identities()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        logging::Key(&key(42)).to_string(),
+        format!("work ({fingerprint})")
+    );
+    let state = mux.state.to_string();
+    assert!(
+        state.contains(&format!("work ({fingerprint}): mock1")),
+        "{state}"
+    );
+}
+
+#[tokio::test]
+async fn applies_reloaded_config_to_existing_sessions() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2)
+        .with_key(key(2), "two")
+        .with_key(key(3), "three");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock1], "", futures::future::pending());
+    let mut client = connect(&mux.socket).await;
+    let identities = client.request_identities().await.unwrap();
+    assert_eq!(identities[0].comment, "one");
+
+    mux.state
+        .reload(&load_config(&dir, &[&mock2], "max_identities = 1"));
+
+    let identities = client.request_identities().await.unwrap();
+    let comments: Vec<_> = identities.iter().map(|i| i.comment.as_str()).collect();
+    assert_eq!(comments, ["two"]);
+    assert!(client.sign(sign_request(1)).await.is_err());
+    let signature = client.sign(sign_request(3)).await.unwrap();
+    assert_eq!(signature, mock2.signature());
+    assert!(mux.state.to_string().contains("connected to mock2:"));
+}
+
+#[tokio::test]
+async fn keeps_keys_on_their_targets_when_listed_across_a_reload() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(2), "two");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock1, &mock2], "", futures::future::pending());
+    let stream = UnixStream::connect(&mux.socket).await.unwrap();
+    let mut framed = Framed::new(stream, Codec::<Response, Request>::default());
+    mock1.set_delay(Duration::from_millis(300));
+
+    // A listing of both targets, still in flight when mock1 is removed and
+    // a request on the remaining targets lists the keys again.
+    framed.send(Request::RequestIdentities).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    mux.state.reload(&load_config(&dir, &[&mock2], ""));
+    framed
+        .send(Request::SignRequest(sign_request(2)))
+        .await
+        .unwrap();
+    framed.next().await.unwrap().unwrap();
+    framed.next().await.unwrap().unwrap();
+    framed
+        .send(Request::SignRequest(sign_request(2)))
+        .await
+        .unwrap();
+    let response = framed.next().await.unwrap().unwrap();
+
+    assert_eq!(response, Response::SignResponse(mock2.signature()));
+}
+
+#[tokio::test]
+async fn streams_events_to_event_socket_clients() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(40), "forty");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "");
+    let event_socket = dir.path("events.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&event_socket).unwrap();
+    tokio::spawn(events::serve(listener));
+    let subscriber = UnixStream::connect(&event_socket).await.unwrap();
+    // Let the mux subscribe the client before there are events.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    client.sign(sign_request(40)).await.unwrap();
+
+    // Other tests' events are streamed too.
+    let fingerprint = key(40).fingerprint(HashAlg::Sha256);
+    let mut lines = BufReader::new(subscriber).lines();
+    let line = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let line = lines.next_line().await.unwrap().unwrap();
+            if line.contains(&fingerprint.to_string()) {
+                return line;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(
+        line.starts_with("{\"event\":\"key_used\",\"time\":"),
+        "{line}"
+    );
+    // SAFETY: getuid always succeeds.
+    let uid = unsafe { libc::getuid() };
+    assert!(
+        line.ends_with(&format!(
+            ",\"key\":\"{fingerprint}\",\"target\":\"mock1\",\"client\":\"uid {uid}\",\"nickname\":\"\"}}"
+        )),
+        "{line}"
+    );
+}
+
+#[tokio::test]
+async fn streams_changes_to_the_keys_targets_list() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(47), "old");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "");
+    let event_socket = dir.path("events.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&event_socket).unwrap();
+    tokio::spawn(events::serve(listener));
+    let subscriber = UnixStream::connect(&event_socket).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    mock.set_key(key(48), "new");
+    client.request_identities().await.unwrap();
+
+    let added = key(48).fingerprint(HashAlg::Sha256);
+    let removed = key(47).fingerprint(HashAlg::Sha256);
+    let mut lines = BufReader::new(subscriber).lines();
+    let line = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let line = lines.next_line().await.unwrap().unwrap();
+            if line.contains(&added.to_string()) {
+                return line;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(
+        line.starts_with("{\"event\":\"target_identities_changed\",\"time\":"),
+        "{line}"
+    );
+    assert!(
+        line.ends_with(&format!(
+            ",\"target\":\"mock1\",\"added\":\"{added}\",\"removed\":\"{removed}\"}}"
+        )),
+        "{line}"
+    );
+}
+
+#[tokio::test]
+async fn rejects_clients_running_as_other_users() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    // SAFETY: getuid always succeeds.
+    let uid = unsafe { libc::getuid() };
+    let mux = spawn_mux(&dir, &[&mock], &format!("allowed_uids = [{}]", uid + 1));
+
+    assert!(connect(&mux).await.request_identities().await.is_err());
+    assert!(mock.requests().is_empty());
+}
+
+#[tokio::test]
+async fn serves_clients_in_allowed_groups() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    // SAFETY: getuid and getgid always succeed.
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mux = spawn_mux(
+        &dir,
+        &[&mock],
+        &format!("allowed_uids = [{}]\nallowed_gids = [{gid}]", uid + 1),
+    );
+
+    let identities = connect(&mux).await.request_identities().await.unwrap();
+
+    assert_eq!(identities.len(), 1);
+}
+
+#[tokio::test]
+async fn closes_sessions_sending_messages_over_the_size_limit() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "max_message_size = 1024");
+    let request = |len: usize| SignRequest {
+        pubkey: key(1),
+        data: vec![0; len],
+        flags: 0,
+    };
+
+    let mut client = connect(&mux).await;
+    client.sign(request(512)).await.unwrap();
+    assert!(client.sign(request(2048)).await.is_err());
+    // Only the message within the limit reached the target.
+    let signed = mock
+        .requests()
+        .into_iter()
+        .filter(|request| matches!(request, Request::SignRequest(_)))
+        .count();
+    assert_eq!(signed, 1);
+
+    // Sessions are closed on the length alone, without waiting for the
+    // message.
+    let mut stream = UnixStream::connect(&mux).await.unwrap();
+    stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut rest))
+        .await
+        .expect("the session wasn't closed")
+        .unwrap();
+    assert!(rest.is_empty());
+}
+
+#[tokio::test]
+async fn closes_sessions_exceeding_their_byte_budget() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "session_max_bytes = 100");
+
+    // Each request for the identities takes 5 bytes.
+    let mut client = connect(&mux).await;
+    for _ in 0..20 {
+        client.request_identities().await.unwrap();
+    }
+    assert!(client.request_identities().await.is_err());
+    // Other sessions have budgets of their own.
+    connect(&mux).await.request_identities().await.unwrap();
+}
+
+#[tokio::test]
+async fn refuses_sessions_beyond_the_limit() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "max_sessions = 2");
+
+    let mut first = connect(&mux).await;
+    first.request_identities().await.unwrap();
+    let mut second = connect(&mux).await;
+    second.request_identities().await.unwrap();
+    assert!(connect(&mux).await.request_identities().await.is_err());
+
+    // Sessions can be started again once one ends.
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    connect(&mux).await.request_identities().await.unwrap();
+    second.request_identities().await.unwrap();
+}
+
+/// Sends `message` on `stream` and reads the reply, without their length
+/// prefixes.
+async fn exchange(stream: &mut UnixStream, message: &[u8]) -> Vec<u8> {
+    let length = u32::try_from(message.len()).unwrap().to_be_bytes();
+    stream
+        .write_all(&[&length, message].concat())
+        .await
+        .unwrap();
+    let mut length = [0; 4];
+    stream.read_exact(&mut length).await.unwrap();
+    let mut reply = vec![0; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut reply).await.unwrap();
+    reply
+}
+
+#[tokio::test]
+async fn answers_protocol_1_requests_when_configured() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "legacy_v1 = true");
+    let (failure, success) = ([5], [6]);
+
+    let mut stream = UnixStream::connect(&mux).await.unwrap();
+    // Requesting the identities and removing them all find none.
+    assert_eq!(exchange(&mut stream, &[1]).await, [2, 0, 0, 0, 0]);
+    assert_eq!(exchange(&mut stream, &[9]).await, success);
+    // Challenges, additions and removals fail.
+    for request in [3, 7, 8, 24] {
+        assert_eq!(
+            exchange(&mut stream, &[request]).await,
+            failure,
+            "{request}"
+        );
+    }
+    assert!(mock.requests().is_empty());
+    // Protocol 2 requests are still served in the same session.
+    assert_eq!(exchange(&mut stream, &[11]).await[0], 12);
+}
+
+#[tokio::test]
+async fn fails_protocol_1_requests_by_default() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "");
+
+    let mut stream = UnixStream::connect(&mux).await.unwrap();
+    for request in [1, 9] {
+        assert_eq!(exchange(&mut stream, &[request]).await, [5], "{request}");
+    }
+}
+
+/// Sends `request` to the admin endpoint on `socket`, returning the status
+/// line and body of the response.
+async fn admin_request(socket: &std::path::Path, request: &str) -> (String, String) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = UnixStream::connect(socket).await.unwrap();
+    stream
+        .write_all(format!("{request} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.lines().next().unwrap().to_owned();
+    (status, body.trim_end().to_owned())
+}
+
+#[tokio::test]
+async fn generates_ssh_config_for_the_host_and_views() {
+    let dir = TestDir::new();
+    let first = MockAgent::new(1)
+        .with_key(key(1), "deploy")
+        .with_key(key(2), "personal");
+    let second = MockAgent::new(2).with_key(key(3), "work");
+    first.spawn(&dir);
+    second.spawn(&dir);
+    let host = dir.path("mux 100%.sock");
+    let config = load_config(
+        &dir,
+        &[&first, &second],
+        &format!(
+            "host = \"unix://{}\"\n[views.deploy]\nhost = \"unix://{}\"\nkeys = [\"{}\"]\n\
+             [views.remote]\nhost = \"tcp://127.0.0.1:7000\"",
+            host.display(),
+            dir.path("deploy.sock").display(),
+            key(1).fingerprint(HashAlg::Sha256)
+        ),
+    );
+    let identities = MuxAgentBind::new(&config).list_identities().await.unwrap();
+    let key_files = sshconfig::write_public_keys(&dir.path("keys"), identities).unwrap();
+    let snippet = sshconfig::generate(&Binding::FilePath(host.clone()), &config.views, &key_files);
+
+    let deploy = &key_files[0];
+    assert_eq!(deploy.identity.pubkey, key(1));
+    assert_eq!(
+        PublicKey::from_openssh(&std::fs::read_to_string(&deploy.path).unwrap())
+            .unwrap()
+            .key_data(),
+        &key(1)
+    );
+    assert_eq!(
+        snippet,
+        format!(
+            "# The deploy view: put the hosts to use it for in place of `deploy`.\n\
+             Host deploy\n    IdentityAgent {}\n    IdentityFile {}\n    IdentitiesOnly yes\n\n\
+             # The remote view: put the hosts to use it for in place of `remote`.\n\
+             # The view is not served on a Unix socket or named pipe, which ssh needs.\n\n\
+             Host *\n    IdentityAgent \"{}\"\n",
+            dir.path("deploy.sock").display(),
+            deploy.path.display(),
+            host.display().to_string().replace('%', "%%")
+        )
+    );
+    assert_eq!(
+        sshconfig::agent_path(&Binding::NamedPipe(r"\\.\pipe\ssh-agent-mux".into())).unwrap(),
+        "//./pipe/ssh-agent-mux"
+    );
+}
+
+#[tokio::test]
+async fn serves_views_of_some_targets_and_keys() {
+    let dir = TestDir::new();
+    let first = MockAgent::new(1)
+        .with_key(key(1), "deploy")
+        .with_key(key(2), "personal");
+    let second = MockAgent::new(2).with_key(key(3), "work");
+    first.spawn(&dir);
+    second.spawn(&dir);
+    let view = |key: u8| {
+        format!(
+            "[views.deploy]\nhost = \"unix://{}\"\ntargets = [\"mock1\"]\nkeys = [\"{}\"]",
+            dir.path("deploy.sock").display(),
+            common::key(key).fingerprint(HashAlg::Sha256)
+        )
+    };
+    let mut state = None;
+    let mux = spawn_tcp_mux_with(&dir, &[&first, &second], &view(1), |config| {
+        let mux = MuxAgentBind::new(config);
+        state = Some(mux.state());
+        mux.view(config, "deploy").unwrap()
+    });
+    let mut client = Client::new(TcpStream::connect(mux).await.unwrap());
+
+    let identities = client.request_identities().await.unwrap();
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].pubkey, key(1));
+    client.sign(sign_request(1)).await.unwrap();
+    assert!(client.sign(sign_request(2)).await.is_err());
+    assert!(client.sign(sign_request(3)).await.is_err());
+
+    let config = load_config(
+        &dir,
+        &[&first, &second],
+        &format!("host = \"tcp://127.0.0.1:0\"\n{}", view(2)),
+    );
+    state.unwrap().reload(&config);
+    let identities = client.request_identities().await.unwrap();
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].pubkey, key(2));
+}
+
+#[tokio::test]
+async fn keeps_views_of_some_keys_from_changing_other_keys() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "deploy")
+        .with_key(key(2), "personal");
+    mock1.spawn(&dir);
+    let view = format!(
+        "[views.deploy]\nhost = \"unix://{}\"\nkeys = [\"{}\"]",
+        dir.path("deploy.sock").display(),
+        key(1).fingerprint(HashAlg::Sha256)
+    );
+    let mux = spawn_tcp_mux_with(&dir, &[&mock1], &view, |config| {
+        MuxAgentBind::new(config).view(config, "deploy").unwrap()
+    });
+    let mut client = Client::new(TcpStream::connect(mux).await.unwrap());
+
+    client.remove_all_identities().await.unwrap();
+    assert!(client
+        .remove_identity(RemoveIdentity { pubkey: key(2) })
+        .await
+        .is_err());
+    assert!(client.add_identity(add_identity(3)).await.is_err());
+    assert!(client.lock("hunter2".to_owned()).await.is_err());
+    assert!(client.unlock("hunter2".to_owned()).await.is_err());
+    assert!(client
+        .extension(Extension {
+            name: "other@example.com".to_owned(),
+            details: Vec::new().into(),
+        })
+        .await
+        .is_err());
+
+    let requests = mock1.requests();
+    assert!(
+        matches!(
+            requests.as_slice(),
+            [Request::RequestIdentities, Request::RemoveIdentity(removed)] if removed.pubkey == key(1)
+        ),
+        "{requests:?}"
+    );
+}
+
+#[tokio::test]
+async fn creates_views_of_some_keys() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1)
+        .with_key(key(1), "project")
+        .with_key(key(2), "personal");
+    mock.spawn(&dir);
+    let config_path = dir.path("mux.toml");
+    let socket = dir.path("project.sock");
+    let view_socket = socket.clone();
+    let creator: Arc<ViewCreator> = Arc::new(move |mux, name, keys| {
+        let mut config = Config::load(&config_path).unwrap();
+        config.views.push(ViewConfig {
+            name: name.to_owned(),
+            host: Binding::FilePath(view_socket.clone()),
+            targets: Vec::new(),
+            keys,
+            match_hosts: Vec::new(),
+        });
+        let listener = Binding::FilePath(view_socket.clone()).try_into().unwrap();
+        let view = mux.view(&config, name).unwrap();
+        tokio::spawn(serve_until(
+            listener,
+            view,
+            ServeOptions::from(&config),
+            futures::future::pending(),
+        ));
+        Ok(view_socket.display().to_string())
+    });
+    let mux = spawn_tcp_mux_with(&dir, &[&mock], "", |config| {
+        MuxAgentBind::new(config).with_view_creator(creator)
+    });
+    let host = format!("tcp://{mux}").parse().unwrap();
+    let keys = [key(1).fingerprint(HashAlg::Sha256)];
+
+    let created = view::create_view(&host, "project", &keys).await.unwrap();
+    assert_eq!(created, socket.display().to_string());
+    let identities = connect(&socket).await.request_identities().await.unwrap();
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].pubkey, key(1));
+    assert!(connect(&socket).await.sign(sign_request(2)).await.is_err());
+
+    assert!(view::create_view(&host, "project", &keys).await.is_err());
+    assert!(view::create_view(&host, "../x", &keys).await.is_err());
+    let view_host = Binding::FilePath(socket);
+    assert!(view::create_view(&view_host, "other", &keys).await.is_err());
+}
+
+#[tokio::test]
+async fn answers_admin_requests_with_json() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(41), "forty-one");
+    mock.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock], "", futures::future::pending());
+    let admin_socket = dir.path("admin.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&admin_socket).unwrap();
+    let config_path = dir.path("mux.toml");
+    let reload_path = config_path.clone();
+    tokio::spawn(admin::serve(
+        listener.into(),
+        mux.state.clone(),
+        move || Config::load(&reload_path),
+    ));
+
+    let (status, body) = admin_request(&admin_socket, "GET /status").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(body.starts_with("{\"schema_version\":1,"), "{body}");
+
+    let (_, body) = admin_request(&admin_socket, "GET /keys").await;
+    let fingerprint = key(41).fingerprint(HashAlg::Sha256);
+    assert!(
+        body.starts_with(&format!("[{{\"fingerprint\":\"{fingerprint}\",")),
+        "{body}"
+    );
+    assert!(body.ends_with(",\"target\":\"mock1\",\"targets\":[\"mock1\"]}]"));
+
+    let config = std::fs::read_to_string(&config_path).unwrap();
+    std::fs::write(&config_path, format!("{config}priority = 5\n")).unwrap();
+    let (status, body) = admin_request(&admin_socket, "POST /reload").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body, "{\"reloaded\":true}");
+    let (_, body) = admin_request(&admin_socket, "GET /targets").await;
+    assert_eq!(
+        body,
+        "[{\"name\":\"mock1\",\"healthy\":true,\"failed\":null,\"priority\":5,\"sign_latency_ms\":null,\"hidden\":false}]"
+    );
+
+    let (status, _) = admin_request(&admin_socket, "GET /reload").await;
+    assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+    let (status, _) = admin_request(&admin_socket, "GET /nowhere").await;
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+}
+
+#[tokio::test]
+async fn serves_a_dashboard_of_recent_signatures() {
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(42), "forty-two");
+    mock.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock], "", futures::future::pending());
+    let admin_socket = dir.path("admin.sock");
+    let listener = std::os::unix::net::UnixListener::bind(&admin_socket).unwrap();
+    let config_path = dir.path("mux.toml");
+    tokio::spawn(admin::serve(
+        listener.into(),
+        mux.state.clone(),
+        move || Config::load(&config_path),
+    ));
+    // Let the endpoint start keeping signatures.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = connect(&mux.socket).await;
+    client.sign(sign_request(42)).await.unwrap();
+
+    let (status, body) = admin_request(&admin_socket, "GET /").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(body.contains("<title>ssh-agent-mux</title>"));
+    // Other tests' signatures are kept too.
+    let (status, body) = admin_request(&admin_socket, "GET /activity").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let fingerprint = key(42).fingerprint(HashAlg::Sha256);
+    assert!(
+        body.contains(&format!(",\"key\":\"{fingerprint}\",\"target\":\"mock1\",")),
+        "{body}"
+    );
+}
+
+#[tokio::test]
+async fn shows_the_targets_and_signatures_of_a_mux_live() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(44), "forty-four");
+    let mock2 = MockAgent::new(2);
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+    let mut monitor = top::Monitor::default();
+
+    monitor.set_status(&status::status(&Binding::FilePath(mux), true).await.unwrap());
+    assert_eq!(monitor.problem, None);
+    let health: Vec<_> = monitor
+        .targets
+        .iter()
+        .map(|target| (target.name.as_str(), target.healthy))
+        .collect();
+    assert_eq!(health, [("mock1", true), ("mock2", false)]);
+    monitor.add_event(
+        r#"{"event":"key_used","time":3600,"key":"SHA256:abcdefgh12345678","target":"mock1","client":"uid 1000","nickname":""}"#,
+    );
+    monitor.add_event(
+        r#"{"event":"sign_denied","time":3661,"key":"SHA256:zyxwvuts","reason":"quota exceeded","client":"uid 1000","nickname":"deploy"}"#,
+    );
+    monitor.add_event(r#"{"event":"target_up","time":3662,"target":"mock2"}"#);
+    let signatures: Vec<_> = monitor
+        .signatures
+        .iter()
+        .map(|signature| (signature.key.as_str(), signature.outcome.as_str()))
+        .collect();
+    assert_eq!(
+        signatures,
+        [("deploy", "quota exceeded"), ("SHA256:abcdefgh", "mock1")]
+    );
+    assert_eq!(monitor.usage["deploy"].denied, 1);
+    assert_eq!(monitor.usage["SHA256:abcdefgh"].signatures, 1);
+
+    let mut terminal = Terminal::new(TestBackend::new(100, 16)).unwrap();
+    terminal.draw(|frame| monitor.draw(frame)).unwrap();
+    let screen: String = terminal
+        .backend()
+        .buffer()
+        .content()
+        .iter()
+        .map(|cell| cell.symbol())
+        .collect();
+    for shown in ["1/2 targets healthy", "mock2", "01:01:01", "quota exceeded"] {
+        assert!(screen.contains(shown), "{shown} isn't shown");
+    }
+}
+
+#[tokio::test]
+async fn reports_a_versioned_json_status() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(43), "forty-three");
+    let mock2 = MockAgent::new(2);
+    mock1.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+    let host = Binding::FilePath(mux.clone());
+
+    let unlisted = status::status(&host, true).await.unwrap();
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    let json = status::status(&host, true).await.unwrap();
+
+    assert!(
+        json.starts_with(&format!(
+            "{{\"schema_version\":{},\"version\":\"{}\",\"targets\":[{{\"name\":\"mock1\",\"healthy\":true,",
+            status::SCHEMA_VERSION,
+            env!("CARGO_PKG_VERSION")
+        )),
+        "{json}"
+    );
+    assert!(
+        json.contains("{\"name\":\"mock2\",\"healthy\":false,"),
+        "{json}"
+    );
+    // Keys are counted once listed to a client.
+    assert!(unlisted.contains(",\"keys\":null,"), "{unlisted}");
+    // The sessions listing and asking count, and other tests' keys are used
+    // too.
+    assert!(
+        json.contains(",\"healthy_targets\":1,\"sessions\":2,\"keys\":1,\"keys_used\":"),
+        "{json}"
+    );
+    let text = status::status(&host, false).await.unwrap();
+    assert!(text.starts_with("Targets:\n  mock1 ("), "{text}");
+}
@@ -0,0 +1,91 @@
+//! End-to-end tests of notifications, in their own process as the webhook
+//! and the digest window are installed for the whole process.
+
+mod common;
+
+use std::time::Duration;
+
+use ssh_agent_lib::{agent::Session, proto::SignRequest};
+use ssh_agent_mux::audit;
+use ssh_agent_mux::config::WebhookConfig;
+use ssh_agent_mux::webhook::{self, Webhook};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+/// Receives the bodies of the requests posted to the returned URL.
+async fn spawn_webhook_receiver() -> (String, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, bodies) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // The mux only sends single line bodies, after the headers.
+            let body = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let request = String::from_utf8_lossy(&request);
+                if let Some((_, body)) = request.split_once("\r\n\r\n") {
+                    if body.ends_with('}') {
+                        break body.to_owned();
+                    }
+                }
+            };
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            sender.send(body).unwrap();
+        }
+    });
+    (url, bodies)
+}
+
+#[tokio::test]
+async fn sends_notifications_within_the_digest_window_as_one() {
+    let (url, mut bodies) = spawn_webhook_receiver().await;
+    webhook::install(
+        Webhook::new(&WebhookConfig {
+            url: url.parse().unwrap(),
+            secret: None,
+            retries: 0,
+        })
+        .unwrap(),
+    );
+    audit::set_digest_window(Duration::from_millis(500));
+    let dir = TestDir::new();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "");
+
+    let mut client = connect(&mux).await;
+    for _ in 0..3 {
+        client
+            .sign(SignRequest {
+                pubkey: key(1),
+                data: b"data".to_vec(),
+                flags: 0,
+            })
+            .await
+            .unwrap();
+    }
+
+    // Finding the key lists the identities, which is notified of first.
+    let first = bodies.recv().await.unwrap();
+    assert!(
+        first.starts_with("{\"event\":\"identities_changed\""),
+        "{first}"
+    );
+    let digest = bodies.recv().await.unwrap();
+    assert!(digest.starts_with("{\"event\":\"digest\""), "{digest}");
+    assert!(
+        digest.contains("\"count\":\"3\",\"summary\":\"3 key_used\""),
+        "{digest}"
+    );
+    assert_eq!(digest.matches("\"event\":\"key_used\"").count(), 3);
+}
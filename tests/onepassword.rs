@@ -0,0 +1,78 @@
+//! Tests of labeling 1Password keys, in their own process as the labels
+//! are kept for the whole process.
+
+mod common;
+
+use service_binding::Binding;
+use ssh_agent_lib::agent::Session;
+use ssh_agent_mux::{logging, onepassword};
+
+use common::{connect, key, load_config, spawn_mux, MockAgent, TestDir};
+
+#[tokio::test]
+async fn labels_1password_keys_with_their_vault_and_item() {
+    let dir = TestDir::new();
+    let agent_toml = dir.path("agent.toml");
+    std::fs::write(
+        &agent_toml,
+        r#"
+[[ssh-keys]]
+item = "GitHub"
+vault = "Work"
+
+[[ssh-keys]]
+vault = "Private"
+
+[[ssh-keys]]
+item = "Servers"
+"#,
+    )
+    .unwrap();
+    onepassword::set_agent_toml(Some(&agent_toml)).unwrap();
+    let mock1 = MockAgent::new(1)
+        .with_key(key(1), "GitHub")
+        .with_key(key(2), "Laptop")
+        .with_target_options("flavor = \"1password\"");
+    mock1.spawn(&dir);
+    let mock2 = MockAgent::new(2).with_key(key(3), "Servers");
+    mock2.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "");
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+
+    assert_eq!(
+        logging::key_nickname(&key(1)).as_deref(),
+        Some("Work/GitHub")
+    );
+    assert_eq!(logging::key_nickname(&key(2)), None);
+    // Not a 1Password target.
+    assert_eq!(logging::key_nickname(&key(3)), None);
+
+    // Labeled from the listing of a running mux, which the key cache tells
+    // the keys of 1Password targets apart in.
+    let dir = TestDir::new();
+    let mock4 = MockAgent::new(4)
+        .with_key(key(5), "Servers")
+        .with_target_options("flavor = \"1password\"");
+    mock4.spawn(&dir);
+    let mock5 = MockAgent::new(5).with_key(key(6), "Servers");
+    mock5.spawn(&dir);
+    let host = Binding::FilePath(spawn_mux(&dir, &[&mock4, &mock5], ""));
+    let targets = load_config(&dir, &[&mock4, &mock5], "").targets;
+    let cached = vec![
+        (key(5), vec!["mock4".to_owned()]),
+        (key(6), vec!["mock5".to_owned()]),
+    ];
+    onepassword::learn_from_mux(&host, &targets, Some(&cached))
+        .await
+        .unwrap();
+    assert_eq!(logging::key_nickname(&key(5)).as_deref(), Some("Servers"));
+    assert_eq!(logging::key_nickname(&key(6)), None);
+
+    // Without a key cache, every key listed is labeled.
+    onepassword::learn_from_mux(&host, &targets, None)
+        .await
+        .unwrap();
+    assert_eq!(logging::key_nickname(&key(6)).as_deref(), Some("Servers"));
+}
@@ -0,0 +1,62 @@
+//! Tests of named pipes: the host's security descriptor, and the OpenSSH
+//! agent of Windows as the default target (Windows).
+
+#![cfg(windows)]
+
+use std::path::Path;
+
+use service_binding::Binding;
+use ssh_agent_lib::agent::ListeningSocket;
+use ssh_agent_mux::config::{Config, PipeSecurityConfig};
+use ssh_agent_mux::pipe::NamedPipeListener;
+use tokio::net::windows::named_pipe::{ClientOptions, ServerOptions};
+
+/// A pipe name unique to the test.
+fn pipe_name(test: &str) -> String {
+    format!(r"\\.\pipe\ssh-agent-mux-test-{}-{test}", std::process::id())
+}
+
+#[tokio::test]
+async fn lets_the_current_user_connect_by_default() {
+    let name = pipe_name("default");
+    let mut listener = NamedPipeListener::bind(&name, &PipeSecurityConfig::default()).unwrap();
+
+    let _client = ClientOptions::new().open(&name).unwrap();
+
+    listener.accept().await.unwrap();
+}
+
+#[tokio::test]
+async fn refuses_whoever_the_descriptor_leaves_out() {
+    let name = pipe_name("nobody");
+    let config = PipeSecurityConfig {
+        allow_groups: Vec::new(),
+        // Protected and empty, so that no one may connect.
+        sddl: Some("D:P".to_owned()),
+    };
+    let _listener = NamedPipeListener::bind(&name, &config).unwrap();
+
+    let error = ClientOptions::new().open(&name).unwrap_err();
+
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[tokio::test]
+async fn uses_the_windows_agent_when_no_targets_are_given() {
+    const AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+    // Stands in for the agent service, unless that is running.
+    let _agent =
+        (!Path::new(AGENT_PIPE).exists()).then(|| ServerOptions::new().create(AGENT_PIPE).unwrap());
+    let mut config = Config::default();
+
+    assert!(config.add_windows_agent());
+    assert_eq!(config.targets.len(), 1);
+    assert_eq!(config.targets[0].name, "windows");
+    assert_eq!(
+        config.targets[0].binding,
+        Binding::NamedPipe(AGENT_PIPE.into())
+    );
+    // Not once there are targets.
+    assert!(!config.add_windows_agent());
+    assert_eq!(config.targets.len(), 1);
+}
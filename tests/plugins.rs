@@ -0,0 +1,229 @@
+//! Tests of how WebAssembly plugins are loaded and bounded as they run.
+
+mod common;
+
+use std::path::Path;
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+use ssh_agent_lib::proto::SignRequest;
+use ssh_agent_mux::plugin::{Error, Plugin};
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+/// A plugin with `memory` and `alloc`, routing with `route`, a function
+/// body, beside the functions in `extra`.
+fn plugin(route: &str, extra: &str) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "route") (param i32 i32) (result i64) {route})
+            {extra})"#
+    ))
+    .unwrap()
+}
+
+fn load(bytes: &[u8]) -> Result<Plugin, Error> {
+    Plugin::new(Path::new("test.wasm"), bytes)
+}
+
+fn abi_error(bytes: &[u8]) -> String {
+    match load(bytes) {
+        Err(Error::Abi(message)) => message,
+        Err(e) => panic!("not an ABI error: {e}"),
+        Ok(_) => panic!("loaded"),
+    }
+}
+
+fn sign_request() -> SignRequest {
+    SignRequest {
+        pubkey: key(1),
+        data: b"data".to_vec(),
+        flags: 0,
+    }
+}
+
+/// Whether a mux running the plugin with `route` and `extra` signs, denying
+/// requests the plugin fails on.
+async fn signs_with(route: &str, extra: &str) -> bool {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    std::fs::write(dir.path("route.wasm"), plugin(route, extra)).unwrap();
+    let mux = spawn_mux(&dir, &[&mock1], "plugins = [\"route.wasm\"]");
+
+    let mut client = connect(&mux).await;
+    let signed = tokio::time::timeout(Duration::from_secs(10), client.sign(sign_request()))
+        .await
+        .expect("the plugin wasn't stopped")
+        .is_ok();
+    // The mux still answers after the plugin fails.
+    client.request_identities().await.unwrap();
+    signed
+}
+
+#[test]
+fn loads_plugins_following_the_abi() {
+    let plugin = load(&plugin("i64.const 0", "")).unwrap();
+
+    assert_eq!(plugin.path(), Path::new("test.wasm"));
+}
+
+#[test]
+fn refuses_malformed_modules() {
+    let bytes = plugin("i64.const 0", "");
+
+    assert!(matches!(load(b""), Err(Error::Wasm(_))));
+    assert!(matches!(load(b"\0elf\x01\0\0\0"), Err(Error::Wasm(_))));
+    assert!(matches!(
+        load(&bytes[..bytes.len() - 3]),
+        Err(Error::Wasm(_))
+    ));
+}
+
+#[test]
+fn refuses_out_of_range_indexes() {
+    for route in ["call 9", "local.get 5 drop i64.const 0", "global.get 0"] {
+        assert!(
+            matches!(load(&plugin(route, "")), Err(Error::Wasm(_))),
+            "{route}"
+        );
+    }
+}
+
+#[test]
+fn refuses_modules_breaking_the_typing_rules() {
+    for route in [
+        "i32.const 0",
+        "",
+        "i64.const 0 i64.const 0",
+        "i32.const 0 i64.add",
+    ] {
+        assert!(
+            matches!(load(&plugin(route, "")), Err(Error::Wasm(_))),
+            "{route}"
+        );
+    }
+}
+
+#[test]
+fn refuses_plugins_breaking_the_abi() {
+    let module = |text: &str| wat::parse_str(format!("(module {text})")).unwrap();
+
+    assert_eq!(
+        abi_error(&module(
+            r#"(memory (export "memory") 1)
+               (func (export "route") (param i32 i32) (result i64) i64.const 0)"#
+        )),
+        "alloc isn't exported"
+    );
+    assert_eq!(
+        abi_error(&module(
+            r#"(func (export "alloc") (param i32) (result i32) i32.const 0)
+               (func (export "route") (param i32 i32) (result i64) i64.const 0)"#
+        )),
+        "memory isn't exported"
+    );
+    assert_eq!(
+        abi_error(&module(
+            r#"(memory (export "memory") 1)
+               (func (export "alloc") (param i32) (result i32) i32.const 0)
+               (func (export "route") (param i32) (result i64) i64.const 0)"#
+        )),
+        "route isn't (i32, i32) -> i64"
+    );
+    assert_eq!(
+        abi_error(&module(
+            r#"(memory (export "memory") 1)
+               (func (export "alloc") (param i32) (result i32) i32.const 0)"#
+        )),
+        "exports neither route nor event"
+    );
+}
+
+#[test]
+fn refuses_imports_other_than_log() {
+    let allowed = r#"(import "env" "log" (func (param i32 i32)))"#;
+    let unknown = r#"(import "env" "exec" (func (param i32 i32)))"#;
+    let mistyped = r#"(import "env" "log" (func (param i32)))"#;
+    let memory = r#"(import "env" "memory" (memory 1))"#;
+    let with_import = |import: &str| {
+        wat::parse_str(format!(
+            r#"(module {import}
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const 0)
+                (func (export "route") (param i32 i32) (result i64) i64.const 0))"#
+        ))
+        .unwrap()
+    };
+
+    load(&with_import(allowed)).unwrap();
+    for import in [unknown, mistyped, memory] {
+        assert!(
+            matches!(load(&with_import(import)), Err(Error::Wasm(_))),
+            "{import}"
+        );
+    }
+}
+
+#[test]
+fn refuses_memory_beyond_the_limit() {
+    let memory = |pages: u32| {
+        wat::parse_str(format!(
+            r#"(module
+                (memory (export "memory") {pages})
+                (func (export "alloc") (param i32) (result i32) i32.const 0)
+                (func (export "route") (param i32 i32) (result i64) i64.const 0))"#
+        ))
+        .unwrap()
+    };
+
+    load(&memory(256)).unwrap();
+    assert!(matches!(load(&memory(257)), Err(Error::Wasm(_))));
+}
+
+#[test]
+fn stops_start_functions_running_forever() {
+    let bytes = plugin("i64.const 0", "(func $start (loop (br 0))) (start $start)");
+
+    assert!(matches!(load(&bytes), Err(Error::Wasm(_))));
+}
+
+#[tokio::test]
+async fn signs_as_plugins_decide() {
+    assert!(signs_with("i64.const 0", "").await);
+}
+
+#[tokio::test]
+async fn denies_signatures_on_traps() {
+    assert!(!signs_with("unreachable", "").await);
+    assert!(!signs_with("i32.const 1 i32.const 0 i32.div_u drop i64.const 0", "").await);
+    assert!(!signs_with("i32.const 70000 i32.load drop i64.const 0", "").await);
+}
+
+#[tokio::test]
+async fn denies_signatures_of_plugins_running_out_of_fuel() {
+    assert!(!signs_with("(loop (br 0)) i64.const 0", "").await);
+}
+
+#[tokio::test]
+async fn denies_signatures_of_plugins_recursing_too_deep() {
+    assert!(!signs_with("call $deeper", "(func $deeper (result i64) call $deeper)").await);
+}
+
+#[tokio::test]
+async fn denies_signatures_of_plugins_growing_memory_beyond_the_limit() {
+    // memory.grow fails rather than traps, so the plugin traps itself.
+    let route = "(if (i32.eq (memory.grow (i32.const 256)) (i32.const -1)) (then unreachable)) \
+                 i64.const 0";
+
+    assert!(signs_with("(drop (memory.grow (i32.const 255))) i64.const 0", "").await);
+    assert!(!signs_with(route, "").await);
+}
+
+#[tokio::test]
+async fn denies_signatures_of_decisions_out_of_bounds() {
+    // Four bytes at 0xffff_0000, beyond the one page of memory.
+    assert!(!signs_with("i64.const 0xffff000000000004", "").await);
+}
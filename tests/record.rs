@@ -0,0 +1,74 @@
+//! Tests of recording sessions, in their own process as recording is
+//! started for the whole process.
+
+mod common;
+
+use service_binding::Binding;
+use ssh_agent_lib::{
+    agent::Session,
+    proto::{AddIdentity, Credential, Request, SignRequest},
+};
+use ssh_agent_mux::record::{self, Destination, Recording};
+use ssh_agent_mux::MuxAgentBind;
+use ssh_key::private::{Ed25519Keypair, KeypairData};
+
+use common::{connect, key, load_config, spawn_mux, MockAgent, TestDir};
+
+#[tokio::test]
+async fn records_sessions_without_secrets_to_replay() {
+    let dir = TestDir::new();
+    let path = dir.path("recording");
+    record::start(&path).unwrap();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux(&dir, &[&mock], "");
+
+    let mut client = connect(&mux).await;
+    client.request_identities().await.unwrap();
+    client
+        .sign(SignRequest {
+            pubkey: key(1),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .await
+        .unwrap();
+    let _ = client
+        .add_identity(AddIdentity {
+            credential: Credential::Key {
+                privkey: KeypairData::Ed25519(Ed25519Keypair::from_seed(&[0x77; 32])),
+                comment: "added".to_owned(),
+            },
+        })
+        .await;
+    let _ = client.lock("hunter2".to_owned()).await;
+    drop(client);
+
+    let recording = std::fs::read_to_string(&path).unwrap();
+    assert!(!recording.contains("7777777777777777"), "{recording}");
+    let passphrase: String = b"hunter2".iter().map(|b| format!("{b:02x}")).collect();
+    assert!(!recording.contains(&passphrase), "{recording}");
+    let recording = Recording::parse(&recording).unwrap();
+    assert_eq!(recording.0.len(), 1);
+    let exchanges = recording.0.values().next().unwrap();
+    assert_eq!(exchanges.len(), 4);
+    assert!(exchanges.iter().all(|exchange| exchange.response.is_some()));
+    assert_eq!(exchanges[3].request, Request::Lock("redacted".to_owned()));
+
+    let mut mux = MuxAgentBind::new(&load_config(&dir, &[&mock], ""));
+    let replayed = record::replay(&recording, Destination::Mux(&mut mux))
+        .await
+        .unwrap();
+    assert_eq!(replayed.len(), 4);
+    assert!(
+        replayed.iter().all(|replayed| !replayed.differs()),
+        "{replayed:?}"
+    );
+    let target = Binding::FilePath(mock.socket(&dir));
+    let replayed = record::replay(&recording, Destination::Agent(&target))
+        .await
+        .unwrap();
+    assert!(replayed.iter().all(|replayed| replayed.response.is_ok()));
+    assert!(Recording::parse("0 1.1 > 0b\n5 1.2 < 06").is_err());
+    assert!(Recording::parse("0 1.1 > 0").is_err());
+}
@@ -0,0 +1,174 @@
+//! Tests of how keys, comments and messages are logged, in their own
+//! process as redaction is switched on and off for the whole process.
+
+use std::sync::{Mutex, MutexGuard};
+
+use ssh_agent_lib::proto::{
+    AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Credential, Identity,
+    KeyConstraint, RemoveIdentity, Request, Response, SignRequest, SmartcardKey,
+};
+use ssh_agent_mux::logging::{self, Comment, Fingerprint, Key, Message};
+use ssh_key::private::{Ed25519Keypair, KeypairData};
+use ssh_key::public::KeyData;
+use ssh_key::HashAlg;
+
+/// Held by tests while they log with `redact`, as it is switched for the
+/// whole process.
+fn redacting(redact: bool) -> MutexGuard<'static, ()> {
+    static REDACT: Mutex<()> = Mutex::new(());
+    let guard = REDACT.lock().unwrap_or_else(|e| e.into_inner());
+    logging::set_redact(redact);
+    guard
+}
+
+fn add_identity() -> AddIdentity {
+    AddIdentity {
+        credential: Credential::Key {
+            privkey: KeypairData::Ed25519(Ed25519Keypair::from_seed(&[0x77; 32])),
+            comment: "added".to_owned(),
+        },
+    }
+}
+
+fn smartcard() -> SmartcardKey {
+    SmartcardKey {
+        id: "token".to_owned(),
+        pin: "1234".to_owned().into(),
+    }
+}
+
+fn shown(request: Request) -> String {
+    Message(&request).to_string()
+}
+
+#[test]
+fn shows_requests_holding_secrets_by_type_even_in_full() {
+    let _redacting = redacting(false);
+
+    assert_eq!(shown(Request::Lock("hunter2".to_owned())), "Lock");
+    assert_eq!(shown(Request::Unlock("hunter2".to_owned())), "Unlock");
+    assert_eq!(
+        shown(Request::AddIdentity(add_identity())),
+        "AddIdentity(\"added\")"
+    );
+    assert_eq!(
+        shown(Request::AddSmartcardKey(smartcard())),
+        "AddSmartcardKey"
+    );
+    // Requests without secrets are still shown in full.
+    assert_eq!(
+        shown(Request::RequestIdentities),
+        format!("{:?}", Request::RequestIdentities)
+    );
+}
+
+#[test]
+fn shows_constrained_additions_forwarded_to_targets_by_type() {
+    let _redacting = redacting(false);
+    let constraints = vec![KeyConstraint::Confirm];
+
+    assert_eq!(
+        shown(Request::AddIdConstrained(AddIdentityConstrained {
+            identity: add_identity(),
+            constraints: constraints.clone(),
+        })),
+        "AddIdConstrained(\"added\")"
+    );
+    assert_eq!(
+        shown(Request::AddSmartcardKeyConstrained(
+            AddSmartcardKeyConstrained {
+                key: smartcard(),
+                constraints,
+            }
+        )),
+        "AddSmartcardKeyConstrained"
+    );
+}
+
+fn public_key() -> KeyData {
+    KeyData::Ed25519(Ed25519Keypair::from_seed(&[0x42; 32]).public)
+}
+
+fn fingerprint() -> String {
+    public_key().fingerprint(HashAlg::Sha256).to_string()
+}
+
+fn identities() -> Response {
+    Response::IdentitiesAnswer(vec![Identity {
+        pubkey: public_key(),
+        comment: "me@laptop".to_owned(),
+    }])
+}
+
+#[test]
+fn shows_comments_as_hashes_when_redacting() {
+    let _redacting = redacting(true);
+    let hash = Comment("me@laptop").to_string();
+
+    assert!(hash.starts_with('#'), "{hash}");
+    assert_eq!(hash.len(), 9, "{hash}");
+    assert!(!hash.contains("laptop"), "{hash}");
+    // The same comments hash the same, so they can still be told apart.
+    assert_eq!(Comment("me@laptop").to_string(), hash);
+    assert_ne!(Comment("me@desktop").to_string(), hash);
+}
+
+#[test]
+fn shortens_fingerprints_when_redacting() {
+    let fingerprint = fingerprint();
+    let short = &fingerprint[..15];
+
+    let _redacting = redacting(true);
+    assert_eq!(Key(&public_key()).to_string(), short);
+    assert_eq!(Fingerprint(&fingerprint).to_string(), short);
+}
+
+#[test]
+fn summarizes_messages_without_comments_or_full_fingerprints_when_redacting() {
+    let _redacting = redacting(true);
+    let fingerprint = fingerprint();
+    let short = &fingerprint[..15];
+    let comment = Comment("me@laptop").to_string();
+
+    assert_eq!(
+        Message(&identities()).to_string(),
+        format!("IdentitiesAnswer({short} {comment})")
+    );
+    assert_eq!(
+        shown(Request::SignRequest(SignRequest {
+            pubkey: public_key(),
+            data: b"session data".to_vec(),
+            flags: 0,
+        })),
+        format!("SignRequest({short})")
+    );
+    assert_eq!(
+        shown(Request::RemoveIdentity(RemoveIdentity {
+            pubkey: public_key()
+        })),
+        format!("RemoveIdentity({short})")
+    );
+    assert_eq!(
+        shown(Request::AddIdentity(add_identity())),
+        format!("AddIdentity({})", Comment("added"))
+    );
+}
+
+#[test]
+fn shows_messages_and_keys_in_full_otherwise() {
+    let _redacting = redacting(false);
+    let request = Request::SignRequest(SignRequest {
+        pubkey: public_key(),
+        data: b"session data".to_vec(),
+        flags: 0,
+    });
+
+    assert_eq!(Key(&public_key()).to_string(), fingerprint());
+    assert_eq!(Fingerprint(&fingerprint()).to_string(), fingerprint());
+    assert_eq!(Comment("me@laptop").to_string(), "\"me@laptop\"");
+    assert_eq!(shown(request.clone()), format!("{request:?}"));
+    assert_eq!(
+        Message(&identities()).to_string(),
+        format!("{:?}", identities())
+    );
+}
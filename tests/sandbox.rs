@@ -0,0 +1,180 @@
+//! Smoke tests of the sandbox on each system it confines the mux on, on
+//! muxes run as processes of their own as confinement can't be undone.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+use ssh_agent_lib::proto::SignRequest;
+
+use common::{connect, key, load_config, MockAgent, TestDir};
+
+/// A mux process, killed when dropped.
+struct Mux {
+    process: Child,
+    socket: PathBuf,
+}
+
+impl Mux {
+    /// Starts a mux in front of `targets` with `config`, once it serves.
+    async fn spawn(dir: &TestDir, targets: &[&MockAgent], config: &str) -> Self {
+        let socket = dir.path("mux.sock");
+        let process = command(dir, targets, config)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut mux = Self { process, socket };
+        while !mux.socket.exists() {
+            assert!(mux.process.try_wait().unwrap().is_none(), "the mux exited");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        mux
+    }
+
+    /// Lists the keys of the mux and signs with the first, as the sandbox
+    /// must let it.
+    async fn serves(&mut self) {
+        let mut client = connect(&self.socket).await;
+        let identities = client.request_identities().await.unwrap();
+        assert_eq!(identities[0].comment, "one");
+        client
+            .sign(SignRequest {
+                pubkey: identities[0].pubkey.clone(),
+                data: b"data".to_vec(),
+                flags: 0,
+            })
+            .await
+            .unwrap();
+        assert!(self.process.try_wait().unwrap().is_none());
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// The command running a mux in front of `targets` with `config`.
+fn command(dir: &TestDir, targets: &[&MockAgent], config: &str) -> Command {
+    load_config(
+        dir,
+        targets,
+        &format!(
+            "host = \"unix://{}\"\n{config}",
+            dir.path("mux.sock").display()
+        ),
+    );
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"));
+    command
+        .arg("--config")
+        .arg(dir.path("mux.toml"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    command
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn serves_with_landlock() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mut mux = Mux::spawn(&dir, &[&mock1], "[sandbox]\nlandlock = true").await;
+
+    mux.serves().await;
+}
+
+#[cfg(target_os = "openbsd")]
+#[tokio::test]
+async fn serves_pledged_and_unveiled() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mut mux = Mux::spawn(&dir, &[&mock1], "[sandbox]\npledge = true").await;
+
+    mux.serves().await;
+}
+
+#[cfg(target_os = "freebsd")]
+#[tokio::test]
+async fn serves_in_capability_mode() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mut mux = Mux::spawn(&dir, &[&mock1], "[sandbox]\ncapsicum = true").await;
+
+    mux.serves().await;
+}
+
+/// Only root can switch users, so this passes trivially otherwise.
+#[tokio::test]
+async fn serves_as_the_user_switched_to() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    // SAFETY: getuid always succeeds.
+    if unsafe { libc::getuid() } != 0 {
+        return;
+    }
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    // Reachable by the user switched to.
+    let permissions = std::fs::Permissions::from_mode(0o777);
+    std::fs::set_permissions(mock1.socket(&dir), permissions).unwrap();
+    let mut mux = Mux::spawn(&dir, &[&mock1], "[sandbox]\nuser = \"nobody\"").await;
+
+    mux.serves().await;
+    // SAFETY: the name is NUL-terminated, and the entry is read before any
+    // other lookup.
+    let nobody = unsafe { (*libc::getpwnam(c"nobody".as_ptr())).pw_uid };
+    assert_eq!(std::fs::metadata(&mux.socket).unwrap().uid(), nobody);
+}
+
+/// The mux refuses to start rather than serve a socket without the label
+/// when it can't be set, as where neither SMACK nor root can set it.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn labels_the_host_socket_for_smack() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let socket = dir.path("mux.sock");
+    let mut process = command(&dir, &[&mock1], "[socket_label]\nsmack = \"SshAgentMux\"")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    while !socket.exists() {
+        if process.try_wait().unwrap().is_some() {
+            let output = process.wait_with_output().unwrap();
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(stderr.contains("security.SMACK64"), "{stderr}");
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let mut mux = Mux { process, socket };
+
+    mux.serves().await;
+    let path = std::ffi::CString::new(mux.socket.as_os_str().as_bytes()).unwrap();
+    let mut label = [0u8; 64];
+    // SAFETY: the path and name are NUL-terminated, and `label` is valid for
+    // its length.
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            c"security.SMACK64".as_ptr(),
+            label.as_mut_ptr().cast(),
+            label.len(),
+        )
+    };
+    assert_eq!(label.get(..len.max(0) as usize), Some(&b"SshAgentMux"[..]));
+}
@@ -0,0 +1,141 @@
+//! Tests of how route scripts are loaded and bounded as they run.
+
+mod common;
+
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+use ssh_agent_lib::proto::SignRequest;
+use ssh_agent_mux::config::{Config, ConfigError};
+use ssh_key::Signature;
+
+use common::{connect, key, spawn_mux, MockAgent, TestDir};
+
+fn sign_request() -> SignRequest {
+    SignRequest {
+        pubkey: key(1),
+        data: b"data".to_vec(),
+        flags: 0,
+    }
+}
+
+/// The signature a mux with the route script `body` of `route(request,
+/// targets)`, beside the functions in `extra`, makes, if it signs.
+async fn signature_with(body: &str, extra: &str) -> Option<Signature> {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let mock2 = MockAgent::new(2).with_key(key(1), "also one");
+    mock1.spawn(&dir);
+    mock2.spawn(&dir);
+    let script = format!("fn route(request, targets) {{\n{body}\n}}\n{extra}");
+    std::fs::write(dir.path("route.rhai"), script).unwrap();
+    let mux = spawn_mux(&dir, &[&mock1, &mock2], "route_script = \"route.rhai\"");
+
+    let mut client = connect(&mux).await;
+    let signature = tokio::time::timeout(Duration::from_secs(10), client.sign(sign_request()))
+        .await
+        .expect("the script wasn't stopped");
+    // The mux still answers after the script fails.
+    client.request_identities().await.unwrap();
+    signature.ok()
+}
+
+fn load_error(script: &str) -> String {
+    let dir = TestDir::new();
+    let path = dir.path("mux.toml");
+    std::fs::write(&path, "route_script = \"route.rhai\"\n").unwrap();
+    std::fs::write(dir.path("route.rhai"), script).unwrap();
+    let Err(ConfigError::Invalid(_, errors)) = Config::load(&path) else {
+        panic!("config loaded");
+    };
+    errors[0].message.clone()
+}
+
+#[tokio::test]
+async fn signs_as_scripts_decide() {
+    let first = MockAgent::new(1).signature();
+    let second = MockAgent::new(2).signature();
+
+    assert_eq!(signature_with("", "").await, Some(first.clone()));
+    assert_eq!(signature_with("true", "").await, Some(first.clone()));
+    assert_eq!(signature_with("false", "").await, None);
+    assert_eq!(signature_with("targets[1]", "").await, Some(second.clone()));
+    assert_eq!(
+        signature_with("[targets[1], targets[0]]", "").await,
+        Some(second)
+    );
+    // Rhai's own functions and closures are available.
+    assert_eq!(
+        signature_with(
+            "let picked = targets.filter(|name| name.ends_with(\"1\"));\npicked",
+            ""
+        )
+        .await,
+        Some(first)
+    );
+}
+
+#[tokio::test]
+async fn denies_signatures_of_unexpected_decisions() {
+    assert_eq!(signature_with("42", "").await, None);
+    assert_eq!(signature_with("[targets[0], 42]", "").await, None);
+    assert_eq!(signature_with("\"mock3\"", "").await, None);
+}
+
+#[tokio::test]
+async fn denies_signatures_on_script_errors() {
+    assert_eq!(signature_with("request.missing.len()", "").await, None);
+    assert_eq!(signature_with("throw \"no\";", "").await, None);
+    assert_eq!(signature_with("1 / 0", "").await, None);
+}
+
+#[tokio::test]
+async fn stops_scripts_running_too_long() {
+    assert_eq!(signature_with("loop {}", "").await, None);
+}
+
+#[tokio::test]
+async fn stops_scripts_recursing_too_deep() {
+    assert_eq!(
+        signature_with("deeper(0)", "fn deeper(n) { deeper(n + 1) }").await,
+        None
+    );
+}
+
+#[tokio::test]
+async fn stops_scripts_growing_strings_and_arrays_too_far() {
+    assert_eq!(
+        signature_with("let s = \"x\"; loop { s += s; }", "").await,
+        None
+    );
+    assert_eq!(
+        signature_with("let a = [1]; loop { a += a; }", "").await,
+        None
+    );
+}
+
+#[test]
+fn refuses_scripts_without_route() {
+    assert_eq!(
+        load_error("fn other(request, targets) {}"),
+        "no 'route' function"
+    );
+    assert_eq!(
+        load_error("fn route(request) {}"),
+        "'route' must take (request, targets)"
+    );
+}
+
+#[test]
+fn refuses_eval() {
+    let error = load_error("fn route(request, targets) { eval(\"false\") }");
+
+    assert!(error.contains("eval"), "{error}");
+}
+
+#[test]
+fn refuses_imports() {
+    let error = load_error("import \"other\" as other;\nfn route(request, targets) {}");
+
+    assert!(error.contains("import"), "{error}");
+}
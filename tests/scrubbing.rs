@@ -0,0 +1,118 @@
+//! Tests that passphrases forwarded to targets are scrubbed from memory, in
+//! their own process as every allocation the process frees is checked.
+
+mod common;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use zeroize::Zeroizing;
+
+use common::{spawn_mux, TestDir};
+
+const PASSPHRASE: &[u8] = b"correct horse battery staple";
+
+const SSH_AGENTC_LOCK: u8 = 22;
+const SSH_AGENTC_UNLOCK: u8 = 23;
+const SSH_AGENT_SUCCESS: u8 = 6;
+
+/// Counts the freed allocations still holding [`PASSPHRASE`] while
+/// [`WATCHING`].
+struct Watcher;
+
+static WATCHING: AtomicBool = AtomicBool::new(false);
+static UNSCRUBBED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Watcher {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if WATCHING.load(Ordering::Relaxed) {
+            let freed = std::slice::from_raw_parts(ptr, layout.size());
+            if freed
+                .windows(PASSPHRASE.len())
+                .any(|window| window == PASSPHRASE)
+            {
+                UNSCRUBBED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Watcher = Watcher;
+
+/// A target answering every request with success, reading them onto the
+/// stack so that it frees no copies of its own.
+fn spawn_target(dir: &TestDir) -> std::path::PathBuf {
+    let socket = dir.path("target.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut message = [0; 256];
+                loop {
+                    let mut length = [0; 4];
+                    if stream.read_exact(&mut length).await.is_err() {
+                        return;
+                    }
+                    let length = u32::from_be_bytes(length) as usize;
+                    stream.read_exact(&mut message[..length]).await.unwrap();
+                    stream
+                        .write_all(&[0, 0, 0, 1, SSH_AGENT_SUCCESS])
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    });
+    socket
+}
+
+/// Sends a lock or unlock request with [`PASSPHRASE`] from a buffer that is
+/// scrubbed once sent, returning the answer's type.
+async fn send(client: &mut UnixStream, kind: u8) -> u8 {
+    let mut request = Zeroizing::new(Vec::new());
+    request.extend((1 + 4 + PASSPHRASE.len() as u32).to_be_bytes());
+    request.push(kind);
+    request.extend((PASSPHRASE.len() as u32).to_be_bytes());
+    request.extend(PASSPHRASE);
+    client.write_all(&request).await.unwrap();
+    let mut answer = [0; 5];
+    client.read_exact(&mut answer).await.unwrap();
+    answer[4]
+}
+
+#[tokio::test]
+async fn scrubs_forwarded_passphrases() {
+    let dir = TestDir::new();
+    let target = spawn_target(&dir);
+    let mux = spawn_mux(
+        &dir,
+        &[],
+        &format!(
+            "[targets.a]\nbinding = \"unix://{}\"\n[targets.b]\nbinding = \"unix://{0}\"",
+            target.display()
+        ),
+    );
+    let mut client = UnixStream::connect(&mux).await.unwrap();
+
+    WATCHING.store(true, Ordering::Relaxed);
+    assert_eq!(send(&mut client, SSH_AGENTC_LOCK).await, SSH_AGENT_SUCCESS);
+    assert_eq!(
+        send(&mut client, SSH_AGENTC_UNLOCK).await,
+        SSH_AGENT_SUCCESS
+    );
+    drop(client);
+    // The session ends once the mux sees the client gone.
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    WATCHING.store(false, Ordering::Relaxed);
+
+    assert_eq!(UNSCRUBBED.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,270 @@
+//! Tests of the seccomp filter, on muxes run as processes of their own, as
+//! the filter applies to the whole process and can't be removed.
+
+#![cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+use ssh_agent_lib::proto::SignRequest;
+use ssh_agent_mux::admin::grpc::proto::{self, admin_client::AdminClient};
+use ssh_key::HashAlg;
+
+use common::{connect, key, load_config, MockAgent, TestDir};
+
+/// A mux process with `seccomp = "enforce"`, killed when dropped.
+struct Mux {
+    process: Child,
+    socket: PathBuf,
+}
+
+impl Mux {
+    /// Starts a mux in front of `targets` with `config` and `args`, once it
+    /// serves.
+    async fn spawn(dir: &TestDir, targets: &[&MockAgent], config: &str, args: &[&str]) -> Self {
+        let socket = dir.path("mux.sock");
+        let process = command(dir, targets, config)
+            .args(args)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut mux = Self { process, socket };
+        while !mux.socket.exists() {
+            assert!(mux.process.try_wait().unwrap().is_none(), "the mux exited");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        mux
+    }
+
+    /// Whether the mux is still running a moment after the requests made,
+    /// rather than killed by the filter.
+    async fn survives(&mut self) -> bool {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        self.process.try_wait().unwrap().is_none()
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// The error a mux in front of `targets` with `config` refuses to start
+/// with.
+fn refusal(dir: &TestDir, targets: &[&MockAgent], config: &str) -> String {
+    let output = command(dir, targets, config).output().unwrap();
+    assert!(!output.status.success());
+    String::from_utf8(output.stderr).unwrap()
+}
+
+fn sign_request(n: u8) -> SignRequest {
+    SignRequest {
+        pubkey: key(n),
+        data: b"data".to_vec(),
+        flags: 0,
+    }
+}
+
+/// The command running a mux in front of `targets` with `config`.
+fn command(dir: &TestDir, targets: &[&MockAgent], config: &str) -> Command {
+    load_config(
+        dir,
+        targets,
+        &format!(
+            "host = \"unix://{}\"\n{config}\n[sandbox]\nseccomp = \"enforce\"",
+            dir.path("mux.sock").display()
+        ),
+    );
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"));
+    command
+        .arg("--config")
+        .arg(dir.path("mux.toml"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    command
+}
+
+#[tokio::test]
+async fn replaces_the_key_cache() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let cache = dir.path("keys");
+    let mut mux = Mux::spawn(
+        &dir,
+        &[&mock1],
+        &format!("key_cache = \"{}\"", cache.display()),
+        &[],
+    )
+    .await;
+
+    let mut client = connect(&mux.socket).await;
+    client.request_identities().await.unwrap();
+
+    assert!(mux.survives().await);
+    assert!(cache.exists());
+}
+
+#[tokio::test]
+async fn counts_signatures_in_the_usage_file() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let usage = dir.path("usage");
+    let mut mux = Mux::spawn(
+        &dir,
+        &[&mock1],
+        &format!("usage_file = \"{}\"", usage.display()),
+        &[],
+    )
+    .await;
+
+    let mut client = connect(&mux.socket).await;
+    client.sign(sign_request(1)).await.unwrap();
+
+    assert!(mux.survives().await);
+    assert!(usage.exists());
+}
+
+#[tokio::test]
+async fn rotates_the_log_file() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let log = dir.path("mux.log");
+    let mut mux = Mux::spawn(
+        &dir,
+        &[&mock1],
+        "",
+        &[
+            "--log-file",
+            log.to_str().unwrap(),
+            "--log-max-size",
+            "200",
+            "-vv",
+        ],
+    )
+    .await;
+
+    let mut client = connect(&mux.socket).await;
+    for _ in 0..5 {
+        client.request_identities().await.unwrap();
+    }
+
+    assert!(mux.survives().await);
+    assert!(dir.path("mux.log.1").exists());
+}
+
+#[tokio::test]
+async fn creates_views() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let mut mux = Mux::spawn(&dir, &[&mock1], "", &[]).await;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg("--config")
+        .arg(dir.path("mux.toml"))
+        .args(["view", "create", "deploy", "--keys"])
+        .arg(key(1).fingerprint(HashAlg::Sha256).to_string())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let mut client = connect(&dir.path("mux-deploy.sock")).await;
+    assert_eq!(client.request_identities().await.unwrap().len(), 1);
+
+    assert!(mux.survives().await);
+}
+
+#[tokio::test]
+async fn resolves_targets_by_name() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    let port = mock1.spawn_tcp().await;
+    let mut mux = Mux::spawn(
+        &dir,
+        &[],
+        &format!("[targets.remote]\nbinding = \"tcp://localhost:{port}\""),
+        &[],
+    )
+    .await;
+
+    let mut client = connect(&mux.socket).await;
+    assert_eq!(client.request_identities().await.unwrap().len(), 1);
+
+    assert!(mux.survives().await);
+}
+
+#[tokio::test]
+async fn serves_the_grpc_admin_service() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let socket = dir.path("grpc.sock");
+    let mut mux = Mux::spawn(
+        &dir,
+        &[&mock1],
+        &format!("admin_grpc = \"{}\"", socket.display()),
+        &[],
+    )
+    .await;
+    // Bound after the mux's own socket.
+    while !socket.exists() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let channel = tonic::transport::Endpoint::from_static("http://localhost")
+        .connect_with_connector(tower::service_fn(move |_| {
+            let socket = socket.clone();
+            async move {
+                let stream = tokio::net::UnixStream::connect(socket).await?;
+                Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(stream))
+            }
+        }))
+        .await
+        .unwrap();
+    let keys = AdminClient::new(channel)
+        .list_keys(proto::ListKeysRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .keys;
+    assert_eq!(keys.len(), 1);
+
+    assert!(mux.survives().await);
+}
+
+#[test]
+fn refuses_to_run_approvers() {
+    let dir = TestDir::new();
+    let error = refusal(
+        &dir,
+        &[],
+        &format!(
+            "[keys.\"{}\"]\nexec = \"/bin/true\"",
+            key(1).fingerprint(HashAlg::Sha256)
+        ),
+    );
+
+    assert!(error.contains(".exec: seccomp = \"enforce\""), "{error}");
+}
+
+#[test]
+fn refuses_to_run_ssh_askpass() {
+    let dir = TestDir::new();
+    let error = refusal(&dir, &[], "anomalies = \"confirm\"");
+
+    assert!(
+        error.contains("anomalies: \"confirm\" runs SSH_ASKPASS"),
+        "{error}"
+    );
+}
@@ -0,0 +1,89 @@
+//! Tests of the signals a running mux handles, on muxes run as processes of
+//! their own.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{ChildStderr, Command};
+
+use common::{connect, key, load_config, MockAgent, TestDir};
+
+/// Reads records until one contains `needle`, returning those before it.
+async fn read_until(records: &mut Lines<BufReader<ChildStderr>>, needle: &str) -> Vec<String> {
+    let mut before = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let record = records.next_line().await.unwrap().expect(needle);
+            if record.contains(needle) {
+                return;
+            }
+            before.push(record);
+        }
+    })
+    .await
+    .expect(needle);
+    before
+}
+
+#[tokio::test]
+async fn toggles_debug_logging_on_sigusr2() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    let socket = dir.path("mux.sock");
+    load_config(
+        &dir,
+        &[&mock1],
+        &format!("host = \"unix://{}\"", socket.display()),
+    );
+    let mut mux = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg("--config")
+        .arg(dir.path("mux.toml"))
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut records = BufReader::new(mux.stderr.take().unwrap()).lines();
+    while !socket.exists() {
+        assert!(mux.try_wait().unwrap().is_none(), "the mux exited");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let pid = mux.id().unwrap() as libc::pid_t;
+    let sigusr2 = || {
+        // SAFETY: kill has no memory safety requirements.
+        assert_eq!(unsafe { libc::kill(pid, libc::SIGUSR2) }, 0);
+    };
+    let mut client = connect(&socket).await;
+
+    client.request_identities().await.unwrap();
+    sigusr2();
+    let before = read_until(&mut records, "Debug logging on").await;
+    assert!(
+        before.iter().all(|record| !record.contains("Request:")),
+        "{before:?}"
+    );
+    client.request_identities().await.unwrap();
+    read_until(&mut records, "Request: RequestIdentities").await;
+
+    sigusr2();
+    read_until(&mut records, "Debug logging off").await;
+    client.request_identities().await.unwrap();
+    mux.kill().await.unwrap();
+    let mut after = Vec::new();
+    while let Some(record) = records.next_line().await.unwrap() {
+        after.push(record);
+    }
+    assert!(
+        after.iter().all(|record| !record.contains("Request:")),
+        "{after:?}"
+    );
+}
@@ -0,0 +1,53 @@
+//! Tests of serving a session on stdin and stdout, on a mux run as a
+//! process of its own over a pair of pipes.
+
+mod common;
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+use ssh_agent_lib::client::Client;
+use ssh_agent_lib::proto::SignRequest;
+use tokio::process::Command;
+
+use common::{key, load_config, MockAgent, TestDir};
+
+#[tokio::test]
+async fn serves_a_session_on_stdio_until_it_ends() {
+    let dir = TestDir::new();
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(&dir);
+    load_config(&dir, &[&mock1], "host = \"stdio://\"");
+    let mut mux = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg("--config")
+        .arg(dir.path("mux.toml"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let pipes = tokio::io::join(mux.stdout.take().unwrap(), mux.stdin.take().unwrap());
+    let mut client = Client::new(pipes);
+
+    let identities = client.request_identities().await.unwrap();
+    let signature = client
+        .sign(SignRequest {
+            pubkey: key(1),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].comment, "one");
+    assert_eq!(signature, mock1.signature());
+    // Closing stdin ends the session, and the mux with it.
+    drop(client);
+    let status = tokio::time::timeout(Duration::from_secs(5), mux.wait())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(status.success());
+}
@@ -0,0 +1,129 @@
+//! Tests of how logs are shown on a terminal and elsewhere, on muxes run as
+//! processes of their own with stderr on a pseudo-terminal or a pipe.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+use ssh_agent_lib::proto::SignRequest;
+
+use common::{connect, key, load_config, MockAgent, TestDir};
+
+/// A pseudo-terminal, as its controlling side and the terminal itself.
+fn pseudo_terminal() -> (File, OwnedFd) {
+    let (mut controller, mut terminal) = (-1, -1);
+    // SAFETY: the descriptors are written on success, and the rest may be
+    // null.
+    let result = unsafe {
+        libc::openpty(
+            &mut controller,
+            &mut terminal,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(result, 0, "{}", std::io::Error::last_os_error());
+    // SAFETY: openpty opened both, and nothing else owns them.
+    unsafe {
+        (
+            File::from_raw_fd(controller),
+            OwnedFd::from_raw_fd(terminal),
+        )
+    }
+}
+
+/// Runs a mux with `-v` and stderr on `stderr`, and kills it once it has
+/// signed a request.
+async fn sign_and_kill(dir: &TestDir, stderr: Stdio) -> std::process::Child {
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(dir);
+    let socket = dir.path("mux.sock");
+    load_config(
+        dir,
+        &[&mock1],
+        &format!("host = \"unix://{}\"", socket.display()),
+    );
+    let mut mux = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"))
+        .arg("--config")
+        .arg(dir.path("mux.toml"))
+        .arg("-v")
+        .env_remove("RUST_LOG")
+        .env_remove("NO_COLOR")
+        .env("TERM", "xterm")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(stderr)
+        .spawn()
+        .unwrap();
+    while !socket.exists() {
+        assert!(mux.try_wait().unwrap().is_none(), "the mux exited");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    connect(&socket)
+        .await
+        .sign(SignRequest {
+            pubkey: key(1),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .await
+        .unwrap();
+    mux.kill().unwrap();
+    mux
+}
+
+#[tokio::test]
+async fn shows_columns_in_color_and_summaries_on_a_terminal() {
+    let dir = TestDir::new();
+    let (mut controller, terminal) = pseudo_terminal();
+
+    let mut mux = sign_and_kill(&dir, terminal.into()).await;
+    mux.wait().unwrap();
+    let mut output = Vec::new();
+    // Reading fails rather than ending once the mux has closed the terminal.
+    let _ = controller.read_to_end(&mut output);
+    let output = String::from_utf8_lossy(&output);
+
+    let line = output
+        .lines()
+        .find(|line| line.contains("sign request "))
+        .expect(&output);
+    assert!(line.contains("\x1b["), "{line:?}");
+    assert!(line.contains("mux       "), "{line:?}");
+    assert!(!line.contains("ssh_agent_mux::mux"), "{line:?}");
+    let fingerprint = key(1).fingerprint(ssh_key::HashAlg::Sha256).to_string();
+    assert!(
+        line.contains(&format!("sign request {}, 4 bytes", &fingerprint[..15])),
+        "{line:?}"
+    );
+    assert!(!line.contains(&fingerprint), "{line:?}");
+}
+
+#[tokio::test]
+async fn shows_plain_records_in_full_elsewhere() {
+    let dir = TestDir::new();
+
+    let mux = sign_and_kill(&dir, Stdio::piped()).await;
+    let output = mux.wait_with_output().unwrap();
+    let output = String::from_utf8(output.stderr).unwrap();
+
+    let line = output
+        .lines()
+        .find(|line| line.contains("sign request "))
+        .expect(&output);
+    assert!(!line.contains('\x1b'), "{line:?}");
+    assert!(line.contains(" ssh_agent_mux::mux]"), "{line:?}");
+    assert!(
+        line.contains("sign request SignRequest { pubkey:"),
+        "{line:?}"
+    );
+}
@@ -0,0 +1,165 @@
+//! End-to-end tests of key usage counting and reporting, in their own
+//! process as the usage file and audit log are kept for the whole process.
+
+mod common;
+
+use ssh_agent_lib::{agent::Session, proto::SignRequest};
+use ssh_agent_mux::config::KeyConfig;
+use ssh_agent_mux::metrics::{self, KeyUsage};
+use ssh_agent_mux::report::{Report, Usage};
+use ssh_agent_mux::{audit, export, logging};
+use ssh_key::HashAlg;
+
+use common::{connect, key, spawn_mux_until, MockAgent, TestDir};
+
+#[tokio::test]
+async fn counts_signatures_per_key_on_top_of_the_usage_file() {
+    let dir = TestDir::new();
+    let path = dir.path("usage");
+    let fingerprint = key(1).fingerprint(HashAlg::Sha256).to_string();
+    std::fs::write(
+        &path,
+        format!("# fingerprint signatures last_used\n{fingerprint} 5 100\n"),
+    )
+    .unwrap();
+    metrics::persist_key_usage(&path).unwrap();
+    let mock = MockAgent::new(1).with_key(key(1), "one");
+    mock.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock], "", futures::future::pending());
+
+    let mut client = connect(&mux.socket).await;
+    for _ in 0..2 {
+        client
+            .sign(SignRequest {
+                pubkey: key(1),
+                data: b"data".to_vec(),
+                flags: 0,
+            })
+            .await
+            .unwrap();
+    }
+
+    let usage = metrics::read_key_usage(&path).unwrap();
+    let KeyUsage {
+        signatures,
+        last_used,
+    } = usage[&fingerprint];
+    assert_eq!(signatures, 7);
+    assert!(last_used > 100);
+    let state = mux.state.to_string();
+    assert!(
+        state.contains(&format!("\n  {fingerprint}: 7 signatures, last")),
+        "{state}"
+    );
+}
+
+#[tokio::test]
+async fn appends_signatures_with_their_client_to_the_audit_log() {
+    let dir = TestDir::new();
+    let path = dir.path("audit.log");
+    audit::open_log(&path).unwrap();
+    let mock = MockAgent::new(2).with_key(key(2), "two");
+    mock.spawn(&dir);
+    let mux = spawn_mux_until(&dir, &[&mock], "", futures::future::pending());
+
+    connect(&mux.socket)
+        .await
+        .sign(SignRequest {
+            pubkey: key(2),
+            data: b"data".to_vec(),
+            flags: 0,
+        })
+        .await
+        .unwrap();
+
+    let fingerprint = key(2).fingerprint(HashAlg::Sha256).to_string();
+    // SAFETY: getuid always succeeds.
+    let client = format!("uid {}", unsafe { libc::getuid() });
+    let log = std::fs::read_to_string(&path).unwrap();
+    let report = Report::new(&log, 0).unwrap();
+    assert_eq!(report.keys[&fingerprint].signatures, 1);
+    assert_eq!(report.targets["mock2"].signatures, 1);
+    assert_eq!(report.clients[&client].signatures, 1);
+}
+
+#[test]
+fn reports_usage_by_key_target_and_client_since_a_time() {
+    let log = r#"{"event":"key_used","time":100,"key":"SHA256:a","target":"yubikey","client":"uid 1000"}
+{"event":"key_used","time":200,"key":"SHA256:a","target":"yubikey","client":"uid 1000"}
+{"event":"target_down","time":250,"target":"yubikey","error":"gone"}
+{"event":"key_used","time":300,"key":"SHA256:b","target":"vault","client":"192.0.2.1"}
+{"event":"sign_denied","time":400,"key":"SHA256:a","reason":"denied by policy","client":"192.0.2.1"}
+{"event":"key_used","time":500,"key":"SHA256:b","target":"vault"}
+"#;
+
+    let report = Report::new(log, 200).unwrap();
+
+    let usage = |signatures, denied, last_seen| Usage {
+        signatures,
+        denied,
+        last_seen,
+    };
+    assert_eq!(report.total, usage(3, 1, 500));
+    assert_eq!(report.keys["SHA256:a"], usage(1, 1, 400));
+    assert_eq!(report.keys["SHA256:b"], usage(2, 0, 500));
+    assert_eq!(report.targets.len(), 2);
+    assert_eq!(report.targets["yubikey"], usage(1, 0, 200));
+    assert_eq!(report.clients["192.0.2.1"], usage(1, 1, 400));
+    assert_eq!(report.clients["unknown"], usage(1, 0, 500));
+    assert!(report.to_json().starts_with(
+        r#"{"since":200,"signatures":3,"denied":1,"keys":{"SHA256:a":{"signatures":1,"denied":1,"last_seen":400},"#
+    ));
+    assert!(report
+        .to_string()
+        .contains("Clients:\n  192.0.2.1: 1 signatures, 1 denied, last 1970-01-01T00:06:40Z\n"));
+
+    let error = Report::new(&format!("{log}not json\n"), 0).unwrap_err();
+    assert_eq!(error.line, 7);
+}
+
+#[test]
+fn exports_key_usage_with_nicknames() {
+    let fingerprint = key(9).fingerprint(HashAlg::Sha256);
+    logging::set_nicknames(&[KeyConfig {
+        fingerprint,
+        nickname: Some("deploy \"prod\"".to_owned()),
+        max_signatures_per_hour: None,
+        cooldown: None,
+        allowed_host_keys: Vec::new(),
+        require_session_bind: false,
+        allowed_times: Vec::new(),
+        exec: None,
+    }]);
+    let fingerprint = fingerprint.to_string();
+    let usage = [
+        (
+            fingerprint.clone(),
+            Some(KeyUsage {
+                signatures: 3,
+                last_used: 1760000000,
+            }),
+        ),
+        ("SHA256:unused".to_owned(), None),
+    ];
+
+    let exported: serde_json::Value =
+        serde_json::from_str(&export::key_usage_json(&usage)).unwrap();
+
+    assert_eq!(
+        exported,
+        serde_json::json!([
+            {
+                "fingerprint": fingerprint,
+                "nickname": "deploy \"prod\"",
+                "signatures": 3,
+                "last_used": 1760000000,
+            },
+            {
+                "fingerprint": "SHA256:unused",
+                "nickname": null,
+                "signatures": 0,
+                "last_used": null,
+            },
+        ])
+    );
+}
@@ -0,0 +1,98 @@
+//! Tests of `-v` and `-q`, on muxes run as processes of their own as the
+//! levels are set for the whole process.
+
+mod common;
+
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use ssh_agent_lib::agent::Session;
+
+use common::{connect, key, load_config, MockAgent, TestDir};
+
+/// The command running a mux in front of a target with `args`, with
+/// `RUST_LOG` set to `rust_log`, or unset.
+fn command(dir: &TestDir, args: &[&str], rust_log: Option<&str>) -> Command {
+    let mock1 = MockAgent::new(1).with_key(key(1), "one");
+    mock1.spawn(dir);
+    load_config(
+        dir,
+        &[&mock1],
+        &format!("host = \"unix://{}\"", dir.path("mux.sock").display()),
+    );
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ssh-agent-mux"));
+    command
+        .arg("--config")
+        .arg(dir.path("mux.toml"))
+        .args(args)
+        .env_remove("SSH_AGENT_MUX_VERBOSE")
+        .env_remove("SSH_AGENT_MUX_QUIET")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    match rust_log {
+        Some(rust_log) => command.env("RUST_LOG", rust_log),
+        None => command.env_remove("RUST_LOG"),
+    };
+    command
+}
+
+/// The levels of the records a mux run with `args` logs while listing
+/// identities.
+async fn levels(args: &[&str], rust_log: Option<&str>) -> Vec<String> {
+    let dir = TestDir::new();
+    let socket = dir.path("mux.sock");
+    let mut mux = command(&dir, args, rust_log).spawn().unwrap();
+    while !socket.exists() {
+        assert!(mux.try_wait().unwrap().is_none(), "the mux exited");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    connect(&socket).await.request_identities().await.unwrap();
+    mux.kill().unwrap();
+    let output = mux.wait_with_output().unwrap();
+    let mut levels: Vec<String> = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .filter_map(|line| {
+            Some(
+                line.strip_prefix('[')?
+                    .split_whitespace()
+                    .nth(1)?
+                    .to_owned(),
+            )
+        })
+        .collect();
+    levels.sort();
+    levels.dedup();
+    levels
+}
+
+#[tokio::test]
+async fn logs_errors_only_by_default() {
+    assert_eq!(levels(&[], None).await, Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn logs_more_for_each_v() {
+    assert_eq!(levels(&["-v"], None).await, ["INFO"]);
+    assert_eq!(levels(&["-vv"], None).await, ["DEBUG", "INFO"]);
+    assert!(levels(&["-vvv"], None).await.contains(&"TRACE".to_owned()));
+}
+
+#[tokio::test]
+async fn sets_the_level_over_rust_log() {
+    assert_eq!(levels(&["-v"], Some("debug")).await, ["INFO"]);
+    assert_eq!(levels(&["-q"], Some("info")).await, Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn refuses_to_be_verbose_and_quiet() {
+    let dir = TestDir::new();
+
+    let output = command(&dir, &["-v", "-q"], None).output().unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+}
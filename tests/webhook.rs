@@ -0,0 +1,133 @@
+//! Tests of posting events to a webhook, against a local listener.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use ssh_agent_mux::config::WebhookConfig;
+use ssh_agent_mux::webhook::Webhook;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// A request the webhook received.
+#[derive(Debug)]
+struct Posted {
+    request_line: String,
+    /// Lowercased names and their values.
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Posted {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Receives the requests posted to the returned URL, answering each with
+/// the next of `statuses`, then with 204.
+async fn spawn_receiver(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<Posted>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, posted) = mpsc::unbounded_channel();
+    let mut statuses = statuses.into_iter();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let (head, body_start) = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "the request ended early");
+                request.extend_from_slice(&buf[..n]);
+                if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break (String::from_utf8(request[..i].to_vec()).unwrap(), i + 4);
+                }
+            };
+            let mut lines = head.lines();
+            let request_line = lines.next().unwrap().to_owned();
+            let headers: Vec<_> = lines
+                .map(|line| {
+                    let (name, value) = line.split_once(':').unwrap();
+                    (name.to_lowercase(), value.trim().to_owned())
+                })
+                .collect();
+            let length: usize = headers
+                .iter()
+                .find(|(name, _)| name == "content-length")
+                .map(|(_, value)| value.parse().unwrap())
+                .unwrap();
+            while request.len() < body_start + length {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = String::from_utf8(request[body_start..].to_vec()).unwrap();
+            let status = statuses.next().unwrap_or(204);
+            stream
+                .write_all(
+                    format!("HTTP/1.1 {status} Status\r\nContent-Length: 0\r\n\r\n").as_bytes(),
+                )
+                .await
+                .unwrap();
+            sender
+                .send(Posted {
+                    request_line,
+                    headers,
+                    body,
+                })
+                .unwrap();
+        }
+    });
+    (url, posted)
+}
+
+fn webhook(url: &str, secret: Option<&str>, retries: u32) -> &'static Webhook {
+    let webhook = Webhook::new(&WebhookConfig {
+        url: url.parse().unwrap(),
+        secret: secret.map(str::to_owned),
+        retries,
+    })
+    .unwrap();
+    Box::leak(Box::new(webhook))
+}
+
+#[tokio::test]
+async fn posts_events_signed_with_the_secret() {
+    let (url, mut posted) = spawn_receiver(Vec::new()).await;
+    let body = r#"{"event":"key_used","time":1760000000}"#;
+
+    webhook(&url, Some("hunter2"), 0).post("key_used", body.to_owned());
+
+    let posted = posted.recv().await.unwrap();
+    assert_eq!(posted.request_line, "POST /hook HTTP/1.1");
+    assert_eq!(posted.body, body);
+    assert_eq!(posted.header("content-type"), Some("application/json"));
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"hunter2").unwrap();
+    mac.update(body.as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    assert_eq!(
+        posted.header("x-signature-256"),
+        Some(format!("sha256={signature}").as_str())
+    );
+}
+
+#[tokio::test]
+async fn retries_events_the_webhook_fails_to_take() {
+    let (url, mut posted) = spawn_receiver(vec![500]).await;
+    let body = r#"{"event":"sign_denied","time":1760000000}"#;
+
+    webhook(&url, None, 1).post("sign_denied", body.to_owned());
+
+    let first = posted.recv().await.unwrap();
+    assert_eq!(first.body, body);
+    assert_eq!(first.header("x-signature-256"), None);
+    let retried = posted.recv().await.unwrap();
+    assert_eq!(retried.body, body);
+}